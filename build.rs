@@ -0,0 +1,27 @@
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .unwrap_or_else(|| String::from("unknown"));
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=BUILD_TARGET={}", env::var("TARGET").unwrap());
+    println!("cargo:rustc-env=BUILD_PROFILE={}", env::var("PROFILE").unwrap());
+    // a release build names the public key file (see keygen) its releases are
+    // signed with, for self-update to check downloads against
+    if let Ok(file) = env::var("BACKUP_AUDITOR_RELEASE_KEY") {
+        let text = std::fs::read_to_string(&file).unwrap_or_else(|e| panic!("BACKUP_AUDITOR_RELEASE_KEY {:?}: {}", file, e));
+        let key = text.lines().nth(1).unwrap_or_else(|| panic!("BACKUP_AUDITOR_RELEASE_KEY {:?} is not a public key file", file));
+        println!("cargo:rustc-env=BUILD_RELEASE_KEY={}", key.trim());
+        println!("cargo:rerun-if-changed={}", file);
+    }
+    println!("cargo:rerun-if-env-changed=BACKUP_AUDITOR_RELEASE_KEY");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+}
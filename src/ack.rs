@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+// Findings someone has looked at and accepted as permanent, keyed by kind and
+// path relative to its root, or by finding ID under the pseudo-kind "id".
+// One "KIND<tab>PATH<tab>NOTE" line each, so the file can be reviewed and
+// edited by hand.
+pub const ID: &str = "id";
+
+#[derive(Default)]
+pub struct Acks(BTreeMap<(String, String), String>);
+
+impl Acks {
+    pub fn load(path: &Path) -> io::Result<Acks> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Acks::default()),
+            Err(e) => return Err(e),
+        };
+        let mut acks = BTreeMap::new();
+        for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.is_empty() && !l.starts_with('#')) {
+            let mut fields = line.splitn(3, '\t');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(kind), Some(path), note) => {
+                    acks.insert((kind.to_string(), path.to_string()), note.unwrap_or_default().to_string());
+                }
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("line {}: expected KIND<tab>PATH<tab>NOTE", n + 1))),
+            }
+        }
+        Ok(Acks(acks))
+    }
+
+    pub fn note(&self, kind: &str, rel_path: &str, id: &str) -> Option<&str> {
+        self.0
+            .get(&(kind.to_string(), rel_path.to_string()))
+            .or_else(|| self.0.get(&(ID.to_string(), id.to_string())))
+            .map(String::as_str)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &str, &str)> {
+        self.0.iter().map(|((kind, path), note)| (kind.as_str(), path.as_str(), note.as_str()))
+    }
+}
+
+// The form paths are acknowledged in: relative to the root, '/' separated.
+pub fn relative_key(root: &str, path: &str) -> Option<String> {
+    let rel = Path::new(path).strip_prefix(root).ok()?;
+    Some(rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"))
+}
+
+// Returns false if the finding was already acknowledged.
+pub fn add(file: &Path, kind: &str, rel_path: &str, note: &str) -> io::Result<bool> {
+    if Acks::load(file)?.0.contains_key(&(kind.to_string(), rel_path.to_string())) {
+        return Ok(false);
+    }
+    if rel_path.contains(['\t', '\n']) || note.contains('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "paths with tabs or newlines can't be acknowledged"));
+    }
+    let mut out = OpenOptions::new().append(true).create(true).open(file)?;
+    writeln!(out, "{}\t{}\t{}", kind, rel_path, note.replace('\t', " "))?;
+    Ok(true)
+}
@@ -0,0 +1,67 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+// "0-3,8,10-11" as used by taskset and /sys/devices/system/node/*/cpulist.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for part in s.trim().split(',').filter(|p| !p.is_empty()) {
+        let parse = |n: &str| n.trim().parse::<usize>().map_err(|_| format!("{:?} is not a CPU number", n));
+        match part.split_once('-') {
+            Some((lo, hi)) => {
+                let (lo, hi) = (parse(lo)?, parse(hi)?);
+                if lo > hi {
+                    return Err(format!("{:?} is not an ascending range", part));
+                }
+                cpus.extend(lo..=hi)
+            }
+            None => cpus.push(parse(part)?),
+        }
+    }
+    if cpus.is_empty() {
+        return Err(String::from("no CPUs given"));
+    }
+    if let Some(cpu) = cpus.iter().find(|c| **c >= libc::CPU_SETSIZE as usize) {
+        return Err(format!("CPU {} is out of range", cpu));
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+// The NUMA node of the controller behind the block device holding `root`:
+// the nearest ancestor of its sysfs device with a numa_node file. Network and
+// virtual filesystems (NFS, device mapper) have none.
+pub fn numa_node(root: &Path) -> Option<u32> {
+    let dev = fs::metadata(root).ok()?.dev();
+    let mut dir: PathBuf = fs::canonicalize(format!("/sys/dev/block/{}:{}", libc::major(dev), libc::minor(dev))).ok()?;
+    loop {
+        if let Ok(node) = fs::read_to_string(dir.join("numa_node")) {
+            // -1 when the platform doesn't report one
+            return node.trim().parse().ok();
+        }
+        if !dir.pop() || dir == Path::new("/sys/devices") {
+            return None;
+        }
+    }
+}
+
+pub fn node_cpus(node: u32) -> io::Result<Vec<usize>> {
+    let list = fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node))?;
+    parse_cpu_list(&list).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn pin_current_thread(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        for cpu in cpus {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,123 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+const FS_APPEND_FL: libc::c_int = 0x0000_0020;
+const FS_IMMUTABLE_FL: libc::c_int = 0x0000_0010;
+
+const XATTR_CAPABILITY: &str = "security.capability";
+const XATTR_SELINUX: &str = "security.selinux";
+
+pub struct AttrDiff {
+    pub field: &'static str,
+    pub src_value: String,
+    pub tgt_value: String,
+}
+
+// Only the flags that change behaviour after a restore are compared; others
+// such as extents or compression depend on the filesystem, not the backup.
+pub fn inode_flags(file: &File) -> io::Result<libc::c_int> {
+    let mut flags: libc::c_int = 0;
+    let r = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags & (FS_APPEND_FL | FS_IMMUTABLE_FL))
+}
+
+pub fn xattr(file: &File, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let cname = std::ffi::CString::new(name).unwrap();
+    let fd = file.as_raw_fd();
+    loop {
+        let size = unsafe { libc::fgetxattr(fd, cname.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::ENODATA) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let mut buf = vec![0u8; size as usize];
+        let read = unsafe { libc::fgetxattr(fd, cname.as_ptr(), buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if read < 0 {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                // grew between the two calls, size it again
+                Some(libc::ERANGE) => continue,
+                Some(libc::ENODATA) => return Ok(None),
+                _ => return Err(e),
+            }
+        }
+        buf.truncate(read as usize);
+        return Ok(Some(buf));
+    }
+}
+
+fn describe_flags(flags: libc::c_int) -> String {
+    let mut names = Vec::new();
+    if flags & FS_IMMUTABLE_FL != 0 {
+        names.push("immutable");
+    }
+    if flags & FS_APPEND_FL != 0 {
+        names.push("append-only");
+    }
+    if names.is_empty() {
+        String::from("-")
+    } else {
+        names.join(",")
+    }
+}
+
+fn describe_xattr(value: &io::Result<Option<Vec<u8>>>) -> String {
+    match value {
+        Ok(Some(v)) => crate::report::to_hex(v),
+        Ok(None) => String::from("-"),
+        Err(e) => format!("unavailable ({})", e),
+    }
+}
+
+fn differs<T: PartialEq>(src: &io::Result<T>, tgt: &io::Result<T>) -> bool {
+    match (src, tgt) {
+        (Ok(s), Ok(t)) => s != t,
+        // the filesystem doesn't support it on either side, nothing to report
+        (Err(_), Err(_)) => false,
+        _ => true,
+    }
+}
+
+pub fn compare_attrs(src: &File, tgt: &File) -> Vec<AttrDiff> {
+    let mut diffs = Vec::new();
+
+    let src_flags = inode_flags(src);
+    let tgt_flags = inode_flags(tgt);
+    if differs(&src_flags, &tgt_flags) {
+        let describe = |f: &io::Result<libc::c_int>| match f {
+            Ok(f) => describe_flags(*f),
+            Err(e) => format!("unavailable ({})", e),
+        };
+        diffs.push(AttrDiff { field: "flags", src_value: describe(&src_flags), tgt_value: describe(&tgt_flags) });
+    }
+
+    let src_caps = xattr(src, XATTR_CAPABILITY);
+    let tgt_caps = xattr(tgt, XATTR_CAPABILITY);
+    if differs(&src_caps, &tgt_caps) {
+        diffs.push(AttrDiff { field: "capabilities", src_value: describe_xattr(&src_caps), tgt_value: describe_xattr(&tgt_caps) });
+    }
+
+    diffs
+}
+
+pub fn compare_selinux(src: &File, tgt: &File) -> Option<AttrDiff> {
+    let src_label = xattr(src, XATTR_SELINUX);
+    let tgt_label = xattr(tgt, XATTR_SELINUX);
+    if !differs(&src_label, &tgt_label) {
+        return None;
+    }
+    // labels are NUL terminated strings like "system_u:object_r:etc_t:s0"
+    let describe = |l: &io::Result<Option<Vec<u8>>>| match l {
+        Ok(Some(v)) => String::from_utf8_lossy(v).trim_end_matches('\0').to_string(),
+        Ok(None) => String::from("-"),
+        Err(e) => format!("unavailable ({})", e),
+    };
+    Some(AttrDiff { field: "selinux context", src_value: describe(&src_label), tgt_value: describe(&tgt_label) })
+}
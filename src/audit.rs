@@ -0,0 +1,1231 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use jwalk::{Parallelism, WalkDirGeneric};
+use rayon::prelude::*;
+#[cfg(target_os = "linux")]
+use crate::{attrs, extents};
+use crate::backuplog::JobStatus;
+use crate::chargeback::{self, GroupBy, Ledger};
+use crate::damage;
+use crate::encoding;
+use crate::filter::{self, SkipReason, WalkFilter};
+use crate::fsstat::{self, FsStats, Threshold};
+use crate::hash;
+use crate::index;
+use crate::manifest;
+use crate::remote::Remote;
+use crate::repair;
+use crate::report::{Finding, Report, ReportOptions, RunInfo, Tally};
+use crate::rules;
+use crate::skiplist;
+use crate::strategy::{Method, Strategy};
+
+// Entries carry the reason they were not descended into, if any.
+pub type Walk = WalkDirGeneric<((), Option<SkipReason>)>;
+// Target entries carry whether nothing exists at their path in the source.
+pub type OrphanWalk = WalkDirGeneric<((), bool)>;
+
+// What was being done when a Finding::Error was raised. Only listing happens
+// on the source alone, the others read both sides.
+pub const LISTING: &str = "listing the directory";
+const READING_METADATA: &str = "reading metadata";
+const READING_CONTENT: &str = "reading content";
+
+pub struct CompareOptions {
+    pub check_attrs: bool,
+    pub check_selinux: bool,
+    pub detect_clones: bool,
+    pub budget: hash::Budget,
+    pub hashing: hash::HashSpec,
+    pub rules: rules::Rules,
+    pub target_index: Option<Arc<index::TargetIndex>>,
+    pub verify_etags: bool,
+    // a remote target asked for the checksum it keeps of each object, which
+    // is compared instead of downloading or going by the ETag when it has one
+    pub checksums: Option<Arc<dyn Remote>>,
+    // a remote target whose objects are downloaded and hashed, when sizes
+    // and ETags from its listing aren't enough
+    pub remote: Option<Arc<dyn Remote>>,
+    pub quick: bool,
+    pub verify_on_match: bool,
+    pub check_metadata: bool,
+    // of METADATA_FIELDS, those --check-metadata compares
+    pub metadata_fields: Vec<&'static str>,
+    pub mtime_tolerance: u64,
+    pub follow_symlinks: bool,
+    pub storage_efficiency: bool,
+    pub dir_counts: bool,
+    pub baseline: Option<Arc<manifest::Baseline>>,
+    pub classify_damage: bool,
+    pub hard_links: HardLinks,
+    // compare where identical files hold data and where they have holes
+    pub check_sparse: bool,
+    // note how text files whose content differs are stored on each side
+    pub check_encoding: bool,
+    // picks quick, sampled or full verification per file instead of
+    // `quick` and `hashing.sample` deciding for all of them
+    pub strategy: Option<Arc<Strategy>>,
+}
+
+// (device, inode) and, for a sampled digest, the sample's block and stride
+type DigestKey = ((u64, u64), Option<(u64, u64)>);
+
+// What the audit remembers of inodes with more than one name, by (device,
+// inode): their digests, so another name of one isn't read again, and with
+// --check-hard-links the first pairing of each side's, to tell whether the
+// target links the same files together as the source.
+#[derive(Default)]
+pub struct HardLinks {
+    // kept apart by how they were hashed, since --adaptive can sample one
+    // name of an inode and read another in full
+    digests: Mutex<HashMap<DigestKey, hash::Digests>>,
+    check: bool,
+    pairs: Mutex<LinkPairs>,
+}
+
+#[derive(Default)]
+struct LinkPairs {
+    // a linked inode to the other side's inode and the source path first
+    // seen with it
+    by_src: HashMap<(u64, u64), ((u64, u64), String)>,
+    by_tgt: HashMap<(u64, u64), ((u64, u64), String)>,
+}
+
+impl HardLinks {
+    pub fn new(check: bool) -> HardLinks {
+        HardLinks { check, ..HardLinks::default() }
+    }
+
+    fn known(&self, links: [Option<(u64, u64)>; 2], hashing: &hash::HashSpec) -> [Option<hash::Digests>; 2] {
+        let digests = self.digests.lock().unwrap();
+        let how = hashing.sample.map(|s| (s.block, s.stride));
+        links.map(|id| id.and_then(|id| digests.get(&(id, how)).cloned()))
+    }
+
+    fn remember(&self, links: [Option<(u64, u64)>; 2], hashing: &hash::HashSpec, hashed: [&hash::Digests; 2]) {
+        let mut digests = self.digests.lock().unwrap();
+        let how = hashing.sample.map(|s| (s.block, s.stride));
+        for (id, d) in links.into_iter().zip(hashed) {
+            if let Some(id) = id {
+                digests.entry((id, how)).or_insert_with(|| d.clone());
+            }
+        }
+    }
+
+    // A finding for a pair whose linked side's earlier pairing disagrees:
+    // the source's links split into separate target files, or the target
+    // linking files the source keeps apart.
+    fn check(&self, src_path: &str, src_meta: &fs::Metadata, tgt_path: &str, tgt_meta: &fs::Metadata) -> Option<Finding> {
+        if !self.check {
+            return None;
+        }
+        let (src_inode, tgt_inode) = (inode_identity(src_meta)?, inode_identity(tgt_meta)?);
+        let mut pairs = self.pairs.lock().unwrap();
+        let mismatch = |check, other: &String| Finding::HardLinkMismatch { src: src_path.to_string(), tgt: tgt_path.to_string(), other: other.clone(), check };
+        if hard_link_identity(src_meta).is_some() {
+            let (paired, first) = pairs.by_src.entry(src_inode).or_insert_with(|| (tgt_inode, src_path.to_string()));
+            if *paired != tgt_inode {
+                return Some(mismatch("split", first));
+            }
+        }
+        if hard_link_identity(tgt_meta).is_some() {
+            let (paired, first) = pairs.by_tgt.entry(tgt_inode).or_insert_with(|| (src_inode, src_path.to_string()));
+            if *paired != src_inode {
+                return Some(mismatch("merged", first));
+            }
+        }
+        None
+    }
+}
+
+pub struct AuditConfig {
+    pub source_dir: String,
+    pub target_dir: String,
+    pub output_file: String,
+    pub report: ReportOptions,
+    // written to the chain-of-custody header
+    pub command_line: Vec<String>,
+    pub compare: CompareOptions,
+    pub filter: Arc<WalkFilter>,
+    pub bidirectional: bool,
+    pub min_free_space: Option<Threshold>,
+    pub inject_findings: u64,
+    // collect a repair::Action per finding that has one, for AuditSummary
+    pub plan_repairs: bool,
+    // the backup job's log, to check what it claims against the findings
+    pub job_log: Option<(String, JobStatus)>,
+    // threads reading directories in each walk; 0 for one per CPU
+    pub walk_threads: usize,
+    // total up entries and findings per owner or project, for AuditSummary
+    pub chargeback: Option<GroupBy>,
+    // collect the source paths missing in the target, for AuditSummary
+    pub collect_missing: bool,
+}
+
+// What an audit reports as it goes. Every entry the walk yields gets a
+// Checked, after a Checking if its pair was opened; findings arrive as they
+// are recorded, from whichever thread found them.
+pub enum AuditEvent<'a> {
+    Counted { files: u64, bytes: u64 },
+    Checking { src: &'a str, tgt: &'a str },
+    // `compared` is false for skipped entries and pairs that couldn't be opened
+    Checked { src: &'a str, bytes: u64, compared: bool },
+    Finding { finding: &'a Finding, id: &'a str },
+}
+
+pub struct AuditSummary {
+    pub files_verified: u64,
+    pub bytes_verified: u64,
+    pub files_not_verified: u64,
+    pub findings: BTreeMap<&'static str, u64>,
+    pub tally: Tally,
+    // entries only in the target, when the target was walked
+    pub orphans: Option<u64>,
+    pub stopped_early: bool,
+    // source files that exist but couldn't be read, sorted
+    pub unreadable_source: Vec<String>,
+    // with plan_repairs, in target path order so directories come before
+    // what goes into them
+    pub repairs: Vec<repair::Action>,
+    // with chargeback, by owner or project
+    pub chargeback: BTreeMap<String, chargeback::Row>,
+    // with collect_missing, unsorted
+    pub missing_in_target: Vec<String>,
+}
+
+type Handler = Arc<dyn Fn(&AuditEvent) + Send + Sync>;
+
+// One audit of `source_dir` against `target_dir`, written to one report.
+// run() does all of it; the CLI calls the steps on their own to set up its
+// progress display in between, or check() per file in watch and log modes.
+pub struct Auditor {
+    source_dir: String,
+    target_dir: String,
+    compare: CompareOptions,
+    filter: Arc<WalkFilter>,
+    bidirectional: bool,
+    min_free_space: Option<Threshold>,
+    low_target: Mutex<Option<FsStats>>,
+    inject_findings: u64,
+    report: Arc<Report>,
+    handler: Arc<RwLock<Handler>>,
+    orphans: Mutex<Option<u64>>,
+    unreadable: Arc<Mutex<Vec<String>>>,
+    repairs: Arc<Mutex<Vec<repair::Action>>>,
+    missing: Arc<Mutex<Vec<String>>>,
+    job_log: Option<(String, JobStatus)>,
+    walk_threads: usize,
+    ledger: Option<Arc<Ledger>>,
+}
+
+impl Auditor {
+    // Creates the report and writes its header; nothing is read yet.
+    pub fn new(config: AuditConfig) -> io::Result<Auditor> {
+        let handler: Arc<RwLock<Handler>> = Arc::new(RwLock::new(Arc::new(|_: &AuditEvent| {})));
+        let mut report = Report::create(&config.output_file, config.report)?;
+        let forward = handler.clone();
+        let unreadable = Arc::new(Mutex::new(Vec::new()));
+        let collect = unreadable.clone();
+        let repairs = Arc::new(Mutex::new(Vec::new()));
+        let plan = config.plan_repairs.then(|| repairs.clone());
+        let missing = Arc::new(Mutex::new(Vec::new()));
+        let gather = config.collect_missing.then(|| missing.clone());
+        let ledger = config.chargeback.map(|by| Arc::new(Ledger::new(by, &config.source_dir, &config.target_dir)));
+        let tenants = ledger.clone();
+        report.observe(move |finding, id| {
+            if let Some(tenants) = &tenants {
+                tenants.finding(finding);
+            }
+            if let Some(path) = skiplist::unreadable_source(finding) {
+                collect.lock().unwrap().push(path.to_string());
+            }
+            if let (Some(plan), Some(action)) = (&plan, repair::Action::for_finding(finding)) {
+                plan.lock().unwrap().push(action);
+            }
+            if let (Some(gather), Finding::MissingInTarget { src, .. }) = (&gather, finding) {
+                gather.lock().unwrap().push(src.clone());
+            }
+            let handler = forward.read().unwrap().clone();
+            handler(&AuditEvent::Finding { finding, id });
+        });
+
+        let mut filesystems = String::from("== Filesystems ==\n");
+        let mut low_target = None;
+        for (side, root) in [("Source", &config.source_dir), ("Target", &config.target_dir)] {
+            match fsstat::stat(Path::new(root)) {
+                Ok(stats) => {
+                    filesystems.push_str(&format!("{}: {}\n", side, stats));
+                    if side == "Target" && config.min_free_space.map(|t| t.below(&stats)).unwrap_or(false) {
+                        low_target = Some(stats);
+                    }
+                }
+                Err(e) => filesystems.push_str(&format!("{}: unavailable ({})\n", side, e)),
+            }
+        }
+        report.header(&RunInfo {
+            source_dir: &config.source_dir,
+            target_dir: &config.target_dir,
+            command_line: &config.command_line,
+            filesystems: &filesystems,
+        });
+
+        Ok(Auditor {
+            source_dir: config.source_dir,
+            target_dir: config.target_dir,
+            compare: config.compare,
+            filter: config.filter,
+            bidirectional: config.bidirectional,
+            min_free_space: config.min_free_space,
+            low_target: Mutex::new(low_target),
+            inject_findings: config.inject_findings,
+            report: Arc::new(report),
+            handler,
+            orphans: Mutex::new(None),
+            unreadable,
+            repairs,
+            missing,
+            job_log: config.job_log,
+            walk_threads: config.walk_threads,
+            ledger,
+        })
+    }
+
+    // Replaces the event handler; events before this go to the previous one.
+    pub fn on_event(&self, handler: impl Fn(&AuditEvent) + Send + Sync + 'static) {
+        *self.handler.write().unwrap() = Arc::new(handler);
+    }
+
+    fn emit(&self, event: &AuditEvent) {
+        if let (Some(ledger), AuditEvent::Checked { src, bytes, compared }) = (&self.ledger, event) {
+            ledger.checked(src, *bytes, *compared);
+        }
+        let handler = self.handler.read().unwrap().clone();
+        handler(event);
+    }
+
+    pub fn report(&self) -> &Arc<Report> {
+        &self.report
+    }
+
+    pub fn run(&self) -> io::Result<AuditSummary> {
+        self.start();
+        self.count();
+        self.compare();
+        if self.bidirectional && !self.report.stopped() {
+            self.find_orphans();
+        }
+        self.finish()
+    }
+
+    // Findings known before any file is read: a target low on space and the
+    // synthetic ones asked for to test alerting.
+    pub fn start(&self) {
+        if let (Some(stats), Some(threshold)) = (self.low_target.lock().unwrap().take(), self.min_free_space) {
+            self.report.record(Finding::LowFreeSpace {
+                tgt: self.target_dir.clone(),
+                free: format!("{} of {}", indicatif::HumanBytes(stats.free_bytes), indicatif::HumanBytes(stats.total_bytes)),
+                threshold: threshold.to_string(),
+            });
+        }
+        for index in 1..=self.inject_findings {
+            let name = format!("__backup_auditor_synthetic_{}", index);
+            self.report.record(Finding::Synthetic {
+                index,
+                count: self.inject_findings,
+                src: format!("{}/{}", self.source_dir, name),
+                tgt: format!("{}/{}", self.target_dir, name),
+            });
+        }
+    }
+
+    // Entries and regular file bytes the compare walk will go through.
+    pub fn count(&self) -> (u64, u64) {
+        let _counting = tracing::info_span!("walk", pass = "count").entered();
+        let mut files_count: u64 = 0;
+        let mut bytes_count: u64 = 0;
+        for entry in walk_dir(&self.source_dir, &self.filter).into_iter().flatten() {
+            files_count += 1;
+            if entry.file_type.is_file() {
+                bytes_count += entry.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+        }
+        self.emit(&AuditEvent::Counted { files: files_count, bytes: bytes_count });
+        (files_count, bytes_count)
+    }
+
+    // Walks the source and checks every entry against its target path.
+    pub fn compare(&self) {
+        let _walk = tracing::info_span!("walk", pass = "compare").entered();
+        let roots_abs = [&self.source_dir, &self.target_dir].map(|r| Path::new(r).canonicalize().unwrap_or_default());
+        walk_dir(&self.source_dir, &self.filter)
+            .parallelism(Parallelism::RayonNewPool(self.walk_threads))
+            .into_iter()
+            .take_while(|_| !self.report.stopped())
+            .par_bridge()
+            .for_each(|src_entry| {
+                let src_entry = match src_entry {
+                    Ok(e) => e,
+                    Err(e) if e.io_error().map(is_name_too_long).unwrap_or(false) => {
+                        let path = e.path().map(|p| p.display().to_string()).unwrap_or_default();
+                        self.report.record(Finding::PathTooLong { side: "src", path: path.clone(), reason: e.into_io_error().unwrap() });
+                        self.report.not_covered("path too long", None);
+                        self.emit(&AuditEvent::Checked { src: &path, bytes: 0, compared: false });
+                        return;
+                    }
+                    // an unreadable directory or a symlink loop costs its
+                    // subtree, not the audit
+                    Err(e) => {
+                        let path = e.path().map(|p| p.display().to_string()).unwrap_or_else(|| self.source_dir.clone());
+                        let tgt = e.path().filter(|p| p.starts_with(&self.source_dir)).map(|_| target_path(&self.source_dir, &self.target_dir, &path));
+                        let reason = e.to_string();
+                        self.report.record(Finding::Error {
+                            src: path.clone(),
+                            tgt: tgt.unwrap_or_default(),
+                            operation: LISTING,
+                            reason: e.into_io_error().unwrap_or_else(|| io::Error::other(reason)),
+                        });
+                        self.report.not_covered("read error", None);
+                        self.emit(&AuditEvent::Checked { src: &path, bytes: 0, compared: false });
+                        return;
+                    }
+                };
+                let src_path = src_entry.path().display().to_string();
+                let src_size = if src_entry.file_type.is_file() {
+                    Some(src_entry.metadata().map(|m| m.len()).unwrap_or(0))
+                } else {
+                    None
+                };
+                if let Some(reason) = src_entry.client_state {
+                    if reason.listed() {
+                        self.report.record(Finding::Skipped { src: src_path.clone(), reason: reason.to_string() });
+                    }
+                    self.report.not_covered(&reason.to_string(), src_size);
+                    self.emit(&AuditEvent::Checked { src: &src_path, bytes: src_size.unwrap_or(0), compared: false });
+                    return;
+                }
+                self.check(&src_path, src_size);
+                if self.compare.dir_counts && src_entry.file_type.is_dir() {
+                    self.cmp_entry_counts(&src_path, &roots_abs);
+                }
+            });
+    }
+
+    // A cheap first look at where a copy is incomplete: the number of entries
+    // directly in a directory on each side, minus what the walk excludes.
+    // Either side unreadable is left to the walk and check_pair to report.
+    fn cmp_entry_counts(&self, src_path: &str, roots_abs: &[PathBuf; 2]) {
+        let tgt_path = target_path(&self.source_dir, &self.target_dir, src_path);
+        let count = |root: &str, root_abs: &Path, dir: &str| -> io::Result<u64> {
+            let mut count = 0;
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                let rel = path.strip_prefix(root).unwrap_or(&path);
+                let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                if self.filter.excluded_by(rel, is_dir).is_none() && !self.filter.is_own_file(root_abs, rel) {
+                    count += 1;
+                }
+            }
+            Ok(count)
+        };
+        if let (Ok(src_count), Ok(tgt_count)) = (count(&self.source_dir, &roots_abs[0], src_path), count(&self.target_dir, &roots_abs[1], &tgt_path)) {
+            if src_count != tgt_count {
+                self.report.record(Finding::EntryCountMismatch { src: src_path.to_string(), tgt: tgt_path, src_count, tgt_count });
+            }
+        }
+    }
+
+    // Checks one source entry against the same path under the target root.
+    // Returns the bytes verified when both sides opened.
+    pub fn check(&self, src_path: &str, src_size: Option<u64>) -> Option<u64> {
+        let tgt_path = target_path(&self.source_dir, &self.target_dir, src_path);
+        self.emit(&AuditEvent::Checking { src: src_path, tgt: &tgt_path });
+        let bytes = check_pair(&self.report, &self.compare, src_path, &tgt_path, src_size);
+        self.emit(&AuditEvent::Checked { src: src_path, bytes: bytes.unwrap_or(0), compared: bytes.is_some() });
+        bytes
+    }
+
+    pub fn find_orphans(&self) -> u64 {
+        let orphans = find_orphans(&self.report, &self.source_dir, &self.target_dir, &self.filter, self.walk_threads);
+        *self.orphans.lock().unwrap() = Some(orphans);
+        orphans
+    }
+
+    // A job that reports success while the audit finds target files missing or
+    // different, or failures while the audit finds nothing wrong, gets a
+    // finding; a log that agrees with the audit gets none.
+    fn check_job_log(&self, log: &str, status: &JobStatus) {
+        let findings = self.report.stats().findings;
+        let count = |kinds: &[&str]| kinds.iter().filter_map(|k| findings.get(k)).sum::<u64>();
+        let missing = count(&["missing_in_target", "missing_in_both"]);
+        let different = count(&["hash_mismatch", "size_mismatch", "type_mismatch"]);
+        let claim = match (status.exit_code, status.failed) {
+            (Some(code), _) => format!("exited with code {}", code),
+            (None, Some(failed)) => format!("reported {} failed files", failed),
+            (None, None) => return,
+        };
+        let (check, detail) = match status.reported_success() {
+            Some(true) if missing + different > 0 => (
+                "reported_success",
+                format!("the backup {} but {} target files are missing and {} differ from the source", claim, missing, different),
+            ),
+            Some(false) if missing + different == 0 && status.failed.unwrap_or(0) > 0 => (
+                "reported_failures",
+                format!("the backup {} but no target file is missing or differs from the source", claim),
+            ),
+            _ => return,
+        };
+        self.report.record(Finding::BackupLogMismatch {
+            tgt: self.target_dir.clone(),
+            log: log.to_string(),
+            check,
+            detail,
+        });
+    }
+
+    // Writes the report's coverage and summary; nothing is recorded after.
+    // Fails when the report couldn't be written in full.
+    pub fn finish(&self) -> io::Result<AuditSummary> {
+        if let Some((log, status)) = &self.job_log {
+            if !self.report.stopped() {
+                self.check_job_log(log, status);
+            }
+        }
+        if self.compare.storage_efficiency {
+            self.report.dataset_efficiency(fsstat::dataset_efficiency(Path::new(&self.target_dir)));
+        }
+        self.report.finish()?;
+        let stats = self.report.stats();
+        let mut unreadable_source = self.unreadable.lock().unwrap().clone();
+        unreadable_source.sort();
+        let mut repairs = std::mem::take(&mut *self.repairs.lock().unwrap());
+        repairs.sort_by(|a, b| a.target().cmp(b.target()));
+        Ok(AuditSummary {
+            files_verified: stats.files_verified,
+            bytes_verified: stats.bytes_verified,
+            files_not_verified: stats.files_not_verified,
+            findings: stats.findings,
+            tally: self.report.tally(),
+            orphans: *self.orphans.lock().unwrap(),
+            stopped_early: self.report.stopped(),
+            unreadable_source,
+            repairs,
+            chargeback: self.ledger.as_ref().map(|l| l.rows()).unwrap_or_default(),
+            missing_in_target: std::mem::take(&mut *self.missing.lock().unwrap()),
+        })
+    }
+}
+
+// Opens both sides and compares them, or records why they couldn't be.
+// Returns the bytes verified when both sides opened.
+pub fn check_pair(report: &Report, compare: &CompareOptions, src_path: &str, tgt_path: &str, src_size: Option<u64>) -> Option<u64> {
+    let _file = tracing::info_span!("file", path = src_path).entered();
+    if let Some(index) = &compare.target_index {
+        return check_indexed(report, compare, index, src_path, tgt_path, src_size);
+    }
+    if !compare.follow_symlinks && cmp_links(report, src_path, tgt_path) {
+        return Some(0);
+    }
+    let opening = tracing::info_span!("open").entered();
+    let src_r = open_file(src_path);
+    let tgt_r = open_file(tgt_path);
+    drop(opening);
+
+    match (src_r, tgt_r) {
+        (Err(src), _) if is_name_too_long(&src) => {
+            report.record(Finding::PathTooLong { side: "src", path: src_path.to_string(), reason: src });
+            report.not_covered("path too long", src_size);
+        }
+        (_, Err(tgt)) if is_name_too_long(&tgt) => {
+            report.record(Finding::PathTooLong { side: "tgt", path: tgt_path.to_string(), reason: tgt });
+            report.not_covered("path too long", src_size);
+        }
+        (Ok(src), Ok(tgt)) => {
+            return Some(cmp_files(report, compare, src_path, &src, tgt_path, &tgt));
+        }
+        (Ok(_), Err(tgt)) => {
+            report.record(Finding::MissingInTarget { src: src_path.to_string(), tgt: tgt_path.to_string(), reason: tgt });
+            report.not_covered("missing in target", src_size);
+        }
+        (Err(src), Ok(_)) => {
+            report.record(Finding::MissingInSource { src: src_path.to_string(), tgt: tgt_path.to_string(), reason: src });
+            report.not_covered("unreadable in source", src_size);
+        }
+        (Err(src), Err(tgt)) => {
+            report.record(Finding::MissingInBoth {
+                src: src_path.to_string(),
+                tgt: tgt_path.to_string(),
+                src_reason: src,
+                tgt_reason: tgt,
+            });
+            report.not_covered("unreadable in source and target", src_size);
+        }
+    }
+    None
+}
+
+// Symlinks are compared as links: the target must have a link at the same
+// path pointing to the same path, as written. Returns false when neither side
+// is a link, leaving the pair to the content comparison.
+fn cmp_links(report: &Report, src_path: &str, tgt_path: &str) -> bool {
+    let src_meta = match fs::symlink_metadata(src_path) {
+        Ok(m) => m,
+        Err(_) => return false,
+    };
+    let tgt_meta = fs::symlink_metadata(tgt_path);
+    let tgt_link = tgt_meta.as_ref().map(|m| m.file_type().is_symlink()).unwrap_or(false);
+    if !src_meta.file_type().is_symlink() && !tgt_link {
+        return false;
+    }
+    match (src_meta.file_type().is_symlink(), tgt_meta) {
+        (true, Err(reason)) => {
+            report.record(Finding::MissingInTarget { src: src_path.to_string(), tgt: tgt_path.to_string(), reason });
+            report.not_covered("missing in target", None);
+        }
+        (true, Ok(_)) if tgt_link => match (fs::read_link(src_path), fs::read_link(tgt_path)) {
+            (Ok(src_to), Ok(tgt_to)) if src_to != tgt_to => report.record(Finding::MetadataMismatch {
+                src: src_path.to_string(),
+                tgt: tgt_path.to_string(),
+                field: "link target",
+                src_value: src_to.display().to_string(),
+                tgt_value: tgt_to.display().to_string(),
+            }),
+            (Ok(_), Ok(_)) => {}
+            _ => return false,
+        },
+        _ => {
+            report.record(Finding::TypeMismatch { src: src_path.to_string(), tgt: tgt_path.to_string() });
+            report.not_covered("type mismatch", src_meta.is_file().then_some(src_meta.len()));
+        }
+    }
+    true
+}
+
+// Existence, type and size against the target index. Content is never read,
+// so every file counts as not verified. Directories and other entries go by
+// the source side: a non-file that the index knows as a file is a mismatch.
+fn check_indexed(report: &Report, compare: &CompareOptions, index: &index::TargetIndex, src_path: &str, tgt_path: &str, src_size: Option<u64>) -> Option<u64> {
+    let entry = match index.get(tgt_path) {
+        Some(e) => e,
+        None => {
+            report.record(Finding::MissingInTarget {
+                src: src_path.to_string(),
+                tgt: tgt_path.to_string(),
+                reason: io::Error::new(io::ErrorKind::NotFound, "not in target index"),
+            });
+            report.not_covered("missing in target", src_size);
+            return None;
+        }
+    };
+    match src_size {
+        Some(size) if entry.kind != index::EntryKind::File => {
+            report.record(Finding::TypeMismatch { src: src_path.to_string(), tgt: tgt_path.to_string() });
+            report.not_covered("type mismatch", Some(size));
+        }
+        None if entry.kind == index::EntryKind::File => {
+            report.record(Finding::TypeMismatch { src: src_path.to_string(), tgt: tgt_path.to_string() });
+            report.not_covered("type mismatch", None);
+        }
+        Some(size) if size != entry.size => {
+            report.record(Finding::SizeMismatch {
+                src: src_path.to_string(),
+                tgt: tgt_path.to_string(),
+                src_size: size,
+                tgt_size: entry.size,
+            });
+            report.not_covered("checked against target index", Some(size));
+        }
+        Some(size) => {
+            let stored = match (&entry.digests, &compare.checksums) {
+                (None, Some(store)) => store.checksum(tgt_path),
+                _ => Ok(None),
+            };
+            match (stored, &entry.digests, &compare.remote, entry.etag.as_deref().filter(|_| compare.verify_etags)) {
+                (Err(reason), ..) => {
+                    report.record(Finding::Error { src: src_path.to_string(), tgt: tgt_path.to_string(), operation: READING_METADATA, reason });
+                    report.not_covered("read error", Some(size));
+                }
+                (Ok(Some((algorithm, checksum))), ..) => check_stored(report, compare, src_path, tgt_path, size, algorithm, checksum),
+                (Ok(None), Some(digests), _, _) => check_hashed(report, compare, &compare.hashing, src_path, tgt_path, size, || Ok(digests.clone())),
+                (Ok(None), None, Some(remote), _) => check_hashed(report, compare, &compare.hashing, src_path, tgt_path, size, || {
+                    remote.open(tgt_path).and_then(|object| hash::hash_stream(&compare.hashing, object, None)).map(|h| h.expect("no read cap"))
+                }),
+                (Ok(None), None, None, Some(etag)) => check_etag(report, compare, src_path, tgt_path, size, etag),
+                (Ok(None), None, None, None) => report.not_covered("checked against target index", Some(size)),
+            }
+        }
+        None => {}
+    }
+    Some(src_size.unwrap_or(0))
+}
+
+fn check_etag(report: &Report, compare: &CompareOptions, src_path: &str, tgt_path: &str, size: u64, etag: &str) {
+    if compare.budget.max_bytes.map(|m| size > m).unwrap_or(false) {
+        report.not_covered("comparison budget exceeded", Some(size));
+        return;
+    }
+    let src = open_file(src_path);
+    // the target is never read here
+    let links = [src.as_ref().ok().and_then(|f| f.metadata().ok()).and_then(|m| hard_link_identity(&m)), None];
+    let checked = src.and_then(|f| hash::check_s3_etag(&compare.hashing, &f, size, etag));
+    match checked {
+        Ok(hash::EtagCheck::Match(digests)) => {
+            report.covered(size, links);
+            report.verified(src_path, tgt_path, &digests);
+        }
+        Ok(hash::EtagCheck::Mismatch { computed, stored }) => {
+            report.covered(size, links);
+            report.record(Finding::HashMismatch {
+                src: src_path.to_string(),
+                src_hash: computed,
+                tgt: tgt_path.to_string(),
+                tgt_hash: stored,
+                damage: None,
+            });
+        }
+        Ok(hash::EtagCheck::Unknown) => report.not_covered("ETag part size not detected", Some(size)),
+        Err(e) => {
+            report.record(Finding::MissingInSource { src: src_path.to_string(), tgt: tgt_path.to_string(), reason: e });
+            report.not_covered("unreadable in source", Some(size));
+        }
+    }
+}
+
+// Hashes the source file whole with the algorithm the store's checksum of the
+// object was made with and compares the two. Sampling and --hash-key don't
+// apply: the store checksummed all of the content, without a key.
+fn check_stored(report: &Report, compare: &CompareOptions, src_path: &str, tgt_path: &str, size: u64, algorithm: hash::Algorithm, checksum: String) {
+    let hashing = hash::HashSpec { algorithms: vec![algorithm], key: None, command: None, sample: None, ..compare.hashing.clone() };
+    let stored = hash::Digests::new(vec![(algorithm.name(), checksum)]);
+    check_hashed(report, compare, &hashing, src_path, tgt_path, size, || Ok(stored));
+}
+
+// Hashes the source file whole and compares it with the digests of the target
+// side: those of a remote object as it downloads, or of an archive member as
+// the archive was indexed, hashing the source as `hashing` says. The budget's
+// byte cap applies, its timeout doesn't.
+fn check_hashed(report: &Report, compare: &CompareOptions, hashing: &hash::HashSpec, src_path: &str, tgt_path: &str, size: u64, target: impl FnOnce() -> io::Result<hash::Digests>) {
+    if compare.budget.max_bytes.map(|m| size > m).unwrap_or(false) {
+        report.not_covered("comparison budget exceeded", Some(size));
+        return;
+    }
+    let src = open_file(src_path);
+    let links = [src.as_ref().ok().and_then(|f| f.metadata().ok()).and_then(|m| hard_link_identity(&m)), None];
+    let src_hash = match src.and_then(|f| hash::hash_stream(hashing, &f, None)) {
+        Ok(h) => h.expect("no read cap"),
+        Err(e) => {
+            report.record(Finding::MissingInSource { src: src_path.to_string(), tgt: tgt_path.to_string(), reason: e });
+            report.not_covered("unreadable in source", Some(size));
+            return;
+        }
+    };
+    let tgt_hash = match target() {
+        Ok(h) => h,
+        Err(reason) => {
+            report.record(Finding::Error { src: src_path.to_string(), tgt: tgt_path.to_string(), operation: READING_CONTENT, reason });
+            report.not_covered("read error", Some(size));
+            return;
+        }
+    };
+    report.covered(size, links);
+    if src_hash == tgt_hash {
+        report.verified(src_path, tgt_path, &src_hash);
+    } else {
+        report.record(Finding::HashMismatch { src: src_path.to_string(), src_hash, tgt: tgt_path.to_string(), tgt_hash, damage: None });
+    }
+}
+
+// Opening a named pipe blocks until a writer shows up; O_NONBLOCK makes the
+// open return at once and has no effect on reads of regular files.
+#[cfg(unix)]
+fn open_file(path: &str) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK).open(path)
+}
+
+#[cfg(not(unix))]
+fn open_file(path: &str) -> io::Result<File> {
+    File::open(path)
+}
+
+// Kept apart from missing files: the entry may well exist, the OS just can't
+// address it by that path (ENAMETOOLONG / ERROR_FILENAME_EXCED_RANGE).
+fn is_name_too_long(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    return e.raw_os_error() == Some(libc::ENAMETOOLONG);
+    #[cfg(windows)]
+    return e.raw_os_error() == Some(206);
+    #[cfg(not(any(unix, windows)))]
+    return false;
+}
+
+// (device, inode) of a file with more than one name, for counting the bytes
+// behind hard links once.
+#[cfg(unix)]
+fn hard_link_identity(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    (meta.nlink() > 1).then(|| (meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn hard_link_identity(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn inode_identity(meta: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode_identity(_meta: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+pub fn target_path(source_dir: &str, target_dir: &str, src_path: &str) -> String {
+    let rel = Path::new(src_path).strip_prefix(source_dir).unwrap();
+    if rel.as_os_str().is_empty() {
+        target_dir.to_string()
+    } else {
+        Path::new(target_dir).join(rel).display().to_string()
+    }
+}
+
+// Nothing is dropped from the walk: skipped entries are still yielded, with
+// their reason and without their children, so coverage can count them.
+pub fn walk_dir(root: &str, filter: &Arc<WalkFilter>) -> Walk {
+    let filter = filter.clone();
+    let (walk_root, root_abs) = (PathBuf::from(root), Path::new(root).canonicalize().unwrap_or_default());
+    Walk::new(root).skip_hidden(false).process_read_dir(move |_, _, _, children| {
+        for entry in children.iter_mut().flatten() {
+            let path = entry.path();
+            let rel = path.strip_prefix(&walk_root).unwrap_or(&path);
+            if filter.is_own_file(&root_abs, rel) {
+                entry.read_children_path = None;
+                entry.client_state = Some(SkipReason::Excluded("own output"));
+            } else if let Some(by) = filter.excluded_by(rel, entry.file_type.is_dir()) {
+                entry.read_children_path = None;
+                entry.client_state = Some(SkipReason::Excluded(by));
+            } else if entry.file_type.is_dir() && !filter.include_virtual_fs {
+                if let Some(fs_type) = filter::virtual_fs_type(&entry.path()) {
+                    entry.read_children_path = None;
+                    entry.client_state = Some(SkipReason::VirtualFs(fs_type));
+                }
+            }
+        }
+    })
+}
+
+// Entries the source walk can't see: anything in the target with nothing at
+// the same path in the source. An orphaned directory is reported once rather
+// than with everything under it. Exclusions apply to the target as well.
+pub fn find_orphans(report: &Report, source_dir: &str, target_dir: &str, filter: &Arc<WalkFilter>, walk_threads: usize) -> u64 {
+    let _walk = tracing::info_span!("walk", pass = "orphans").entered();
+    let (walk_source, walk_target, filter) = (source_dir.to_string(), target_dir.to_string(), filter.clone());
+    let target_abs = Path::new(target_dir).canonicalize().unwrap_or_default();
+    let walk = OrphanWalk::new(target_dir).skip_hidden(false).parallelism(Parallelism::RayonNewPool(walk_threads)).process_read_dir(move |_, _, _, children| {
+        for entry in children.iter_mut().flatten() {
+            let path = entry.path();
+            let rel = path.strip_prefix(&walk_target).unwrap_or(&path);
+            let virtual_fs = entry.file_type.is_dir() && !filter.include_virtual_fs && filter::virtual_fs_type(&path).is_some();
+            if filter.excluded_by(rel, entry.file_type.is_dir()).is_some() || virtual_fs || filter.is_own_file(&target_abs, rel) {
+                entry.read_children_path = None;
+                continue;
+            }
+            let src_path = target_path(&walk_target, &walk_source, &entry.path().display().to_string());
+            if fs::symlink_metadata(&src_path).err().map(|e| e.kind()) == Some(io::ErrorKind::NotFound) {
+                entry.read_children_path = None;
+                entry.client_state = true;
+            }
+        }
+    });
+    let orphans = AtomicU64::new(0);
+    walk.into_iter().par_bridge().for_each(|tgt_entry| {
+        let tgt_entry = match tgt_entry {
+            Ok(e) if e.client_state => e,
+            // unreadable target directories are the source walk's to report
+            _ => return,
+        };
+        let tgt_path = tgt_entry.path().display().to_string();
+        report.record(Finding::MissingInSource {
+            src: target_path(target_dir, source_dir, &tgt_path),
+            tgt: tgt_path,
+            reason: io::Error::new(io::ErrorKind::NotFound, "only in target"),
+        });
+        orphans.fetch_add(1, Ordering::Relaxed);
+    });
+    orphans.into_inner()
+}
+
+fn cmp_files(report: &Report, opts: &CompareOptions, src_path: &str, src: &File, tgt_path: &str, tgt: &File) -> u64 {
+    let (src_meta, tgt_meta) = match (src.metadata(), tgt.metadata()) {
+        (Ok(s), Ok(t)) => (s, t),
+        (Err(reason), _) | (_, Err(reason)) => {
+            report.record(Finding::Error { src: src_path.to_string(), tgt: tgt_path.to_string(), operation: READING_METADATA, reason });
+            report.not_covered("read error", None);
+            return 0;
+        }
+    };
+    let same_type = (src_meta.is_dir() && tgt_meta.is_dir()) || (src_meta.is_file() && tgt_meta.is_file());
+    if same_type {
+        if opts.check_metadata {
+            cmp_metadata(report, opts, src_path, &src_meta, tgt_path, &tgt_meta);
+        }
+        cmp_attrs(report, opts, src_path, src, tgt_path, tgt);
+    }
+    if (src_meta.is_dir() && tgt_meta.is_dir()) || (src_meta.is_symlink() && tgt_meta.is_symlink()) {
+        // nothing to compare
+    } else if let (true, true, Some(rule)) = (src_meta.is_file(), tgt_meta.is_file(), opts.rules.rule_for(src_path)) {
+        if let Err(detail) = rule.evaluate(src_meta.len(), tgt_meta.len()) {
+            report.record(Finding::RuleViolation {
+                src: src_path.to_string(),
+                tgt: tgt_path.to_string(),
+                rule: rule.name.clone(),
+                detail,
+            });
+        }
+        report.not_covered(&format!("checked by rule {}", rule.name), Some(src_meta.len()));
+    } else if src_meta.is_file() && tgt_meta.is_file() {
+        if let Some(allocated) = fsstat::allocated_bytes(&tgt_meta).filter(|_| opts.storage_efficiency) {
+            report.stored(tgt_meta.len(), allocated);
+        }
+        if let Some(finding) = opts.hard_links.check(src_path, &src_meta, tgt_path, &tgt_meta) {
+            report.record(finding);
+        }
+        let method = opts.strategy.as_ref().map(|s| s.choose(src_path, &src_meta, &tgt_meta));
+        if let Some(m) = method {
+            report.method(src_path, m.name());
+        }
+        if (opts.quick || method == Some(Method::Quick)) && !cmp_quick(report, opts, src_path, &src_meta, tgt_path, &tgt_meta) {
+            return src_meta.len();
+        }
+        let hashing = match (&opts.strategy, method) {
+            (Some(strategy), Some(Method::Sample)) => &strategy.sampled,
+            _ => &opts.hashing,
+        };
+        let links = [hard_link_identity(&src_meta), hard_link_identity(&tgt_meta)];
+        let outcome = match hash::hash_pair(hashing, &opts.budget, src, tgt, opts.hard_links.known(links, hashing)) {
+            Ok(hash::Outcome::Hashed { src, tgt }) => {
+                opts.hard_links.remember(links, hashing, [&src, &tgt]);
+                hash::Outcome::Hashed { src, tgt }
+            }
+            Ok(o) => o,
+            Err(reason) => {
+                report.record(Finding::Error { src: src_path.to_string(), tgt: tgt_path.to_string(), operation: READING_CONTENT, reason });
+                report.not_covered("read error", Some(src_meta.len()));
+                return src_meta.len();
+            }
+        };
+        match outcome {
+            hash::Outcome::Hashed { src: src_hash, tgt: tgt_hash } if src_hash != tgt_hash => match opts.rules.expected_difference(src_path) {
+                Some(pattern) => {
+                    report.record(Finding::ExpectedDifference {
+                        src: src_path.to_string(),
+                        tgt: tgt_path.to_string(),
+                        pattern: pattern.to_string(),
+                    });
+                    report.not_covered("expected to differ", Some(src_meta.len()));
+                }
+                None => {
+                    report.covered(src_meta.len(), links);
+                    if let Some(strategy) = &opts.strategy {
+                        strategy.failed(src_path);
+                    }
+                    let (src_file, tgt_file) = (src, tgt);
+                    let (src, tgt) = (src_path.to_string(), tgt_path.to_string());
+                    // a size change is damage enough; a read failing now only
+                    // leaves the mismatch unclassified
+                    let damage = match opts.classify_damage && src_meta.len() == tgt_meta.len() {
+                        true => damage::analyze(&opts.hashing, src_file, tgt_file).ok().flatten(),
+                        false => None,
+                    };
+                    let encodings = match opts.check_encoding {
+                        true => cmp_encoding(&opts.hashing, src_file, tgt_file),
+                        false => Vec::new(),
+                    };
+                    match opts.baseline.as_ref().and_then(|b| b.classify(src_path, &src_hash, &tgt_hash)) {
+                        Some(verdict) => report.record(Finding::BaselineMismatch { src, src_hash, tgt, tgt_hash, damage, verdict }),
+                        None => report.record(Finding::HashMismatch { src, src_hash, tgt, tgt_hash, damage }),
+                    }
+                    for (aspect, src_value, tgt_value) in encodings {
+                        report.record(Finding::EncodingDifference { src: src_path.to_string(), tgt: tgt_path.to_string(), aspect, src_value, tgt_value });
+                    }
+                }
+            },
+            hash::Outcome::Hashed { src: src_hash, .. } if src_hash.sampled() => {
+                let reason = match hashing.sample {
+                    Some(sample) => format!("sampled, match is probabilistic ({})", sample),
+                    None => String::from("sampled, match is probabilistic"),
+                };
+                report.not_covered(&reason, Some(src_meta.len()));
+            }
+            hash::Outcome::Hashed { src: src_hash, .. } => {
+                report.covered(src_meta.len(), links);
+                if let Some(strategy) = &opts.strategy {
+                    strategy.verified(src_path, &src_meta, &tgt_meta);
+                }
+                if opts.detect_clones {
+                    report.cloned(shared_extent_bytes(src, tgt).min(src_meta.len()));
+                }
+                if opts.check_sparse {
+                    if let Some((src_value, tgt_value)) = cmp_holes(src, tgt, src_meta.len()) {
+                        report.record(Finding::MetadataMismatch { src: src_path.to_string(), tgt: tgt_path.to_string(), field: "holes", src_value, tgt_value });
+                    }
+                }
+                report.verified(src_path, tgt_path, &src_hash);
+            }
+            hash::Outcome::Exceeded(reason) => {
+                report.record(Finding::BudgetExceeded { src: src_path.to_string(), tgt: tgt_path.to_string(), reason });
+                report.not_covered("comparison budget exceeded", Some(src_meta.len()));
+            }
+        }
+    } else if !src_meta.is_file() && !src_meta.is_dir() && src_meta.file_type() == tgt_meta.file_type() {
+        // pipes, sockets and devices: only their type is compared, never their content
+    } else {
+        report.record(Finding::TypeMismatch { src: src_path.to_string(), tgt: tgt_path.to_string() });
+        report.not_covered("type mismatch", src_meta.is_file().then_some(src_meta.len()));
+    }
+    if src_meta.is_file() { src_meta.len() } else { 0 }
+}
+
+// Size and modification time only, the latter in whole seconds since backup
+// targets (FAT, SMB, many archive formats) rarely keep finer timestamps.
+// Returns true when the pair matched and --verify-on-match wants it hashed.
+fn cmp_quick(report: &Report, opts: &CompareOptions, src_path: &str, src_meta: &fs::Metadata, tgt_path: &str, tgt_meta: &fs::Metadata) -> bool {
+    let size = src_meta.len();
+    let mtime = |m: &fs::Metadata| m.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+    let show = |m: &fs::Metadata| m.modified().map(|t| humantime::format_rfc3339_seconds(t).to_string()).unwrap_or_else(|e| e.to_string());
+    if size != tgt_meta.len() {
+        report.record(Finding::SizeMismatch {
+            src: src_path.to_string(),
+            tgt: tgt_path.to_string(),
+            src_size: size,
+            tgt_size: tgt_meta.len(),
+        });
+    } else if mtime(src_meta) != mtime(tgt_meta) {
+        report.record(Finding::MetadataMismatch {
+            src: src_path.to_string(),
+            tgt: tgt_path.to_string(),
+            field: "mtime",
+            src_value: show(src_meta),
+            tgt_value: show(tgt_meta),
+        });
+    } else if opts.verify_on_match {
+        return true;
+    }
+    report.not_covered("quick check (size and mtime)", Some(size));
+    false
+}
+
+// What --check-metadata can compare. mode, uid and gid are unix only and
+// readonly is the rest's stand-in for them; btime, the creation time, is
+// compared only where both filesystems record it (statx on Linux, APFS, NTFS).
+pub const METADATA_FIELDS: &[&str] = &["mode", "uid", "gid", "readonly", "mtime", "btime"];
+// Few copy tools can set a creation time, so it is only compared on request.
+pub const DEFAULT_METADATA_FIELDS: &[&str] = &["mode", "uid", "gid", "readonly", "mtime"];
+
+// What a restore has to get right besides content. Directory mtimes change
+// whenever an entry in them does, so only files are held to theirs; --quick
+// already compares file mtimes.
+fn cmp_metadata(report: &Report, opts: &CompareOptions, src_path: &str, src_meta: &fs::Metadata, tgt_path: &str, tgt_meta: &fs::Metadata) {
+    let mut diffs: Vec<(&'static str, String, String)> = Vec::new();
+    let wanted = |field: &str| opts.metadata_fields.contains(&field);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if wanted("mode") && src_meta.mode() & 0o7777 != tgt_meta.mode() & 0o7777 {
+            diffs.push(("mode", format!("{:04o}", src_meta.mode() & 0o7777), format!("{:04o}", tgt_meta.mode() & 0o7777)));
+        }
+        if wanted("uid") && src_meta.uid() != tgt_meta.uid() {
+            diffs.push(("uid", src_meta.uid().to_string(), tgt_meta.uid().to_string()));
+        }
+        if wanted("gid") && src_meta.gid() != tgt_meta.gid() {
+            diffs.push(("gid", src_meta.gid().to_string(), tgt_meta.gid().to_string()));
+        }
+    }
+    #[cfg(not(unix))]
+    if wanted("readonly") && src_meta.permissions().readonly() != tgt_meta.permissions().readonly() {
+        diffs.push(("readonly", src_meta.permissions().readonly().to_string(), tgt_meta.permissions().readonly().to_string()));
+    }
+    if src_meta.is_file() && !opts.quick && wanted("mtime") {
+        if let Some((src_value, tgt_value)) = cmp_time(src_meta.modified(), tgt_meta.modified(), opts.mtime_tolerance) {
+            diffs.push(("mtime", src_value, tgt_value));
+        }
+    }
+    if src_meta.is_file() && wanted("btime") {
+        // unsupported on either side is not a difference
+        if let (Ok(src_time), Ok(tgt_time)) = (src_meta.created(), tgt_meta.created()) {
+            if let Some((src_value, tgt_value)) = cmp_time(Ok(src_time), Ok(tgt_time), opts.mtime_tolerance) {
+                diffs.push(("btime", src_value, tgt_value));
+            }
+        }
+    }
+    for (field, src_value, tgt_value) in diffs {
+        report.record(Finding::MetadataMismatch { src: src_path.to_string(), tgt: tgt_path.to_string(), field, src_value, tgt_value });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn cmp_attrs(report: &Report, opts: &CompareOptions, src_path: &str, src: &File, tgt_path: &str, tgt: &File) {
+    let mut diffs = Vec::new();
+    if opts.check_attrs {
+        diffs.extend(attrs::compare_attrs(src, tgt));
+    }
+    if opts.check_selinux {
+        diffs.extend(attrs::compare_selinux(src, tgt));
+    }
+    for diff in diffs {
+        report.record(Finding::MetadataMismatch {
+            src: src_path.to_string(),
+            tgt: tgt_path.to_string(),
+            field: diff.field,
+            src_value: diff.src_value,
+            tgt_value: diff.tgt_value,
+        });
+    }
+}
+
+// The two times as shown in a finding if they are more than `tolerance`
+// seconds apart, in whole seconds since the epoch.
+fn cmp_time(src: io::Result<SystemTime>, tgt: io::Result<SystemTime>, tolerance: u64) -> Option<(String, String)> {
+    let secs = |t: &io::Result<SystemTime>| t.as_ref().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs());
+    let show = |t: &io::Result<SystemTime>| match t {
+        Ok(t) => humantime::format_rfc3339_seconds(*t).to_string(),
+        Err(e) => e.to_string(),
+    };
+    let within = match (secs(&src), secs(&tgt)) {
+        (Some(a), Some(b)) => a.abs_diff(b) <= tolerance,
+        (a, b) => a == b,
+    };
+    (!within).then(|| (show(&src), show(&tgt)))
+}
+
+// An error reading either extent map is treated as nothing shared: the copy
+// was verified either way, this only qualifies how independent it is.
+#[cfg(target_os = "linux")]
+fn shared_extent_bytes(src: &File, tgt: &File) -> u64 {
+    extents::shared_bytes(src, tgt).unwrap_or(0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn shared_extent_bytes(_src: &File, _tgt: &File) -> u64 {
+    0
+}
+
+// What differs in how two text files are stored; nothing when either isn't
+// text or can't be read again.
+fn cmp_encoding(spec: &hash::HashSpec, src: &File, tgt: &File) -> Vec<(&'static str, String, String)> {
+    match (encoding::profile(spec, src), encoding::profile(spec, tgt)) {
+        (Ok(Some(s)), Ok(Some(t))) => s.differences(&t),
+        _ => Vec::new(),
+    }
+}
+
+// How much data each side holds and in how many ranges, when that differs:
+// a sparse image copied fully allocated, or one whose copy gained holes.
+// Exact offsets aren't compared, as filesystems round holes to their own
+// block sizes. Nothing when either map can't be read.
+#[cfg(target_os = "linux")]
+fn cmp_holes(src: &File, tgt: &File, len: u64) -> Option<(String, String)> {
+    let layout = |file| {
+        let ranges = extents::data_ranges(file, len).ok()?;
+        let data: u64 = ranges.iter().map(|(_, n)| n).sum();
+        Some((data, ranges.len()))
+    };
+    let (src, tgt) = (layout(src)?, layout(tgt)?);
+    let show = |(data, ranges): (u64, usize)| format!("{} of data in {} range{}", indicatif::HumanBytes(data), ranges, if ranges == 1 { "" } else { "s" });
+    (src != tgt).then(|| (show(src), show(tgt)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cmp_holes(_src: &File, _tgt: &File, _len: u64) -> Option<(String, String)> {
+    None
+}
+
+#[cfg(not(target_os = "linux"))]
+fn cmp_attrs(_report: &Report, _opts: &CompareOptions, _src_path: &str, _src: &File, _tgt_path: &str, _tgt: &File) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(sample: Option<hash::Sample>) -> hash::HashSpec {
+        hash::HashSpec {
+            algorithms: vec![hash::Algorithm::Sha256],
+            key: None,
+            s3_part_size: hash::DEFAULT_S3_PART_SIZE,
+            io_control: None,
+            read_limit: None,
+            read_progress: None,
+            skip_holes: false,
+            fadvise: false,
+            command: None,
+            sample,
+            buffer_size: hash::DEFAULT_BUFFER_SIZE,
+        }
+    }
+
+    #[test]
+    fn hard_link_digests_are_reused_only_as_hashed() {
+        let links = HardLinks::new(false);
+        let full = spec(None);
+        let sampled = spec(Some(hash::Sample { block: 1 << 20, stride: 64 << 20 }));
+        let digests = |v: &str| hash::Digests::new(vec![("sha256", v.to_string())]);
+        let known = |ids, spec| links.known(ids, spec).map(|d| d.map(|d| d.to_string()));
+        let (a, b) = (Some((1, 10)), Some((1, 11)));
+
+        assert_eq!(known([a, b], &full), [None, None]);
+        links.remember([a, b], &sampled, [&digests("s-a"), &digests("s-b")]);
+        assert_eq!(known([a, b], &sampled), [Some(digests("s-a").to_string()), Some(digests("s-b").to_string())]);
+        // a sample stands in neither for a full read nor for another sample
+        assert_eq!(known([a, b], &full), [None, None]);
+        let other = spec(Some(hash::Sample { block: 4096, stride: 1 << 20 }));
+        assert_eq!(known([a, None], &other), [None, None]);
+        // the first digest of an inode is the one kept
+        links.remember([a, None], &full, [&digests("f-a"), &digests("x")]);
+        links.remember([a, None], &full, [&digests("later"), &digests("x")]);
+        assert_eq!(known([a, None], &full), [Some(digests("f-a").to_string()), None]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_link_pairings() {
+        let dir = std::env::temp_dir().join(format!("backup_auditor-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let (src, tgt) = (dir.join("src"), dir.join("tgt"));
+        fs::create_dir_all(&src).unwrap();
+        fs::create_dir_all(&tgt).unwrap();
+        // a and b linked in the source only, c and d in the target only
+        for name in ["a", "c", "d"] {
+            fs::write(src.join(name), name).unwrap();
+        }
+        fs::hard_link(src.join("a"), src.join("b")).unwrap();
+        for name in ["a", "b", "c"] {
+            fs::write(tgt.join(name), name).unwrap();
+        }
+        fs::hard_link(tgt.join("c"), tgt.join("d")).unwrap();
+
+        let links = HardLinks::new(true);
+        let check = |name: &str| {
+            let (s, t) = (src.join(name), tgt.join(name));
+            let (s_meta, t_meta) = (fs::metadata(&s).unwrap(), fs::metadata(&t).unwrap());
+            match links.check(&s.to_string_lossy(), &s_meta, &t.to_string_lossy(), &t_meta) {
+                Some(Finding::HardLinkMismatch { check, other, .. }) => Some((check, other)),
+                _ => None,
+            }
+        };
+        let first = |name: &str| src.join(name).to_string_lossy().into_owned();
+        assert_eq!(check("a"), None);
+        assert_eq!(check("b"), Some(("split", first("a"))));
+        assert_eq!(check("c"), None);
+        assert_eq!(check("d"), Some(("merged", first("c"))));
+        assert!(HardLinks::new(false).check(&first("b"), &fs::metadata(src.join("b")).unwrap(), "", &fs::metadata(tgt.join("b")).unwrap()).is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy)]
+pub enum LogFormat {
+    Rsync,
+    Robocopy,
+}
+
+impl LogFormat {
+    pub fn parse(name: &str) -> Option<LogFormat> {
+        match name {
+            "rsync" => Some(LogFormat::Rsync),
+            "robocopy" => Some(LogFormat::Robocopy),
+            _ => None,
+        }
+    }
+
+    fn detect(log: &str) -> LogFormat {
+        if log.lines().take(20).any(|l| l.contains("ROBOCOPY")) {
+            LogFormat::Robocopy
+        } else {
+            LogFormat::Rsync
+        }
+    }
+}
+
+// rsync --itemize-changes output, either on stdout or via --log-file (which
+// prefixes "YYYY/MM/DD HH:MM:SS [pid] "). Only ">f"/"<f" lines are file data
+// actually sent; "." and "c" lines are attribute updates and creations.
+fn rsync_paths(log: &str) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for line in log.lines() {
+        let line = match line.find("] ") {
+            Some(i) if line.starts_with(|c: char| c.is_ascii_digit()) => &line[i + 2..],
+            _ => line,
+        };
+        let (item, path) = match line.split_once(' ') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if (item.starts_with(">f") || item.starts_with("<f")) && (item.len() == 9 || item.len() == 11) {
+            paths.push(PathBuf::from(path));
+        }
+    }
+    paths
+}
+
+// robocopy's tab separated job log. File lines are "class, size, name" under
+// the last directory line; names are full paths with /FP. The "Source :"
+// header line gives the root those paths are relative to.
+fn robocopy_paths(log: &str) -> Vec<PathBuf> {
+    const COPIED: &[&str] = &["New File", "Newer", "Older", "Changed", "Modified"];
+    let mut source_root = String::new();
+    let mut dir = String::new();
+    let mut paths = Vec::new();
+    for line in log.lines() {
+        let trimmed = line.trim();
+        if let Some(root) = trimmed.strip_prefix("Source :") {
+            source_root = root.trim().to_string();
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').map(str::trim).filter(|f| !f.is_empty()).collect();
+        let name = match fields.last() {
+            Some(n) if fields.len() >= 2 => *n,
+            _ => continue,
+        };
+        if name.ends_with('\\') {
+            dir = name.to_string();
+            continue;
+        }
+        if fields.len() < 3 || !COPIED.contains(&fields[0]) {
+            continue;
+        }
+        let full = if name.contains(":\\") || name.starts_with("\\\\") {
+            name.to_string()
+        } else {
+            format!("{}{}", dir, name)
+        };
+        let rel = match full.get(..source_root.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(&source_root) => &full[source_root.len()..],
+            _ => continue,
+        };
+        paths.push(rel.split('\\').filter(|c| !c.is_empty()).collect());
+    }
+    paths
+}
+
+// Paths, relative to the backup's source root, of every file the log says was
+// transferred. Repeated transfers of a file are listed once.
+pub fn transferred_files(path: &Path, format: Option<LogFormat>) -> io::Result<Vec<PathBuf>> {
+    let bytes = fs::read(path)?;
+    let log = String::from_utf8_lossy(&bytes);
+    let mut paths = match format.unwrap_or_else(|| LogFormat::detect(&log)) {
+        LogFormat::Rsync => rsync_paths(&log),
+        LogFormat::Robocopy => robocopy_paths(&log),
+    };
+    paths.sort();
+    paths.dedup();
+    Ok(paths)
+}
+
+// What a backup job says about its own run, from the summary at the end of
+// its log. Anything the log doesn't state is None.
+pub struct JobStatus {
+    pub format: LogFormat,
+    pub copied: Option<u64>,
+    pub failed: Option<u64>,
+    pub exit_code: Option<i32>,
+}
+
+impl JobStatus {
+    // Whether the job claims to have copied everything: robocopy exit codes
+    // below 8 mean no copy failed, rsync's only 0. Without an exit code, a
+    // failure count of zero is taken as the claim.
+    pub fn reported_success(&self) -> Option<bool> {
+        match (self.exit_code, self.format) {
+            (Some(code), LogFormat::Robocopy) => Some(code < 8),
+            (Some(code), LogFormat::Rsync) => Some(code == 0),
+            (None, _) => self.failed.map(|f| f == 0),
+        }
+    }
+}
+
+// The counts of a robocopy job summary's "Files :" row: Total, Copied,
+// Skipped, Mismatch, FAILED, Extras. Robocopy doesn't log its exit code.
+fn robocopy_status(log: &str) -> JobStatus {
+    let mut status = JobStatus { format: LogFormat::Robocopy, copied: None, failed: None, exit_code: None };
+    for line in log.lines() {
+        let counts = match line.trim().strip_prefix("Files :") {
+            Some(rest) => rest.split_whitespace().map(|c| c.parse::<u64>().ok()).collect::<Vec<_>>(),
+            None => continue,
+        };
+        if counts.len() == 6 {
+            status.copied = counts[1];
+            status.failed = counts[4];
+        }
+    }
+    status
+}
+
+// rsync states its exit code on the "rsync error: ... (code N)" line and
+// otherwise finishes with the "sent N bytes" line; files it couldn't read or
+// write are "rsync: ... failed" lines. --stats gives the number copied,
+// otherwise the itemized transfers are counted.
+fn rsync_status(log: &str) -> JobStatus {
+    let mut status = JobStatus { format: LogFormat::Rsync, copied: None, failed: Some(0), exit_code: None };
+    let mut finished = false;
+    for line in log.lines() {
+        let line = match line.find("] ") {
+            Some(i) if line.starts_with(|c: char| c.is_ascii_digit()) => &line[i + 2..],
+            _ => line,
+        };
+        if let Some(rest) = line.strip_prefix("rsync error: ") {
+            status.exit_code = rest.split("(code ").nth(1).and_then(|c| c.split(')').next()).and_then(|c| c.parse().ok());
+        } else if line.starts_with("rsync: ") && line.contains(" failed") {
+            status.failed = status.failed.map(|f| f + 1);
+        } else if let Some(n) = line.strip_prefix("Number of regular files transferred: ") {
+            status.copied = n.replace(',', "").trim().parse().ok();
+        } else if line.starts_with("sent ") && line.contains(" bytes") {
+            finished = true;
+        }
+    }
+    if status.exit_code.is_none() && finished {
+        status.exit_code = Some(0);
+    }
+    if status.copied.is_none() {
+        status.copied = Some(rsync_paths(log).len() as u64);
+    }
+    status
+}
+
+pub fn job_status(path: &Path, format: Option<LogFormat>) -> io::Result<JobStatus> {
+    let bytes = fs::read(path)?;
+    let log = String::from_utf8_lossy(&bytes);
+    Ok(match format.unwrap_or_else(|| LogFormat::detect(&log)) {
+        LogFormat::Rsync => rsync_status(&log),
+        LogFormat::Robocopy => robocopy_status(&log),
+    })
+}
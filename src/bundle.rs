@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::SystemTime;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use sha2::{Digest, Sha256};
+use crate::report::to_hex;
+
+const MANIFEST: &str = "bundle.json";
+const FORMAT: u64 = 1;
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn append(tar: &mut tar::Builder<GzEncoder<File>>, name: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    header.set_cksum();
+    tar.append_data(&mut header, name, data)
+}
+
+// One .tar.gz holding the given run outputs and a manifest of their sizes and
+// digests, so a copy carried across an air gap can be checked on arrival.
+pub fn export(output: &Path, inputs: &[String]) -> io::Result<usize> {
+    let mut files = BTreeMap::new();
+    for input in inputs {
+        let name = Path::new(input)
+            .file_name()
+            .ok_or_else(|| invalid(format!("{}: not a file", input)))?
+            .to_string_lossy()
+            .into_owned();
+        let data = fs::read(input).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", input, e)))?;
+        if files.insert(name.clone(), data).is_some() {
+            return Err(invalid(format!("two inputs are named {:?}", name)));
+        }
+    }
+
+    let manifest = serde_json::json!({
+        "format": FORMAT,
+        "tool": format!("Backup Auditor v{}", env!("CARGO_PKG_VERSION")),
+        "host": gethostname::gethostname().to_string_lossy(),
+        "created": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "files": files.iter().map(|(name, data)| serde_json::json!({
+            "name": name,
+            "size": data.len(),
+            "sha256": to_hex(&Sha256::digest(data)),
+        })).collect::<Vec<_>>(),
+    });
+
+    let out = OpenOptions::new().write(true).create_new(true).open(output)?;
+    let mut tar = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    append(&mut tar, MANIFEST, serde_json::to_string_pretty(&manifest)?.as_bytes())?;
+    for (name, data) in &files {
+        append(&mut tar, &format!("files/{}", name), data)?;
+    }
+    tar.into_inner()?.finish()?.sync_all()?;
+    Ok(files.len())
+}
+
+pub struct BundledFile {
+    pub name: String,
+    pub data: Vec<u8>,
+    // None when the manifest doesn't list the file
+    pub intact: Option<bool>,
+}
+
+pub struct Bundle {
+    pub manifest: serde_json::Value,
+    pub files: Vec<BundledFile>,
+    // listed in the manifest but absent from the archive
+    pub missing: Vec<String>,
+}
+
+pub fn inspect(path: &Path) -> io::Result<Bundle> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    let mut manifest = None;
+    let mut contents = BTreeMap::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        if name == MANIFEST {
+            manifest = Some(serde_json::from_slice::<serde_json::Value>(&data)?);
+        } else if let Some(file) = name.strip_prefix("files/") {
+            // bundled names are plain file names; anything else could point
+            // --extract outside its directory
+            if Path::new(file).file_name().map(|n| n != file).unwrap_or(true) || file.contains('\\') {
+                return Err(invalid(format!("bundle entry {:?} is not a plain file name", name)));
+            }
+            contents.insert(file.to_string(), data);
+        }
+    }
+    let manifest = manifest.ok_or_else(|| invalid(format!("no {} in bundle", MANIFEST)))?;
+    if manifest["format"].as_u64() != Some(FORMAT) {
+        return Err(invalid(format!("unsupported bundle format {}", manifest["format"])));
+    }
+
+    let listed: BTreeMap<&str, &str> = manifest["files"]
+        .as_array()
+        .map(|files| {
+            files
+                .iter()
+                .filter_map(|f| Some((f["name"].as_str()?, f["sha256"].as_str()?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let missing = listed.keys().filter(|n| !contents.contains_key(**n)).map(|n| n.to_string()).collect();
+    let files = contents
+        .into_iter()
+        .map(|(name, data)| {
+            let intact = listed.get(name.as_str()).map(|digest| to_hex(&Sha256::digest(&data)) == *digest);
+            BundledFile { name, data, intact }
+        })
+        .collect();
+    Ok(Bundle { manifest, files, missing })
+}
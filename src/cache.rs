@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use crate::manifest::{escape, unescape};
+use crate::report::to_hex;
+
+// When each file pair was last verified by hashing all of both, and what the
+// two looked like then, so a --method-policy can skip rereading pairs that
+// haven't changed since. Plain text:
+//
+//   # backup_auditor verify cache v2
+//   VERIFIED<TAB>SRC_SIZE<TAB>SRC_MTIME<TAB>TGT_SIZE<TAB>TGT_MTIME<TAB>PATH
+//   # sha256 DIGEST
+//
+// Times are whole seconds since the epoch; PATH is relative to the source
+// root, escaped as in manifests. DIGEST is of every line before it, so a
+// cache that was truncated or altered is caught before a damaged record can
+// pass for a recent verification. Version 1 had no digest line.
+const MAGIC: &str = "# backup_auditor verify cache v";
+const VERSION: u32 = 2;
+const CHECKSUM: &str = "# sha256 ";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    mtime: u64,
+}
+
+impl Stamp {
+    fn of(meta: &fs::Metadata) -> Stamp {
+        let mtime = meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0);
+        Stamp { size: meta.len(), mtime }
+    }
+}
+
+struct Record {
+    verified: u64,
+    src: Stamp,
+    tgt: Stamp,
+}
+
+pub struct VerifyCache {
+    path: PathBuf,
+    records: Mutex<HashMap<String, Record>>,
+    quarantine: Option<Quarantine>,
+}
+
+// A cache file that failed its checks, moved aside for inspection; the run
+// starts from an empty cache instead.
+pub struct Quarantine {
+    pub moved_to: PathBuf,
+    pub reason: String,
+}
+
+fn parse(text: &str) -> Result<HashMap<String, Record>, String> {
+    let mut lines: Vec<&str> = text.lines().collect();
+    let version = lines
+        .first()
+        .and_then(|l| l.strip_prefix(MAGIC))
+        .and_then(|v| v.parse::<u32>().ok())
+        .ok_or("line 1: not a backup_auditor verify cache")?;
+    match version {
+        1 => {}
+        VERSION => {
+            let stored = lines.pop().and_then(|l| l.strip_prefix(CHECKSUM)).ok_or("no checksum line at the end; the file was cut short")?;
+            let body_len = text.rfind(CHECKSUM).unwrap_or(0);
+            let computed = to_hex(&Sha256::digest(&text.as_bytes()[..body_len]));
+            if stored != computed {
+                return Err(format!("checksum mismatch: stored {}, contents hash to {}", stored, computed));
+            }
+        }
+        v => return Err(format!("version {} is not supported; this build reads 1 and {}", v, VERSION)),
+    }
+    let mut records = HashMap::new();
+    for (i, line) in lines.iter().enumerate().skip(1) {
+        let n = i + 1;
+        let fields: Vec<&str> = line.splitn(6, '\t').collect();
+        if fields.len() != 6 {
+            return Err(format!("line {}: expected VERIFIED, SRC_SIZE, SRC_MTIME, TGT_SIZE, TGT_MTIME and PATH", n));
+        }
+        let numbers = fields[..5].iter().map(|f| f.parse::<u64>().map_err(|_| format!("line {}: invalid number", n))).collect::<Result<Vec<_>, _>>()?;
+        records.insert(
+            unescape(fields[5]),
+            Record { verified: numbers[0], src: Stamp { size: numbers[1], mtime: numbers[2] }, tgt: Stamp { size: numbers[3], mtime: numbers[4] } },
+        );
+    }
+    Ok(records)
+}
+
+impl VerifyCache {
+    // A file that doesn't exist yet is an empty cache, written by save(). One
+    // that can't be trusted is renamed to NAME.corrupt-SECONDS next to it and
+    // the cache starts empty, so every pair gets verified in full again.
+    pub fn open(path: &Path) -> io::Result<VerifyCache> {
+        let mut cache = VerifyCache { path: path.to_path_buf(), records: Mutex::new(HashMap::new()), quarantine: None };
+        let bytes = match fs::read(path) {
+            Ok(b) => b,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e),
+        };
+        match String::from_utf8(bytes).map_err(|_| String::from("not text")).and_then(|text| parse(&text)) {
+            Ok(records) => cache.records = Mutex::new(records),
+            Err(reason) => {
+                let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+                let moved_to = path.with_file_name(format!("{}.corrupt-{}", path.file_name().unwrap_or_default().to_string_lossy(), secs));
+                fs::rename(path, &moved_to)?;
+                cache.quarantine = Some(Quarantine { moved_to, reason });
+            }
+        }
+        Ok(cache)
+    }
+
+    pub fn quarantine(&self) -> Option<&Quarantine> {
+        self.quarantine.as_ref()
+    }
+
+    // How long ago the pair was last verified, provided neither side has
+    // changed size or modification time since.
+    pub fn verified_ago(&self, rel: &str, src: &fs::Metadata, tgt: &fs::Metadata) -> Option<Duration> {
+        let records = self.records.lock().unwrap();
+        let record = records.get(rel).filter(|r| r.src == Stamp::of(src) && r.tgt == Stamp::of(tgt))?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        Some(Duration::from_secs(now.saturating_sub(record.verified)))
+    }
+
+    pub fn record(&self, rel: &str, src: &fs::Metadata, tgt: &fs::Metadata) {
+        let verified = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.records.lock().unwrap().insert(rel.to_string(), Record { verified, src: Stamp::of(src), tgt: Stamp::of(tgt) });
+    }
+
+    pub fn forget(&self, rel: &str) {
+        self.records.lock().unwrap().remove(rel);
+    }
+
+    // Written next to the cache and renamed over it, so an interrupted run
+    // leaves the previous cache intact. Returns the number of pairs.
+    pub fn save(&self) -> io::Result<usize> {
+        let records = self.records.lock().unwrap();
+        let mut sorted: Vec<_> = records.iter().collect();
+        sorted.sort_by(|a, b| a.0.cmp(b.0));
+        let name = self.path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "cache has no file name"))?;
+        let partial = self.path.with_file_name(format!(".{}.partial", name.to_string_lossy()));
+        let mut body = format!("{}{}\n", MAGIC, VERSION);
+        for (rel, r) in &sorted {
+            body.push_str(&format!("{}\t{}\t{}\t{}\t{}\t{}\n", r.verified, r.src.size, r.src.mtime, r.tgt.size, r.tgt.mtime, escape(rel)));
+        }
+        let mut out = BufWriter::new(File::create(&partial)?);
+        out.write_all(body.as_bytes())?;
+        writeln!(out, "{}{}", CHECKSUM, to_hex(&Sha256::digest(body.as_bytes())))?;
+        out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&partial, &self.path)?;
+        Ok(sorted.len())
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use crate::audit::target_path;
+use crate::report::{self, csv_escape, Finding};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum GroupBy {
+    // the source entry's uid
+    Owner,
+    // the directory directly under the root the entry is in, or is
+    Project,
+}
+
+impl GroupBy {
+    pub fn parse(name: &str) -> Option<GroupBy> {
+        match name {
+            "owner" => Some(GroupBy::Owner),
+            "project" => Some(GroupBy::Project),
+            _ => None,
+        }
+    }
+}
+
+// Entries directly in the root belong to no project.
+const ROOT_GROUP: &str = "(root)";
+// Owners of entries that can't be stat'ed on either side.
+const UNKNOWN_GROUP: &str = "(unknown)";
+
+#[derive(Clone, Default)]
+pub struct Row {
+    pub entries_compared: u64,
+    pub bytes_compared: u64,
+    pub entries_not_compared: u64,
+    pub findings: BTreeMap<&'static str, u64>,
+}
+
+// Per tenant totals of one audit, fed from its events and findings, for
+// storage teams attributing backup quality and capacity.
+pub struct Ledger {
+    by: GroupBy,
+    source_dir: String,
+    target_dir: String,
+    rows: Mutex<BTreeMap<String, Row>>,
+}
+
+impl Ledger {
+    pub fn new(by: GroupBy, source_dir: &str, target_dir: &str) -> Ledger {
+        Ledger { by, source_dir: source_dir.to_string(), target_dir: target_dir.to_string(), rows: Mutex::new(BTreeMap::new()) }
+    }
+
+    fn group(&self, side: &str, path: &str) -> String {
+        let (root, other_root) = match side {
+            "tgt" => (&self.target_dir, &self.source_dir),
+            _ => (&self.source_dir, &self.target_dir),
+        };
+        match self.by {
+            GroupBy::Project => {
+                let rel = Path::new(path).strip_prefix(root).unwrap_or(Path::new(""));
+                let mut components = rel.components();
+                match (components.next(), components.next()) {
+                    (Some(project), Some(_)) => project.as_os_str().to_string_lossy().into_owned(),
+                    (Some(project), None) if fs::symlink_metadata(path).is_ok_and(|m| m.is_dir()) => project.as_os_str().to_string_lossy().into_owned(),
+                    _ => ROOT_GROUP.to_string(),
+                }
+            }
+            // what's only in the target is owned as it is there
+            GroupBy::Owner => fs::symlink_metadata(path)
+                .or_else(|_| fs::symlink_metadata(target_path(root, other_root, path)))
+                .ok()
+                .and_then(|m| owner(&m))
+                .unwrap_or_else(|| UNKNOWN_GROUP.to_string()),
+        }
+    }
+
+    pub fn checked(&self, src_path: &str, bytes: u64, compared: bool) {
+        let group = self.group("src", src_path);
+        let mut rows = self.rows.lock().unwrap();
+        let row = rows.entry(group).or_default();
+        if compared {
+            row.entries_compared += 1;
+            row.bytes_compared += bytes;
+        } else {
+            row.entries_not_compared += 1;
+        }
+    }
+
+    pub fn finding(&self, finding: &Finding) {
+        let (side, path) = finding.subject();
+        let group = self.group(side, path);
+        *self.rows.lock().unwrap().entry(group).or_default().findings.entry(finding.kind()).or_insert(0) += 1;
+    }
+
+    pub fn rows(&self) -> BTreeMap<String, Row> {
+        self.rows.lock().unwrap().clone()
+    }
+}
+
+#[cfg(unix)]
+fn owner(meta: &fs::Metadata) -> Option<String> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.uid().to_string())
+}
+
+#[cfg(not(unix))]
+fn owner(_meta: &fs::Metadata) -> Option<String> {
+    None
+}
+
+// One line per group, findings tallied the way the report's summary does.
+pub fn write(file: &Path, by: GroupBy, rows: &BTreeMap<String, Row>) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(file)?);
+    let group = match by {
+        GroupBy::Owner => "uid",
+        GroupBy::Project => "project",
+    };
+    writeln!(out, "{},entries_compared,bytes_compared,entries_not_compared,mismatches,missing,errors", group)?;
+    for (name, row) in rows {
+        let tally = report::tally_of(&row.findings);
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{}",
+            csv_escape(name), row.entries_compared, row.bytes_compared, row.entries_not_compared, tally.mismatches, tally.missing, tally.errors,
+        )?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
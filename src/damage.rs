@@ -0,0 +1,94 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use serde_json::{json, Value};
+use crate::hash::HashSpec;
+use crate::throttle::ReadLimit;
+
+// Differences confined to this many bytes, or to one block of this size, look
+// like media decay rather than a rewrite: a flipped bit, a bad sector.
+const ROT_MAX_BYTES: u64 = 16;
+const ROT_MAX_SPAN: u64 = 4096;
+
+// Where two files of the same size differ, from reading both side by side
+// after their digests disagreed.
+pub struct Damage {
+    pub bytes: u64,
+    pub bits: u64,
+    pub first: u64,
+    pub last: u64,
+}
+
+impl Damage {
+    // "bit_rot" for a few bytes in one place, worth restoring from another copy
+    // before it spreads; "replaced" for differences throughout, more likely
+    // a newer version of the file than corruption.
+    pub fn class(&self) -> &'static str {
+        if self.bytes <= ROT_MAX_BYTES || self.last - self.first < ROT_MAX_SPAN {
+            "bit_rot"
+        } else {
+            "replaced"
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "class": self.class(),
+            "bytes": self.bytes,
+            "bits": self.bits,
+            "first_offset": self.first,
+            "last_offset": self.last,
+        })
+    }
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let class = match self.class() {
+            "bit_rot" => "likely bit rot",
+            _ => "file replaced",
+        };
+        write!(f, "{}: {} bits in {} bytes differ, offsets {}..={}", class, self.bits, self.bytes, self.first, self.last)
+    }
+}
+
+fn fill(mut file: &File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match file.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+// Compares `src` and `tgt` byte for byte from the start, up to the end of the
+// shorter one. None when they turn out not to differ after all, e.g.
+// rewritten between hashing and now.
+pub fn analyze(spec: &HashSpec, mut src: &File, mut tgt: &File) -> io::Result<Option<Damage>> {
+    let _slot = spec.read_limit.as_deref().map(ReadLimit::acquire);
+    src.seek(SeekFrom::Start(0))?;
+    tgt.seek(SeekFrom::Start(0))?;
+    let (mut src_buf, mut tgt_buf) = (vec![0; spec.buffer_size], vec![0; spec.buffer_size]);
+    let mut damage: Option<Damage> = None;
+    let mut offset = 0;
+    loop {
+        let (src_n, tgt_n) = (fill(src, &mut src_buf)?, fill(tgt, &mut tgt_buf)?);
+        let n = src_n.min(tgt_n);
+        for (i, (a, b)) in src_buf[..n].iter().zip(&tgt_buf[..n]).enumerate() {
+            if a == b {
+                continue;
+            }
+            let at = offset + i as u64;
+            let d = damage.get_or_insert(Damage { bytes: 0, bits: 0, first: at, last: at });
+            d.bytes += 1;
+            d.bits += u64::from((a ^ b).count_ones());
+            d.last = at;
+        }
+        if src_n < src_buf.len() || tgt_n < tgt_buf.len() {
+            return Ok(damage);
+        }
+        offset += n as u64;
+    }
+}
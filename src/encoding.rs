@@ -0,0 +1,116 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use crate::hash::HashSpec;
+use crate::throttle::ReadLimit;
+
+// How much of the start of a file is read to tell how its text is stored.
+const SNIFF_BYTES: u64 = 64 * 1024;
+
+// How a text file is stored, from its start: what some backup gateways change
+// when they transcode documents on the way through.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct TextProfile {
+    // ascii, utf-8, utf-16le, utf-16be, utf-32le, utf-32be, or 8-bit for
+    // single byte code pages (latin-1, windows-1252) that aren't valid UTF-8
+    pub encoding: &'static str,
+    pub bom: bool,
+    // lf, crlf, cr, mixed, or none for a single line
+    pub line_endings: &'static str,
+}
+
+impl TextProfile {
+    // The aspects two profiles differ in, named as the findings name them,
+    // with each side's value.
+    pub fn differences(&self, other: &TextProfile) -> Vec<(&'static str, String, String)> {
+        let bom = |p: &TextProfile| if p.bom { "byte order mark" } else { "no byte order mark" }.to_string();
+        let mut differences = Vec::new();
+        if self.encoding != other.encoding {
+            differences.push(("encoding", self.encoding.to_string(), other.encoding.to_string()));
+        }
+        if self.bom != other.bom {
+            differences.push(("bom", bom(self), bom(other)));
+        }
+        if self.line_endings != other.line_endings {
+            differences.push(("line_endings", self.line_endings.to_string(), other.line_endings.to_string()));
+        }
+        differences
+    }
+}
+
+const BOMS: &[(&[u8], &str)] = &[
+    (&[0xef, 0xbb, 0xbf], "utf-8"),
+    (&[0xff, 0xfe, 0x00, 0x00], "utf-32le"),
+    (&[0x00, 0x00, 0xfe, 0xff], "utf-32be"),
+    (&[0xff, 0xfe], "utf-16le"),
+    (&[0xfe, 0xff], "utf-16be"),
+];
+
+// The start of the text as code units, for counting line breaks whatever the
+// width of a character.
+fn code_units(encoding: &str, text: &[u8]) -> Vec<u32> {
+    match encoding {
+        "utf-16le" => text.chunks_exact(2).map(|c| u32::from(u16::from_le_bytes([c[0], c[1]]))).collect(),
+        "utf-16be" => text.chunks_exact(2).map(|c| u32::from(u16::from_be_bytes([c[0], c[1]]))).collect(),
+        "utf-32le" => text.chunks_exact(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect(),
+        "utf-32be" => text.chunks_exact(4).map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]])).collect(),
+        _ => text.iter().map(|b| u32::from(*b)).collect(),
+    }
+}
+
+fn line_endings(units: &[u32]) -> &'static str {
+    let (mut crlf, mut lf, mut cr) = (0, 0, 0);
+    let mut i = 0;
+    while i < units.len() {
+        match (units[i], units.get(i + 1)) {
+            (0x0d, Some(0x0a)) => {
+                crlf += 1;
+                i += 1;
+            }
+            (0x0d, _) => cr += 1,
+            (0x0a, _) => lf += 1,
+            _ => {}
+        }
+        i += 1;
+    }
+    match (crlf > 0, lf > 0, cr > 0) {
+        (false, false, false) => "none",
+        (true, false, false) => "crlf",
+        (false, true, false) => "lf",
+        (false, false, true) => "cr",
+        _ => "mixed",
+    }
+}
+
+// Control characters other than tab, line breaks, form feed and escape don't
+// occur in text.
+fn is_binary(bytes: &[u8]) -> bool {
+    bytes.iter().any(|&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+}
+
+// None for content that doesn't look like text.
+pub fn sniff(start: &[u8]) -> Option<TextProfile> {
+    if let Some((bom, encoding)) = BOMS.iter().find(|(bom, _)| start.starts_with(bom)) {
+        let units = code_units(encoding, &start[bom.len()..]);
+        return Some(TextProfile { encoding, bom: true, line_endings: line_endings(&units) });
+    }
+    if is_binary(start) {
+        return None;
+    }
+    let encoding = match std::str::from_utf8(start) {
+        Ok(_) if start.is_ascii() => "ascii",
+        Ok(_) => "utf-8",
+        // cut off in the middle of a character by the end of the sample
+        Err(e) if e.error_len().is_none() => "utf-8",
+        Err(_) => "8-bit",
+    };
+    Some(TextProfile { encoding, bom: false, line_endings: line_endings(&code_units(encoding, start)) })
+}
+
+// Rereads the start of `file`.
+pub fn profile(spec: &HashSpec, mut file: &File) -> io::Result<Option<TextProfile>> {
+    let _slot = spec.read_limit.as_deref().map(ReadLimit::acquire);
+    file.seek(SeekFrom::Start(0))?;
+    let mut start = Vec::new();
+    file.take(SNIFF_BYTES).read_to_end(&mut start)?;
+    Ok(sniff(&start))
+}
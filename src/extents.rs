@@ -0,0 +1,122 @@
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+// struct fiemap and struct fiemap_extent from linux/fiemap.h
+#[repr(C)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [FiemapExtent; BATCH],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct FiemapExtent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+const FS_IOC_FIEMAP: libc::c_ulong = 0xc020_660b;
+const FIEMAP_FLAG_SYNC: u32 = 0x1;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+// location not known, or not a plain block range that could be shared
+const FIEMAP_EXTENT_UNUSABLE: u32 = 0x2 | 0x8 | 0x100 | 0x200;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+const BATCH: usize = 256;
+
+// Physical ranges of the file's extents the filesystem marks as shared with
+// another file (reflinks, snapshots, dedupe). Sorted by physical offset.
+fn shared_ranges(file: &File) -> io::Result<Vec<(u64, u64)>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    loop {
+        let mut map = Fiemap {
+            start,
+            length: u64::MAX - start,
+            flags: FIEMAP_FLAG_SYNC,
+            mapped_extents: 0,
+            extent_count: BATCH as u32,
+            reserved: 0,
+            extents: [FiemapExtent::default(); BATCH],
+        };
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut map) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let extents = &map.extents[..map.mapped_extents as usize];
+        for e in extents {
+            if e.flags & FIEMAP_EXTENT_SHARED != 0 && e.flags & FIEMAP_EXTENT_UNUSABLE == 0 {
+                ranges.push((e.physical, e.physical + e.length));
+            }
+        }
+        match extents.last() {
+            Some(e) if e.flags & FIEMAP_EXTENT_LAST == 0 => start = e.logical + e.length,
+            _ => break,
+        }
+    }
+    ranges.sort_unstable();
+    Ok(ranges)
+}
+
+// Bytes of on-disk extents the two files have in common: an identical target
+// that shares them is a clone of the source, not an independent copy. Zero
+// when the filesystem keeps no extent map (ext4 without sharing, tmpfs, NFS).
+pub fn shared_bytes(src: &File, tgt: &File) -> io::Result<u64> {
+    let a = shared_ranges(src)?;
+    if a.is_empty() {
+        return Ok(0);
+    }
+    let b = shared_ranges(tgt)?;
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        let (start, end) = (a[i].0.max(b[j].0), a[i].1.min(b[j].1));
+        if start < end {
+            shared += end - start;
+        }
+        if a[i].1 < b[j].1 {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    Ok(shared)
+}
+
+// Logical ranges of the file that hold data, from SEEK_DATA and SEEK_HOLE;
+// the rest are holes, which read as zeros. Filesystems that don't track holes
+// report the whole file as data. Moves the file offset.
+pub fn data_ranges(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let data = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let e = io::Error::last_os_error();
+            // nothing but a hole from `offset` to the end
+            if e.raw_os_error() == Some(libc::ENXIO) {
+                break;
+            }
+            return Err(e);
+        }
+        let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (data, hole) = (data as u64, (hole as u64).min(len));
+        if hole <= data {
+            break;
+        }
+        ranges.push((data, hole - data));
+        offset = hole;
+    }
+    Ok(ranges)
+}
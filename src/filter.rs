@@ -0,0 +1,351 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use globset::{Glob, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+
+#[derive(Clone, Copy, Debug)]
+pub enum SkipReason {
+    VirtualFs(&'static str),
+    Excluded(&'static str),
+}
+
+impl SkipReason {
+    // Exclusions are deliberate and only counted; other skips get a finding.
+    pub fn listed(&self) -> bool {
+        !matches!(self, SkipReason::Excluded(_))
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::VirtualFs(fs_type) => write!(f, "virtual filesystem ({})", fs_type),
+            SkipReason::Excluded(by) => write!(f, "excluded ({})", by),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Preset {
+    OsJunk,
+}
+
+impl Preset {
+    pub fn parse(name: &str) -> Option<Preset> {
+        match name {
+            "os-junk" => Some(Preset::OsJunk),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Preset::OsJunk => "os-junk",
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Preset::OsJunk => {
+                OS_JUNK_NAMES.iter().any(|n| name.eq_ignore_ascii_case(n))
+                    || OS_JUNK_PREFIXES.iter().any(|p| name.starts_with(p))
+            }
+        }
+    }
+}
+
+// Files and directories the OS or desktop environment creates on its own.
+// Windows names are matched case-insensitively since that filesystem is.
+const OS_JUNK_NAMES: &[&str] = &[
+    ".DS_Store",
+    ".Spotlight-V100",
+    ".Trashes",
+    ".fseventsd",
+    "Thumbs.db",
+    "desktop.ini",
+    "$RECYCLE.BIN",
+    "System Volume Information",
+    "lost+found",
+];
+const OS_JUNK_PREFIXES: &[&str] = &[".Trash-"];
+
+pub const IGNORE_FILE: &str = ".auditignore";
+
+struct IgnoreRule {
+    glob: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+    // matched against the path below the file's directory, not just the name
+    anchored: bool,
+}
+
+// One .auditignore, in the style of .gitignore: a pattern per line, '#'
+// comments, '!' to re-include, a trailing '/' for directories only, and a
+// '/' anywhere else to anchor the pattern at the file's directory rather
+// than match names at any depth below it. The last matching line wins.
+struct IgnoreFile(Vec<IgnoreRule>);
+
+impl IgnoreFile {
+    // Lines that don't parse are left out and described in the second value.
+    fn parse(text: &str) -> (IgnoreFile, Vec<String>) {
+        let (mut rules, mut errors) = (Vec::new(), Vec::new());
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(p) => (true, p),
+                None => (false, line.strip_prefix('\\').unwrap_or(line)),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(p) => (true, p),
+                None => (false, pattern),
+            };
+            let anchored = pattern.contains('/');
+            match GlobBuilder::new(pattern.trim_start_matches('/')).literal_separator(true).build() {
+                Ok(glob) => rules.push(IgnoreRule { glob: glob.compile_matcher(), negated, dir_only, anchored }),
+                Err(e) => errors.push(format!("line {}: {}", n + 1, e)),
+            }
+        }
+        (IgnoreFile(rules), errors)
+    }
+
+    // Some(true) to exclude, Some(false) to re-include, None if no line matches.
+    fn verdict(&self, rel: &Path, is_dir: bool) -> Option<bool> {
+        let name = rel.file_name()?;
+        self.0.iter().rev().find_map(|rule| {
+            let matched = (is_dir || !rule.dir_only) && if rule.anchored { rule.glob.is_match(rel) } else { rule.glob.is_match(name) };
+            matched.then_some(!rule.negated)
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct WalkFilter {
+    presets: Vec<Preset>,
+    pub include_virtual_fs: bool,
+    // absolute, with symlinks resolved
+    own_files: Vec<PathBuf>,
+    excludes: GlobSet,
+    includes: Option<GlobSet>,
+    // the source root whose .auditignore files apply, to either side's walk
+    ignore_root: Option<PathBuf>,
+    // by directory relative to that root; None where there is no file
+    ignore_files: Mutex<HashMap<PathBuf, Option<Arc<IgnoreFile>>>>,
+}
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| e.to_string())?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+// A glob matches the path relative to the walked root or, since a name has no
+// '/' in it, just the entry's name at any depth: `node_modules` and `*.vmdk`
+// need no leading `**/`.
+fn glob_matches(set: &GlobSet, rel: &Path) -> bool {
+    set.is_match(rel) || rel.file_name().is_some_and(|name| set.is_match(name))
+}
+
+impl WalkFilter {
+    pub fn add_preset(&mut self, preset: Preset) {
+        self.presets.push(preset);
+    }
+
+    // --exclude prunes matching files and whole directories. With --include,
+    // only files matching one of those are audited; directories are still
+    // descended into since files under them may match.
+    pub fn set_globs(&mut self, excludes: &[String], includes: &[String]) -> Result<(), String> {
+        self.excludes = glob_set(excludes)?;
+        self.includes = if includes.is_empty() { None } else { Some(glob_set(includes)?) };
+        Ok(())
+    }
+
+    // Honors .auditignore files under `root` from here on. The one at the root
+    // is read now so a bad pattern in it stops the run; those in directories
+    // below are read as the walk reaches them, with lines that don't parse
+    // left out.
+    pub fn use_ignore_files(&mut self, root: &Path) -> Result<(), String> {
+        let top = match fs::read_to_string(root.join(IGNORE_FILE)) {
+            Ok(text) => match IgnoreFile::parse(&text) {
+                (file, errors) if errors.is_empty() => Some(Arc::new(file)),
+                (_, errors) => return Err(errors.join("; ")),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.to_string()),
+        };
+        self.ignore_files.get_mut().unwrap().insert(PathBuf::new(), top);
+        self.ignore_root = Some(root.to_path_buf());
+        Ok(())
+    }
+
+    fn ignore_file(&self, dir: &Path) -> Option<Arc<IgnoreFile>> {
+        let root = self.ignore_root.as_ref()?;
+        if let Some(cached) = self.ignore_files.lock().unwrap().get(dir) {
+            return cached.clone();
+        }
+        let loaded = fs::read_to_string(root.join(dir).join(IGNORE_FILE)).ok().map(|text| Arc::new(IgnoreFile::parse(&text).0));
+        self.ignore_files.lock().unwrap().insert(dir.to_path_buf(), loaded.clone());
+        loaded
+    }
+
+    // Files deeper down override those above them.
+    fn ignored(&self, rel: &Path, is_dir: bool) -> bool {
+        if self.ignore_root.is_none() {
+            return false;
+        }
+        let dirs: Vec<&Path> = rel.ancestors().skip(1).collect();
+        dirs.iter()
+            .rev()
+            .filter_map(|dir| Some((*dir, self.ignore_file(dir)?)))
+            .filter_map(|(dir, file)| file.verdict(rel.strip_prefix(dir).ok()?, is_dir))
+            .last()
+            .unwrap_or(false)
+    }
+
+    // `rel` is relative to the walked root.
+    pub fn excluded_by(&self, rel: &Path, is_dir: bool) -> Option<&'static str> {
+        let name = rel.file_name()?.to_string_lossy();
+        if let Some(preset) = self.presets.iter().find(|p| p.matches(&name)) {
+            return Some(preset.name());
+        }
+        if glob_matches(&self.excludes, rel) {
+            return Some("--exclude");
+        }
+        if self.ignored(rel, is_dir) {
+            return Some(IGNORE_FILE);
+        }
+        match &self.includes {
+            Some(includes) if !is_dir && !glob_matches(includes, rel) => Some("--include"),
+            _ => None,
+        }
+    }
+
+    // Files this run writes or keeps its state in (report, trace, ack file),
+    // and its scratch directory.
+    // Inside a walked tree they would be audited while they change, and in
+    // watch mode every report write would trigger another check.
+    pub fn add_own_file(&mut self, path: &Path) {
+        let dir = match path.parent() {
+            Some(d) if !d.as_os_str().is_empty() => d,
+            _ => Path::new("."),
+        };
+        // the file itself may not exist yet
+        if let (Ok(dir), Some(name)) = (dir.canonicalize(), path.file_name()) {
+            self.own_files.push(dir.join(name));
+        }
+    }
+
+    // `root` is the absolute root `rel` was reached from.
+    pub fn is_own_file(&self, root: &Path, rel: &Path) -> bool {
+        let name = rel.file_name();
+        self.own_files.iter().any(|f| f.file_name() == name && *f == root.join(rel))
+    }
+
+    // For paths reached without a walk: excluded if it or any directory above
+    // it would have been pruned on the way down.
+    pub fn excludes_path(&self, rel: &Path, is_dir: bool) -> bool {
+        self.excluded_by(rel, is_dir).is_some()
+            || rel.ancestors().skip(1).any(|dir| self.excluded_by(dir, true).is_some())
+    }
+}
+
+// Kernel-provided trees that hang readers or produce garbage findings when a
+// root filesystem is audited. devtmpfs reports the tmpfs magic, which real data
+// lives on too, so /dev is left to the special-file handling instead.
+#[cfg(target_os = "linux")]
+const VIRTUAL_FS: &[(i64, &str)] = &[
+    (0x9fa0, "proc"),
+    (0x6265_6572, "sysfs"),
+    (0x1cd1, "devpts"),
+    (0x0027_e0eb, "cgroup"),
+    (0x6367_7270, "cgroup2"),
+    (0x6462_6720, "debugfs"),
+    (0x7472_6163, "tracefs"),
+    (0x7363_6673, "securityfs"),
+    (0x6165_676c, "pstore"),
+    (0xcafe_4a11, "bpf"),
+    (0x6265_6570, "configfs"),
+    (0x6573_5543, "fusectl"),
+    (0x1980_0202, "mqueue"),
+    (0x9584_58f6, "hugetlbfs"),
+    (0x4249_4e4d, "binfmt_misc"),
+    (0xde5e_81e4, "efivarfs"),
+    (0xf97c_ff8c, "selinuxfs"),
+];
+
+#[cfg(target_os = "linux")]
+pub fn virtual_fs_type(path: &Path) -> Option<&'static str> {
+    use std::os::unix::ffi::OsStrExt;
+    let c = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(c.as_ptr(), &mut buf) } < 0 {
+        return None;
+    }
+    let magic = buf.f_type as i64;
+    VIRTUAL_FS.iter().find(|(m, _)| *m == magic).map(|(_, name)| *name)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn virtual_fs_type(_path: &Path) -> Option<&'static str> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn verdict(text: &str, rel: &str, is_dir: bool) -> Option<bool> {
+        let (file, errors) = IgnoreFile::parse(text);
+        assert!(errors.is_empty(), "{:?}", errors);
+        file.verdict(Path::new(rel), is_dir)
+    }
+
+    #[test]
+    fn ignore_lines() {
+        // names match at any depth; the last matching line wins
+        assert_eq!(verdict("*.log\n", "a/b/x.log", false), Some(true));
+        assert_eq!(verdict("*.log\n!keep.log\n", "a/keep.log", false), Some(false));
+        assert_eq!(verdict("!keep.log\n*.log\n", "a/keep.log", false), Some(true));
+        assert_eq!(verdict("# *.log\n\n", "x.log", false), None);
+        assert_eq!(verdict("\\!bang\n", "!bang", false), Some(true));
+        // a trailing '/' only matches directories
+        assert_eq!(verdict("cache/\n", "a/cache", true), Some(true));
+        assert_eq!(verdict("cache/\n", "a/cache", false), None);
+        // a '/' anywhere else anchors the pattern at the file's directory
+        assert_eq!(verdict("/build\n", "build", true), Some(true));
+        assert_eq!(verdict("/build\n", "src/build", true), None);
+        assert_eq!(verdict("doc/*.pdf\n", "doc/a.pdf", false), Some(true));
+        assert_eq!(verdict("doc/*.pdf\n", "x/doc/a.pdf", false), None);
+        assert_eq!(verdict("doc/*.pdf\n", "doc/sub/a.pdf", false), None);
+        assert_eq!(IgnoreFile::parse("ok\n[unclosed\n").1.len(), 1);
+    }
+
+    #[test]
+    fn deeper_files_override() {
+        let root = std::env::temp_dir().join(format!("backup_auditor-filter-{}", std::process::id()));
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join(IGNORE_FILE), "*.tmp\nscratch/\n").unwrap();
+        fs::write(root.join("a").join(IGNORE_FILE), "!keep.tmp\n").unwrap();
+        fs::write(root.join("a/b").join(IGNORE_FILE), "keep.tmp\n").unwrap();
+        let mut filter = WalkFilter::default();
+        filter.use_ignore_files(&root).unwrap();
+        let ignored = |rel: &str, is_dir: bool| filter.ignored(Path::new(rel), is_dir);
+        assert!(ignored("x.tmp", false));
+        assert!(ignored("a/x.tmp", false));
+        assert!(!ignored("a/keep.tmp", false));
+        assert!(ignored("a/b/keep.tmp", false));
+        assert!(ignored("a/scratch", true));
+        assert!(!ignored("a/scratch", false));
+        assert_eq!(filter.excluded_by(Path::new("a/x.tmp"), false), Some(IGNORE_FILE));
+        fs::write(root.join(IGNORE_FILE), "[bad\n").unwrap();
+        assert!(WalkFilter::default().use_ignore_files(&root).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
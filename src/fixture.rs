@@ -0,0 +1,133 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+pub struct FixtureSpec {
+    pub matched: u64,
+    pub missing: u64,
+    pub mismatched: u64,
+    pub symlinked: u64,
+    pub special: u64,
+    pub max_size: u64,
+    pub seed: u64,
+}
+
+// splitmix64, so the same seed produces byte-identical trees on every
+// platform and release.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let v = self.next().to_le_bytes();
+            chunk.copy_from_slice(&v[..chunk.len()]);
+        }
+    }
+}
+
+fn relative_path(kind: &str, i: u64) -> PathBuf {
+    // spread entries over a few directories so walks see some depth
+    PathBuf::from(format!("d{:02}", i % 16)).join(format!("{}_{:06}", kind, i))
+}
+
+fn write_file(root: &Path, rel: &Path, content: &[u8]) -> io::Result<()> {
+    let path = root.join(rel);
+    fs::create_dir_all(path.parent().unwrap())?;
+    File::create(path)?.write_all(content)
+}
+
+#[cfg(unix)]
+fn make_link(link_to: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(link_to, path)
+}
+
+#[cfg(windows)]
+fn make_link(link_to: &Path, path: &Path) -> io::Result<()> {
+    std::os::windows::fs::symlink_file(link_to, path)
+}
+
+#[cfg(unix)]
+fn make_special(path: &Path) -> io::Result<()> {
+    let c = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+    if unsafe { libc::mkfifo(c.as_ptr(), 0o644) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_special(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "special files are only generated on unix"))
+}
+
+pub fn generate(dir: &Path, spec: &FixtureSpec) -> io::Result<()> {
+    let source = dir.join("source");
+    let target = dir.join("target");
+    for root in [&source, &target] {
+        if root.exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", root.display())));
+        }
+    }
+    fs::create_dir_all(&source)?;
+    fs::create_dir_all(&target)?;
+
+    let mut rng = Rng(spec.seed);
+    let content = |rng: &mut Rng, min: u64| {
+        let size = min + rng.next() % (spec.max_size.saturating_sub(min) + 1);
+        let mut buf = vec![0u8; size as usize];
+        rng.fill(&mut buf);
+        buf
+    };
+
+    for i in 0..spec.matched {
+        let rel = relative_path("matched", i);
+        let buf = content(&mut rng, 0);
+        write_file(&source, &rel, &buf)?;
+        write_file(&target, &rel, &buf)?;
+    }
+
+    for i in 0..spec.missing {
+        let rel = relative_path("missing", i);
+        write_file(&source, &rel, &content(&mut rng, 0))?;
+    }
+
+    for i in 0..spec.mismatched {
+        let rel = relative_path("mismatched", i);
+        let buf = content(&mut rng, 1);
+        write_file(&source, &rel, &buf)?;
+        // same size, one flipped bit: the hardest case for shortcuts
+        let mut corrupt = buf.clone();
+        let at = (rng.next() % corrupt.len() as u64) as usize;
+        corrupt[at] ^= 1 << (rng.next() % 8);
+        write_file(&target, &rel, &corrupt)?;
+    }
+
+    for i in 0..spec.symlinked {
+        let rel = relative_path("symlinked", i);
+        let link_to = PathBuf::from(format!("symlinked_{:06}.data", i));
+        let buf = content(&mut rng, 0);
+        for root in [&source, &target] {
+            write_file(root, &rel.with_file_name(&link_to), &buf)?;
+            make_link(&link_to, &root.join(&rel))?;
+        }
+    }
+
+    for i in 0..spec.special {
+        let rel = relative_path("special", i);
+        for root in [&source, &target] {
+            let path = root.join(&rel);
+            fs::create_dir_all(path.parent().unwrap())?;
+            make_special(&path)?;
+        }
+    }
+
+    Ok(())
+}
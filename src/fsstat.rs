@@ -0,0 +1,188 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use indicatif::HumanBytes;
+
+pub struct FsStats {
+    pub fs_type: &'static str,
+    pub total_bytes: u64,
+    // what an unprivileged writer such as the backup tool can still use
+    pub free_bytes: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+}
+
+impl fmt::Display for FsStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {} free of {}", self.fs_type, HumanBytes(self.free_bytes), HumanBytes(self.total_bytes))?;
+        // some filesystems (btrfs, many network ones) allocate inodes on demand
+        // and report no limit
+        if self.total_inodes > 0 {
+            write!(f, ", {} of {} inodes free", self.free_inodes, self.total_inodes)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Threshold {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl Threshold {
+    pub fn parse(s: &str, parse_size: impl Fn(&str) -> Result<u64, String>) -> Result<Threshold, String> {
+        match s.strip_suffix('%') {
+            Some(p) => match p.parse::<f64>() {
+                Ok(p) if (0.0..=100.0).contains(&p) => Ok(Threshold::Percent(p)),
+                _ => Err(format!("{:?} is not a percentage", s)),
+            },
+            None => parse_size(s).map(Threshold::Bytes),
+        }
+    }
+
+    pub fn below(&self, stats: &FsStats) -> bool {
+        match *self {
+            Threshold::Bytes(b) => stats.free_bytes < b,
+            Threshold::Percent(p) => (stats.free_bytes as f64) < stats.total_bytes as f64 * p / 100.0,
+        }
+    }
+}
+
+impl fmt::Display for Threshold {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Threshold::Bytes(b) => write!(f, "{}", HumanBytes(*b)),
+            Threshold::Percent(p) => write!(f, "{}%", p),
+        }
+    }
+}
+
+#[cfg(unix)]
+pub fn stat(path: &Path) -> io::Result<FsStats> {
+    use std::os::unix::ffi::OsStrExt;
+    let c = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    let mut buf: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c.as_ptr(), &mut buf) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block = buf.f_frsize as u64;
+    Ok(FsStats {
+        fs_type: fs_type(&c),
+        total_bytes: buf.f_blocks as u64 * block,
+        free_bytes: buf.f_bavail as u64 * block,
+        total_inodes: buf.f_files as u64,
+        free_inodes: buf.f_favail as u64,
+    })
+}
+
+#[cfg(not(unix))]
+pub fn stat(_path: &Path) -> io::Result<FsStats> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "filesystem statistics are only read on unix"))
+}
+
+// Space a file takes up on disk: less than its length when ZFS compressed it
+// or it is sparse. btrfs reports the uncompressed size here; its compression
+// only shows in dataset_efficiency().
+#[cfg(unix)]
+pub fn allocated_bytes(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.blocks() * 512)
+}
+
+#[cfg(not(unix))]
+pub fn allocated_bytes(_meta: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    let out = Command::new(program).args(args).output().map_err(|e| format!("{}: {}", program, e))?;
+    if !out.status.success() {
+        return Err(format!("{} {} failed: {}", program, args.join(" "), String::from_utf8_lossy(&out.stderr).trim()));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+// Compression and dedup figures for the whole dataset or filesystem holding
+// `root`, as its own tools report them: `zfs`/`zpool` on ZFS, `compsize` on
+// btrfs (which walks the tree, so this takes a while on large targets).
+pub fn dataset_efficiency(root: &Path) -> Result<String, String> {
+    let fs_type = stat(root).map(|s| s.fs_type).map_err(|e| e.to_string())?;
+    let path = root.to_string_lossy();
+    match fs_type {
+        "zfs" => {
+            let dataset = run("zfs", &["list", "-H", "-o", "name", &path])?.trim().to_string();
+            let props = run("zfs", &["get", "-Hp", "-o", "property,value", "used,logicalused,compressratio", &dataset])?;
+            let prop = |name: &str| props.lines().find_map(|l| l.strip_prefix(name)?.strip_prefix('\t')).unwrap_or("-").to_string();
+            let bytes = |name: &str| prop(name).parse().map(|b: u64| HumanBytes(b).to_string()).unwrap_or_else(|_| prop(name));
+            let pool = dataset.split('/').next().unwrap_or(&dataset);
+            let dedup = run("zpool", &["get", "-Hp", "-o", "value", "dedupratio", pool]).map(|v| v.trim().to_string()).unwrap_or_else(|_| String::from("-"));
+            Ok(format!(
+                "zfs dataset {}: {} logical in {} used, compressratio {}x, pool dedupratio {}x",
+                dataset,
+                bytes("logicalused"),
+                bytes("used"),
+                prop("compressratio").trim_end_matches('x'),
+                dedup.trim_end_matches('x'),
+            ))
+        }
+        "btrfs" => {
+            // TOTAL  <percent>  <disk usage>  <uncompressed>  <referenced>
+            let out = run("compsize", &["-b", &path])?;
+            let total: Vec<&str> = out.lines().find(|l| l.starts_with("TOTAL")).map(|l| l.split_whitespace().collect()).unwrap_or_default();
+            match total[..] {
+                [_, percent, disk, uncompressed, referenced] => {
+                    let bytes = |v: &str| v.parse().map(|b: u64| HumanBytes(b).to_string()).unwrap_or_else(|_| v.to_string());
+                    Ok(format!(
+                        "btrfs (compsize): {} referenced, {} of extents stored in {} on disk ({})",
+                        bytes(referenced),
+                        bytes(uncompressed),
+                        bytes(disk),
+                        percent,
+                    ))
+                }
+                _ => Err(String::from("compsize printed no TOTAL line")),
+            }
+        }
+        other => Err(format!("not reported for {} filesystems", other)),
+    }
+}
+
+#[cfg(target_os = "linux")]
+const FS_TYPES: &[(i64, &str)] = &[
+    (0xef53, "ext2/3/4"),
+    (0x5846_5342, "xfs"),
+    (0x9123_683e, "btrfs"),
+    (0x2fc1_2fc1, "zfs"),
+    (0x0102_1994, "tmpfs"),
+    (0x794c_7630, "overlayfs"),
+    (0x6969, "nfs"),
+    (0xff53_4d42, "cifs"),
+    (0xfe53_4d42, "smb2"),
+    (0x6573_5546, "fuse"),
+    (0x4d44, "vfat"),
+    (0x2011_bab0, "exfat"),
+    (0x5346_544e, "ntfs"),
+    (0x7366_746e, "ntfs3"),
+    (0xf2f5_2010, "f2fs"),
+    (0x9660, "iso9660"),
+    (0x1501_3346, "udf"),
+    (0x7371_7368, "squashfs"),
+];
+
+#[cfg(target_os = "linux")]
+fn fs_type(path: &std::ffi::CStr) -> &'static str {
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut buf) } < 0 {
+        return "unknown";
+    }
+    let magic = buf.f_type as i64;
+    FS_TYPES.iter().find(|(m, _)| *m == magic).map(|(_, name)| *name).unwrap_or("unknown")
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn fs_type(_path: &std::ffi::CStr) -> &'static str {
+    "unknown"
+}
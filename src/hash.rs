@@ -0,0 +1,836 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+use indicatif::{HumanBytes, HumanDuration};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512, Digest};
+use xxhash_rust::xxh64::Xxh64;
+use crate::report::to_hex;
+use crate::throttle::{IoControl, LatencyController, ReadLimit, Timed};
+
+#[derive(Clone, Copy, Default)]
+pub struct Budget {
+    pub timeout: Option<Duration>,
+    pub max_bytes: Option<u64>,
+}
+
+pub trait StreamHasher: Send {
+    fn update(&mut self, data: &[u8]);
+    fn finish(self: Box<Self>) -> io::Result<String>;
+}
+
+macro_rules! digest_hashers {
+    ($($t:ty),*) => {$(
+        impl StreamHasher for $t {
+            fn update(&mut self, data: &[u8]) {
+                Digest::update(self, data);
+            }
+
+            fn finish(self: Box<Self>) -> io::Result<String> {
+                Ok(to_hex(&self.finalize()))
+            }
+        }
+
+        impl StreamHasher for Hmac<$t> {
+            fn update(&mut self, data: &[u8]) {
+                Mac::update(self, data);
+            }
+
+            fn finish(self: Box<Self>) -> io::Result<String> {
+                Ok(to_hex(&self.finalize().into_bytes()))
+            }
+        }
+    )*};
+}
+
+digest_hashers!(Md5, Sha1, Sha256, Sha512);
+
+impl StreamHasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<String> {
+        Ok(self.finalize().to_hex().to_string())
+    }
+}
+
+// CRC-32C (Castagnoli) and CRC-64/NVME, the checksums S3 computes itself for
+// objects uploaded without one of their own. Both are the reflected kind,
+// `table` being for the reversed polynomial.
+struct Crc {
+    table: &'static [u64; 256],
+    mask: u64,
+    state: u64,
+}
+
+const fn crc_table(poly: u64) -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u64;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ poly } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+static CRC32C_TABLE: [u64; 256] = crc_table(0x82f6_3b78);
+static CRC64_NVME_TABLE: [u64; 256] = crc_table(0x9a6c_9329_ac4b_c9b5);
+
+impl Crc {
+    fn new(table: &'static [u64; 256], bits: u32) -> Crc {
+        let mask = u64::MAX >> (64 - bits);
+        Crc { table, mask, state: mask }
+    }
+}
+
+impl StreamHasher for Crc {
+    fn update(&mut self, data: &[u8]) {
+        for b in data {
+            self.state = self.table[((self.state ^ *b as u64) & 0xff) as usize] ^ (self.state >> 8);
+        }
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<String> {
+        let width = (self.mask.count_ones() / 4) as usize;
+        Ok(format!("{:0width$x}", !self.state & self.mask, width = width))
+    }
+}
+
+impl StreamHasher for Xxh64 {
+    fn update(&mut self, data: &[u8]) {
+        Xxh64::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> io::Result<String> {
+        Ok(format!("{:016x}", self.digest()))
+    }
+}
+
+// Content piped through an external program, for digests that aren't built in
+// or are computed elsewhere (an HSM, a vendor tool). The digest is the first
+// word the program prints, as sha256sum, b3sum and xxhsum print theirs.
+pub struct HashCommand(String);
+
+impl HashCommand {
+    pub fn new(command: &str) -> HashCommand {
+        HashCommand(command.to_string())
+    }
+
+    fn spawn(&self) -> io::Result<CommandHasher> {
+        #[cfg(unix)]
+        let mut command = Command::new("sh");
+        #[cfg(unix)]
+        command.arg("-c");
+        #[cfg(not(unix))]
+        let mut command = Command::new("cmd");
+        #[cfg(not(unix))]
+        command.arg("/C");
+        let mut child = command
+            .arg(&self.0)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("hash command {:?}: {}", self.0, e)))?;
+        let stdin = child.stdin.take();
+        Ok(CommandHasher { command: self.0.clone(), child, stdin, error: None })
+    }
+
+    // Runs the command on empty input, so a typo or missing tool is a setup
+    // error rather than a failure on the first file.
+    pub fn check(&self) -> io::Result<String> {
+        Box::new(self.spawn()?).finish()
+    }
+}
+
+struct CommandHasher {
+    command: String,
+    child: Child,
+    stdin: Option<ChildStdin>,
+    // the first failed write; later ones would only repeat it
+    error: Option<io::Error>,
+}
+
+impl StreamHasher for CommandHasher {
+    fn update(&mut self, data: &[u8]) {
+        if let (Some(stdin), None) = (self.stdin.as_mut(), &self.error) {
+            if let Err(e) = stdin.write_all(data) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        // closing stdin is the end of the content
+        drop(self.stdin.take());
+        let output = self.child.wait_with_output()?;
+        let failed = |msg: String| io::Error::other(format!("hash command {:?} {}", self.command, msg));
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(failed(match stderr.trim() {
+                "" => output.status.to_string(),
+                message => format!("{}: {}", output.status, message),
+            }));
+        }
+        if let Some(e) = self.error {
+            return Err(failed(format!("stopped reading its input: {}", e)));
+        }
+        match String::from_utf8_lossy(&output.stdout).split_whitespace().next() {
+            Some(digest) => Ok(digest.to_ascii_lowercase()),
+            None => Err(failed(String::from("printed no digest"))),
+        }
+    }
+}
+
+// The ETag S3 assigns to an object uploaded in `part_size` parts: the MD5 of
+// the concatenated part MD5s, suffixed with the part count. Objects that fit in
+// one part were uploaded with a plain PUT and carry the whole-object MD5.
+struct EtagHasher {
+    part_size: u64,
+    part: Md5,
+    part_len: u64,
+    parts: Vec<[u8; 16]>,
+    // a one-part multipart upload still gets the hash-of-hashes form, "-1"
+    multipart: bool,
+}
+
+impl EtagHasher {
+    fn new(part_size: u64, multipart: bool) -> EtagHasher {
+        EtagHasher { part_size, part: Md5::new(), part_len: 0, parts: Vec::new(), multipart }
+    }
+}
+
+impl StreamHasher for EtagHasher {
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // close a full part only once more data shows it isn't the last
+            if self.part_len == self.part_size {
+                self.parts.push(self.part.finalize_reset().into());
+                self.part_len = 0;
+            }
+            let n = data.len().min((self.part_size - self.part_len) as usize);
+            Digest::update(&mut self.part, &data[..n]);
+            self.part_len += n as u64;
+            data = &data[n..];
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> io::Result<String> {
+        if self.parts.is_empty() && !self.multipart {
+            return Ok(to_hex(&self.part.finalize()));
+        }
+        self.parts.push(self.part.finalize_reset().into());
+        let mut outer = Md5::new();
+        for p in &self.parts {
+            Digest::update(&mut outer, p);
+        }
+        Ok(format!("{}-{}", to_hex(&outer.finalize()), self.parts.len()))
+    }
+}
+
+// aws s3 cp / boto3 default multipart chunk size
+pub const DEFAULT_S3_PART_SIZE: u64 = 8 * 1024 * 1024;
+
+// Part sizes in MiB that common upload tools use, tried after the configured one.
+const COMMON_PART_SIZES_MIB: &[u64] = &[8, 5, 16, 15, 32, 64, 100, 128, 256, 512, 1024];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    // only read from md5sum lists (see manifest::load_checksums); too weak
+    // to offer for new digests
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+    Xxhash64,
+    S3Etag,
+    // only compared with the checksums S3 keeps (see Remote::checksum)
+    Crc32c,
+    Crc64Nvme,
+}
+
+impl Algorithm {
+    pub fn parse(name: &str) -> Option<Algorithm> {
+        match name {
+            "sha1" => Some(Algorithm::Sha1),
+            "sha256" => Some(Algorithm::Sha256),
+            "sha512" => Some(Algorithm::Sha512),
+            "blake3" => Some(Algorithm::Blake3),
+            "xxhash64" => Some(Algorithm::Xxhash64),
+            "s3-etag" => Some(Algorithm::S3Etag),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "md5",
+            Algorithm::Sha1 => "sha1",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Sha512 => "sha512",
+            Algorithm::Blake3 => "blake3",
+            Algorithm::Xxhash64 => "xxhash64",
+            Algorithm::S3Etag => "s3-etag",
+            Algorithm::Crc32c => "crc32c",
+            Algorithm::Crc64Nvme => "crc64nvme",
+        }
+    }
+
+    // An ETag is whatever S3 computed, so there is no keyed form of it, and
+    // xxHash and the CRCs are checksums: a key wouldn't keep anyone from
+    // forging them.
+    pub fn keyable(&self) -> bool {
+        !matches!(self, Algorithm::S3Etag | Algorithm::Xxhash64 | Algorithm::Crc32c | Algorithm::Crc64Nvme)
+    }
+
+    pub(crate) fn keyed_name(&self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "hmac-md5",
+            Algorithm::Sha1 => "hmac-sha1",
+            Algorithm::Sha256 => "hmac-sha256",
+            Algorithm::Sha512 => "hmac-sha512",
+            Algorithm::Blake3 => "blake3-keyed",
+            Algorithm::Xxhash64 => "xxhash64",
+            Algorithm::S3Etag => "s3-etag",
+            Algorithm::Crc32c => "crc32c",
+            Algorithm::Crc64Nvme => "crc64nvme",
+        }
+    }
+
+    fn hasher(&self, key: Option<&HashKey>, s3_part_size: u64) -> Box<dyn StreamHasher> {
+        match (self, key) {
+            (Algorithm::Md5, None) => Box::new(Md5::new()),
+            (Algorithm::Md5, Some(k)) => Box::new(Hmac::<Md5>::new_from_slice(&k.0).expect("HMAC accepts any key length")),
+            (Algorithm::Sha1, None) => Box::new(Sha1::new()),
+            (Algorithm::Sha1, Some(k)) => Box::new(Hmac::<Sha1>::new_from_slice(&k.0).expect("HMAC accepts any key length")),
+            (Algorithm::Sha256, None) => Box::new(Sha256::new()),
+            (Algorithm::Sha256, Some(k)) => Box::new(Hmac::<Sha256>::new_from_slice(&k.0).expect("HMAC accepts any key length")),
+            (Algorithm::Sha512, None) => Box::new(Sha512::new()),
+            (Algorithm::Sha512, Some(k)) => Box::new(Hmac::<Sha512>::new_from_slice(&k.0).expect("HMAC accepts any key length")),
+            (Algorithm::Blake3, None) => Box::new(blake3::Hasher::new()),
+            (Algorithm::Blake3, Some(k)) => Box::new(blake3::Hasher::new_keyed(&k.0)),
+            (Algorithm::Xxhash64, _) => Box::new(Xxh64::new(0)),
+            (Algorithm::S3Etag, _) => Box::new(EtagHasher::new(s3_part_size, false)),
+            (Algorithm::Crc32c, _) => Box::new(Crc::new(&CRC32C_TABLE, 32)),
+            (Algorithm::Crc64Nvme, _) => Box::new(Crc::new(&CRC64_NVME_TABLE, 64)),
+        }
+    }
+}
+
+// A secret both HMAC and keyed BLAKE3 can use. Digests recorded with it can't
+// be recomputed by whoever tampers with the target, unlike plain hashes.
+pub struct HashKey([u8; 32]);
+
+impl HashKey {
+    pub fn from_secret(secret: &[u8]) -> HashKey {
+        HashKey(blake3::derive_key("backup_auditor 2024 file digest key", secret))
+    }
+
+    // Tells keys apart without giving away anything about them, so digests
+    // made with one key aren't checked with another.
+    pub fn id(&self) -> String {
+        to_hex(&blake3::keyed_hash(&self.0, b"backup_auditor key id").as_bytes()[..8])
+    }
+}
+
+#[derive(Clone)]
+pub struct HashSpec {
+    pub algorithms: Vec<Algorithm>,
+    pub key: Option<Arc<HashKey>>,
+    pub s3_part_size: u64,
+    pub io_control: Option<Arc<IoControl>>,
+    // --io-concurrency: files read at once, held for the whole file
+    pub read_limit: Option<Arc<ReadLimit>>,
+    pub fadvise: bool,
+    // hashed alongside `algorithms`, reported as "cmd"
+    pub command: Option<Arc<HashCommand>>,
+    pub sample: Option<Sample>,
+    // bytes per read(); see feed()
+    pub buffer_size: usize,
+    // bytes read so far from either side, as they are read, for the progress
+    // display
+    pub read_progress: Option<Arc<AtomicU64>>,
+    // read only the ranges of sparse files that hold data (Linux)
+    pub skip_holes: bool,
+}
+
+// Reads `block` bytes at the start and end of a file and at every `stride`
+// in between, instead of all of it. Content that differs only outside those
+// ranges goes unnoticed, so a match is probabilistic; a mismatch is not.
+#[derive(Clone, Copy)]
+pub struct Sample {
+    pub block: u64,
+    pub stride: u64,
+}
+
+impl Sample {
+    // None when the ranges would cover the whole file anyway.
+    fn ranges(&self, len: u64) -> Option<Vec<(u64, u64)>> {
+        let mut ranges = vec![(0, self.block)];
+        let mut offset = self.stride;
+        while offset + self.block < len.saturating_sub(self.block) {
+            ranges.push((offset, self.block));
+            offset += self.stride;
+        }
+        ranges.push((len.saturating_sub(self.block), self.block));
+        let read: u64 = ranges.iter().map(|(_, n)| n).sum();
+        (read < len).then_some(ranges)
+    }
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at the start, the end and every {}", HumanBytes(self.block), HumanBytes(self.stride))
+    }
+}
+
+// Digests of sampled content aren't the file's digests, and say so.
+fn sampled_name(name: &'static str) -> &'static str {
+    match name {
+        "sha1" => "sha1-sampled",
+        "sha256" => "sha256-sampled",
+        "sha512" => "sha512-sampled",
+        "blake3" => "blake3-sampled",
+        "xxhash64" => "xxhash64-sampled",
+        "hmac-sha1" => "hmac-sha1-sampled",
+        "hmac-sha256" => "hmac-sha256-sampled",
+        "hmac-sha512" => "hmac-sha512-sampled",
+        "blake3-keyed" => "blake3-keyed-sampled",
+        "cmd" => "cmd-sampled",
+        other => other,
+    }
+}
+
+// Feeds every selected algorithm from the same read, so extra digests cost
+// CPU but no extra I/O.
+struct MultiHasher(Vec<(&'static str, Box<dyn StreamHasher>)>);
+
+impl MultiHasher {
+    fn update(&mut self, data: &[u8]) {
+        for (_, h) in self.0.iter_mut() {
+            h.update(data);
+        }
+    }
+}
+
+// Large enough for spinning disks and SATA SSDs; NVMe arrays and network
+// filesystems with high per-request latency want a few MiB per read.
+pub const DEFAULT_BUFFER_SIZE: usize = 256 * 1024;
+
+thread_local! {
+    // Allocated once per worker (or timeout helper) and reused for every file
+    // it reads, so millions of small files don't mean millions of buffers.
+    static READ_BUF: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+// Hashes at most `limit` bytes of `reader` in reads of `buffer_size`, adding
+// each read to `counted`; returns how many there were.
+fn feed(mut reader: impl Read, hasher: &mut MultiHasher, limit: u64, buffer_size: usize, counted: Option<&AtomicU64>) -> io::Result<u64> {
+    READ_BUF.with(|buf| {
+        let mut buf = buf.borrow_mut();
+        if buf.len() != buffer_size {
+            *buf = vec![0; buffer_size];
+        }
+        let mut total = 0;
+        while total < limit {
+            let want = buf.len().min(usize::try_from(limit - total).unwrap_or(usize::MAX));
+            let n = match reader.read(&mut buf[..want]) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            hasher.update(&buf[..n]);
+            if let Some(counter) = counted {
+                counter.fetch_add(n as u64, Ordering::Relaxed);
+            }
+            total += n as u64;
+        }
+        Ok(total)
+    })
+}
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Digests(Vec<(&'static str, String)>);
+
+impl Digests {
+    pub fn new(pairs: Vec<(&'static str, String)>) -> Digests {
+        Digests(pairs)
+    }
+
+    pub fn names(&self) -> String {
+        self.0.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(",")
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|(_, d)| d.as_str())
+    }
+
+    pub fn pairs(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        self.0.iter().map(|(n, d)| (*n, d.as_str()))
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.0.iter().any(|(n, _)| n.ends_with("-sampled"))
+    }
+}
+
+impl fmt::Display for Digests {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|(n, d)| format!("{}={}", n, d)).collect();
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+pub enum EtagCheck {
+    Match(Digests),
+    Mismatch { computed: Digests, stored: Digests },
+    // no candidate part size gives the stored part count
+    Unknown,
+}
+
+// Checks a local file against an ETag S3 reported for its copy. The part size
+// isn't recorded anywhere, so every candidate size that yields the ETag's part
+// count is hashed in the same read and any of them matching counts.
+pub fn check_s3_etag(spec: &HashSpec, file: &File, size: u64, stored: &str) -> io::Result<EtagCheck> {
+    let stored = stored.trim_matches('"').to_ascii_lowercase();
+    let mut candidates = Vec::new();
+    match stored.split_once('-') {
+        None => candidates.push((u64::MAX, false)),
+        Some((_, parts)) => {
+            let parts: u64 = match parts.parse() {
+                Ok(p) => p,
+                Err(_) => return Ok(EtagCheck::Unknown),
+            };
+            let sizes = std::iter::once(spec.s3_part_size).chain(COMMON_PART_SIZES_MIB.iter().map(|m| m << 20));
+            for part_size in sizes {
+                if size.div_ceil(part_size) == parts && !candidates.contains(&(part_size, true)) {
+                    candidates.push((part_size, true));
+                }
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return Ok(EtagCheck::Unknown);
+    }
+
+    let mut hasher = MultiHasher(
+        candidates
+            .iter()
+            .map(|&(part_size, multipart)| ("s3-etag", Box::new(EtagHasher::new(part_size, multipart)) as Box<dyn StreamHasher>))
+            .collect(),
+    );
+    let slot = spec.read_limit.as_deref().map(ReadLimit::acquire);
+    feed(file, &mut hasher, u64::MAX, spec.buffer_size, spec.read_progress.as_deref())?;
+    drop(slot);
+    let computed = hasher.0.into_iter().map(|(_, h)| h.finish()).collect::<io::Result<Vec<String>>>()?;
+    if computed.contains(&stored) {
+        Ok(EtagCheck::Match(Digests(vec![("s3-etag", stored)])))
+    } else {
+        Ok(EtagCheck::Mismatch {
+            computed: Digests(vec![("s3-etag", computed[0].clone())]),
+            stored: Digests(vec![("s3-etag", stored)]),
+        })
+    }
+}
+
+pub enum Outcome {
+    Hashed { src: Digests, tgt: Digests },
+    Exceeded(String),
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    static HELPER: RefCell<Option<Sender<Job>>> = const { RefCell::new(None) };
+}
+
+fn spawn_helper() -> Sender<Job> {
+    let (tx, rx) = mpsc::channel::<Job>();
+    thread::spawn(move || {
+        for job in rx {
+            job();
+        }
+    });
+    tx
+}
+
+// Each worker hands its hashing to a helper thread and waits at most
+// `timeout`. A read blocked in the kernel can't be cancelled, so a helper that
+// times out is abandoned (it exits once the read returns) and replaced.
+fn run_with_timeout<T: Send + 'static>(timeout: Duration, f: impl FnOnce() -> T + Send + 'static) -> Option<T> {
+    HELPER.with(|helper| {
+        let mut helper = helper.borrow_mut();
+        let (result_tx, result_rx) = mpsc::channel();
+        let job: Job = Box::new(move || {
+            let _ = result_tx.send(f());
+        });
+        if let Err(mpsc::SendError(job)) = helper.get_or_insert_with(spawn_helper).send(job) {
+            *helper = Some(spawn_helper());
+            helper.as_ref().unwrap().send(job).ok()?;
+        }
+        match result_rx.recv_timeout(timeout) {
+            Ok(r) => Some(r),
+            Err(_) => {
+                *helper = None;
+                None
+            }
+        }
+    })
+}
+
+fn copy_capped(reader: impl Read, hasher: &mut MultiHasher, max_bytes: Option<u64>, buffer_size: usize, counted: Option<&AtomicU64>) -> io::Result<bool> {
+    match max_bytes {
+        None => {
+            feed(reader, hasher, u64::MAX, buffer_size, counted)?;
+            Ok(true)
+        }
+        Some(cap) => Ok(feed(reader, hasher, cap + 1, buffer_size, counted)? <= cap),
+    }
+}
+
+// Below this the kernel's default read-ahead already covers the file and the
+// extra syscalls aren't worth it.
+const FADVISE_MIN_SIZE: u64 = 8 * 1024 * 1024;
+
+// Hints are best effort: a filesystem that ignores them reads as before.
+#[cfg(target_os = "linux")]
+fn advise_streaming(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL);
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_WILLNEED);
+    }
+}
+
+// A file is read once per audit, so its pages would only push out the cache
+// the rest of the system relies on.
+#[cfg(target_os = "linux")]
+fn advise_done(file: &File) {
+    use std::os::unix::io::AsRawFd;
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_streaming(_file: &File) {}
+
+#[cfg(not(target_os = "linux"))]
+fn advise_done(_file: &File) {}
+
+fn spec_hasher(spec: &HashSpec) -> io::Result<MultiHasher> {
+    let key = spec.key.as_deref();
+    let mut hasher = MultiHasher(
+        spec.algorithms
+            .iter()
+            .map(|a| (if key.is_some() { a.keyed_name() } else { a.name() }, a.hasher(key, spec.s3_part_size)))
+            .collect(),
+    );
+    if let Some(command) = &spec.command {
+        hasher.0.push(("cmd", Box::new(command.spawn()?)));
+    }
+    Ok(hasher)
+}
+
+fn hash_capped(spec: &HashSpec, file: &File, max_bytes: Option<u64>, gate: Option<&LatencyController>) -> io::Result<Option<Digests>> {
+    let counted = spec.read_progress.as_deref();
+    let mut hasher = spec_hasher(spec)?;
+    let len = file.metadata()?.len();
+    let _slot = spec.read_limit.as_deref().map(ReadLimit::acquire);
+    if let Some(ranges) = spec.sample.and_then(|s| s.ranges(len)) {
+        return hash_sampled(hasher, file, len, &ranges, max_bytes, gate, spec);
+    }
+    if let Some(ranges) = spec.skip_holes.then(|| sparse_ranges(file, len)).transpose()?.flatten() {
+        return hash_sparse(hasher, file, len, &ranges, max_bytes, gate, spec);
+    }
+    let advised = spec.fadvise && len >= FADVISE_MIN_SIZE;
+    if advised {
+        advise_streaming(file);
+    }
+    let complete = match gate {
+        Some(controller) => {
+            let _permit = controller.acquire();
+            copy_capped(Timed { inner: file, controller }, &mut hasher, max_bytes, spec.buffer_size, counted)
+        }
+        None => copy_capped(file, &mut hasher, max_bytes, spec.buffer_size, counted),
+    };
+    if advised {
+        advise_done(file);
+    }
+    if !complete? {
+        return Ok(None);
+    }
+    let digests = hasher.0.into_iter().map(|(a, h)| Ok((a, h.finish()?))).collect::<io::Result<_>>()?;
+    Ok(Some(Digests(digests)))
+}
+
+fn hash_sampled(mut hasher: MultiHasher, mut file: &File, len: u64, ranges: &[(u64, u64)], max_bytes: Option<u64>, gate: Option<&LatencyController>, spec: &HashSpec) -> io::Result<Option<Digests>> {
+    let read: u64 = ranges.iter().map(|(_, n)| n).sum();
+    if max_bytes.map(|cap| read > cap).unwrap_or(false) {
+        return Ok(None);
+    }
+    let _permit = gate.map(|c| c.acquire());
+    // files of different lengths sample different offsets, but say so outright
+    hasher.update(&len.to_le_bytes());
+    for &(offset, n) in ranges {
+        file.seek(SeekFrom::Start(offset))?;
+        match gate {
+            Some(controller) => feed(Timed { inner: file, controller }, &mut hasher, n, spec.buffer_size, spec.read_progress.as_deref())?,
+            None => feed(file, &mut hasher, n, spec.buffer_size, spec.read_progress.as_deref())?,
+        };
+    }
+    let digests = hasher.0.into_iter().map(|(a, h)| Ok((sampled_name(a), h.finish()?))).collect::<io::Result<_>>()?;
+    Ok(Some(Digests(digests)))
+}
+
+// Below this finding a file's holes costs more than reading them would.
+#[cfg(target_os = "linux")]
+const SPARSE_MIN_SIZE: u64 = 1024 * 1024;
+
+// Where a file holds data, when it has holes; None for files without any, or
+// on filesystems that can't tell. The file is read from the start after.
+#[cfg(target_os = "linux")]
+fn sparse_ranges(mut file: &File, len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    if len < SPARSE_MIN_SIZE {
+        return Ok(None);
+    }
+    let ranges = crate::extents::data_ranges(file, len);
+    file.seek(SeekFrom::Start(0))?;
+    Ok(ranges.ok().filter(|r| r.iter().map(|(_, n)| n).sum::<u64>() < len))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sparse_ranges(_file: &File, _len: u64) -> io::Result<Option<Vec<(u64, u64)>>> {
+    Ok(None)
+}
+
+// Reads the ranges holding data and hashes zeros for the holes in between, so
+// the digests are the whole file's at the I/O cost of what is allocated.
+// Holes count towards read_progress as if they had been read.
+fn hash_sparse(mut hasher: MultiHasher, mut file: &File, len: u64, ranges: &[(u64, u64)], max_bytes: Option<u64>, gate: Option<&LatencyController>, spec: &HashSpec) -> io::Result<Option<Digests>> {
+    if max_bytes.map(|cap| len > cap).unwrap_or(false) {
+        return Ok(None);
+    }
+    let _permit = gate.map(|c| c.acquire());
+    let counted = spec.read_progress.as_deref();
+    let mut at = 0;
+    for &(offset, n) in ranges {
+        feed(io::repeat(0), &mut hasher, offset - at, spec.buffer_size, counted)?;
+        file.seek(SeekFrom::Start(offset))?;
+        match gate {
+            Some(controller) => feed(Timed { inner: file, controller }, &mut hasher, n, spec.buffer_size, counted)?,
+            None => feed(file, &mut hasher, n, spec.buffer_size, counted)?,
+        };
+        at = offset + n;
+    }
+    feed(io::repeat(0), &mut hasher, len - at, spec.buffer_size, counted)?;
+    let digests = hasher.0.into_iter().map(|(a, h)| Ok((a, h.finish()?))).collect::<io::Result<_>>()?;
+    Ok(Some(Digests(digests)))
+}
+
+// All of a stream, such as an object downloaded from a remote target, with
+// the spec's algorithms. Streams can't seek, so --sample and hole skipping
+// don't apply; None when it is longer than `max_bytes`.
+pub fn hash_stream(spec: &HashSpec, reader: impl Read, max_bytes: Option<u64>) -> io::Result<Option<Digests>> {
+    let mut hasher = spec_hasher(spec)?;
+    if !copy_capped(reader, &mut hasher, max_bytes, spec.buffer_size, spec.read_progress.as_deref())? {
+        return Ok(None);
+    }
+    let digests = hasher.0.into_iter().map(|(a, h)| Ok((a, h.finish()?))).collect::<io::Result<_>>()?;
+    Ok(Some(Digests(digests)))
+}
+
+// A whole file with no budget, for hashing one tree on its own.
+pub fn hash_file(spec: &HashSpec, file: &File) -> io::Result<Digests> {
+    Ok(hash_capped(spec, file, None, spec.io_control.as_deref().map(|c| &c.src))?.expect("no read cap"))
+}
+
+fn hash_pair_inline(spec: &HashSpec, max_bytes: Option<u64>, src: &File, tgt: &File, known: [Option<Digests>; 2]) -> io::Result<Outcome> {
+    let exceeded = |cap: u64| Outcome::Exceeded(format!("read cap of {} exceeded", HumanBytes(cap)));
+    let io_control = spec.io_control.as_deref();
+    let [known_src, known_tgt] = known;
+    let src_hash = match known_src {
+        Some(h) => h,
+        None => match tracing::info_span!("hash-src").in_scope(|| hash_capped(spec, src, max_bytes, io_control.map(|c| &c.src)))? {
+            Some(h) => h,
+            None => return Ok(exceeded(max_bytes.unwrap())),
+        },
+    };
+    let tgt_hash = match known_tgt {
+        Some(h) => h,
+        None => match tracing::info_span!("hash-tgt").in_scope(|| hash_capped(spec, tgt, max_bytes, io_control.map(|c| &c.tgt)))? {
+            Some(h) => h,
+            None => return Ok(exceeded(max_bytes.unwrap())),
+        },
+    };
+    Ok(Outcome::Hashed { src: src_hash, tgt: tgt_hash })
+}
+
+// `known` has the digests of either side already hashed under another name;
+// that side isn't read again.
+pub fn hash_pair(spec: &HashSpec, budget: &Budget, src: &File, tgt: &File, known: [Option<Digests>; 2]) -> io::Result<Outcome> {
+    let timeout = match budget.timeout {
+        Some(t) => t,
+        None => return hash_pair_inline(spec, budget.max_bytes, src, tgt, known),
+    };
+    let src = src.try_clone()?;
+    let tgt = tgt.try_clone()?;
+    let spec = spec.clone();
+    let max_bytes = budget.max_bytes;
+    // the worker thread would otherwise start its spans outside the file's
+    let span = tracing::Span::current();
+    match run_with_timeout(timeout, move || span.in_scope(|| hash_pair_inline(&spec, max_bytes, &src, &tgt, known))) {
+        Some(r) => r,
+        None => Ok(Outcome::Exceeded(format!("timed out after {}", HumanDuration(timeout)))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(algorithm: Algorithm, data: &[u8]) -> String {
+        let mut hasher = algorithm.hasher(None, DEFAULT_S3_PART_SIZE);
+        hasher.update(data);
+        hasher.finish().unwrap()
+    }
+
+    // the catalogued check values, of "123456789"
+    #[test]
+    fn crc_check_values() {
+        assert_eq!(digest(Algorithm::Crc32c, b"123456789"), "e3069283");
+        assert_eq!(digest(Algorithm::Crc64Nvme, b"123456789"), "ae8b14860a799888");
+    }
+
+    #[test]
+    fn crc_of_nothing() {
+        assert_eq!(digest(Algorithm::Crc32c, b""), "00000000");
+        assert_eq!(digest(Algorithm::Crc64Nvme, b""), "0000000000000000");
+    }
+
+    #[test]
+    fn crc_split_updates() {
+        let mut hasher = Algorithm::Crc32c.hasher(None, DEFAULT_S3_PART_SIZE);
+        hasher.update(b"1234");
+        hasher.update(b"56789");
+        assert_eq!(hasher.finish().unwrap(), "e3069283");
+    }
+}
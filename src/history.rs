@@ -0,0 +1,174 @@
+use std::collections::BTreeSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::SystemTime;
+use serde_json::{json, Value};
+
+// runs looked at unless told otherwise (--flaky-window)
+pub const DEFAULT_WINDOW: usize = 10;
+
+// What earlier audits of the same source and target raised, from a file each
+// full audit appends a line to when it finishes (--history):
+//
+//   {"type":"run","run_id":...,"finished":...,"source":...,"target":...,"findings":[ID, ...]}
+//
+// With --ticket-template, the run's line also lists its ticket groups (see
+// ticket::groups) and each ticket opened gets a line before it:
+//
+//   {"type":"ticket","run_id":...,"source":...,"target":...,"group":KEY,"ticket":REFERENCE}
+//
+// Lines of other pairs of trees are passed over.
+pub struct History {
+    // the IDs each of the last runs raised, oldest first
+    runs: Vec<BTreeSet<String>>,
+}
+
+// How a finding raised now has come and gone before.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Record {
+    // not in any of the runs looked at
+    New,
+    // in every run since the first of them that raised it
+    Persistent { seen: usize, runs: usize },
+    // raised, then not, then raised again
+    Flaky { seen: usize, runs: usize },
+}
+
+pub fn lines(path: &Path) -> io::Result<Vec<Value>> {
+    let text = match fs::read_to_string(path) {
+        Ok(t) => t,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    text.lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(n, line)| serde_json::from_str(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", n + 1, e))))
+        .collect()
+}
+
+pub fn append(path: &Path, line: &Value) -> io::Result<()> {
+    let mut out = OpenOptions::new().append(true).create(true).open(path)?;
+    // one write, so a line is never left half written next to another's
+    out.write_all(format!("{}\n", line).as_bytes())?;
+    out.sync_all()
+}
+
+impl History {
+    // The last `window` runs of `source` against `target` in `path`; none
+    // when the file doesn't exist yet.
+    pub fn load(path: &Path, source: &str, target: &str, window: usize) -> io::Result<History> {
+        let mut runs: Vec<BTreeSet<String>> = lines(path)?
+            .iter()
+            .filter(|l| l["type"] == "run" && l["source"] == source && l["target"] == target)
+            .map(|l| l["findings"].as_array().map(|ids| ids.iter().filter_map(|id| id.as_str().map(String::from)).collect()).unwrap_or_default())
+            .collect();
+        runs.drain(..runs.len().saturating_sub(window));
+        Ok(History { runs })
+    }
+
+    pub fn record(&self, id: &str) -> Record {
+        let present: Vec<bool> = self.runs.iter().map(|run| run.contains(id)).collect();
+        let first = match present.iter().position(|p| *p) {
+            Some(first) => first,
+            None => return Record::New,
+        };
+        let seen = present.iter().filter(|p| **p).count();
+        let runs = present.len();
+        match present[first..].contains(&false) {
+            true => Record::Flaky { seen, runs },
+            false => Record::Persistent { seen, runs },
+        }
+    }
+}
+
+// The line a finished run adds, with the IDs of the findings it raised and,
+// when tickets are opened, the groups it found.
+pub fn run_line(run_id: &str, source: &str, target: &str, findings: &BTreeSet<String>, groups: Option<&[String]>) -> Value {
+    let mut line = json!({
+        "type": "run",
+        "run_id": run_id,
+        "finished": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        "source": source,
+        "target": target,
+        "findings": findings,
+    });
+    if let Some(groups) = groups {
+        line["groups"] = json!(groups);
+    }
+    line
+}
+
+pub fn ticket_line(run_id: &str, source: &str, target: &str, group: &str, ticket: &str) -> Value {
+    json!({ "type": "ticket", "run_id": run_id, "source": source, "target": target, "group": group, "ticket": ticket })
+}
+
+// The ticket already open for `group`: one opened since the last run that
+// looked for groups and didn't find this one, after which it would be a new
+// occurrence deserving a new ticket.
+pub fn open_ticket(lines: &[Value], source: &str, target: &str, group: &str) -> Option<String> {
+    let mut open = None;
+    for line in lines.iter().filter(|l| l["source"] == source && l["target"] == target) {
+        match line["type"].as_str() {
+            Some("ticket") if line["group"] == group => open = line["ticket"].as_str().map(String::from),
+            Some("run") => {
+                if let Some(groups) = line["groups"].as_array() {
+                    if !groups.iter().any(|g| g == group) {
+                        open = None;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    open
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history(runs: &[&[&str]]) -> History {
+        History { runs: runs.iter().map(|ids| ids.iter().map(|id| id.to_string()).collect()).collect() }
+    }
+
+    #[test]
+    fn records() {
+        let h = history(&[&["F-a"], &["F-a", "F-b"], &["F-b", "F-c"], &["F-b", "F-c", "F-a"]]);
+        assert_eq!(h.record("F-x"), Record::New);
+        assert_eq!(h.record("F-b"), Record::Persistent { seen: 3, runs: 4 });
+        assert_eq!(h.record("F-c"), Record::Persistent { seen: 2, runs: 4 });
+        assert_eq!(h.record("F-a"), Record::Flaky { seen: 3, runs: 4 });
+    }
+
+    #[test]
+    fn load_keeps_the_last_runs_of_the_pair() {
+        let dir = std::env::temp_dir().join(format!("backup_auditor-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.jsonl");
+        let _ = fs::remove_file(&path);
+        for (source, ids) in [("s", vec!["F-a"]), ("other", vec!["F-b"]), ("s", vec![]), ("s", vec!["F-a"])] {
+            let ids = ids.into_iter().map(String::from).collect();
+            append(&path, &run_line("r", source, "t", &ids, None)).unwrap();
+        }
+        assert_eq!(History::load(&path, "s", "t", 10).unwrap().record("F-a"), Record::Flaky { seen: 2, runs: 3 });
+        assert_eq!(History::load(&path, "s", "t", 2).unwrap().record("F-a"), Record::Persistent { seen: 1, runs: 2 });
+        assert_eq!(History::load(&path, "s", "t", 10).unwrap().record("F-b"), Record::New);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn tickets_stay_open_while_their_group_is_found() {
+        let none = BTreeSet::new();
+        let g = [String::from("g")];
+        let mut lines = vec![run_line("1", "s", "t", &none, None), ticket_line("2", "s", "t", "g", "T-1"), run_line("2", "s", "t", &none, Some(&g))];
+        assert_eq!(open_ticket(&lines, "s", "t", "g").as_deref(), Some("T-1"));
+        assert_eq!(open_ticket(&lines, "s", "t2", "g"), None);
+        // a run without tickets says nothing either way
+        lines.push(run_line("3", "s", "t", &none, None));
+        assert_eq!(open_ticket(&lines, "s", "t", "g").as_deref(), Some("T-1"));
+        lines.push(run_line("4", "s", "t", &none, Some(&[])));
+        assert_eq!(open_ticket(&lines, "s", "t", "g"), None);
+    }
+}
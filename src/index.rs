@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use flate2::read::GzDecoder;
+use crate::hash::Digests;
+#[cfg(feature = "archive")]
+use crate::hash::{self, HashSpec};
+use crate::remote;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Other,
+}
+
+pub struct Entry {
+    pub kind: EntryKind,
+    pub size: u64,
+    // as reported by an object store, quotes stripped
+    pub etag: Option<String>,
+    // of an archive member, hashed as the archive was indexed
+    pub digests: Option<Digests>,
+}
+
+#[derive(Clone, Copy)]
+pub enum IndexFormat {
+    Find,
+    Csv,
+    S3Inventory,
+}
+
+impl IndexFormat {
+    pub fn parse(name: &str) -> Option<IndexFormat> {
+        match name {
+            "find" => Some(IndexFormat::Find),
+            "csv" => Some(IndexFormat::Csv),
+            "s3-inventory" => Some(IndexFormat::S3Inventory),
+            _ => None,
+        }
+    }
+}
+
+// Splits one CSV record, honouring double quotes ("" inside quotes is a quote).
+pub fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+// S3 Inventory URL-encodes object keys, with '+' for spaces.
+fn url_decode(s: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], bytes.get(i + 1).copied().and_then(hex), bytes.get(i + 2).copied().and_then(hex)) {
+            (b'%', Some(hi), Some(lo)) => {
+                out.push(hi << 4 | lo);
+                i += 3;
+            }
+            (b'+', _, _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (b, _, _) => {
+                out.push(b);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn kind_of(t: &str) -> EntryKind {
+    match t {
+        "f" => EntryKind::File,
+        "d" => EntryKind::Dir,
+        _ => EntryKind::Other,
+    }
+}
+
+// A target given as an archive file rather than a directory.
+pub fn is_tar_archive(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_ascii_lowercase();
+    [".tar", ".tar.gz", ".tgz"].iter().any(|ext| name.ends_with(ext)) && path.is_file()
+}
+
+// A listing of the target tree loaded up front, so existence and size checks
+// need no target I/O. Keys are target paths as the walk builds them.
+#[derive(Default)]
+pub struct TargetIndex {
+    target_dir: String,
+    prefix: String,
+    entries: HashMap<String, Entry>,
+}
+
+impl TargetIndex {
+    // Listed paths outside `prefix` are dropped; the prefix itself maps to
+    // the target root. Object stores and file-only listings don't list
+    // directories, so every ancestor of a listed path exists as one.
+    fn insert(&mut self, listed: &str, kind: EntryKind, size: u64, etag: Option<String>) -> Option<&mut Entry> {
+        let prefix = self.prefix.trim_end_matches('/');
+        let rel = match listed.trim_start_matches("./").strip_prefix(prefix) {
+            Some(rel) if prefix.is_empty() || rel.is_empty() || rel.starts_with('/') => rel.trim_matches('/'),
+            _ => return None,
+        };
+        let mut dir = Path::new(rel).parent();
+        while let Some(d) = dir {
+            let key = self.key(&d.to_string_lossy());
+            self.entries.entry(key).or_insert(Entry { kind: EntryKind::Dir, size: 0, etag: None, digests: None });
+            dir = d.parent();
+        }
+        let key = self.key(rel);
+        self.entries.insert(key.clone(), Entry { kind, size, etag, digests: None });
+        self.entries.get_mut(&key)
+    }
+
+    fn key(&self, rel: &str) -> String {
+        if rel.is_empty() {
+            self.target_dir.clone()
+        } else {
+            Path::new(&self.target_dir).join(rel).display().to_string()
+        }
+    }
+
+    pub fn get(&self, tgt_path: &str) -> Option<&Entry> {
+        self.entries.get(tgt_path)
+    }
+
+    pub fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    // The listing of a remote target, its URL being the target root.
+    pub fn from_objects(target_dir: &str, objects: Vec<remote::Object>) -> TargetIndex {
+        let mut index = TargetIndex { target_dir: target_dir.to_string(), prefix: String::new(), entries: HashMap::new() };
+        for object in objects {
+            index.insert(&object.key, object.kind, object.size, object.etag);
+        }
+        index
+    }
+
+    // A tar archive given as the target, gzipped or not, read once from start
+    // to end: its members are the target tree and the files among them are
+    // hashed with `spec` on the way, so nothing is unpacked. A hard link
+    // member stands for the file it links to.
+    #[cfg(feature = "archive")]
+    pub fn from_archive(path: &Path, spec: &HashSpec) -> io::Result<TargetIndex> {
+        let target_dir = path.display().to_string();
+        let mut index = TargetIndex { target_dir, prefix: String::new(), entries: HashMap::new() };
+        let mut file = io::BufReader::new(File::open(path)?);
+        let gzipped = io::BufRead::fill_buf(&mut file)?.starts_with(&[0x1f, 0x8b]);
+        let reader: Box<dyn Read> = if gzipped { Box::new(GzDecoder::new(file)) } else { Box::new(file) };
+        // counted as the audit reads, not while indexing
+        let spec = HashSpec { read_progress: None, sample: None, ..spec.clone() };
+        let mut archive = tar::Archive::new(reader);
+        for member in archive.entries()? {
+            let mut member = member?;
+            let name = member.path()?.to_string_lossy().into_owned();
+            let kind = member.header().entry_type();
+            if kind.is_hard_link() {
+                let linked = member.link_name()?.map(|l| index.key(l.to_string_lossy().trim_start_matches("./").trim_matches('/')));
+                let target = linked.and_then(|l| index.entries.get(&l)).map(|e| (e.size, e.digests.clone()));
+                if let (Some((size, digests)), Some(entry)) = (target, index.insert(&name, EntryKind::File, 0, None)) {
+                    entry.size = size;
+                    entry.digests = digests;
+                }
+                continue;
+            }
+            let kind = match kind {
+                k if k.is_file() || k.is_contiguous() => EntryKind::File,
+                k if k.is_dir() => EntryKind::Dir,
+                _ => EntryKind::Other,
+            };
+            let size = member.size();
+            let digests = match kind {
+                EntryKind::File => Some(hash::hash_stream(&spec, &mut member, None)?.expect("no read cap")),
+                _ => None,
+            };
+            if let Some(entry) = index.insert(&name, kind, size, None) {
+                entry.digests = digests;
+            }
+        }
+        Ok(index)
+    }
+
+    pub fn load(path: &Path, format: IndexFormat, target_dir: &str, prefix: &str) -> io::Result<TargetIndex> {
+        let mut index = TargetIndex {
+            target_dir: target_dir.to_string(),
+            prefix: prefix.to_string(),
+            entries: HashMap::new(),
+        };
+        match format {
+            IndexFormat::Find => index.load_find(&fs::read_to_string(path)?)?,
+            IndexFormat::Csv => index.load_csv(&fs::read_to_string(path)?)?,
+            IndexFormat::S3Inventory => index.load_s3_inventory(path)?,
+        }
+        Ok(index)
+    }
+
+    // `find DIR -printf '%y\t%s\t%P\n'`, or `'%s\t%P\n'` for a files-only listing.
+    fn load_find(&mut self, text: &str) -> io::Result<()> {
+        for (n, line) in text.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+            let fields: Vec<&str> = line.splitn(3, '\t').collect();
+            let (kind, size, rel) = match fields[..] {
+                [t, size, rel] => (kind_of(t), size, rel),
+                [size, rel] => (EntryKind::File, size, rel),
+                _ => return Err(invalid(format!("line {}: expected TYPE<tab>SIZE<tab>PATH", n + 1))),
+            };
+            let size = size.parse().map_err(|_| invalid(format!("line {}: size is not a number", n + 1)))?;
+            self.insert(rel, kind, size, None);
+        }
+        Ok(())
+    }
+
+    // A header names the path column (path, key or name, the last being what
+    // GCS inventory reports use) and the size column, plus optional type (in
+    // find's letters) and etag columns.
+    fn load_csv(&mut self, text: &str) -> io::Result<()> {
+        let mut lines = text.lines().enumerate();
+        let header = lines.next().map(|(_, l)| csv_fields(l)).unwrap_or_default();
+        let column = |names: &[&str]| header.iter().position(|h| names.contains(&h.trim().to_ascii_lowercase().as_str()));
+        let path_col = column(&["path", "key", "name"]).ok_or_else(|| invalid(String::from("line 1: no path, key or name column")))?;
+        let size_col = column(&["size"]).ok_or_else(|| invalid(String::from("line 1: no size column")))?;
+        let type_col = column(&["type"]);
+        let etag_col = column(&["etag"]);
+        for (n, line) in lines.filter(|(_, l)| !l.is_empty()) {
+            let fields = csv_fields(line);
+            let rel = fields.get(path_col).ok_or_else(|| invalid(format!("line {}: missing path", n + 1)))?;
+            let size = fields
+                .get(size_col)
+                .and_then(|s| s.trim().parse().ok())
+                .ok_or_else(|| invalid(format!("line {}: size is not a number", n + 1)))?;
+            let kind = type_col.and_then(|c| fields.get(c)).map(|t| kind_of(t.trim())).unwrap_or(EntryKind::File);
+            let etag = etag_col.and_then(|c| fields.get(c)).filter(|e| !e.is_empty()).map(|e| e.trim_matches('"').to_string());
+            self.insert(rel, kind, size, etag);
+        }
+        Ok(())
+    }
+
+    // An S3 Inventory manifest.json and its gzipped CSV data files, found
+    // next to the manifest or in the ../data directory S3 delivers them to.
+    fn load_s3_inventory(&mut self, manifest_path: &Path) -> io::Result<()> {
+        let manifest: serde_json::Value = serde_json::from_slice(&fs::read(manifest_path)?)?;
+        match manifest["fileFormat"].as_str() {
+            Some("CSV") => {}
+            other => return Err(invalid(format!("inventory format {:?} is not supported, only CSV", other.unwrap_or("unknown")))),
+        }
+        let schema: Vec<String> = manifest["fileSchema"]
+            .as_str()
+            .ok_or_else(|| invalid(String::from("manifest has no fileSchema")))?
+            .split(',')
+            .map(|c| c.trim().to_string())
+            .collect();
+        let column = |name: &str| schema.iter().position(|c| c == name);
+        let key_col = column("Key").ok_or_else(|| invalid(String::from("inventory has no Key field")))?;
+        let size_col = column("Size").ok_or_else(|| invalid(String::from("inventory has no Size field; enable it in the inventory configuration")))?;
+        let etag_col = column("ETag");
+        let latest_col = column("IsLatest");
+        let delete_col = column("IsDeleteMarker");
+
+        let dir = manifest_path.parent().unwrap_or(Path::new("."));
+        for file in manifest["files"].as_array().map(Vec::as_slice).unwrap_or_default() {
+            let key = file["key"].as_str().ok_or_else(|| invalid(String::from("manifest file without key")))?;
+            let name = Path::new(key).file_name().unwrap_or_default();
+            let data_path = [dir.join(name), dir.join("..").join("data").join(name)]
+                .into_iter()
+                .find(|p| p.exists())
+                .ok_or_else(|| invalid(format!("inventory data file {:?} not found near the manifest", name)))?;
+            let mut text = String::new();
+            GzDecoder::new(File::open(&data_path)?)
+                .read_to_string(&mut text)
+                .map_err(|e| invalid(format!("{}: {}", PathBuf::from(&data_path).display(), e)))?;
+            for line in text.lines().filter(|l| !l.is_empty()) {
+                let fields = csv_fields(line);
+                let field = |col: Option<usize>| col.and_then(|c| fields.get(c)).map(String::as_str);
+                // versioned buckets list old versions and delete markers too
+                if field(latest_col) == Some("false") || field(delete_col) == Some("true") {
+                    continue;
+                }
+                let key = url_decode(field(Some(key_col)).unwrap_or_default());
+                let size = field(Some(size_col)).and_then(|s| s.parse().ok()).unwrap_or(0);
+                let kind = if key.ends_with('/') { EntryKind::Dir } else { EntryKind::File };
+                let etag = field(etag_col).filter(|e| !e.is_empty()).map(|e| e.trim_matches('"').to_string());
+                self.insert(&key, kind, size, etag);
+            }
+        }
+        Ok(())
+    }
+}
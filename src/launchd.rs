@@ -0,0 +1,100 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use crate::schedule::Schedule;
+
+pub const DEFAULT_LABEL: &str = "io.github.mjsmith707.backup_auditor";
+
+pub struct Job<'a> {
+    pub label: &'a str,
+    pub program: &'a str,
+    pub args: &'a [String],
+    // launchd starts agents in /, so relative paths in `args` resolve here
+    pub working_dir: &'a str,
+    pub log: &'a str,
+    pub schedule: &'a Schedule,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+pub fn plist(job: &Job) -> String {
+    let mut out = String::from(concat!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+        "<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n",
+        "<plist version=\"1.0\">\n<dict>\n",
+    ));
+    out.push_str(&format!("  <key>Label</key>\n  <string>{}</string>\n", escape(job.label)));
+    out.push_str("  <key>ProgramArguments</key>\n  <array>\n");
+    for arg in std::iter::once(job.program).chain(job.args.iter().map(String::as_str)) {
+        out.push_str(&format!("    <string>{}</string>\n", escape(arg)));
+    }
+    out.push_str("  </array>\n");
+    out.push_str(&format!("  <key>WorkingDirectory</key>\n  <string>{}</string>\n", escape(job.working_dir)));
+    // a Mac asleep at the scheduled time runs the audit when it wakes instead
+    out.push_str("  <key>StartCalendarInterval</key>\n  <dict>\n");
+    if let Some(day) = job.schedule.weekday {
+        out.push_str(&format!("    <key>Weekday</key>\n    <integer>{}</integer>\n", day));
+    }
+    out.push_str(&format!(
+        "    <key>Hour</key>\n    <integer>{}</integer>\n    <key>Minute</key>\n    <integer>{}</integer>\n  </dict>\n",
+        job.schedule.hour, job.schedule.minute
+    ));
+    out.push_str("  <key>LowPriorityIO</key>\n  <true/>\n  <key>ProcessType</key>\n  <string>Background</string>\n");
+    for key in ["StandardOutPath", "StandardErrorPath"] {
+        out.push_str(&format!("  <key>{}</key>\n  <string>{}</string>\n", key, escape(job.log)));
+    }
+    out.push_str("</dict>\n</plist>\n");
+    out
+}
+
+fn home() -> io::Result<PathBuf> {
+    env::var_os("HOME").map(PathBuf::from).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "HOME is not set"))
+}
+
+// Per-user agents run while the user is logged in, which is when a Time
+// Machine disk or clone target is usually attached and unlocked.
+pub fn plist_path(label: &str) -> io::Result<PathBuf> {
+    Ok(home()?.join("Library/LaunchAgents").join(format!("{}.plist", label)))
+}
+
+pub fn log_path() -> io::Result<PathBuf> {
+    Ok(home()?.join("Library/Logs/backup_auditor.log"))
+}
+
+fn launchctl(args: &[&str]) -> io::Result<()> {
+    let status = Command::new("launchctl").args(args).status()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("launchctl {} failed ({})", args.join(" "), status)))
+    }
+}
+
+// Replaces any agent already installed under the same label.
+pub fn install(job: &Job) -> io::Result<PathBuf> {
+    let path = plist_path(job.label)?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    if path.exists() {
+        let _ = launchctl(&["unload", &path.to_string_lossy()]);
+    }
+    fs::write(&path, plist(job))?;
+    launchctl(&["load", "-w", &path.to_string_lossy()])?;
+    Ok(path)
+}
+
+pub fn uninstall(label: &str) -> io::Result<PathBuf> {
+    let path = plist_path(label)?;
+    if !path.exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no schedule installed at {}", path.display())));
+    }
+    // already unloaded by hand is fine, the file is what makes it come back
+    let _ = launchctl(&["unload", "-w", &path.to_string_lossy()]);
+    fs::remove_file(&path)?;
+    Ok(path)
+}
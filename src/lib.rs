@@ -0,0 +1,54 @@
+pub mod ack;
+#[cfg(target_os = "linux")]
+pub mod affinity;
+#[cfg(target_os = "linux")]
+mod attrs;
+pub mod audit;
+pub mod backuplog;
+#[cfg(feature = "bundle")]
+pub mod bundle;
+pub mod cache;
+pub mod chargeback;
+pub mod damage;
+pub mod encoding;
+#[cfg(target_os = "linux")]
+mod extents;
+pub mod filter;
+pub mod fixture;
+pub mod fsstat;
+pub mod hash;
+pub mod history;
+pub mod index;
+#[cfg(target_os = "macos")]
+pub mod launchd;
+pub mod manifest;
+pub mod merge;
+pub mod otlp;
+pub mod progress;
+pub mod query;
+pub mod remote;
+pub mod repair;
+pub mod report;
+pub mod recheck;
+pub mod rules;
+pub mod schedule;
+#[cfg(feature = "network")]
+mod s3;
+pub mod scratch;
+mod sftp;
+pub mod signing;
+pub mod skiplist;
+pub mod sqlite;
+pub mod strategy;
+pub mod template;
+pub mod throttle;
+pub mod ticket;
+#[cfg(feature = "network")]
+pub mod update;
+pub mod view;
+#[cfg(feature = "watch")]
+pub mod watch;
+#[cfg(windows)]
+pub mod winservice;
+
+pub use audit::{AuditConfig, AuditEvent, AuditSummary, Auditor, CompareOptions};
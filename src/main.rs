@@ -1,26 +1,99 @@
 extern crate getopts;
-use getopts::Options;
+
+use getopts::{Matches, Options};
 use std::{env, io, thread};
-use std::borrow::Borrow;
-use std::fs::File;
-use std::cell::RefCell;
-use std::io::Write;
+use std::io::IsTerminal;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::sync::atomic::Ordering;
-use sha2::{Sha256, Digest};
-use jwalk::{Parallelism, WalkDir};
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
 use rayon::prelude::*;
-use terminal_size;
+#[cfg(target_os = "linux")]
+use backup_auditor::affinity;
+#[cfg(target_os = "macos")]
+use backup_auditor::launchd;
+#[cfg(windows)]
+use backup_auditor::winservice;
+#[cfg(any(target_os = "macos", windows))]
+use backup_auditor::schedule::Schedule;
+#[cfg(feature = "bundle")]
+use backup_auditor::bundle;
+#[cfg(feature = "network")]
+use backup_auditor::update;
+#[cfg(feature = "watch")]
+use backup_auditor::watch;
+use backup_auditor::{ack, backuplog, chargeback, fixture, fsstat, hash, history, index, manifest, merge, otlp, progress, query, recheck, remote, report, rules, skiplist, template, throttle, ticket, view};
+use backup_auditor::audit::{self, AuditConfig, AuditEvent, AuditSummary, Auditor, CompareOptions, HardLinks};
+use backup_auditor::filter::{self, Preset, WalkFilter};
+use backup_auditor::cache::VerifyCache;
+use backup_auditor::scratch::Scratch;
+use backup_auditor::signing::{self, PublicKey, SecretKey};
+use backup_auditor::strategy::{self, Strategy};
+use progress::{Milestones, Progress, Refresh, Slots};
+use report::{Custody, Report, ReportOptions, RunInfo};
 
 struct Args {
     source_dir: String,
     target_dir: String,
     output_file: String,
+    report: Option<ReportOptions>,
+    command_line: Vec<String>,
+    compare: Option<CompareOptions>,
+    filter: Arc<WalkFilter>,
+    bidirectional: bool,
+    no_progress: bool,
+    progress_refresh: Duration,
+    milestones: Milestones,
+    inject_findings: u64,
+    watch: Option<Duration>,
+    from_log: Option<(String, Option<backuplog::LogFormat>)>,
+    recheck: Option<String>,
+    min_free_space: Option<fsstat::Threshold>,
+    otlp_endpoint: Option<String>,
+    skip_list: Option<(String, skiplist::SkipListFormat)>,
+    chargeback: Option<(String, chargeback::GroupBy)>,
+    // --history, added to when a full audit finishes
+    history: Option<String>,
+    // --ticket-template and --ticket-mismatches
+    tickets: Option<(ticket::Template, u64)>,
+    job_log: Option<(String, backuplog::JobStatus)>,
+    repair: Option<Repair>,
+    // hashing workers, which is also how many files are read at once
+    workers: usize,
+    walk_threads: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Profile {
+    Default,
+    // for NAS boxes with 512 MB or so: a few workers with small buffers, no
+    // target index held in memory and fewer progress redraws
+    LowMemory,
+}
+
+impl Profile {
+    fn parse(name: &str) -> Option<Profile> {
+        match name {
+            "default" => Some(Profile::Default),
+            "low-memory" => Some(Profile::LowMemory),
+            _ => None,
+        }
+    }
+}
+
+struct Repair {
+    dry_run: bool,
+    delete_extra: bool,
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} FILE [options]", program);
+    let brief = format!(
+        "Usage: {0} -s SOURCE -t TARGET -o OUTPUT [options]\n       {0} [options] -o OUTPUT [--] SOURCE TARGET\nPut -- before SOURCE and TARGET when either begins with '-'.\nExits 0 if the trees match, 1 if they differ, 2 if files couldn't be read and 3 on a usage error.",
+        program
+    );
     print!("{}", opts.usage(&brief));
 }
 
@@ -28,186 +101,2562 @@ fn print_banner() {
     println!("============\nBackup Auditor v0.1.0\n============\n")
 }
 
+// Cargo features that leave out optional dependencies, and whether this build
+// has them.
+const FEATURES: &[(&str, bool)] = &[
+    ("network", cfg!(feature = "network")),
+    ("watch", cfg!(feature = "watch")),
+    ("bundle", cfg!(feature = "bundle")),
+    ("archive", cfg!(feature = "archive")),
+    ("trace", cfg!(feature = "trace")),
+];
+
+fn require_feature(json: bool, what: &str, feature: &str) {
+    if !FEATURES.iter().any(|(name, built)| *name == feature && *built) {
+        config_error(json, &format!("{} needs a build with the {:?} feature", what, feature));
+    }
+}
+
+// The banner already carries the version, this adds what was compiled in.
+fn print_version() {
+    println!("commit: {}", env!("BUILD_GIT_COMMIT"));
+    println!("target: {}", env!("BUILD_TARGET"));
+    println!("profile: {}", env!("BUILD_PROFILE"));
+    println!("hash backends: sha1, sha256, sha512, blake3, xxhash64, s3-etag (keyed: hmac-sha1, hmac-sha256, hmac-sha512, blake3-keyed)");
+    println!("remote targets: {}sftp (S3 Inventory listings via --target-index)", if cfg!(feature = "network") { "s3, " } else { "" });
+    let features: Vec<String> = FEATURES.iter().map(|(name, built)| format!("{}{}", if *built { '+' } else { '-' }, name)).collect();
+    println!("features: {}", features.join(" "));
+    println!("io_uring: no");
+    println!("platform checks: {}", if cfg!(target_os = "linux") { "attrs, selinux, clones" } else { "none" });
+}
+
 fn main() {
     print_banner();
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
+    if args.get(1).map(|a| a == "gen-fixture").unwrap_or(false) {
+        gen_fixture(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "export-bundle").unwrap_or(false) {
+        require_feature(wants_json(&args[2..]), "export-bundle", "bundle");
+        #[cfg(feature = "bundle")]
+        export_bundle(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "inspect-bundle").unwrap_or(false) {
+        require_feature(wants_json(&args[2..]), "inspect-bundle", "bundle");
+        #[cfg(feature = "bundle")]
+        inspect_bundle(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "ack").unwrap_or(false) {
+        ack_finding(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "view").unwrap_or(false) {
+        view_report(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "query").unwrap_or(false) {
+        query_results(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "merge-reports").unwrap_or(false) {
+        merge_reports(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "manifest").unwrap_or(false) {
+        write_manifest(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "update-manifest").unwrap_or(false) {
+        update_manifest(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "verify").unwrap_or(false) {
+        verify_manifest(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "keygen").unwrap_or(false) {
+        generate_key(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "verify-seal").unwrap_or(false) {
+        verify_seal(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "sign").unwrap_or(false) {
+        sign_file(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "self-update").unwrap_or(false) {
+        require_feature(subcommand_wants_json(&args[2..]), "self-update", "network");
+        #[cfg(feature = "network")]
+        self_update(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "install-schedule").unwrap_or(false) {
+        install_schedule(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "uninstall-schedule").unwrap_or(false) {
+        uninstall_schedule(&program, &args[2..]);
+        return;
+    }
+    if args.get(1).map(|a| a == "service").unwrap_or(false) {
+        service_command(&program, &args[2..]);
+        return;
+    }
+
     let mut opts = Options::new();
-    opts.optopt("s", "", "set the source directory (required)", "SOURCE");
-    opts.optopt("t", "", "set the target directory (required)", "TARGET");
+    opts.optopt("s", "", "set the source directory (required unless given as an argument)", "SOURCE");
+    opts.optopt("t", "", "set the target directory, a .tar or .tar.gz archive, s3://BUCKET/PREFIX for a bucket or sftp://[USER@]HOST[:PORT]/PATH for a directory on a server reachable over SSH (required unless given as an argument)", "TARGET");
     opts.optopt("o", "", "output filename (required)", "OUTPUT");
+    opts.optopt("", "template", "add the options in FILE, one per line (e.g. \"-o /var/log/{{dataset}}-{{date}}.txt\"), after replacing {{date}} (UTC, YYYY-MM-DD), {{hostname}} and --var variables; an option can't be given both there and on the command line", "FILE");
+    opts.optmulti("", "var", "with --template, replace {{NAME}} with VALUE, e.g. dataset=tank/home", "NAME=VALUE");
+    opts.optopt("", "format", "output file format: text, json (JSON Lines, one object per finding and a summary), csv (one row per finding, no summary) or html (one page with the summary and a sortable table per kind of finding, written at the end) or sqlite (a database runs are added to, see the query subcommand; needs sqlite3; default text)", "FORMAT");
+    opts.optopt("", "custody", "write a chain-of-custody report attributed to OPERATOR", "OPERATOR");
+    opts.optopt("", "custody-key", "add a MAC (HMAC-SHA256, not a signature: checking it takes the same secret) of the chain-of-custody seal keyed with the secret in FILE; check it with printf %s REPORT_SHA256 | openssl dgst -sha256 -mac HMAC -macopt hexkey:$(xxd -p FILE | tr -d '\\n')", "FILE");
+    opts.optopt("", "custody-sign", "sign the chain-of-custody seal with the ed25519 secret key in FILE (see keygen), which anyone with the public key can check with verify-seal", "FILE");
+    opts.optflag("", "append-only", "append to the output file with strictly sequential writes (WORM media)");
+    opts.optflag("", "follow-symlinks", "compare the content symlinks point to instead of the paths they point to");
+    opts.optflag("", "check-hard-links", "report source files linked together whose target copies aren't, and target files linked together whose sources aren't");
+    opts.optflag("", "check-metadata", "compare permission bits, owner, group and modification time");
+    opts.optopt("", "metadata-fields", "with --check-metadata, compare only these of mode, uid, gid, readonly (non-unix), mtime and btime (creation time, where both sides record it); default all but btime", "LIST");
+    opts.optopt("", "mtime-tolerance", "with --check-metadata, accept modification and creation times up to SECS seconds apart (default 0; 2 for FAT targets)", "SECS");
+    opts.optflag("", "check-attrs", "compare immutable/append-only flags and file capabilities (Linux)");
+    opts.optflag("", "check-selinux", "compare security.selinux labels (Linux)");
+    opts.optflag("", "detect-clones", "note identical target files that share on-disk extents with their source, i.e. reflinks or dedupe on btrfs/XFS rather than independent copies (Linux)");
+    opts.optflag("", "read-holes", "read the holes of sparse files like any other data instead of skipping them and hashing the zeros they hold (Linux)");
+    opts.optflag("", "check-sparse", "report identical files holding a different amount of data, or in a different number of ranges, e.g. a sparse VM image copied fully allocated (Linux)");
+    opts.optflag("", "fadvise", "hint sequential read-ahead for large files and drop them from the page cache once hashed (Linux)");
+    opts.optopt("", "cpu-affinity", "pin hashing workers to these CPUs (e.g. 0-7,16-23), or \"auto\" for the NUMA nodes of the disks holding the roots (Linux)", "LIST");
+    opts.optmulti("", "preset-excludes", "skip a bundled set of paths (os-junk)", "PRESET");
+    opts.optmulti("", "exclude", "skip files and whole directories matching GLOB, by name (node_modules, *.vmdk) or by path relative to the root", "GLOB");
+    opts.optflag("", "no-auditignore", "don't honor .auditignore files (.gitignore-style patterns) in the source root and the directories below it");
+    opts.optmulti("", "include", "audit only files matching GLOB; directories are still walked", "GLOB");
+    opts.optflag("", "no-progress", "no progress bars; log milestone lines instead");
+    opts.optopt("", "progress-refresh", "redraw the progress bars at most every MS milliseconds, e.g. 1000 over slow SSH links (default 66)", "MS");
+    opts.optopt("", "milestone-percent", "with --no-progress, log every N percent (default 5)", "N");
+    opts.optopt("", "milestone-minutes", "with --no-progress, log at least every N minutes (default 10)", "N");
+    opts.optflag("", "check-update", "check GitHub for a newer release and exit (the self-update subcommand installs it)");
+    opts.optopt("", "hash", "comma separated hash algorithms computed in one pass: sha1, sha256, sha512, blake3, xxhash64, s3-etag (default sha256)", "LIST");
+    opts.optopt("", "s3-part-size", "multipart chunk size the s3-etag digest assumes (default 8M)", "SIZE");
+    opts.optopt("", "hash-cmd", "also hash content by piping it to CMD (run by the shell) and taking the first word it prints, e.g. 'xxhsum -H3'; replaces the default sha256 unless --hash is given", "CMD");
+    opts.optopt("", "hash-key", "use keyed digests (HMAC-SHA256, keyed BLAKE3) with the secret in FILE", "FILE");
+    opts.optopt("", "baseline-manifest", "tell content mismatches apart by a manifest of the source from when both sides last matched: source changed, target corrupted, or both; hashes with its algorithms", "FILE");
+    opts.optflag("", "watch", "instead of a full audit, verify files as they change in the source; runs until interrupted");
+    opts.optopt("", "watch-delay", "seconds to give the backup tool to copy a changed file before verifying it (default 60)", "SECS");
+    opts.optopt("", "from-log", "verify only the files a backup log says were transferred", "FILE");
+    opts.optopt("", "recheck", "audit only the entries a previous report written with --format json has findings about, e.g. once they've been fixed", "REPORT");
+    opts.optopt("", "job-log", "check the copied and failed counts and exit status in the backup job's log against the findings (backup said success but files are missing)", "FILE");
+    opts.optopt("", "job-exit-code", "the backup job's exit code, for logs that don't state it (robocopy)", "N");
+    opts.optopt("", "log-format", "format of the --from-log and --job-log files: rsync (--itemize-changes) or robocopy (default: detected)", "FORMAT");
+    opts.optmulti("", "expect-different", "paths matching GLOB (relative to the source) must exist in the target, but content differences are only informational; repeatable", "GLOB");
+    opts.optopt("", "rules", "per-path tolerance rules, one \"NAME GLOB exists|size-within N%\" per line", "FILE");
+    opts.optopt("", "template-dir", "word findings using the <kind>.txt templates in DIR ({src}, {tgt}, {reason}, ... placeholders)", "DIR");
+    opts.optopt("", "target-index", "check existence and size against this listing of the target instead of reading it", "FILE");
+    opts.optopt("", "index-format", "format of --target-index: find (find -printf '%y\\t%s\\t%P\\n'), csv or s3-inventory (a manifest.json; default: by file name, else find)", "FORMAT");
+    opts.optopt("", "index-prefix", "only use index entries under PREFIX, which stands for the target root", "PREFIX");
+    opts.optflag("", "verify-etags", "compare source content against the S3 ETags in the target index (reads the source only; always on for s3:// targets)");
+    opts.optflag("", "read-remote", "with an s3:// target, download each object and hash it like the source instead of going by its size and ETag (ETags of SSE-KMS and SSE-C encrypted objects never match)");
+    opts.optflag("", "s3-checksums", "with an s3:// target, compare the source with the SHA-256, CRC64NVME or CRC32C checksum S3 keeps of each object (one HEAD request each), and only with --read-remote or the ETag for objects without one");
+    opts.optopt("", "s3-endpoint", "S3-compatible endpoint an s3:// target is on, e.g. http://minio:9000 (default: $AWS_ENDPOINT_URL, else AWS); credentials come from $AWS_ACCESS_KEY_ID and $AWS_SECRET_ACCESS_KEY", "URL");
+    opts.optopt("", "ssh-command", "ssh client an sftp:// target is reached with, and its options (default: ssh); there is one connection per file read at once, see --threads and --io-concurrency", "COMMAND");
+    opts.optopt("", "s3-region", "region of the s3:// target's bucket (default: $AWS_REGION, else us-east-1)", "REGION");
+    opts.optopt("", "target-latency", "adapt the number of files read at once on each root to keep read latency under MS milliseconds", "MS");
+    opts.optopt("", "max-findings-per-category", "list at most N findings of each kind; the rest are only counted", "N");
+    opts.optopt("", "ack-file", "findings acknowledged with the ack subcommand are reported as acknowledged instead of raised", "FILE");
+    opts.optopt("", "history", "keep a history of full audits of this source and target in FILE (JSON Lines, appended to), and report findings that came and went across its runs as flaky instead of raising them", "FILE");
+    opts.optopt("", "flaky-window", "with --history, how many of the last runs tell whether a finding is flaky (default 10)", "N");
+    opts.optopt("", "ticket-template", "with --history, open a ticket with the HTTP request in FILE (METHOD URL, headers, a blank line and the body; {title}, {description}, {env:NAME}, ... placeholders) for each directory missing from the target and for more than --ticket-mismatches differing files, unless one is still open from an earlier run", "FILE");
+    opts.optopt("", "ticket-mismatches", "with --ticket-template, how many differing files it takes to open a ticket (default 100)", "N");
+    opts.optopt("", "canary-dir", "before auditing, write, read back and delete a test file in DIR on the target media; abort if that fails", "DIR");
+    opts.optflag("", "storage-efficiency", "summarize how much disk the compared target files take for their data, and the compression and dedup ratios of the target's ZFS dataset or btrfs filesystem (needs zfs or compsize)");
+    opts.optopt("", "min-free-space", "raise a warning if the target has less than SIZE or N% free", "SIZE|N%");
+    opts.optopt("", "skip-list", "write the source files that couldn't be read (permissions, I/O errors) to FILE for the backup tool to exclude", "FILE");
+    opts.optopt("", "skip-list-format", "format of --skip-list: rsync (--exclude-from) or borg (--exclude-from, pf: patterns; default rsync)", "FORMAT");
+    opts.optopt("", "chargeback", "write entries and bytes compared and findings per tenant to FILE as CSV", "FILE");
+    opts.optopt("", "chargeback-by", "group --chargeback by project (the directory directly under the root, default) or owner (uid)", "GROUPING");
+    opts.optflag("", "repair", "after the audit, copy missing and mismatched files from the source to the target, logging each action");
+    opts.optflag("", "repair-dry-run", "like --repair, but only log what would be done");
+    opts.optflag("", "repair-delete-extra", "let --repair delete target entries not in the source, and entries of the wrong type before copying over them");
+    opts.optopt("", "otlp-endpoint", "export run metrics and stage spans over OTLP/HTTP to URL (e.g. http://collector:4318)", "URL");
+    opts.optopt("", "trace-output", "write a Chrome trace (chrome://tracing, Perfetto) of where time goes per file to FILE", "FILE");
+    opts.optopt("", "file-timeout", "give up on a file pair after SECS seconds", "SECS");
+    opts.optflag("", "quick", "compare only size and modification time (to the second) instead of hashing content");
+    opts.optflag("", "classify-damage", "reread files of the same size whose content differs to tell likely bit rot (a few bytes in one place) from a replaced file");
+    opts.optflag("", "check-encoding", "for text files whose content differs, also note differences in encoding, byte order mark and line endings, as left by gateways that transcode documents (informational)");
+    opts.optflag("", "adaptive", "pick how to verify each file: in full below 64M, by size and modification time if verified in full in the last 7 days (see --verify-cache), sampled at 1G or more when unmodified for 30 days, otherwise in full; the report lists the method per file");
+    opts.optopt("", "method-policy", "like --adaptive, with the policies in FILE, the first that applies deciding: one \"full|sample|quick CONDITION...\" per line, conditions being path=GLOB, size>=SIZE, size<SIZE, cold=DURATION and verified-within=DURATION", "FILE");
+    opts.optopt("", "verify-cache", "with --adaptive or --method-policy, remember in FILE which pairs were verified in full and when, for verified-within", "FILE");
+    opts.optflag("", "verify-on-match", "with --quick, still hash files whose size and modification time match");
+    opts.optopt("", "sample", "hash only SIZE at the start and end of each file and every --sample-stride in between; matches are probabilistic (with --adaptive or --method-policy, only files the sample method is picked for, default 1M)", "SIZE");
+    opts.optopt("", "sample-stride", "with --sample, distance between sampled blocks (default 64M)", "SIZE");
+    opts.optopt("", "threads", "hash with N worker threads instead of one per CPU; fewer keep a spinning disk from thrashing", "N");
+    opts.optopt("", "io-concurrency", "read at most N files at once, whatever the number of threads", "N");
+    opts.optopt("", "profile", "tune for the machine: default, or low-memory (4 workers, 64K buffers, progress redrawn once a second, no --target-index) for small NAS devices", "NAME");
+    opts.optopt("", "buffer-size", "read files in chunks of SIZE (default 256K); 4M or more helps saturate NVMe arrays, at one buffer per worker thread", "SIZE");
+    opts.optopt("", "max-read", "give up on files larger than SIZE (e.g. 64M, 2G)", "SIZE");
+    opts.optopt("", "scratch-dir", "keep temporary data (e.g. --format html's table rows) in a directory of the run's own under DIR, removed when the run succeeds and kept for diagnosis when it fails (default: the system temp directory)", "DIR");
+    opts.optopt("", "scratch-quota", "use at most SIZE of scratch space, holding what doesn't fit in memory instead", "SIZE");
+    opts.optflag("", "allow-overlap", "audit even when the target is inside the source, or the other way around");
+    opts.optflag("", "fail-fast", "stop at the first difference or read error instead of auditing everything");
+    opts.optflag("", "dir-counts", "also compare how many entries each directory holds on both sides, a cheap map of where a copy is incomplete (works with --quick)");
+    opts.optopt("", "labels", "name the source and target in findings, e.g. \"copy A,copy B\" when comparing two backups with each other", "SRC,TGT");
+    opts.optopt("", "reference", "which root to walk as the reference: source (default) or target, which swaps the roles of the two; findings then name each by its --labels (default: the paths given)", "ROOT");
+    opts.optflag("", "bidirectional", "also walk the target and report entries that don't exist in the source");
+    opts.optflag("", "include-virtual-fs", "descend into /proc, /sys and other virtual filesystems");
+    opts.optopt("", "inject-findings", "add N clearly labeled synthetic findings to test alerting", "N");
+    opts.optflag("V", "version", "print version and build information");
     opts.optflag("h", "help", "print this help menu");
 
+    // known before parsing so that a parse error is already reported as JSON
+    let json = wants_json(&args);
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!("{}", f.to_string()) }
+        Err(f) => config_error(json, &f.to_string()),
+    };
+    // the template's options are parsed as if given after the command line's,
+    // and recorded that way in the chain of custody
+    let (args, matches) = match matches.opt_str("template") {
+        Some(file) => {
+            let mut vars = template::builtin_vars();
+            for var in matches.opt_strs("var") {
+                match var.split_once('=') {
+                    Some((name, value)) if !name.trim().is_empty() => vars.insert(name.trim().to_string(), value.to_string()),
+                    _ => config_error(json, &format!("--var expects NAME=VALUE, got {:?}", var)),
+                };
+            }
+            let text = fs::read_to_string(&file).unwrap_or_else(|e| config_error(json, &format!("cannot read template {:?}: {}", file, e)));
+            let extra = template::arguments(&text, &vars).unwrap_or_else(|e| config_error(json, &format!("template {:?}: {}", file, e)));
+            let args: Vec<String> = args.into_iter().chain(extra).collect();
+            let json = wants_json(&args);
+            match opts.parse(&args[1..]) {
+                Ok(m) => (args, m),
+                Err(f) => config_error(json, &format!("{} (with template {:?})", f, file)),
+            }
+        }
+        None if matches.opt_present("var") => config_error(json, "--var requires --template"),
+        None => (args, matches),
     };
+    let json = wants_json(&args);
 
     if matches.opt_present("h") {
         print_usage(&program, opts);
         return;
     }
 
-    match (matches.opt_present("s"), matches.opt_present("t"), matches.opt_present("o")) {
-        (true, true, true) => { }
-        (_, _, _) => {
-            print_usage(&program, opts);
-            return;
+    if matches.opt_present("V") {
+        print_version();
+        return;
+    }
+
+    for (option, feature) in [("check-update", "network"), ("otlp-endpoint", "network"), ("ticket-template", "network"), ("watch", "watch"), ("trace-output", "trace")] {
+        if matches.opt_present(option) {
+            require_feature(json, &format!("--{}", option), feature);
+        }
+    }
+
+    #[cfg(feature = "network")]
+    if matches.opt_present("check-update") {
+        match update::check_update() {
+            Ok(update::UpdateStatus::UpToDate) => println!("Backup Auditor v{} is up to date", env!("CARGO_PKG_VERSION")),
+            Ok(update::UpdateStatus::NoReleases) => println!("No releases have been published yet"),
+            Ok(update::UpdateStatus::Available { version, url }) => {
+                println!("A newer release is available: {} (running v{})\n{}", version, env!("CARGO_PKG_VERSION"), url)
+            }
+            Err(e) => runtime_error(json, &format!("Failed to check for updates: {}", e)),
+        }
+        return;
+    }
+
+    // -s/-t or two positional arguments, never a mix of both
+    let (source_arg, target_arg) = match (matches.opt_str("s"), matches.opt_str("t"), &matches.free[..]) {
+        (Some(s), Some(t), []) => (s, t),
+        (None, None, [s, t]) => (s.clone(), t.clone()),
+        _ => usage_error(json, &program, opts, "Source and target are required, either as -s and -t or as two arguments"),
+    };
+    let mut labels = match matches.opt_str("labels") {
+        Some(l) => match l.split_once(',') {
+            Some((src, tgt)) if !src.is_empty() && !tgt.is_empty() => Some((src.to_string(), tgt.to_string())),
+            _ => config_error(json, &format!("Invalid --labels {:?}: expected two names separated by a comma", l)),
+        },
+        None => None,
+    };
+    // Walking the target instead swaps the roles of the two roots; the labels
+    // keep naming the directories as given.
+    let (source_arg, target_arg) = match matches.opt_str("reference").as_deref() {
+        None | Some("source") => (source_arg, target_arg),
+        Some("target") => {
+            let (src, tgt) = labels.take().unwrap_or_else(|| (source_arg.clone(), target_arg.clone()));
+            labels = Some((tgt, src));
+            (target_arg, source_arg)
+        }
+        Some(other) => config_error(json, &format!("Invalid --reference {:?}: expected source or target", other)),
+    };
+    if !matches.opt_present("o") {
+        usage_error(json, &program, opts, "-o is required");
+    }
+
+    for custody_only in ["custody-key", "custody-sign"] {
+        if matches.opt_present(custody_only) && !matches.opt_present("custody") {
+            usage_error(json, &program, opts, &format!("--{} needs --custody", custody_only));
+        }
+    }
+
+    for linux_only in ["check-attrs", "check-selinux", "detect-clones", "fadvise", "cpu-affinity"] {
+        if matches.opt_present(linux_only) && !cfg!(target_os = "linux") {
+            config_error(json, &format!("--{} is only supported on Linux", linux_only));
+        }
+    }
+    if matches.opt_present("check-hard-links") && !cfg!(unix) {
+        config_error(json, "--check-hard-links is only supported on unix");
+    }
+
+    let mut filter = WalkFilter::default();
+    filter.include_virtual_fs = matches.opt_present("include-virtual-fs");
+    for name in matches.opt_strs("preset-excludes").iter().flat_map(|v| v.split(',')) {
+        match Preset::parse(name) {
+            Some(p) => filter.add_preset(p),
+            None => config_error(json, &format!("Unknown exclusion preset {:?}", name)),
+        }
+    }
+    if let Err(e) = filter.set_globs(&matches.opt_strs("exclude"), &matches.opt_strs("include")) {
+        config_error(json, &format!("Invalid --exclude or --include pattern: {}", e));
+    }
+    if !matches.opt_present("no-auditignore") {
+        if let Err(e) = filter.use_ignore_files(Path::new(&source_arg)) {
+            config_error(json, &format!("Invalid {} in the source root: {}", filter::IGNORE_FILE, e));
         }
     }
+    for own in ["o", "trace-output", "ack-file", "history", "skip-list", "chargeback", "verify-cache"].iter().filter_map(|name| matches.opt_str(name)) {
+        filter.add_own_file(Path::new(&own));
+    }
+
+    let profile = match matches.opt_str("profile") {
+        Some(name) => Profile::parse(&name).unwrap_or_else(|| config_error(json, &format!("Unknown profile {:?}", name))),
+        None => Profile::Default,
+    };
+    if profile == Profile::LowMemory && matches.opt_present("target-index") {
+        config_error(json, "--target-index loads the whole listing into memory and can't be combined with --profile low-memory");
+    }
+    let (mut workers, walk_threads) = match profile {
+        Profile::Default => (num_cpus::get(), 0),
+        Profile::LowMemory => (num_cpus::get().min(4), 2),
+    };
+    match matches.opt_get::<usize>("threads") {
+        Ok(Some(0)) => config_error(json, "Invalid --threads: must be at least 1"),
+        Ok(Some(_)) if matches.opt_present("cpu-affinity") => config_error(json, "--threads can't be combined with --cpu-affinity, which runs one worker per CPU it pins to"),
+        Ok(Some(n)) => workers = n,
+        Ok(None) => {}
+        Err(e) => config_error(json, &format!("Invalid --threads: {}", e)),
+    }
+    let io_concurrency = match matches.opt_get::<usize>("io-concurrency") {
+        Ok(Some(0)) => config_error(json, "Invalid --io-concurrency: must be at least 1"),
+        Ok(n) => n,
+        Err(e) => config_error(json, &format!("Invalid --io-concurrency: {}", e)),
+    };
+    let progress_refresh = match matches.opt_get_default("progress-refresh", if profile == Profile::LowMemory { 1000 } else { 66u64 }) {
+        Ok(n) if n > 0 => n,
+        Ok(_) => config_error(json, "Invalid --progress-refresh: must be at least 1"),
+        Err(e) => config_error(json, &format!("Invalid --progress-refresh: {}", e)),
+    };
+    let milestone_percent = match matches.opt_get_default("milestone-percent", 5u64) {
+        Ok(n) => n,
+        Err(e) => config_error(json, &format!("Invalid --milestone-percent: {}", e)),
+    };
+    let milestone_minutes = match matches.opt_get_default("milestone-minutes", 10u64) {
+        Ok(n) => n,
+        Err(e) => config_error(json, &format!("Invalid --milestone-minutes: {}", e)),
+    };
+
+    let inject_findings = match matches.opt_get_default("inject-findings", 0u64) {
+        Ok(n) => n,
+        Err(e) => config_error(json, &format!("Invalid --inject-findings: {}", e)),
+    };
 
-    let parsed_args = Args {
-        source_dir: {
-            let m = matches.opt_str("s").unwrap();
-            m.strip_suffix("/").unwrap_or(m.borrow()).to_string()
+    let watch_delay = match matches.opt_get_default::<u64>("watch-delay", 60) {
+        Ok(d) => Duration::from_secs(d),
+        Err(e) => config_error(json, &format!("Invalid --watch-delay: {}", e)),
+    };
+
+    let log_format = match matches.opt_str("log-format") {
+        Some(name) => match backuplog::LogFormat::parse(&name) {
+            Some(f) => Some(f),
+            None => config_error(json, &format!("Unknown log format {:?}", name)),
         },
-        target_dir: {
-            let m = matches.opt_str("t").unwrap();
-            m.strip_suffix("/").unwrap_or(m.borrow()).to_string()
+        None => None,
+    };
+    let chargeback_by = match matches.opt_str("chargeback-by") {
+        Some(_) if !matches.opt_present("chargeback") => config_error(json, "--chargeback-by needs --chargeback"),
+        Some(name) => match chargeback::GroupBy::parse(&name) {
+            Some(chargeback::GroupBy::Owner) if !cfg!(unix) => config_error(json, "--chargeback-by owner is only supported on unix"),
+            Some(by) => by,
+            None => config_error(json, &format!("Unknown chargeback grouping {:?}", name)),
         },
-        output_file: matches.opt_str("o").unwrap(),
+        None => chargeback::GroupBy::Project,
     };
+    let skip_list_format = match matches.opt_str("skip-list-format") {
+        Some(_) if !matches.opt_present("skip-list") => config_error(json, "--skip-list-format needs --skip-list"),
+        Some(name) => match skiplist::SkipListFormat::parse(&name) {
+            Some(f) => f,
+            None => config_error(json, &format!("Unknown skip list format {:?}", name)),
+        },
+        None => skiplist::SkipListFormat::Rsync,
+    };
+    if matches.opt_present("verify-on-match") && !matches.opt_present("quick") {
+        config_error(json, "--verify-on-match needs --quick");
+    }
+    let mtime_tolerance = match matches.opt_get_default("mtime-tolerance", 0u64) {
+        Ok(_) if matches.opt_present("mtime-tolerance") && !matches.opt_present("check-metadata") => config_error(json, "--mtime-tolerance needs --check-metadata"),
+        Ok(t) => t,
+        Err(e) => config_error(json, &format!("Invalid --mtime-tolerance: {}", e)),
+    };
+    let metadata_fields = match matches.opt_str("metadata-fields") {
+        Some(_) if !matches.opt_present("check-metadata") => config_error(json, "--metadata-fields needs --check-metadata"),
+        Some(list) => list
+            .split(',')
+            .map(|name| match audit::METADATA_FIELDS.iter().find(|f| **f == name.trim()) {
+                Some(field) => *field,
+                None => config_error(json, &format!("Unknown metadata field {:?}", name)),
+            })
+            .collect(),
+        None => audit::DEFAULT_METADATA_FIELDS.to_vec(),
+    };
+    if matches.opt_present("watch") && matches.opt_present("from-log") {
+        config_error(json, "--watch and --from-log can't be combined");
+    }
+    for other in ["watch", "from-log"] {
+        if matches.opt_present("recheck") && matches.opt_present(other) {
+            config_error(json, &format!("--recheck can't be combined with --{}", other));
+        }
+    }
+    if matches.opt_present("fail-fast") && matches.opt_present("watch") {
+        config_error(json, "--fail-fast can't be combined with --watch");
+    }
+    if matches.opt_present("dir-counts") && matches.opt_present("target-index") {
+        config_error(json, "--dir-counts can't be combined with --target-index");
+    }
+    let job_exit_code = match matches.opt_get::<i32>("job-exit-code") {
+        Ok(Some(_)) if !matches.opt_present("job-log") => config_error(json, "--job-exit-code needs --job-log"),
+        Ok(code) => code,
+        Err(e) => config_error(json, &format!("Invalid --job-exit-code: {}", e)),
+    };
+    if matches.opt_present("job-log") && matches.opt_present("watch") {
+        config_error(json, "--job-log can't be combined with --watch");
+    }
+    let job_log = matches.opt_str("job-log").map(|log| match backuplog::job_status(Path::new(&log), log_format) {
+        Ok(mut status) => {
+            status.exit_code = job_exit_code.or(status.exit_code);
+            (log, status)
+        }
+        Err(e) => runtime_error(json, &format!("Failed to read backup job log {:?}: {}", log, e)),
+    });
+    let repairing = matches.opt_present("repair") || matches.opt_present("repair-dry-run");
+    if matches.opt_present("repair-delete-extra") && !repairing {
+        config_error(json, "--repair-delete-extra needs --repair or --repair-dry-run");
+    }
+    if repairing {
+        for other in ["watch", "target-index"] {
+            if matches.opt_present(other) {
+                config_error(json, &format!("--repair can't be combined with --{}", other));
+            }
+        }
+    }
+    if matches.opt_present("bidirectional") {
+        for other in ["watch", "from-log", "recheck", "target-index"] {
+            if matches.opt_present(other) {
+                config_error(json, &format!("--bidirectional can't be combined with --{}", other));
+            }
+        }
+    }
 
-    println!("Source directory: {:?}\nTarget directory: {:?}\nOutput filename: {:?}", parsed_args.source_dir, parsed_args.target_dir, parsed_args.output_file);
+    let file_timeout = match matches.opt_get::<u64>("file-timeout") {
+        Ok(t) => t.map(Duration::from_secs),
+        Err(e) => config_error(json, &format!("Invalid --file-timeout: {}", e)),
+    };
+    let io_control = match matches.opt_get::<u64>("target-latency") {
+        Ok(Some(0)) => config_error(json, "Invalid --target-latency: must be greater than zero"),
+        Ok(target) => target.map(|ms| {
+            let target = Duration::from_millis(ms);
+            Arc::new(throttle::IoControl {
+                src: throttle::LatencyController::new(target, workers),
+                tgt: throttle::LatencyController::new(target, workers),
+            })
+        }),
+        Err(e) => config_error(json, &format!("Invalid --target-latency: {}", e)),
+    };
+    let max_read = match matches.opt_str("max-read").map(|s| parse_size(&s)).transpose() {
+        Ok(m) => m,
+        Err(e) => config_error(json, &format!("Invalid --max-read: {}", e)),
+    };
+    let scratch_quota = match matches.opt_str("scratch-quota").map(|s| parse_size(&s)).transpose() {
+        Ok(q) => q,
+        Err(e) => config_error(json, &format!("Invalid --scratch-quota: {}", e)),
+    };
+    let scratch_dir = matches.opt_str("scratch-dir").map(PathBuf::from).unwrap_or_else(env::temp_dir);
+    let scratch = Arc::new(Scratch::new(&scratch_dir, scratch_quota, &args));
+    filter.add_own_file(scratch.path());
 
-    deep_check(parsed_args);
-}
+    let buffer_size = match matches.opt_str("buffer-size").map(|s| parse_size(&s)).transpose() {
+        Ok(Some(b)) if b < 4096 => config_error(json, "Invalid --buffer-size: must be at least 4K"),
+        Ok(Some(b)) => usize::try_from(b).unwrap_or_else(|_| config_error(json, "Invalid --buffer-size: too large")),
+        Ok(None) if profile == Profile::LowMemory => 64 * 1024,
+        Ok(None) => hash::DEFAULT_BUFFER_SIZE,
+        Err(e) => config_error(json, &format!("Invalid --buffer-size: {}", e)),
+    };
+
+    let sample = match (matches.opt_str("sample").map(|s| parse_size(&s)).transpose(), matches.opt_str("sample-stride").map(|s| parse_size(&s)).transpose()) {
+        (Ok(Some(0)), _) => config_error(json, "Invalid --sample: must be greater than zero"),
+        (Ok(None), Ok(Some(_))) => config_error(json, "--sample-stride needs --sample"),
+        (Ok(Some(block)), Ok(stride)) => {
+            let stride = stride.unwrap_or(64 << 20);
+            if stride < block {
+                config_error(json, "Invalid --sample-stride: must be at least the --sample size");
+            }
+            Some(hash::Sample { block, stride })
+        }
+        (Ok(None), Ok(None)) => None,
+        (Err(e), _) => config_error(json, &format!("Invalid --sample: {}", e)),
+        (_, Err(e)) => config_error(json, &format!("Invalid --sample-stride: {}", e)),
+    };
+    let policies = match (matches.opt_str("method-policy"), matches.opt_present("adaptive")) {
+        (Some(_), true) => config_error(json, "--adaptive and --method-policy can't be combined"),
+        (Some(f), false) => match fs::read_to_string(&f).map_err(|e| e.to_string()).and_then(|t| strategy::parse_policies(&t, parse_size)) {
+            Ok(p) => Some(p),
+            Err(e) => config_error(json, &format!("Invalid --method-policy file {:?}: {}", f, e)),
+        },
+        (None, true) => Some(strategy::parse_policies(strategy::DEFAULT_POLICIES, parse_size).expect("built-in policies parse")),
+        (None, false) => None,
+    };
+    if policies.is_some() {
+        for other in ["quick", "baseline-manifest"] {
+            if matches.opt_present(other) {
+                config_error(json, &format!("--{} can't be combined with --adaptive or --method-policy", other));
+            }
+        }
+    } else if matches.opt_present("verify-cache") {
+        config_error(json, "--verify-cache needs --adaptive or --method-policy");
+    }
+    let verify_cache = matches.opt_str("verify-cache").map(|f| {
+        let cache = VerifyCache::open(Path::new(&f)).unwrap_or_else(|e| config_error(json, &format!("Failed to read --verify-cache {:?}: {}", f, e)));
+        if let Some(q) = cache.quarantine() {
+            eprintln!("The verify cache {:?} is damaged ({}); moved it to {:?}, every file is verified afresh", f, q.reason, q.moved_to);
+        }
+        cache
+    });
+    let hash_command = matches.opt_str("hash-cmd").map(|c| hash::HashCommand::new(&c));
+    if let Some(Err(e)) = hash_command.as_ref().map(|c| c.check()) {
+        config_error(json, &format!("Invalid --hash-cmd: {}", e));
+    }
+    let hash_list = matches.opt_str("hash").or_else(|| hash_command.is_none().then(|| String::from("sha256")));
+    let mut algorithms = Vec::new();
+    for name in hash_list.iter().flat_map(|l| l.split(',')) {
+        match hash::Algorithm::parse(name.trim()) {
+            Some(a) if !algorithms.contains(&a) => algorithms.push(a),
+            Some(_) => {}
+            None => config_error(json, &format!("Unknown hash algorithm {:?}", name)),
+        }
+    }
+    // digests are only comparable with the manifest's, in its order
+    let baseline_manifest = match matches.opt_str("baseline-manifest") {
+        Some(f) => {
+            for other in ["quick", "sample", "hash-key", "hash-cmd"] {
+                if matches.opt_present(other) {
+                    config_error(json, &format!("--baseline-manifest can't be combined with --{}", other));
+                }
+            }
+            match manifest::load(Path::new(&f)) {
+                // the audit hashes without a key, so keyed digests never match
+                Ok(m) if m.key_id.is_some() => config_error(json, &format!("The baseline manifest {:?} has keyed digests, which a three-way audit can't use", f)),
+                Ok(m) => {
+                    if matches.opt_present("hash") && (algorithms.len() != m.algorithms.len() || algorithms.iter().any(|a| !m.algorithms.contains(a))) {
+                        config_error(json, &format!("--hash must match the algorithms of the baseline manifest ({})", m.algorithms.iter().map(|a| a.name()).collect::<Vec<_>>().join(",")));
+                    }
+                    algorithms = m.algorithms.clone();
+                    Some(m)
+                }
+                Err(e) => config_error(json, &format!("Failed to read baseline manifest {:?}: {}", f, e)),
+            }
+        }
+        None => None,
+    };
+    // an ETag is of the whole object by definition
+    if sample.is_some() && algorithms.contains(&hash::Algorithm::S3Etag) {
+        config_error(json, "--sample can't be combined with the s3-etag digest");
+    }
 
-fn deep_check(args: Args) {
+    let s3_part_size = match matches.opt_str("s3-part-size").map(|s| parse_size(&s)).transpose() {
+        Ok(Some(0)) => config_error(json, "Invalid --s3-part-size: must be greater than zero"),
+        Ok(p) => p.unwrap_or(hash::DEFAULT_S3_PART_SIZE),
+        Err(e) => config_error(json, &format!("Invalid --s3-part-size: {}", e)),
+    };
+
+    let hash_key = read_hash_key(json, matches.opt_str("hash-key"), &algorithms);
+    if hash_key.is_some() && hash_command.is_some() {
+        config_error(json, "--hash-key can't be combined with --hash-cmd");
+    }
 
-    let output_file = match File::create(args.output_file) {
-        Ok(o) => {
-            Arc::new(Mutex::new(o))
+    let source_dir = source_arg.strip_suffix('/').filter(|s| !s.is_empty()).unwrap_or(&source_arg).to_string();
+    let baseline = baseline_manifest.map(|m| Arc::new(manifest::Baseline::new(m, &source_dir)));
+    let mut path_rules = Vec::new();
+    if let Some(f) = matches.opt_str("rules") {
+        match fs::read_to_string(&f).map_err(|e| e.to_string()).and_then(|t| rules::parse_rules(&t)) {
+            Ok(r) => path_rules = r,
+            Err(e) => config_error(json, &format!("Invalid --rules file {:?}: {}", f, e)),
         }
-        Err(e) => {
-            panic!("Failed to create output file {:?}", e)
+    }
+    let rules = match rules::Rules::new(&source_dir, matches.opt_strs("expect-different"), path_rules) {
+        Ok(r) => r,
+        Err(e) => config_error(json, &format!("Invalid path pattern: {}", e)),
+    };
+
+    let min_free_space = match matches.opt_str("min-free-space").map(|s| fsstat::Threshold::parse(&s, parse_size)).transpose() {
+        Ok(t) => t,
+        Err(e) => config_error(json, &format!("Invalid --min-free-space: {}", e)),
+    };
+    let max_findings_per_kind = match matches.opt_get::<u64>("max-findings-per-category") {
+        Ok(m) => m,
+        Err(e) => config_error(json, &format!("Invalid --max-findings-per-category: {}", e)),
+    };
+    let acks = match matches.opt_str("ack-file").map(|f| ack::Acks::load(Path::new(&f)).map_err(|e| (f, e))).transpose() {
+        Ok(a) => a.unwrap_or_default(),
+        Err((f, e)) => config_error(json, &format!("Failed to read ack file {:?}: {}", f, e)),
+    };
+    let format = match matches.opt_str("format") {
+        Some(name) => match report::Format::parse(&name) {
+            Some(format) => format,
+            None => config_error(json, &format!("Unknown output format {:?}", name)),
+        },
+        None => report::Format::Text,
+    };
+    if format == report::Format::Sqlite && matches.opt_present("append-only") {
+        config_error(json, "--format sqlite adds each run to the database and can't be combined with --append-only");
+    }
+    if format == report::Format::Html {
+        for streaming in ["watch", "append-only"] {
+            if matches.opt_present(streaming) {
+                config_error(json, &format!("--format html is written when the audit finishes and can't be combined with --{}", streaming));
+            }
+        }
+    }
+    if format != report::Format::Text {
+        for text_only in ["custody", "template-dir"] {
+            if matches.opt_present(text_only) {
+                config_error(json, &format!("--{} only applies to the text format", text_only));
+            }
         }
+    }
+    let templates = match matches.opt_str("template-dir").map(|d| report::Templates::load(Path::new(&d)).map_err(|e| (d, e))).transpose() {
+        Ok(t) => t,
+        Err((d, e)) => config_error(json, &format!("Failed to load templates from {:?}: {}", d, e)),
     };
 
-    let mut files_count: u64 = 0;
-    for _ in WalkDir::new(&args.source_dir) {
-        files_count += 1;
+    let target_dir = target_arg.strip_suffix('/').filter(|s| !s.is_empty()).unwrap_or(&target_arg).to_string();
+    if remote::scheme(&source_dir).is_some() {
+        config_error(json, "Only the target can be a URL (and --reference target makes it the source)");
+    }
+    let remote_scheme = remote::scheme(&target_dir).map(String::from);
+    for (option, scheme) in [("read-remote", "s3"), ("s3-checksums", "s3"), ("s3-endpoint", "s3"), ("s3-region", "s3"), ("ssh-command", "sftp")] {
+        if matches.opt_present(option) && remote_scheme.as_deref() != Some(scheme) {
+            config_error(json, &format!("--{} needs an {}:// target", option, scheme));
+        }
+    }
+    // a URL is listed once up front and then audited like a --target-index
+    let remote = remote_scheme.as_deref().map(|scheme| {
+        if scheme == "s3" {
+            require_feature(json, "An s3:// target", "network");
+        }
+        for other in ["target-index", "watch", "bidirectional", "repair", "dir-counts", "min-free-space", "storage-efficiency"] {
+            if matches.opt_present(other) {
+                config_error(json, &format!("--{} can't be combined with an {}:// target", other, scheme));
+            }
+        }
+        let options = remote::Options {
+            s3_endpoint: matches.opt_str("s3-endpoint"),
+            s3_region: matches.opt_str("s3-region"),
+            ssh_command: matches.opt_str("ssh-command").unwrap_or_else(|| String::from("ssh")),
+            connections: io_concurrency.unwrap_or(workers),
+        };
+        remote::connect(&target_dir, &options).unwrap_or_else(|e| config_error(json, &format!("Invalid target {:?}: {}", target_dir, e)))
+    });
+    // read once up front, its members hashed on the way, and then audited
+    // like a --target-index
+    let archive = remote_scheme.is_none() && index::is_tar_archive(Path::new(&target_dir));
+    if archive {
+        require_feature(json, "A tar archive target", "archive");
+        for other in ["target-index", "watch", "bidirectional", "repair", "dir-counts", "storage-efficiency", "sample"] {
+            if matches.opt_present(other) {
+                config_error(json, &format!("--{} can't be combined with a tar archive target", other));
+            }
+        }
+    }
+    if !matches.opt_present("allow-overlap") {
+        if let Some(overlap) = roots_overlap(Path::new(&source_dir), Path::new(&target_dir)) {
+            config_error(json, &format!("{}; pass --allow-overlap to audit anyway", overlap));
+        }
     }
+    let target_index = match (matches.opt_str("target-index"), &remote) {
+        (None, Some(r)) => match r.list() {
+            Ok(objects) => {
+                println!("Listed {} objects in {}", objects.len(), target_dir);
+                Some(Arc::new(index::TargetIndex::from_objects(&target_dir, objects)))
+            }
+            Err(e) => runtime_error(json, &format!("Failed to list {}: {}", target_dir, e)),
+        },
+        (Some(f), _) => {
+            let format = match matches.opt_str("index-format") {
+                Some(name) => match index::IndexFormat::parse(&name) {
+                    Some(format) => format,
+                    None => config_error(json, &format!("Unknown index format {:?}", name)),
+                },
+                None if f.ends_with(".csv") => index::IndexFormat::Csv,
+                None if f.ends_with("manifest.json") => index::IndexFormat::S3Inventory,
+                None => index::IndexFormat::Find,
+            };
+            match index::TargetIndex::load(Path::new(&f), format, &target_dir, &matches.opt_str("index-prefix").unwrap_or_default()) {
+                Ok(i) => {
+                    println!("Loaded target index {:?}: {} entries", f, i.entry_count());
+                    Some(Arc::new(i))
+                }
+                Err(e) => config_error(json, &format!("Failed to load target index {:?}: {}", f, e)),
+            }
+        }
+        (None, None) => None,
+    };
+    let flaky_window = match matches.opt_get_default("flaky-window", history::DEFAULT_WINDOW) {
+        Ok(0) => config_error(json, "--flaky-window must be at least 1"),
+        Ok(_) if matches.opt_present("flaky-window") && !matches.opt_present("history") => config_error(json, "--flaky-window needs --history"),
+        Ok(n) => n,
+        Err(e) => config_error(json, &format!("Invalid --flaky-window: {}", e)),
+    };
+    let history = match matches.opt_str("history").map(|f| history::History::load(Path::new(&f), &source_dir, &target_dir, flaky_window).map_err(|e| (f, e))).transpose() {
+        Ok(h) => h,
+        Err((f, e)) => config_error(json, &format!("Failed to read history {:?}: {}", f, e)),
+    };
+    let ticket_mismatches = match matches.opt_get_default("ticket-mismatches", ticket::DEFAULT_MAX_MISMATCHES) {
+        Ok(_) if matches.opt_present("ticket-mismatches") && !matches.opt_present("ticket-template") => config_error(json, "--ticket-mismatches needs --ticket-template"),
+        Ok(n) => n,
+        Err(e) => config_error(json, &format!("Invalid --ticket-mismatches: {}", e)),
+    };
+    let tickets = matches.opt_str("ticket-template").map(|f| {
+        if history.is_none() {
+            config_error(json, "--ticket-template needs --history, which keeps the tickets opened");
+        }
+        let template = ticket::Template::load(Path::new(&f)).unwrap_or_else(|e| config_error(json, &format!("Failed to read ticket template {:?}: {}", f, e)));
+        if let Err(e) = template.check_env() {
+            config_error(json, &format!("Invalid ticket template {:?}: {}", f, e));
+        }
+        (template, ticket_mismatches)
+    });
 
-    static BAR_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    let mut parsed_args = Args {
+        source_dir,
+        target_dir,
+        output_file: matches.opt_str("o").unwrap(),
+        report: Some(ReportOptions {
+            custody: matches.opt_str("custody").map(|operator| Custody {
+                operator,
+                key: matches.opt_str("custody-key").map(|k| {
+                    fs::read(&k).unwrap_or_else(|e| config_error(json, &format!("Failed to read custody key {:?}: {}", k, e)))
+                }),
+                signing_key: matches.opt_str("custody-sign").map(|k| {
+                    SecretKey::load(Path::new(&k)).unwrap_or_else(|e| config_error(json, &format!("Failed to read signing key {:?}: {}", k, e)))
+                }),
+            }),
+            append_only: matches.opt_present("append-only"),
+            templates: templates.unwrap_or_default(),
+            max_findings_per_kind,
+            acks,
+            history,
+            format,
+            fail_fast: matches.opt_present("fail-fast"),
+            labels,
+            scratch: Some(scratch.clone()),
+        }),
+        command_line: args.clone(),
+        compare: Some(CompareOptions {
+            check_attrs: matches.opt_present("check-attrs"),
+            check_selinux: matches.opt_present("check-selinux"),
+            detect_clones: matches.opt_present("detect-clones"),
+            check_sparse: matches.opt_present("check-sparse"),
+            check_encoding: matches.opt_present("check-encoding"),
+            budget: hash::Budget { timeout: file_timeout, max_bytes: max_read },
+            hashing: hash::HashSpec {
+                algorithms,
+                key: hash_key,
+                s3_part_size,
+                io_control,
+                read_limit: io_concurrency.map(|n| Arc::new(throttle::ReadLimit::new(n))),
+                fadvise: matches.opt_present("fadvise"),
+                command: hash_command.map(Arc::new),
+                // with policies, only for the files they pick sampling for
+                sample: sample.filter(|_| policies.is_none()),
+                buffer_size,
+                read_progress: Some(Arc::new(AtomicU64::new(0))),
+                skip_holes: !matches.opt_present("read-holes"),
+            },
+            rules,
+            target_index,
+            verify_etags: matches.opt_present("verify-etags") || remote.is_some(),
+            checksums: remote.clone().filter(|_| matches.opt_present("s3-checksums")),
+            // SFTP listings carry no digests to go by
+            remote: remote.filter(|_| matches.opt_present("read-remote") || remote_scheme.as_deref() == Some("sftp")),
+            quick: matches.opt_present("quick"),
+            verify_on_match: matches.opt_present("verify-on-match"),
+            check_metadata: matches.opt_present("check-metadata"),
+            metadata_fields,
+            mtime_tolerance,
+            follow_symlinks: matches.opt_present("follow-symlinks"),
+            storage_efficiency: matches.opt_present("storage-efficiency"),
+            dir_counts: matches.opt_present("dir-counts"),
+            baseline,
+            classify_damage: matches.opt_present("classify-damage"),
+            hard_links: HardLinks::new(matches.opt_present("check-hard-links")),
+            strategy: None,
+        }),
+        filter: Arc::new(filter),
+        bidirectional: matches.opt_present("bidirectional"),
+        no_progress: matches.opt_present("no-progress"),
+        progress_refresh: Duration::from_millis(progress_refresh),
+        milestones: Milestones {
+            percent: milestone_percent,
+            interval: Duration::from_secs(milestone_minutes * 60),
+        },
+        inject_findings,
+        watch: matches.opt_present("watch").then_some(watch_delay),
+        from_log: matches.opt_str("from-log").map(|f| (f, log_format)),
+        recheck: matches.opt_str("recheck"),
+        min_free_space,
+        otlp_endpoint: matches.opt_str("otlp-endpoint"),
+        skip_list: matches.opt_str("skip-list").map(|f| (f, skip_list_format)),
+        chargeback: matches.opt_str("chargeback").map(|f| (f, chargeback_by)),
+        history: matches.opt_str("history"),
+        tickets,
+        job_log,
+        workers,
+        walk_threads,
+        repair: repairing.then(|| Repair {
+            dry_run: matches.opt_present("repair-dry-run"),
+            delete_extra: matches.opt_present("repair-delete-extra"),
+        }),
+    };
 
-    let mbar: MultiProgress = MultiProgress::new();
+    if let Some(policies) = policies {
+        let compare = parsed_args.compare.as_mut().unwrap();
+        let sampled = hash::HashSpec { sample: Some(sample.unwrap_or(strategy::DEFAULT_SAMPLE)), ..compare.hashing.clone() };
+        compare.strategy = Some(Arc::new(Strategy::new(&parsed_args.source_dir, policies, verify_cache, sampled)));
+    }
+    let strategy = parsed_args.compare.as_ref().and_then(|c| c.strategy.clone());
+    #[cfg(feature = "archive")]
+    if archive {
+        let compare = parsed_args.compare.as_mut().unwrap();
+        match index::TargetIndex::from_archive(Path::new(&parsed_args.target_dir), &compare.hashing) {
+            Ok(i) => {
+                println!("Indexed {} entries of the archive {:?}", i.entry_count(), parsed_args.target_dir);
+                compare.target_index = Some(Arc::new(i));
+            }
+            Err(e) => runtime_error(json, &format!("Failed to read the archive {:?}: {}", parsed_args.target_dir, e)),
+        }
+    }
 
-    let bars: Vec<ProgressBar> = (0..=num_cpus::get())
-        .map(|_| {
-            let x = ProgressBar::new_spinner();
-            let s = ProgressStyle::default_spinner().tick_strings(&[
-                "|",
-                "/",
-                "-",
-                "\\",
-            ]);
-            x.set_style(s);
-            mbar.add(x)
-        })
-        .collect();
+    println!("Source directory: {:?}\nTarget directory: {:?}\nOutput filename: {:?}", parsed_args.source_dir, parsed_args.target_dir, parsed_args.output_file);
+    if let Some((log, status)) = &parsed_args.job_log {
+        let count = |c: Option<u64>| c.map_or("unknown".to_string(), |c| c.to_string());
+        println!(
+            "Backup job log {:?}: {} files copied, {} failed, exit code {}",
+            log,
+            count(status.copied),
+            count(status.failed),
+            status.exit_code.map_or("unknown".to_string(), |c| c.to_string()),
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(spec) = matches.opt_str("cpu-affinity") {
+        if let Err(e) = set_cpu_affinity(&spec, &parsed_args.source_dir, &parsed_args.target_dir) {
+            config_error(json, &format!("Invalid --cpu-affinity: {}", e));
+        }
+    }
+    // --cpu-affinity has already sized the pool to the CPUs it pins to
+    if parsed_args.workers != num_cpus::get() {
+        let _ = rayon::ThreadPoolBuilder::new().num_threads(parsed_args.workers).build_global();
+    }
 
-    let pbar = mbar.add(ProgressBar::new(files_count));
+    // flushed when dropped at the end of main
+    #[cfg(feature = "trace")]
+    let _trace = match matches.opt_str("trace-output").map(|f| start_trace(&f).map_err(|e| (f, e))).transpose() {
+        Ok(guard) => guard,
+        Err((f, e)) => runtime_error(json, &format!("Failed to create trace output {:?}: {}", f, e)),
+    };
 
-    thread_local! {
-        static LOCAL_BAR_ID: RefCell<usize> = {
-            let x = BAR_COUNT.fetch_add(1, Ordering::SeqCst);
-            RefCell::new(x)
+    if let Some(dir) = matches.opt_str("canary-dir") {
+        match canary_check(Path::new(&dir)) {
+            Ok(()) => println!("Canary write test in {:?} passed", dir),
+            Err(e) => runtime_error(json, &format!("Canary write test in {:?} failed, not auditing: {}", dir, e)),
         }
     }
 
-    let walk_thread = thread::spawn(move || {
-        WalkDir::new(&args.source_dir)
-            .parallelism(Parallelism::RayonNewPool(0))
-            .into_iter()
-            .par_bridge()
-            .for_each(|src_entry| {
-                let src_path = src_entry.unwrap().path().display().to_string();
-                let stripped_path = src_path.strip_prefix(&args.source_dir).unwrap();
-
-                let tgt_path = format!("{}{}", args.target_dir, stripped_path);
-
-                let src_r = File::open(&src_path);
-                let tgt_r = File::open(&tgt_path);
-
-                match (src_r, tgt_r) {
-                    (Ok(src), Ok(tgt)) => {
-                        LOCAL_BAR_ID.with(|bid| {
-                            let x = bid.borrow().clone();
-                            let b = bars[x].borrow();
-                            let term_width = terminal_size::terminal_size().map(|s| usize::from((s.0.0-5).max(0))).unwrap_or(80);
-                            b.set_message(format!("{}", trim_str(&tgt_path, term_width)));
-                            cmp_files(&output_file, &src_path, &src, &tgt_path, &tgt);
-                            pbar.inc(1);
-                        });
-                    }
-                    (Ok(_), Err(tgt)) => {
-                        output_file
-                            .lock()
-                            .unwrap()
-                            .write_all(format!("Found missing file in target\nsrc={:?}\ntgt={:?}\nReason:{:?}\n", src_path, tgt_path, tgt).as_bytes()).unwrap();
-                    }
-                    (Err(src), Ok(_)) => {
-                        output_file
-                            .lock()
-                            .unwrap()
-                            .write_all(format!("Found missing file in source\nsrc={:?}\ntgt={:?}\nReason:{:?}\n", src_path, tgt_path, src).as_bytes()).unwrap();
-                    }
-                    (Err(src), Err(tgt)) => {
-                        output_file
-                            .lock()
-                            .unwrap()
-                            .write_all(format!("Found missing file in source and target\nsrc={:?}\ntgt={:?}\nSrcReason:{:?}\nTgtReason:{:?}\n", src_path, tgt_path, src, tgt).as_bytes()).unwrap();
-                    }
+    let status = match (parsed_args.watch, parsed_args.from_log.take(), parsed_args.recheck.take()) {
+        #[cfg(feature = "watch")]
+        (Some(delay), _, _) => watch_mode(parsed_args, delay),
+        (_, Some((log, format)), _) => log_check(parsed_args, &log, format),
+        (_, _, Some(prior)) => recheck_findings(parsed_args, &prior),
+        _ => deep_check(parsed_args),
+    };
+    match strategy.as_ref().and_then(|s| s.save_cache()) {
+        Some(Ok(pairs)) => println!("Saved {} verified pairs to the verify cache", pairs),
+        Some(Err(e)) => eprintln!("Failed to save the verify cache: {}", e),
+        None => {}
+    }
+    // an audit that hit errors keeps what it left in scratch space
+    if let Some(dir) = scratch.dir() {
+        match status {
+            EXIT_IO => eprintln!("Kept scratch data for diagnosis in {:?}", dir),
+            _ => {
+                if let Err(e) = scratch.remove() {
+                    eprintln!("Failed to remove scratch data in {:?}: {}", dir, e);
                 }
-            });
+            }
+        }
+    }
+    #[cfg(feature = "trace")]
+    drop(_trace);
+    std::process::exit(status)
+}
 
-        bars.iter().for_each(|b| {
-            b.finish()
-        });
-    });
+// Exit statuses automation can tell apart: an audit exits 0 when the trees
+// match, 1 when it found differences and 2 when files couldn't be read or the
+// audit couldn't proceed at all. A bad command line or setup exits 3 before
+// anything is audited.
+const EXIT_DIFFERENCES: i32 = 1;
+const EXIT_IO: i32 = 2;
+const EXIT_CONFIG: i32 = 3;
 
-    mbar.join().unwrap();
+// Read errors win over differences: the audit is incomplete, so "only
+// differences" can't be claimed either.
+fn audit_status(report: &Report) -> i32 {
+    let tally = report.tally();
+    if tally.errors > 0 {
+        EXIT_IO
+    } else if tally.mismatches + tally.missing > 0 {
+        EXIT_DIFFERENCES
+    } else {
+        0
+    }
+}
 
-    walk_thread.join().expect("failed to join walk thread");
+fn wants_json(args: &[String]) -> bool {
+    args.iter().zip(args.iter().skip(1)).any(|(a, b)| a == "--format" && b == "json") || args.iter().any(|a| a == "--format=json")
 }
 
+// With --format json errors go to stderr as one JSON object, so wrappers
+// needn't scrape prose or usage text.
+fn print_error(json: bool, kind: &str, message: &str) {
+    if json {
+        eprintln!("{}", serde_json::json!({ "error": kind, "message": message }));
+    } else {
+        eprintln!("{}", message);
+    }
+}
 
-fn cmp_files(output_file: &Arc<Mutex<File>>, src_path: &String, mut src: &File, tgt_path: &String, mut tgt: &File) -> () {
-    let src_meta = src.metadata().unwrap();
-    let tgt_meta = tgt.metadata().unwrap();
-    if src_meta.is_dir() && tgt_meta.is_dir() {
-        return;
-    } else if src_meta.is_symlink() && tgt_meta.is_symlink() {
-        return;
-    } else if src_meta.is_file() && tgt_meta.is_file() {
-        let mut src_hasher = Sha256::new();
-        let _ = io::copy(&mut src, &mut src_hasher).unwrap();
-        let src_hash = src_hasher.finalize();
+fn exit_with_error(json: bool, kind: &str, code: i32, message: &str) -> ! {
+    print_error(json, kind, message);
+    std::process::exit(code)
+}
+
+fn config_error(json: bool, message: &str) -> ! {
+    exit_with_error(json, "configuration", EXIT_CONFIG, message)
+}
+
+fn runtime_error(json: bool, message: &str) -> ! {
+    exit_with_error(json, "runtime", EXIT_IO, message)
+}
+
+fn usage_error(json: bool, program: &str, opts: Options, message: &str) -> ! {
+    if !json {
+        print_usage(program, opts);
+    }
+    config_error(json, message)
+}
+
+// Subcommands write no report of their own, so their --format only says how
+// errors are written: one JSON object on stderr, as for an audit, with json.
+fn subcommand_options(opts: &mut Options) {
+    opts.optopt("", "format", "text or json, how errors are written to stderr (default text)", "FORMAT");
+    opts.optflag("h", "help", "print this help menu");
+}
+
+// What follows -- is the subcommand's, like install-schedule's audit options.
+fn subcommand_wants_json(args: &[String]) -> bool {
+    wants_json(&args[..args.iter().position(|a| a == "--").unwrap_or(args.len())])
+}
+
+// Parses a subcommand's arguments and whether errors are JSON; None once -h
+// has printed its help.
+fn parse_subcommand(opts: &Options, brief: &str, args: &[String]) -> Option<(Matches, bool)> {
+    let json = subcommand_wants_json(args);
+    let matches = opts.parse(args).unwrap_or_else(|f| subcommand_usage_error(json, opts, brief, &f.to_string()));
+    match matches.opt_str("format").as_deref() {
+        None | Some("text" | "json") => {}
+        Some(other) => subcommand_usage_error(json, opts, brief, &format!("Unknown error format {:?}", other)),
+    }
+    if matches.opt_present("h") {
+        print!("{}", opts.usage(brief));
+        return None;
+    }
+    Some((matches, json))
+}
+
+fn subcommand_usage_error(json: bool, opts: &Options, brief: &str, message: &str) -> ! {
+    if !json {
+        print!("{}", opts.usage(brief));
+    }
+    config_error(json, message)
+}
 
-        let mut tgt_hasher = Sha256::new();
-        let _ = io::copy(&mut tgt, &mut tgt_hasher).unwrap();
-        let tgt_hash = tgt_hasher.finalize();
+// --hash-key FILE, for the audit and the manifest subcommands alike.
+fn read_hash_key(json: bool, file: Option<String>, algorithms: &[hash::Algorithm]) -> Option<Arc<hash::HashKey>> {
+    let file = file?;
+    let secret = fs::read(&file).unwrap_or_else(|e| config_error(json, &format!("Failed to read hash key {:?}: {}", file, e)));
+    if let Some(a) = algorithms.iter().find(|a| !a.keyable()) {
+        config_error(json, &format!("--hash-key can't be combined with the {} digest", a.name()));
+    }
+    Some(Arc::new(hash::HashKey::from_secret(&secret)))
+}
 
-        if src_hash != tgt_hash {
-            output_file
-                .lock()
-                .unwrap()
-                .write_all(format!("Found mismatched sha256 hashes:\nsrc={:?}\n{:?}\ntgt={:?}\n{:?}\n", src_path, src_hash, tgt_path, tgt_hash).as_bytes()).unwrap()
+// Hashing runs on the global rayon pool, so pinning its threads keeps the
+// buffers they fill on the memory node of the controller doing the reads.
+#[cfg(target_os = "linux")]
+fn set_cpu_affinity(spec: &str, source_dir: &str, target_dir: &str) -> Result<(), String> {
+    let cpus = if spec == "auto" {
+        let mut cpus = Vec::new();
+        for (side, root) in [("source", source_dir), ("target", target_dir)] {
+            match affinity::numa_node(Path::new(root)) {
+                Some(node) => {
+                    println!("The {} is on NUMA node {}", side, node);
+                    cpus.extend(affinity::node_cpus(node).map_err(|e| format!("NUMA node {}: {}", node, e))?);
+                }
+                None => println!("The {} has no NUMA node (network or virtual device)", side),
+            }
         }
+        if cpus.is_empty() {
+            println!("No NUMA placement found; workers are not pinned");
+            return Ok(());
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        cpus
+    } else {
+        affinity::parse_cpu_list(spec)?
+    };
+    println!("Pinning hashing workers to CPUs {:?}", cpus);
+
+    let pinned = cpus.clone();
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(cpus.len())
+        .start_handler(move |_| {
+            if let Err(e) = affinity::pin_current_thread(&pinned) {
+                eprintln!("Failed to pin worker thread: {}", e);
+            }
+        })
+        .build_global()
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(feature = "trace")]
+fn start_trace(path: &str) -> io::Result<tracing_chrome::FlushGuard> {
+    use tracing_subscriber::layer::SubscriberExt;
+    let (layer, guard) = tracing_chrome::ChromeLayerBuilder::new().writer(File::create(path)?).include_args(true).build();
+    tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+        .map_err(io::Error::other)?;
+    Ok(guard)
+}
+
+// A target that went read-only or filled up otherwise only shows as a pile of
+// odd findings hours in. The file is synced so a full device fails here rather
+// than on writeback, and removed even when the read back does not match.
+fn canary_check(dir: &Path) -> io::Result<()> {
+    use std::io::{Read, Write};
+    let path = dir.join(format!(".backup_auditor-canary-{}", std::process::id()));
+    let content: Vec<u8> = (0..65536u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    let result = (|| {
+        let mut file = File::create(&path)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        let mut read_back = Vec::new();
+        File::open(&path)?.read_to_end(&mut read_back)?;
+        if read_back != content {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "read back different content"));
+        }
+        Ok(())
+    })();
+    match (result, fs::remove_file(&path)) {
+        (Err(e), _) => Err(e),
+        (Ok(()), Err(e)) => Err(io::Error::new(e.kind(), format!("deleting the test file: {}", e))),
+        (Ok(()), Ok(())) => Ok(()),
+    }
+}
+
+fn gen_fixture(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "matched", "identical files in both trees (default 100)", "N");
+    opts.optopt("", "missing", "files only in the source (default 10)", "N");
+    opts.optopt("", "mismatched", "files whose target copy has one flipped bit (default 10)", "N");
+    opts.optopt("", "symlinked", "symlinks present in both trees (default 10)", "N");
+    opts.optopt("", "special", "named pipes present in both trees (default 0)", "N");
+    opts.optopt("", "max-size", "largest generated file in bytes (default 65536)", "BYTES");
+    opts.optopt("", "seed", "seed for file sizes and content (default 0)", "N");
+    subcommand_options(&mut opts);
+
+    let brief = format!("Usage: {} gen-fixture DIR [options]\nCreates DIR/source and DIR/target.", program);
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if matches.free.len() != 1 {
+        subcommand_usage_error(json, &opts, &brief, "gen-fixture takes one DIR");
+    }
+
+    let count = |name: &str, default: u64| matches.opt_get_default(name, default).map_err(|e| format!("Invalid --{}: {}", name, e));
+    let spec = (|| {
+        Ok::<_, String>(fixture::FixtureSpec {
+            matched: count("matched", 100)?,
+            missing: count("missing", 10)?,
+            mismatched: count("mismatched", 10)?,
+            symlinked: count("symlinked", 10)?,
+            special: count("special", 0)?,
+            max_size: count("max-size", 65536)?,
+            seed: count("seed", 0)?,
+        })
+    })();
+    let spec = spec.unwrap_or_else(|e| config_error(json, &e));
+
+    let dir = std::path::Path::new(&matches.free[0]);
+    match fixture::generate(dir, &spec) {
+        Ok(()) => println!("Generated fixture in {:?}", dir),
+        Err(e) => runtime_error(json, &format!("Failed to generate fixture: {}", e)),
+    }
+}
+
+fn merge_reports(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("o", "output", "merged report filename", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} merge-reports -o FILE REPORT...\nCombines reports of sharded or multi-pair runs, listing each finding once with the runs that reported it.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let output = match matches.opt_str("o") {
+        Some(o) if !matches.free.is_empty() => o,
+        Some(_) => subcommand_usage_error(json, &opts, &brief, "merge-reports needs at least one REPORT"),
+        None => subcommand_usage_error(json, &opts, &brief, "merge-reports needs -o FILE"),
+    };
+
+    match merge::merge_reports(&matches.free, Path::new(&output)) {
+        Ok(summary) => println!(
+            "Merged {} reports into {:?}: {} findings, {} duplicates dropped",
+            summary.inputs, output, summary.findings, summary.duplicates
+        ),
+        Err(e) => runtime_error(json, &format!("Failed to merge reports: {}", e)),
+    }
+}
+
+fn write_manifest(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("o", "output", "manifest filename", "FILE");
+    opts.optopt("", "hash", "comma separated hash algorithms: sha1, sha256, sha512, blake3, xxhash64 (default sha256)", "LIST");
+    opts.optopt("", "hash-key", "record keyed digests (HMAC, keyed BLAKE3) made with the secret in FILE, which whoever can alter the copy can't forge; verify then needs the same FILE", "FILE");
+    opts.optopt("", "sign", "sign the manifest with the ed25519 secret key in FILE (see keygen), for verify to check with the public key", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} manifest -o FILE DIR\nRecords the size, modification time and digests of every file under DIR, for checking a copy of it later with verify.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let output = match matches.opt_str("o") {
+        Some(o) if matches.free.len() == 1 => o,
+        Some(_) => subcommand_usage_error(json, &opts, &brief, "manifest takes one DIR"),
+        None => subcommand_usage_error(json, &opts, &brief, "manifest needs -o FILE"),
+    };
+    let mut algorithms = Vec::new();
+    for name in matches.opt_str("hash").unwrap_or_else(|| String::from("sha256")).split(',') {
+        match hash::Algorithm::parse(name.trim()) {
+            // an ETag depends on how a copy was uploaded, not on the tree
+            Some(hash::Algorithm::S3Etag) => config_error(json, "s3-etag can't be recorded in a manifest"),
+            None => config_error(json, &format!("Unknown hash algorithm {:?}", name)),
+            Some(a) if !algorithms.contains(&a) => algorithms.push(a),
+            Some(_) => {}
+        }
+    }
+
+    let key = read_hash_key(json, matches.opt_str("hash-key"), &algorithms);
+    let signing_key = matches.opt_str("sign").map(|k| {
+        SecretKey::load(Path::new(&k)).unwrap_or_else(|e| config_error(json, &format!("Failed to read signing key {:?}: {}", k, e)))
+    });
+    let spec = hash::HashSpec { algorithms, key, s3_part_size: hash::DEFAULT_S3_PART_SIZE, io_control: None, read_limit: None, read_progress: None, skip_holes: true, fadvise: false, command: None, sample: None, buffer_size: hash::DEFAULT_BUFFER_SIZE };
+    let dir = match matches.free[0].trim_end_matches('/') {
+        "" => "/",
+        d => d,
+    };
+    let summary = match manifest::write(Path::new(dir), &spec, Path::new(&output), signing_key.as_ref()) {
+        Ok(summary) => summary,
+        Err(e) => runtime_error(json, &format!("Failed to write manifest {:?}: {}", output, e)),
+    };
+    for (rel, e) in &summary.unreadable {
+        eprintln!("Not in the manifest, unreadable: {:?}: {}", rel, e);
+    }
+    println!("Wrote manifest {:?}: {} files ({})", output, summary.files, indicatif::HumanBytes(summary.bytes));
+    // a manifest missing part of the tree would pass a copy missing it too
+    if !summary.unreadable.is_empty() {
+        runtime_error(json, &format!("The manifest leaves out {} entries that couldn't be read", summary.unreadable.len()));
+    }
+}
+
+// A manifest or checksum list is only used once the signature given with it
+// checks out with --public-key, or with --insecure, without checking it.
+fn check_signature(json: bool, opts: &Options, brief: &str, matches: &Matches, file: &str, what: &str, signed: Option<(String, String)>) {
+    match (matches.opt_str("public-key"), matches.opt_present("insecure"), signed) {
+        (Some(_), true, _) => subcommand_usage_error(json, opts, brief, "--public-key and --insecure can't be combined"),
+        (None, false, _) => config_error(json, &format!("Give the public key the {} was signed with as --public-key, or --insecure not to check who made it", what)),
+        (None, true, _) => eprintln!("Not checking the {}'s signature (--insecure)", what),
+        (Some(_), false, None) => runtime_error(json, &format!("The {} {:?} is not signed", what, file)),
+        (Some(k), false, Some((digest, signature))) => {
+            let public_key = PublicKey::load(Path::new(&k)).unwrap_or_else(|e| config_error(json, &format!("Failed to read public key {:?}: {}", k, e)));
+            if let Err(e) = signing::check_detached(&public_key, &digest, &signature) {
+                runtime_error(json, &format!("The {} {:?} failed its signature check, not using it: {}", what, file, e));
+            }
+        }
+    }
+}
+
+fn update_manifest(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "manifest", "manifest to update, written by the manifest subcommand", "FILE");
+    opts.optopt("o", "output", "updated manifest filename, which can be the same FILE", "FILE");
+    opts.optflag("", "full", "list and hash the whole tree again, as the manifest subcommand does, rather than only what changed");
+    opts.optopt("", "hash-key", "the secret in FILE a keyed manifest was made with", "FILE");
+    opts.optopt("", "public-key", "check the manifest's signature with the ed25519 public key in FILE", "FILE");
+    opts.optflag("", "insecure", "update the manifest without checking who made it; digests carried over from it are only as trustworthy as it is");
+    opts.optopt("", "sign", "sign the updated manifest with the ed25519 secret key in FILE", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} update-manifest --manifest FILE (--public-key FILE | --insecure) -o FILE [DIR]\nBrings a manifest of DIR (by default the root it was made from) up to date, listing only directories whose modification time changed since and hashing only files that are new or whose size or modification time changed. Content rewritten in place with its modification time put back goes unnoticed; --full doesn't miss that.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let (manifest_file, output) = match (matches.opt_str("manifest"), matches.opt_str("o")) {
+        (Some(m), Some(o)) => (m, o),
+        (None, _) => subcommand_usage_error(json, &opts, &brief, "update-manifest needs --manifest FILE"),
+        (_, None) => subcommand_usage_error(json, &opts, &brief, "update-manifest needs -o FILE"),
+    };
+    if matches.free.len() > 1 {
+        subcommand_usage_error(json, &opts, &brief, "update-manifest takes at most one DIR");
+    }
+    let old = manifest::load(Path::new(&manifest_file)).unwrap_or_else(|e| config_error(json, &format!("Failed to read manifest {:?}: {}", manifest_file, e)));
+    check_signature(json, &opts, &brief, &matches, &manifest_file, "manifest", old.signed.clone());
+    let key = read_hash_key(json, matches.opt_str("hash-key"), &old.algorithms);
+    match (&old.key_id, &key) {
+        (Some(_), None) => config_error(json, &format!("The manifest {:?} has keyed digests; give the key they were made with as --hash-key", manifest_file)),
+        (None, Some(_)) => config_error(json, &format!("The manifest {:?} has no keyed digests; leave out --hash-key", manifest_file)),
+        (Some(id), Some(k)) if *id != k.id() => config_error(json, &format!("--hash-key is not the key the manifest {:?} was made with", manifest_file)),
+        _ => {}
+    }
+    let signing_key = matches.opt_str("sign").map(|k| {
+        SecretKey::load(Path::new(&k)).unwrap_or_else(|e| config_error(json, &format!("Failed to read signing key {:?}: {}", k, e)))
+    });
+    let dir = match matches.free.first().map_or(old.root.as_str(), |d| d.trim_end_matches('/')) {
+        "" => "/",
+        d => d,
+    };
+    let spec = hash::HashSpec { algorithms: old.algorithms.clone(), key, s3_part_size: hash::DEFAULT_S3_PART_SIZE, io_control: None, read_limit: None, read_progress: None, skip_holes: true, fadvise: false, command: None, sample: None, buffer_size: hash::DEFAULT_BUFFER_SIZE };
+    // neither manifest is part of the tree when kept in it
+    let exclude: Vec<String> = [&manifest_file, &output].iter().filter_map(|f| manifest::inside(Path::new(dir), Path::new(f))).collect();
+    let updated = match matches.opt_present("full") {
+        true => manifest::write(Path::new(dir), &spec, Path::new(&output), signing_key.as_ref()),
+        false => manifest::update(&old, Path::new(dir), &spec, Path::new(&output), signing_key.as_ref(), &exclude),
+    };
+    let summary = match updated {
+        Ok(summary) => summary,
+        Err(e) => runtime_error(json, &format!("Failed to write manifest {:?}: {}", output, e)),
+    };
+    for (rel, e) in &summary.unreadable {
+        eprintln!("Not in the manifest, unreadable: {:?}: {}", rel, e);
+    }
+    println!(
+        "Wrote manifest {:?}: {} files ({}), {} hashed again; listed {} of {} directories",
+        output,
+        summary.files,
+        indicatif::HumanBytes(summary.bytes),
+        summary.files - summary.reused.min(summary.files),
+        summary.dirs_listed,
+        summary.dirs
+    );
+    if !summary.unreadable.is_empty() {
+        runtime_error(json, &format!("The manifest leaves out {} entries that couldn't be read", summary.unreadable.len()));
+    }
+}
+
+fn verify_manifest(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "manifest", "manifest written by the manifest subcommand", "FILE");
+    opts.optopt("", "against-checksums", "instead of a manifest, an md5sum, sha1sum, sha256sum or sha512sum list made in the directory DIR is a copy of", "FILE");
+    opts.optopt("o", "output", "report filename", "FILE");
+    opts.optopt("", "hash-key", "the secret in FILE a keyed manifest was made with", "FILE");
+    opts.optopt("", "public-key", "check the manifest's signature (manifest --sign), or a checksum list's in FILE.sig (the sign subcommand), with the ed25519 public key in FILE", "FILE");
+    opts.optflag("", "insecure", "use the manifest or checksum list without checking who made it; whoever could alter it could hide changes to DIR");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} verify (--manifest FILE | --against-checksums FILE) (--public-key FILE | --insecure) -o REPORT DIR\nChecks DIR as a copy of the tree the manifest or checksum list was made from, without needing that tree.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let (manifest_file, checksums, output) = match (matches.opt_str("manifest"), matches.opt_str("against-checksums"), matches.opt_str("o")) {
+        (Some(m), None, Some(o)) => (m, false, o),
+        (None, Some(c), Some(o)) => (c, true, o),
+        (Some(_), Some(_), _) => subcommand_usage_error(json, &opts, &brief, "--manifest and --against-checksums can't be combined"),
+        (None, None, _) => subcommand_usage_error(json, &opts, &brief, "verify needs --manifest FILE or --against-checksums FILE"),
+        (_, _, None) => subcommand_usage_error(json, &opts, &brief, "verify needs -o REPORT"),
+    };
+    if matches.free.len() != 1 {
+        subcommand_usage_error(json, &opts, &brief, "verify takes one DIR");
+    }
+    let dir = match matches.free[0].trim_end_matches('/') {
+        "" => "/",
+        d => d,
+    };
+    // findings name the listed files by the directory the list is in
+    let list_dir = match Path::new(&manifest_file).parent().map(|p| p.to_string_lossy().into_owned()) {
+        Some(p) if !p.is_empty() => p,
+        _ => String::from("."),
+    };
+    // the list and the report aren't part of the copy when kept in it
+    let exclude: Vec<String> = [&manifest_file, &output].iter().filter_map(|f| manifest::inside(Path::new(dir), Path::new(f))).collect();
+    let loaded = match checksums {
+        true => manifest::load_checksums(Path::new(&manifest_file), &list_dir),
+        false => manifest::load(Path::new(&manifest_file)),
+    };
+    let manifest = match loaded {
+        Ok(m) => m,
+        Err(e) => config_error(json, &format!("Failed to read {} {:?}: {}", if checksums { "checksum list" } else { "manifest" }, manifest_file, e)),
+    };
+    let what = if checksums { "checksum list" } else { "manifest" };
+    // a signed checksum list has its signature beside it, as the sign
+    // subcommand writes it
+    let signed = match checksums {
+        true => fs::read_to_string(format!("{}.sig", manifest_file)).ok().map(|sig| match file_sha256(&manifest_file) {
+            Ok(digest) => (digest, sig),
+            Err(e) => runtime_error(json, &format!("Failed to read checksum list {:?}: {}", manifest_file, e)),
+        }),
+        false => manifest.signed.clone(),
+    };
+    check_signature(json, &opts, &brief, &matches, &manifest_file, what, signed);
+    let key = read_hash_key(json, matches.opt_str("hash-key"), &manifest.algorithms);
+    match (&manifest.key_id, &key) {
+        (Some(_), None) => config_error(json, &format!("The manifest {:?} has keyed digests; give the key they were made with as --hash-key", manifest_file)),
+        (None, Some(_)) if checksums => config_error(json, "--hash-key can't be used with --against-checksums, whose digests aren't keyed"),
+        (None, Some(_)) => config_error(json, &format!("The manifest {:?} has no keyed digests; leave out --hash-key", manifest_file)),
+        (Some(id), Some(k)) if *id != k.id() => config_error(json, &format!("--hash-key is not the key the manifest {:?} was made with", manifest_file)),
+        _ => {}
+    }
+    let options = ReportOptions {
+        custody: None,
+        append_only: false,
+        templates: Default::default(),
+        max_findings_per_kind: None,
+        acks: Default::default(),
+        history: None,
+        format: report::Format::Text,
+        fail_fast: false,
+        labels: None,
+        scratch: None,
+    };
+    let report = match Report::create(&output, options) {
+        Ok(r) => r,
+        Err(e) => runtime_error(json, &format!("Failed to create output file {:?}: {}", output, e)),
+    };
+    report.header(&RunInfo { source_dir: &manifest.root, target_dir: dir, command_line: args, filesystems: "" });
+
+    let spec = hash::HashSpec {
+        algorithms: manifest.algorithms.clone(),
+        key,
+        s3_part_size: hash::DEFAULT_S3_PART_SIZE,
+        io_control: None,
+        read_limit: None,
+        fadvise: false,
+        command: None,
+        sample: None,
+        buffer_size: hash::DEFAULT_BUFFER_SIZE,
+        read_progress: None,
+        skip_holes: true,
+    };
+    let summary = match manifest::verify(&manifest, dir, &exclude, &spec, &report) {
+        Ok(summary) => summary,
+        Err(e) => runtime_error(json, &format!("Failed to read {:?}: {}", dir, e)),
+    };
+    if let Err(e) = report.finish() {
+        runtime_error(json, &format!("Failed to write report {:?}: {}", output, e));
+    }
+    println!(
+        "Checked {} files in {:?} against {} in the {}: {}",
+        summary.checked,
+        dir,
+        summary.listed,
+        if checksums { "checksum list" } else { "manifest" },
+        report.tally()
+    );
+    std::process::exit(audit_status(&report))
+}
+
+fn generate_key(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("o", "output", "secret key filename; the public key goes to FILE.pub", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} keygen -o FILE\nMakes an ed25519 key pair for signing: the secret key in FILE, readable only by you, and the public key others check signatures with in FILE.pub.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let secret = match matches.opt_str("o") {
+        Some(o) if matches.free.is_empty() => o,
+        Some(_) => subcommand_usage_error(json, &opts, &brief, "keygen takes no arguments"),
+        None => subcommand_usage_error(json, &opts, &brief, "keygen needs -o FILE"),
+    };
+    let public = format!("{}.pub", secret);
+    match signing::generate(Path::new(&secret), Path::new(&public)) {
+        Ok(key) => println!("Wrote secret key {:?} and public key {:?} (key {})", secret, public, key.id()),
+        Err(e) => runtime_error(json, &format!("Failed to write key pair {:?}: {}", secret, e)),
+    }
+}
+
+fn verify_seal(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "public-key", "also require each seal to be signed with the secret key belonging to the public key in FILE", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} verify-seal [--public-key FILE] REPORT\nChecks that a chain-of-custody report is unchanged since it was sealed, and with --public-key, who sealed it. A report several runs were appended to has a seal per run.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if matches.free.len() != 1 {
+        subcommand_usage_error(json, &opts, &brief, "verify-seal takes one REPORT");
+    }
+    let report_file = &matches.free[0];
+    let key = matches.opt_str("public-key").map(|k| {
+        PublicKey::load(Path::new(&k)).unwrap_or_else(|e| config_error(json, &format!("Failed to read public key {:?}: {}", k, e)))
+    });
+    let text = fs::read(report_file).unwrap_or_else(|e| runtime_error(json, &format!("Failed to read report {:?}: {}", report_file, e)));
+    let seals = report::check_seals(&text, key.as_ref());
+    if seals.is_empty() {
+        config_error(json, &format!("{:?} has no chain-of-custody seal", report_file));
+    }
+    let mut broken = 0;
+    for (i, seal) in seals.iter().enumerate() {
+        match seal {
+            Ok(()) => println!("Seal {} of {}: intact{}", i + 1, seals.len(), if key.is_some() { " and signed" } else { "" }),
+            Err(e) => {
+                println!("Seal {} of {}: {}", i + 1, seals.len(), e);
+                broken += 1;
+            }
+        }
+    }
+    if broken > 0 {
+        std::process::exit(EXIT_DIFFERENCES);
+    }
+}
+
+// What a detached signature (signing::detached_signature) of a file covers.
+fn file_sha256(file: &str) -> io::Result<String> {
+    let spec = hash::HashSpec { algorithms: vec![hash::Algorithm::Sha256], key: None, s3_part_size: hash::DEFAULT_S3_PART_SIZE, io_control: None, read_limit: None, read_progress: None, skip_holes: true, fadvise: false, command: None, sample: None, buffer_size: hash::DEFAULT_BUFFER_SIZE };
+    Ok(hash::hash_file(&spec, &File::open(file)?)?.values().next().unwrap_or_default().to_string())
+}
+
+fn sign_file(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("k", "key", "ed25519 secret key file (see keygen)", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} sign -k KEY FILE\nWrites a signature of FILE to FILE.sig, as a release binary carries for self-update to check and a checksum list can for verify to.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let key_file = match matches.opt_str("k") {
+        Some(k) if matches.free.len() == 1 => k,
+        Some(_) => subcommand_usage_error(json, &opts, &brief, "sign takes one FILE"),
+        None => subcommand_usage_error(json, &opts, &brief, "sign needs -k KEY"),
+    };
+    let key = SecretKey::load(Path::new(&key_file)).unwrap_or_else(|e| config_error(json, &format!("Failed to read signing key {:?}: {}", key_file, e)));
+    let file = &matches.free[0];
+    let digest = file_sha256(file).unwrap_or_else(|e| runtime_error(json, &format!("Failed to read {:?}: {}", file, e)));
+    let sig_file = format!("{}.sig", file);
+    match fs::write(&sig_file, signing::detached_signature(&key, &digest)) {
+        Ok(()) => println!("Wrote {:?}, signed with key {}", sig_file, key.public_key().id()),
+        Err(e) => runtime_error(json, &format!("Failed to write {:?}: {}", sig_file, e)),
+    }
+}
+
+#[cfg(feature = "network")]
+fn self_update(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "public-key", "check the download's signature with the public key in FILE rather than the one built in", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} self-update [--public-key FILE]\nReplaces this binary with the latest GitHub release's build for {}, once its signature checks out.",
+        program,
+        env!("BUILD_TARGET")
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if !matches.free.is_empty() {
+        subcommand_usage_error(json, &opts, &brief, "self-update takes no arguments");
+    }
+    let key = match matches.opt_str("public-key") {
+        Some(k) => PublicKey::load(Path::new(&k)).unwrap_or_else(|e| config_error(json, &format!("Failed to read public key {:?}: {}", k, e))),
+        None => update::release_key().unwrap_or_else(|| config_error(json, "This build has no release key to check updates with; give the releases' public key as --public-key")),
+    };
+    match update::self_update(&key) {
+        Ok(update::Updated::UpToDate) => println!("Backup Auditor v{} is up to date", env!("CARGO_PKG_VERSION")),
+        Ok(update::Updated::NoReleases) => println!("No releases have been published yet"),
+        Ok(update::Updated::Replaced { version, path }) => println!("Updated {:?} from v{} to {}", path, env!("CARGO_PKG_VERSION"), version),
+        Err(e) => runtime_error(json, &format!("Failed to update: {}", e)),
+    }
+}
+
+// --at and --weekday, for install-schedule and the Windows service.
+#[cfg(any(target_os = "macos", windows))]
+fn schedule_options(opts: &mut Options) {
+    opts.optopt("", "at", "time of day to run the audit, HH:MM (default 03:00)", "TIME");
+    opts.optopt("", "weekday", "run weekly on day N, 0 or 7 being Sunday (default: daily)", "N");
+}
+
+#[cfg(any(target_os = "macos", windows))]
+fn parse_schedule(json: bool, matches: &Matches) -> Schedule {
+    let (hour, minute) = match Schedule::parse_time(&matches.opt_str("at").unwrap_or_else(|| String::from("03:00"))) {
+        Some(t) => t,
+        None => config_error(json, "--at needs a time of day as HH:MM"),
+    };
+    let weekday = match matches.opt_get::<u8>("weekday") {
+        Ok(d) if d.map(|d| d <= 7).unwrap_or(true) => d,
+        _ => config_error(json, "--weekday needs a day from 0 to 7"),
+    };
+    Schedule { hour, minute, weekday }
+}
+
+// a scheduled run has no terminal to draw bars on; milestones read well in a log
+#[cfg(any(target_os = "macos", windows))]
+fn scheduled_audit_args(free: &[String]) -> Vec<String> {
+    let mut audit_args = free.to_vec();
+    if !audit_args.iter().any(|a| a == "--no-progress") {
+        audit_args.insert(0, String::from("--no-progress"));
+    }
+    audit_args
+}
+
+#[cfg(target_os = "macos")]
+fn install_schedule(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    schedule_options(&mut opts);
+    opts.optopt("", "label", &format!("launchd job label (default {})", launchd::DEFAULT_LABEL), "LABEL");
+    opts.optflag("", "print", "print the plist instead of installing it");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {0} install-schedule [options] -- AUDIT OPTIONS\nRuns the audit given after -- periodically as a launchd agent, e.g.\n  {0} install-schedule --at 02:30 -- -o audit.txt /Users/me /Volumes/Clone\nOutput goes to ~/Library/Logs/backup_auditor.log.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if matches.free.is_empty() {
+        subcommand_usage_error(json, &opts, &brief, "install-schedule needs the audit's options after --");
+    }
+    let schedule = parse_schedule(json, &matches);
+    let audit_args = scheduled_audit_args(&matches.free);
+    let (exe, working_dir, log) = match (env::current_exe(), env::current_dir(), launchd::log_path()) {
+        (Ok(exe), Ok(dir), Ok(log)) => (exe, dir, log),
+        (Err(e), _, _) | (_, Err(e), _) | (_, _, Err(e)) => runtime_error(json, &format!("Failed to set up the schedule: {}", e)),
+    };
+    let label = matches.opt_str("label").unwrap_or_else(|| String::from(launchd::DEFAULT_LABEL));
+    let job = launchd::Job {
+        label: &label,
+        program: &exe.to_string_lossy(),
+        args: &audit_args,
+        working_dir: &working_dir.to_string_lossy(),
+        log: &log.to_string_lossy(),
+        schedule: &schedule,
+    };
+    if matches.opt_present("print") {
+        print!("{}", launchd::plist(&job));
+        return;
+    }
+    match launchd::install(&job) {
+        Ok(path) => println!("Installed {:?}; audits run {}", path, schedule),
+        Err(e) => runtime_error(json, &format!("Failed to install the schedule: {}", e)),
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn uninstall_schedule(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "label", &format!("launchd job label (default {})", launchd::DEFAULT_LABEL), "LABEL");
+    subcommand_options(&mut opts);
+
+    let brief = format!("Usage: {} uninstall-schedule [--label LABEL]\nStops and removes an agent installed with install-schedule.", program);
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if !matches.free.is_empty() {
+        subcommand_usage_error(json, &opts, &brief, "uninstall-schedule takes no arguments");
+    }
+    let label = matches.opt_str("label").unwrap_or_else(|| String::from(launchd::DEFAULT_LABEL));
+    match launchd::uninstall(&label) {
+        Ok(path) => println!("Removed {:?}", path),
+        Err(e) => runtime_error(json, &format!("Failed to remove the schedule: {}", e)),
+    }
+}
+
+// Scheduling is launchd's on macOS; elsewhere cron or a systemd timer runs
+// the binary directly.
+#[cfg(not(target_os = "macos"))]
+fn install_schedule(_program: &str, args: &[String]) {
+    config_error(subcommand_wants_json(args), "install-schedule is only supported on macOS; use cron or a systemd timer, or the service subcommand on Windows");
+}
+
+#[cfg(not(target_os = "macos"))]
+fn uninstall_schedule(_program: &str, args: &[String]) {
+    config_error(subcommand_wants_json(args), "uninstall-schedule is only supported on macOS");
+}
+
+#[cfg(windows)]
+fn service_command(program: &str, args: &[String]) {
+    let action = args.first().map(String::as_str).unwrap_or_default();
+    let mut opts = Options::new();
+    opts.optopt("", "name", &format!("service name (default {})", winservice::DEFAULT_NAME), "NAME");
+    if matches!(action, "install" | "run") {
+        schedule_options(&mut opts);
+    }
+    if action == "run" {
+        opts.optopt("", "dir", "working directory of the audit", "DIR");
+    }
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {0} service install [options] -- AUDIT OPTIONS\n       {0} service uninstall|status [--name NAME]\nRuns the audit given after -- periodically as a Windows service, installed from an elevated prompt, e.g.\n  {0} service install --at 02:30 -- -o D:\\audit.txt C:\\Users E:\\Backup\nHow each audit went goes to the Application event log, its output to {1}.",
+        program,
+        winservice::log_path("NAME").display(),
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args.get(1..).unwrap_or_default()) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let name = matches.opt_str("name").unwrap_or_else(|| String::from(winservice::DEFAULT_NAME));
+    match action {
+        "install" | "run" if matches.free.is_empty() => subcommand_usage_error(json, &opts, &brief, &format!("service {} needs the audit's options after --", action)),
+        "uninstall" | "status" if !matches.free.is_empty() => subcommand_usage_error(json, &opts, &brief, &format!("service {} takes no arguments", action)),
+        "install" => {
+            let working_dir = match env::current_dir() {
+                Ok(d) => d.to_string_lossy().into_owned(),
+                Err(e) => runtime_error(json, &format!("Failed to set up the service: {}", e)),
+            };
+            let service = winservice::Service { name, schedule: parse_schedule(json, &matches), working_dir, args: scheduled_audit_args(&matches.free) };
+            match winservice::install(&service) {
+                Ok(()) => println!("Installed and started the service {}; audits run {}", service.name, service.schedule),
+                Err(e) => runtime_error(json, &format!("Failed to install the service: {}", e)),
+            }
+        }
+        "uninstall" => match winservice::uninstall(&name) {
+            Ok(()) => println!("Removed the service {}", name),
+            Err(e) => runtime_error(json, &format!("Failed to remove the service: {}", e)),
+        },
+        "status" => match winservice::status(&name) {
+            Ok(status) => print!("{}", status),
+            Err(e) => runtime_error(json, &format!("Failed to query the service: {}", e)),
+        },
+        // what the service control manager starts
+        "run" => {
+            let working_dir = matches.opt_str("dir").unwrap_or_else(|| String::from("."));
+            let service = winservice::Service { name, schedule: parse_schedule(json, &matches), working_dir, args: matches.free.clone() };
+            if let Err(e) = winservice::run_service(service) {
+                runtime_error(json, &format!("Failed to run as a service (service run is for the service control manager; use service install): {}", e));
+            }
+        }
+        _ => subcommand_usage_error(json, &opts, &brief, "service needs install, uninstall or status"),
+    }
+}
+
+#[cfg(not(windows))]
+fn service_command(_program: &str, args: &[String]) {
+    config_error(subcommand_wants_json(args), "service is only supported on Windows; use cron or a systemd timer, or install-schedule on macOS");
+}
+
+fn ack_finding(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "ack-file", "acknowledgements file, created if missing", "FILE");
+    opts.optopt("", "note", "why the finding is acceptable", "TEXT");
+    opts.optflag("", "list", "list the acknowledged findings instead");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} ack --ack-file FILE (ID | KIND PATH) [--note TEXT]\nAcknowledges a finding, by its ID from a report or by kind (e.g. hash_mismatch) and path relative to its root, so later runs given --ack-file stop raising it.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let file = match matches.opt_str("ack-file") {
+        Some(f) => f,
+        None => subcommand_usage_error(json, &opts, &brief, "ack needs --ack-file FILE"),
+    };
+
+    if matches.opt_present("list") {
+        match ack::Acks::load(Path::new(&file)) {
+            Ok(acks) => acks.entries().for_each(|(kind, path, note)| println!("{}\t{:?}\t{}", kind, path, note)),
+            Err(e) => runtime_error(json, &format!("Failed to read ack file {:?}: {}", file, e)),
+        }
+        return;
+    }
+    let (kind, path) = match &matches.free[..] {
+        [id] if id.starts_with("F-") => (ack::ID, id.as_str()),
+        [kind, path] => (kind.as_str(), path.as_str()),
+        _ => subcommand_usage_error(json, &opts, &brief, "ack takes a finding ID, or a KIND and a PATH"),
+    };
+    if kind != ack::ID && !report::is_kind(kind) {
+        config_error(json, &format!("Unknown finding kind {:?}", kind));
+    }
+    let path = path.trim_start_matches("./").trim_matches('/');
+    match ack::add(Path::new(&file), kind, path, &matches.opt_str("note").unwrap_or_default()) {
+        Ok(true) => println!("Acknowledged {} {:?}", kind, path),
+        Ok(false) => println!("{} {:?} was already acknowledged", kind, path),
+        Err(e) => runtime_error(json, &format!("Failed to update ack file {:?}: {}", file, e)),
+    }
+}
+
+fn view_report(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "kind", "start with only findings of KIND shown, e.g. hash_mismatch", "KIND");
+    opts.optopt("", "search", "start with only findings whose path contains TEXT shown", "TEXT");
+    opts.optflag("", "list", "print the findings shown, KIND<tab>PATH, instead of browsing them (the default when stdout isn't a terminal)");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} view REPORT [options]\nBrowses the findings of a report written with --format json: Tab picks a kind of finding, / searches paths, arrows move through the directories findings are in and open one, t lists them flat, q quits.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let file = match &matches.free[..] {
+        [file] => file,
+        _ => subcommand_usage_error(json, &opts, &brief, "view takes one REPORT"),
+    };
+    if let Some(kind) = matches.opt_str("kind").filter(|k| !report::is_kind(k)) {
+        config_error(json, &format!("Unknown finding kind {:?}", kind));
+    }
+
+    let mut view = match view::load(Path::new(file)) {
+        Ok(v) => v,
+        Err(e) => runtime_error(json, &format!("Failed to read report {:?}: {}", file, e)),
+    };
+    view.kind = matches.opt_str("kind");
+    view.search = matches.opt_str("search").unwrap_or_default();
+    if matches.opt_present("list") || cfg!(not(unix)) || !io::stdout().is_terminal() || !io::stdin().is_terminal() {
+        view.tree = false;
+        for (_, item) in view.matching() {
+            println!("{}\t{}", item.kind, item.rel);
+        }
+    } else {
+        #[cfg(unix)]
+        if let Err(e) = view::run(&mut view) {
+            runtime_error(json, &format!("Failed to run the viewer: {}", e));
+        }
+    }
+}
+
+fn query_results(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "run", "the run to look at (default: the last one started)", "RUN_ID");
+    opts.optflag("", "runs", "list the runs in the database instead: ID, started, finished (or interrupted), source, target and findings");
+    opts.optmulti("", "kind", "only findings of KIND, e.g. hash_mismatch; repeatable", "KIND");
+    opts.optopt("", "under", "only findings at or below DIR, relative to the root", "DIR");
+    opts.optopt("", "min-size", "only findings about files of at least SIZE (e.g. 1G)", "SIZE");
+    opts.optopt("", "max-size", "only findings about files of at most SIZE", "SIZE");
+    opts.optopt("", "by", "count the findings and their bytes by kind, directory or size instead of listing them", "GROUPING");
+    opts.optopt("", "depth", "with --by directory, how many directories down to count by (default 1)", "N");
+    opts.optopt("", "files-from", "also write the source paths of the findings to FILE, one per line relative to the source root, for rsync --files-from", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} query DATABASE [options]\nLists the findings of a run in a database written with --format sqlite, KIND<tab>PATH, or counts them --by kind, directory or size.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let db = match &matches.free[..] {
+        [db] => Path::new(db),
+        _ => subcommand_usage_error(json, &opts, &brief, "query takes one DATABASE"),
+    };
+    if matches.opt_present("runs") {
+        match query::runs(db) {
+            Ok(runs) => {
+                for run in runs {
+                    let field = |name: &str| run[name].as_str().unwrap_or("interrupted").to_string();
+                    println!("{}\t{}\t{}\t{}\t{}\t{}", field("run_id"), field("started"), field("finished"), field("source"), field("target"), run["findings"]);
+                }
+            }
+            Err(e) => runtime_error(json, &format!("Failed to read {:?}: {}", db, e)),
+        }
+        return;
+    }
+    let kinds = matches.opt_strs("kind");
+    if let Some(kind) = kinds.iter().find(|k| !report::is_kind(k)) {
+        config_error(json, &format!("Unknown finding kind {:?}", kind));
+    }
+    let size = |name: &str| match matches.opt_str(name).map(|s| parse_size(&s)).transpose() {
+        Ok(s) => s,
+        Err(e) => config_error(json, &format!("Invalid --{}: {}", name, e)),
+    };
+    let depth = match matches.opt_get_default("depth", 1) {
+        Ok(0) => config_error(json, "--depth must be at least 1"),
+        Ok(_) if matches.opt_present("depth") && matches.opt_str("by").as_deref() != Some("directory") => config_error(json, "--depth needs --by directory"),
+        Ok(d) => d,
+        Err(e) => config_error(json, &format!("Invalid --depth: {}", e)),
+    };
+    let by = match matches.opt_str("by").as_deref() {
+        None => None,
+        Some("kind") => Some(query::By::Kind),
+        Some("directory") => Some(query::By::Directory(depth)),
+        Some("size") => Some(query::By::Size),
+        Some(other) => config_error(json, &format!("Unknown --by grouping {:?}; expected kind, directory or size", other)),
+    };
+    let filter = query::Filter { run: matches.opt_str("run"), kinds, under: matches.opt_str("under"), min_size: size("min-size"), max_size: size("max-size") };
+
+    let (run, found) = match query::findings(db, &filter) {
+        Ok(f) => f,
+        Err(e) => runtime_error(json, &format!("Failed to query {:?}: {}", db, e)),
+    };
+    if let Some(file) = matches.opt_str("files-from") {
+        match fs::write(&file, query::files_from(&found)) {
+            Ok(()) => eprintln!("Wrote the source paths of run {}'s findings to {:?}", run, file),
+            Err(e) => runtime_error(json, &format!("Failed to write {:?}: {}", file, e)),
+        }
+    }
+    match by {
+        Some(by) => {
+            for (group, counted) in query::summarize(&found, by) {
+                println!("{}\t{}\t{}", group, counted.findings, counted.bytes);
+            }
+        }
+        None => {
+            for f in &found {
+                println!("{}\t{}", f.kind, f.rel);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "bundle")]
+fn export_bundle(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("o", "output", "bundle filename, must not exist (e.g. audit.tar.gz)", "FILE");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} export-bundle -o FILE REPORT...\nPackages reports with a digest manifest for review on another machine.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    let output = match matches.opt_str("o") {
+        Some(o) if !matches.free.is_empty() => o,
+        Some(_) => subcommand_usage_error(json, &opts, &brief, "export-bundle needs at least one REPORT"),
+        None => subcommand_usage_error(json, &opts, &brief, "export-bundle needs -o FILE"),
+    };
+
+    match bundle::export(Path::new(&output), &matches.free) {
+        Ok(n) => println!("Bundled {} files into {:?}", n, output),
+        Err(e) => runtime_error(json, &format!("Failed to export bundle: {}", e)),
+    }
+}
+
+#[cfg(feature = "bundle")]
+fn inspect_bundle(program: &str, args: &[String]) {
+    let mut opts = Options::new();
+    opts.optopt("", "extract", "also write the bundled files into DIR", "DIR");
+    subcommand_options(&mut opts);
+
+    let brief = format!(
+        "Usage: {} inspect-bundle BUNDLE [options]\nChecks a bundle's files against its manifest and prints each report's coverage and summary.",
+        program
+    );
+    let (matches, json) = match parse_subcommand(&opts, &brief, args) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+    if matches.free.len() != 1 {
+        subcommand_usage_error(json, &opts, &brief, "inspect-bundle takes one BUNDLE");
+    }
+
+    let b = match bundle::inspect(Path::new(&matches.free[0])) {
+        Ok(b) => b,
+        Err(e) => runtime_error(json, &format!("Failed to read bundle: {}", e)),
+    };
+    println!(
+        "Created {} on {} by {}",
+        b.manifest["created"].as_str().unwrap_or("?"),
+        b.manifest["host"].as_str().unwrap_or("?"),
+        b.manifest["tool"].as_str().unwrap_or("?"),
+    );
+    for name in &b.missing {
+        println!("MISSING  {}", name);
+    }
+    for f in &b.files {
+        let status = match f.intact {
+            Some(true) => "ok",
+            Some(false) => "CORRUPT",
+            None => "UNLISTED",
+        };
+        println!("{:8} {} ({})", status, f.name, indicatif::HumanBytes(f.data.len() as u64));
+    }
+    for f in &b.files {
+        // the coverage and summary sections run to the end of a report
+        let text = String::from_utf8_lossy(&f.data);
+        if let Some(at) = text.find("== Coverage ==") {
+            println!("\n-- {} --\n{}", f.name, text[at..].trim_end());
+        }
+    }
+
+    if let Some(dir) = matches.opt_str("extract") {
+        let dir = Path::new(&dir);
+        let written = fs::create_dir_all(dir).and_then(|_| {
+            b.files.iter().try_for_each(|f| fs::write(dir.join(&f.name), &f.data))
+        });
+        match written {
+            Ok(()) => println!("\nExtracted {} files into {:?}", b.files.len(), dir),
+            Err(e) => runtime_error(json, &format!("Failed to extract bundle: {}", e)),
+        }
+    }
+}
+
+fn open_auditor(args: &mut Args) -> Arc<Auditor> {
+    let options = args.report.take().unwrap();
+    let json = options.format == report::Format::Json;
+    let auditor = match Auditor::new(AuditConfig {
+        source_dir: args.source_dir.clone(),
+        target_dir: args.target_dir.clone(),
+        output_file: args.output_file.clone(),
+        report: options,
+        command_line: args.command_line.clone(),
+        compare: args.compare.take().unwrap(),
+        filter: args.filter.clone(),
+        bidirectional: args.bidirectional,
+        min_free_space: args.min_free_space,
+        inject_findings: args.inject_findings,
+        plan_repairs: args.repair.is_some(),
+        job_log: args.job_log.take(),
+        walk_threads: args.walk_threads,
+        chargeback: args.chargeback.as_ref().map(|(_, by)| *by),
+        collect_missing: args.tickets.is_some(),
+    }) {
+        Ok(a) => Arc::new(a),
+        Err(e) => runtime_error(json, &format!("Failed to create output file {:?}: {}", args.output_file, e)),
+    };
+    println!("Run ID: {}", auditor.report().run_id());
+    auditor
+}
+
+// Writes out the rest of the report; one that couldn't be written in full
+// (a full disk, a closed pipe) fails the run whatever the audit found.
+fn finish_audit(auditor: &Auditor, output_file: &str, command_line: &[String]) -> AuditSummary {
+    match auditor.finish() {
+        Ok(summary) => summary,
+        Err(e) => runtime_error(wants_json(command_line), &format!("Failed to write report {:?}: {}", output_file, e)),
+    }
+}
+
+fn export_telemetry(otlp: Option<&otlp::Exporter>, report: &Report, source_dir: &str, target_dir: &str, finished: bool) {
+    if let Some(Err(e)) = otlp.map(|o| o.export(&report.stats(), source_dir, target_dir, finished)) {
+        eprintln!("Failed to export telemetry: {}", e);
+    }
+}
+
+// The progress display of a full audit, driven by the auditor's events: a
+// spinner per file in flight and an overall bar with the running tally.
+struct AuditBars {
+    bars: Vec<ProgressBar>,
+    pbar: ProgressBar,
+    slots: Slots,
+    // the bar each file in flight holds, by source path
+    held: Mutex<HashMap<String, usize>>,
+    bar_refresh: Vec<Refresh>,
+    tally_refresh: Refresh,
+    checked: AtomicU64,
+    progress: Arc<Progress>,
+    report: Arc<Report>,
+}
+
+impl AuditBars {
+    fn event(&self, event: &AuditEvent) {
+        match *event {
+            AuditEvent::Checking { src, tgt } => {
+                if let Some(index) = self.slots.claim() {
+                    self.held.lock().unwrap().insert(src.to_string(), index);
+                    if self.bar_refresh[index].due() {
+                        let term_width = terminal_size::terminal_size().map(|s| usize::from(s.0.0.saturating_sub(5))).unwrap_or(80);
+                        self.bars[index].set_message(trim_str(tgt, term_width));
+                    }
+                }
+            }
+            AuditEvent::Checked { src, bytes, compared } => {
+                if let Some(index) = self.held.lock().unwrap().remove(src) {
+                    self.slots.release(index);
+                }
+                self.progress.file_done(bytes);
+                if compared {
+                    self.checked.fetch_add(1, Ordering::Relaxed);
+                }
+                if self.tally_refresh.due() {
+                    self.redraw();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Bytes move on as files are read, not only as they finish, so a large
+    // file doesn't stall the bar and its ETA.
+    fn redraw(&self) {
+        self.pbar.set_message(format!("{}/{} files {}", self.checked.load(Ordering::Relaxed), self.progress.total_files, self.report.tally()));
+        self.pbar.set_position(self.progress.bytes().min(self.progress.total_bytes));
+    }
+
+    fn finish(&self) {
+        self.redraw();
+        self.pbar.finish();
+        self.bars.iter().for_each(|b| {
+            b.finish()
+        });
+    }
+}
+
+// The overall bar of a full or log audit, by bytes of source content.
+const BYTES_BAR: &str = "{wide_bar} {bytes}/{total_bytes} {binary_bytes_per_sec} eta {eta} {msg}";
+
+fn deep_check(mut args: Args) -> i32 {
+    let io_control = args.compare.as_ref().and_then(|c| c.hashing.io_control.clone());
+    let read_progress = args.compare.as_ref().and_then(|c| c.hashing.read_progress.clone());
+    let auditor = open_auditor(&mut args);
+    let report = auditor.report().clone();
+    let otlp = args.otlp_endpoint.as_deref().map(|e| otlp::Exporter::new(e, "audit", report.run_id()));
+    auditor.start();
+
+    let stage_started = std::time::SystemTime::now();
+    let (files_count, bytes_count) = auditor.count();
+    if let Some(o) = &otlp {
+        o.stage("count", stage_started);
+    }
+    let stage_started = std::time::SystemTime::now();
+
+    let progress = Arc::new(Progress::new(files_count, bytes_count, read_progress));
+    let milestones = if args.no_progress {
+        Some(progress::log_milestones(progress.clone(), report.clone(), args.milestones))
+    } else {
+        None
+    };
+
+    let mbar: MultiProgress = if args.no_progress {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::with_draw_target(progress_draw_target(args.progress_refresh))
+    };
+
+    let bars: Vec<ProgressBar> = (0..=args.workers)
+        .map(|_| {
+            let x = ProgressBar::new_spinner();
+            let s = ProgressStyle::default_spinner().tick_strings(&[
+                "|",
+                "/",
+                "-",
+                "\\",
+            ]);
+            x.set_style(s);
+            mbar.add(x)
+        })
+        .collect();
+
+    let pbar = mbar.add(ProgressBar::new(bytes_count));
+    pbar.set_style(ProgressStyle::default_bar().template(BYTES_BAR));
+
+    let ui = Arc::new(AuditBars {
+        slots: Slots::new(bars.len()),
+        held: Mutex::new(HashMap::new()),
+        bar_refresh: bars.iter().map(|_| Refresh::new(args.progress_refresh)).collect(),
+        tally_refresh: Refresh::new(args.progress_refresh),
+        checked: AtomicU64::new(0),
+        bars,
+        pbar,
+        progress: progress.clone(),
+        report: report.clone(),
+    });
+    ui.redraw();
+    let events = ui.clone();
+    auditor.on_event(move |e| events.event(e));
+
+    let walk_auditor = auditor.clone();
+    let walk_ui = ui.clone();
+    let walk_thread = thread::spawn(move || {
+        walk_auditor.compare();
+        walk_ui.finish();
+    });
+    let tick_progress = progress.clone();
+    let tick_interval = args.progress_refresh;
+    thread::spawn(move || {
+        while !tick_progress.is_done() && !ui.pbar.is_finished() {
+            thread::sleep(tick_interval);
+            ui.redraw();
+        }
+    });
+
+    mbar.join().unwrap();
+
+    walk_thread.join().expect("failed to join walk thread");
+    if let Some(o) = &otlp {
+        o.stage("compare", stage_started);
+    }
+
+    if args.bidirectional && !report.stopped() {
+        let stage_started = std::time::SystemTime::now();
+        println!("Checking the target for entries not in the source");
+        let orphans = auditor.find_orphans();
+        println!("Found {} entries only in the target", orphans);
+        if let Some(o) = &otlp {
+            o.stage("orphans", stage_started);
+        }
+    }
+
+    progress.finish();
+    if let Some(m) = milestones {
+        m.join().expect("failed to join milestone thread");
+    }
+
+    let summary = finish_audit(&auditor, &args.output_file, &args.command_line);
+    let skip_list_written = write_skip_list(args.skip_list.as_ref(), &args.source_dir, &summary, wants_json(&args.command_line));
+    let chargeback_written = write_chargeback(args.chargeback.as_ref(), &summary, wants_json(&args.command_line));
+    let history_written = write_history(args.history.as_deref(), args.tickets.as_ref(), &report, &summary, &args.source_dir, &args.target_dir, wants_json(&args.command_line));
+    repair(args.repair.as_ref(), &summary);
+    print_io_control(io_control.as_deref());
+    export_telemetry(otlp.as_ref(), &report, &args.source_dir, &args.target_dir, true);
+    if summary.stopped_early {
+        println!("Stopped at the first finding (--fail-fast): {}", summary.tally);
+    }
+    match skip_list_written && chargeback_written && history_written {
+        true => audit_status(&report),
+        false => EXIT_IO,
+    }
+}
+
+// Adds a full audit to the history, after opening tickets for its groups of
+// findings that don't have one open yet. One stopped early says nothing about
+// the findings it didn't get to, so it's left out. False if the history
+// couldn't be written or a ticket couldn't be opened, which fails the run
+// like a report that couldn't be written.
+fn write_history(history: Option<&str>, tickets: Option<&(ticket::Template, u64)>, report: &Report, summary: &AuditSummary, source_dir: &str, target_dir: &str, json: bool) -> bool {
+    let file = match history {
+        Some(f) if !summary.stopped_early => f,
+        _ => return true,
+    };
+    let failed = |what: &str, e: &dyn std::fmt::Display| {
+        print_error(json, "runtime", &format!("Failed to {}: {}", what, e));
+        false
+    };
+    let mut written = true;
+    let mut keys = None;
+    if let Some((template, max_mismatches)) = tickets {
+        let lines = match history::lines(Path::new(file)) {
+            Ok(l) => l,
+            Err(e) => return failed(&format!("read history {:?}", file), &e),
+        };
+        let groups = ticket::groups(source_dir, target_dir, &summary.missing_in_target, summary.tally.mismatches, *max_mismatches);
+        for group in &groups {
+            if let Some(open) = history::open_ticket(&lines, source_dir, target_dir, &group.key) {
+                println!("Ticket {} is still open for {}", open, group.key);
+                continue;
+            }
+            let opened = ticket::open(template, group, report.run_id(), source_dir, target_dir).and_then(|t| {
+                history::append(Path::new(file), &history::ticket_line(report.run_id(), source_dir, target_dir, &group.key, &t)).map_err(|e| format!("opened {} but couldn't add it to the history: {}", t, e))?;
+                Ok(t)
+            });
+            match opened {
+                Ok(t) => println!("Opened ticket {} for {}", t, group.key),
+                Err(e) => written = failed(&format!("open a ticket for {}", group.key), &e),
+            }
+        }
+        keys = Some(groups.into_iter().map(|g| g.key).collect::<Vec<_>>());
+    }
+    let line = history::run_line(report.run_id(), source_dir, target_dir, &report.raised(), keys.as_deref());
+    match history::append(Path::new(file), &line) {
+        Ok(()) => written,
+        Err(e) => failed(&format!("add the run to history {:?}", file), &e),
+    }
+}
+
+#[cfg(feature = "watch")]
+fn watch_mode(mut args: Args, delay: Duration) -> i32 {
+    let auditor = open_auditor(&mut args);
+    let report = auditor.report().clone();
+    let otlp = args.otlp_endpoint.as_deref().map(|e| otlp::Exporter::new(e, "watch", report.run_id()));
+    auditor.start();
+    println!("Watching {:?}; changes are verified {} after they settle", args.source_dir, indicatif::HumanDuration(delay));
+
+    let source_root = Path::new(&args.source_dir);
+    let source_abs = source_root.canonicalize().unwrap_or_default();
+    let result = watch::watch(source_root, delay, |paths| {
+        let batch_started = std::time::SystemTime::now();
+        let mut checked = 0;
+        for path in paths {
+            let meta = match fs::symlink_metadata(&path) {
+                Ok(m) => m,
+                // gone again before the backup could be expected to copy it
+                Err(_) => continue,
+            };
+            // changes under excluded directories are as uninteresting as the
+            // directories themselves
+            match path.strip_prefix(source_root) {
+                Ok(rel) if !args.filter.excludes_path(rel, meta.is_dir()) && !args.filter.is_own_file(&source_abs, rel) => {}
+                _ => continue,
+            }
+            // a directory moved in as a whole only reports itself
+            let entries: Vec<(String, Option<u64>)> = if meta.is_dir() {
+                backup_auditor::audit::walk_dir(&path.display().to_string(), &args.filter)
+                    .into_iter()
+                    .flatten()
+                    .filter(|e| e.client_state.is_none())
+                    // the walk matched path globs against the moved directory
+                    .filter(|e| e.path().strip_prefix(source_root).map_or(true, |rel| !args.filter.excludes_path(rel, e.file_type.is_dir())))
+                    .map(|e| {
+                        let size = e.file_type.is_file().then(|| e.metadata().map(|m| m.len()).unwrap_or(0));
+                        (e.path().display().to_string(), size)
+                    })
+                    .collect()
+            } else {
+                vec![(path.display().to_string(), meta.is_file().then_some(meta.len()))]
+            };
+            for (src_path, src_size) in entries {
+                auditor.check(&src_path, src_size);
+                checked += 1;
+            }
+        }
+        if checked > 0 {
+            println!(
+                "[{}] verified {} changed entries {}",
+                humantime::format_rfc3339_seconds(std::time::SystemTime::now()),
+                checked,
+                report.tally(),
+            );
+            if let Some(o) = &otlp {
+                o.stage("verify-changes", batch_started);
+            }
+            export_telemetry(otlp.as_ref(), &report, &args.source_dir, &args.target_dir, false);
+        }
+    });
+    // changes after the watch stopped went unchecked; the report is still
+    // finished, and the run fails as it would on any I/O error
+    let watched = match result {
+        Ok(()) => true,
+        Err(e) => {
+            print_error(wants_json(&args.command_line), "runtime", &format!("Failed to watch {:?}: {}", args.source_dir, e));
+            false
+        }
+    };
+    let summary = finish_audit(&auditor, &args.output_file, &args.command_line);
+    let skip_list_written = write_skip_list(args.skip_list.as_ref(), &args.source_dir, &summary, wants_json(&args.command_line));
+    let chargeback_written = write_chargeback(args.chargeback.as_ref(), &summary, wants_json(&args.command_line));
+    export_telemetry(otlp.as_ref(), &report, &args.source_dir, &args.target_dir, true);
+    match watched && skip_list_written && chargeback_written {
+        true => audit_status(&report),
+        false => EXIT_IO,
+    }
+}
+
+fn log_check(args: Args, log: &str, format: Option<backuplog::LogFormat>) -> i32 {
+    match backuplog::transferred_files(Path::new(log), format) {
+        Ok(listed) => {
+            let listing = format!("Backup log lists {} transferred files", listed.len());
+            check_listed(args, &listed, "from-log", &listing)
+        }
+        Err(e) => runtime_error(wants_json(&args.command_line), &format!("Failed to read backup log {:?}: {}", log, e)),
+    }
+}
+
+fn recheck_findings(args: Args, prior: &str) -> i32 {
+    match recheck::load(Path::new(prior), &args.source_dir, &args.target_dir) {
+        Ok(r) => {
+            let mut listing = format!("Report {:?} has {} findings about {} entries", prior, r.findings, r.paths.len() as u64 + r.resolved);
+            if r.resolved > 0 {
+                listing.push_str(&format!(" ({} since removed from both sides)", r.resolved));
+            }
+            if r.elsewhere > 0 {
+                listing.push_str(&format!(", and {} about paths under neither root", r.elsewhere));
+            }
+            check_listed(args, &r.paths, "recheck", &listing)
+        }
+        Err(e) => runtime_error(wants_json(&args.command_line), &format!("Failed to read report {:?}: {}", prior, e)),
+    }
+}
+
+// Audits the entries in `listed`, relative to the source root, rather than
+// walking the trees. `listing` says where they came from.
+fn check_listed(mut args: Args, listed: &[PathBuf], mode: &'static str, listing: &str) -> i32 {
+    let io_control = args.compare.as_ref().and_then(|c| c.hashing.io_control.clone());
+    let read_progress = args.compare.as_ref().and_then(|c| c.hashing.read_progress.clone());
+    let auditor = open_auditor(&mut args);
+    let report = auditor.report().clone();
+    let otlp = args.otlp_endpoint.as_deref().map(|e| otlp::Exporter::new(e, mode, report.run_id()));
+    auditor.start();
+    let stage_started = std::time::SystemTime::now();
+
+    let source_root = Path::new(&args.source_dir);
+    let source_abs = source_root.canonicalize().unwrap_or_default();
+    let entries: Vec<(String, Option<u64>)> = listed
+        .iter()
+        .filter(|rel| !args.filter.excludes_path(rel, false) && !args.filter.is_own_file(&source_abs, rel))
+        .map(|rel| {
+            let path = source_root.join(rel);
+            let size = fs::symlink_metadata(&path).ok().filter(|m| m.is_file()).map(|m| m.len());
+            (path.display().to_string(), size)
+        })
+        .collect();
+    println!("{}, {} after exclusions", listing, entries.len());
+
+    let bytes_count = entries.iter().filter_map(|(_, size)| *size).sum();
+    let progress = Arc::new(Progress::new(entries.len() as u64, bytes_count, read_progress));
+    let milestones = if args.no_progress {
+        Some(progress::log_milestones(progress.clone(), report.clone(), args.milestones))
+    } else {
+        None
+    };
+    let pbar = if args.no_progress {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::with_draw_target(bytes_count, progress_draw_target(args.progress_refresh))
+    };
+    pbar.set_style(ProgressStyle::default_bar().template(BYTES_BAR));
+    let redraw = || {
+        pbar.set_message(format!("{}/{} files {}", progress.files_done(), progress.total_files, report.tally()));
+        pbar.set_position(progress.bytes().min(progress.total_bytes));
+    };
+    redraw();
+    let tally_refresh = Refresh::new(args.progress_refresh);
+
+    entries.par_iter().for_each(|(src_path, src_size)| {
+        if report.stopped() {
+            return;
+        }
+        let bytes = auditor.check(src_path, *src_size);
+        progress.file_done(bytes.unwrap_or(0));
+        if tally_refresh.due() {
+            redraw();
+        }
+    });
+    redraw();
+    pbar.finish();
+    if let Some(o) = &otlp {
+        o.stage("compare", stage_started);
+    }
+
+    progress.finish();
+    if let Some(m) = milestones {
+        m.join().expect("failed to join milestone thread");
+    }
+    let summary = finish_audit(&auditor, &args.output_file, &args.command_line);
+    let skip_list_written = write_skip_list(args.skip_list.as_ref(), &args.source_dir, &summary, wants_json(&args.command_line));
+    let chargeback_written = write_chargeback(args.chargeback.as_ref(), &summary, wants_json(&args.command_line));
+    repair(args.repair.as_ref(), &summary);
+    print_io_control(io_control.as_deref());
+    export_telemetry(otlp.as_ref(), &report, &args.source_dir, &args.target_dir, true);
+    if summary.stopped_early {
+        println!("Stopped at the first finding (--fail-fast): {}", summary.tally);
+    }
+    match skip_list_written && chargeback_written {
+        true => audit_status(&report),
+        false => EXIT_IO,
+    }
+}
+
+// False if the chargeback couldn't be written, which fails the run like a
+// report that couldn't be.
+fn write_chargeback(chargeback: Option<&(String, chargeback::GroupBy)>, summary: &AuditSummary, json: bool) -> bool {
+    if let Some((file, by)) = chargeback {
+        match chargeback::write(Path::new(file), *by, &summary.chargeback) {
+            Ok(()) => println!("Wrote chargeback for {} groups to {:?}", summary.chargeback.len(), file),
+            Err(e) => {
+                print_error(json, "runtime", &format!("Failed to write chargeback {:?}: {}", file, e));
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// False if the skip list couldn't be written, which fails the run: the next
+// backup would otherwise retry files the list was meant to leave out.
+fn write_skip_list(skip_list: Option<&(String, skiplist::SkipListFormat)>, source_dir: &str, summary: &AuditSummary, json: bool) -> bool {
+    let (file, format) = match skip_list {
+        Some(s) => s,
+        None => return true,
+    };
+    match skiplist::write(Path::new(file), *format, source_dir, &summary.unreadable_source) {
+        Ok(0) => println!("Wrote {} unreadable source files to the skip list {:?}", summary.unreadable_source.len(), file),
+        Ok(left_out) => println!(
+            "Wrote {} unreadable source files to the skip list {:?}; {} more have names the format can't express",
+            summary.unreadable_source.len() - left_out,
+            file,
+            left_out,
+        ),
+        Err(e) => {
+            print_error(json, "runtime", &format!("Failed to write skip list {:?}: {}", file, e));
+            return false;
+        }
+    }
+    true
+}
+
+// Runs the repairs the audit planned, in target path order, logging each.
+// The report isn't touched: a second audit shows whether they took.
+fn repair(repair: Option<&Repair>, summary: &AuditSummary) {
+    let repair = match repair {
+        Some(r) => r,
+        None => return,
+    };
+    if summary.stopped_early {
+        println!("Not repairing: the audit stopped early, so what it found is incomplete");
+        return;
+    }
+    let (mut done, mut failed, mut held_back) = (0, 0, 0);
+    for action in &summary.repairs {
+        if action.deletes() && !repair.delete_extra {
+            println!("Not repairing (needs --repair-delete-extra): {}", action);
+            held_back += 1;
+        } else if repair.dry_run {
+            println!("Would {}", action);
+            done += 1;
+        } else {
+            let now = humantime::format_rfc3339_seconds(std::time::SystemTime::now());
+            match action.apply() {
+                Ok(()) => {
+                    println!("[{}] repaired: {}", now, action);
+                    done += 1;
+                }
+                Err(e) => {
+                    eprintln!("[{}] failed to {}: {}", now, action, e);
+                    failed += 1;
+                }
+            }
+        }
+    }
+    if repair.dry_run {
+        println!("Repair dry run: {} actions, {} more need --repair-delete-extra", done, held_back);
+    } else {
+        println!("Repaired {} entries, {} failed, {} need --repair-delete-extra; audit again to verify", done, failed, held_back);
+    }
+}
+
+// indicatif limits terminal redraws by rate, not by interval.
+fn progress_draw_target(refresh: Duration) -> ProgressDrawTarget {
+    ProgressDrawTarget::stderr_with_hz((1000 / refresh.as_millis().max(1) as u64).max(1))
+}
+
+fn print_io_control(io_control: Option<&throttle::IoControl>) {
+    if let Some(c) = io_control {
+        for (side, controller) in [("source", &c.src), ("target", &c.tgt)] {
+            let (limit, lowest, latency) = controller.status();
+            println!(
+                "Adaptive I/O on the {}: {} files in flight at the end (lowest {}), read latency {:.2?}",
+                side, limit, lowest, latency
+            );
+        }
+    }
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, ""),
+    };
+    let n: u64 = digits.parse().map_err(|e| format!("{:?}: {}", s, e))?;
+    let shift = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("{:?}: unknown size unit", s)),
+    };
+    n.checked_mul(1 << shift).ok_or_else(|| format!("{:?}: too large", s))
+}
+
+// A target inside the source gets audited against itself (and the report
+// written next to it read back); compared after resolving symlinks and "..".
+// Roots that don't resolve are left to fail where they are opened.
+fn roots_overlap(source: &Path, target: &Path) -> Option<String> {
+    let (source, target) = (source.canonicalize().ok()?, target.canonicalize().ok()?);
+    if source == target {
+        Some(format!("The source and target are the same directory {:?}", source))
+    } else if target.starts_with(&source) {
+        Some(format!("The target {:?} is inside the source {:?}", target, source))
+    } else if source.starts_with(&target) {
+        Some(format!("The source {:?} is inside the target {:?}", source, target))
     } else {
-        output_file
-            .lock()
-            .unwrap()
-            .write_all(format!("Found mismatched file types\nsrc={:?}\ntgt={:?}\n", src_path, tgt_path).as_bytes()).unwrap()
+        None
     }
 }
 
-fn trim_str(str: &String, width: usize) -> String {
+fn trim_str(str: &str, width: usize) -> String {
     let mut len = str.len();
     let c2 = str.chars().skip_while(|_|{
-        len = len-1;
+        len -= 1;
         len > width
     });
     String::from_iter(c2)
@@ -0,0 +1,671 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use jwalk::WalkDir;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use crate::ack::relative_key;
+use crate::audit::LISTING;
+use crate::hash::{self, Algorithm, Digests, HashSpec};
+use crate::report::{to_hex, Finding, Report};
+use crate::signing::{self, SecretKey};
+
+// A snapshot of one tree for checking a copy of it later, when both can't be
+// mounted at once (offline or rotated media). Plain text:
+//
+//   # backup_auditor manifest v1
+//   # root /path/the/manifest/was/made/from
+//   # algorithms sha256,blake3
+//   # keyed KEYID
+//   # dir MTIME<TAB>PATH
+//   SIZE<TAB>MTIME<TAB>DIGEST,DIGEST<TAB>PATH
+//   # Signature ed25519 KEYID: BASE64
+//
+// PATH is relative to the root, '/'-separated, with '\' and newlines escaped
+// as "\\" and "\n". MTIME is whole seconds since the epoch and informational.
+// The keyed line is only there when the digests were made with --hash-key,
+// KEYID naming the key (HashKey::id) so no other is used to check them. Dir
+// lines, one per directory, the root's PATH being empty, are what update
+// tells changed directories by (see dir_mtime); older manifests have none. The
+// signature line, last, is only there when the manifest was signed, and is a
+// detached signature (signing::detached_signature) of everything above it.
+const MAGIC: &str = "# backup_auditor manifest v1";
+const SIGNATURE: &str = "# Signature ";
+const DIR: &str = "# dir ";
+
+pub struct Entry {
+    // None for checksum lists, which don't record them
+    pub size: Option<u64>,
+    pub mtime: Option<u64>,
+    pub digests: Digests,
+}
+
+pub struct Manifest {
+    pub root: String,
+    pub algorithms: Vec<Algorithm>,
+    // the id of the key the digests were made with, if keyed
+    pub key_id: Option<String>,
+    pub entries: BTreeMap<String, Entry>,
+    // directories and their mtimes, as update compares them
+    pub dirs: BTreeMap<String, u64>,
+    // the sha256 of what the signature covers and the signature, if signed
+    pub signed: Option<(String, String)>,
+}
+
+// Passes writes on while hashing them, for signing what was written.
+struct HashingWriter<W> {
+    out: W,
+    digest: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        self.digest.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+pub(crate) fn escape(path: &str) -> String {
+    path.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+pub(crate) fn unescape(path: &str) -> String {
+    let mut out = String::with_capacity(path.len());
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => out.push('\n'),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn invalid(line: usize, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+struct Listing {
+    // path relative to the root, size and mtime, sorted so manifests of the
+    // same tree diff cleanly
+    files: Vec<(String, u64, u64)>,
+    // directories, the root being "", and their mtimes (see dir_mtime)
+    dirs: Vec<(String, u64)>,
+    // directories that couldn't be read and files that couldn't be stat'ed,
+    // relative to the root
+    unlisted: Vec<(String, io::Error)>,
+}
+
+fn mtime_of(meta: &fs::Metadata) -> u64 {
+    meta.modified().ok().and_then(|t| t.duration_since(UNIX_EPOCH).ok()).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// A directory's mtime as recorded for update, which skips listing one whose
+// mtime is still the same. Changed again within the second it was listed in,
+// it would still be, so one that recent is recorded as 0 and listed anyway.
+fn dir_mtime(meta: &fs::Metadata, listed: SystemTime) -> u64 {
+    let listed = listed.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    match mtime_of(meta) {
+        mtime if mtime + 1 >= listed => 0,
+        mtime => mtime,
+    }
+}
+
+// Regular files under `root`. Fails only when the root itself can't be read;
+// anything below it that can't be is left out and listed as such.
+fn list_files(root: &Path) -> io::Result<Listing> {
+    fs::read_dir(root).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", root.display(), e)))?;
+    let root_str = root.to_string_lossy();
+    let listed = SystemTime::now();
+    let mut listing = Listing { files: Vec::new(), dirs: Vec::new(), unlisted: Vec::new() };
+    for entry in WalkDir::new(root).skip_hidden(false).sort(true) {
+        let (e, failed) = match entry {
+            // a directory whose entries couldn't be read comes back with why
+            Ok(mut e) => {
+                let failed = e.read_children_error.take();
+                (Some(e), failed)
+            }
+            Err(e) => (None, Some(e)),
+        };
+        let read_failed = failed.is_some();
+        if let Some(failed) = failed {
+            let path = failed.path().map(Path::to_path_buf).or_else(|| e.as_ref().map(|e| e.path()));
+            let rel = path.and_then(|p| relative_key(&root_str, &p.to_string_lossy())).unwrap_or_default();
+            let reason = failed.to_string();
+            listing.unlisted.push((rel, failed.into_io_error().unwrap_or_else(|| io::Error::other(reason))));
+        }
+        let e = match e {
+            Some(e) if e.file_type.is_file() || e.file_type.is_dir() => e,
+            _ => continue,
+        };
+        let rel = match relative_key(&root_str, &e.path().to_string_lossy()) {
+            Some(rel) => rel,
+            None => continue,
+        };
+        match e.metadata() {
+            Ok(meta) if e.file_type.is_dir() => {
+                if !read_failed {
+                    listing.dirs.push((rel, dir_mtime(&meta, listed)));
+                }
+            }
+            Ok(meta) => listing.files.push((rel, meta.len(), mtime_of(&meta))),
+            Err(e) => {
+                let reason = e.to_string();
+                listing.unlisted.push((rel, e.into_io_error().unwrap_or_else(|| io::Error::other(reason))));
+            }
+        }
+    }
+    Ok(listing)
+}
+
+// `file` relative to `root` if it is somewhere under it, whether or not it
+// exists yet: a manifest, checksum list or report kept in the tree it is
+// about, which isn't part of that tree.
+pub fn inside(root: &Path, file: &Path) -> Option<String> {
+    let dir = match file.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let file = dir.canonicalize().ok()?.join(file.file_name()?);
+    relative_key(&root.canonicalize().ok()?.to_string_lossy(), &file.to_string_lossy())
+}
+
+pub struct WriteSummary {
+    pub files: u64,
+    pub bytes: u64,
+    // files whose digests were carried over from the manifest updated
+    pub reused: u64,
+    // directories listed, of all there are; update skips some
+    pub dirs_listed: u64,
+    pub dirs: u64,
+    // files that couldn't be hashed and directories that couldn't be listed
+    pub unreadable: Vec<(String, io::Error)>,
+}
+
+// A file to record: its path, size and mtime, and its digests if known.
+type Listed = (String, u64, u64, Option<Digests>);
+
+// Hashes what isn't yet and writes the manifest, with `signing_key` ending it
+// in a signature of the rest of it. It is written next to `output` and
+// renamed over it once complete, so one being updated in place isn't lost to
+// a run that fails.
+fn write_listed(root: &Path, spec: &HashSpec, output: &Path, signing_key: Option<&SecretKey>, mut files: Vec<Listed>, mut dirs: Vec<(String, u64)>, mut summary: WriteSummary) -> io::Result<WriteSummary> {
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    dirs.sort();
+    let hashed: Vec<_> = files
+        .into_par_iter()
+        .map(|(rel, size, mtime, known)| {
+            let digests = match known {
+                Some(d) => Ok(d),
+                None => File::open(root.join(&rel)).and_then(|f| hash::hash_file(spec, &f)),
+            };
+            (rel, size, mtime, digests)
+        })
+        .collect();
+
+    let mut partial = output.as_os_str().to_owned();
+    partial.push(".partial");
+    let mut out = HashingWriter { out: BufWriter::new(File::create(&partial)?), digest: Sha256::new() };
+    let names: Vec<&str> = spec.algorithms.iter().map(|a| a.name()).collect();
+    writeln!(out, "{}\n# root {}\n# algorithms {}", MAGIC, root.display(), names.join(","))?;
+    if let Some(key) = &spec.key {
+        writeln!(out, "# keyed {}", key.id())?;
+    }
+    for (rel, mtime) in &dirs {
+        writeln!(out, "{}{}\t{}", DIR, mtime, escape(rel))?;
+    }
+    summary.dirs = dirs.len() as u64;
+    for (rel, size, mtime, digests) in hashed {
+        match digests {
+            Ok(d) => {
+                writeln!(out, "{}\t{}\t{}\t{}", size, mtime, d.values().collect::<Vec<_>>().join(","), escape(&rel))?;
+                summary.files += 1;
+                summary.bytes += size;
+            }
+            Err(e) => summary.unreadable.push((rel, e)),
+        }
+    }
+    let HashingWriter { mut out, digest } = out;
+    if let Some(key) = signing_key {
+        write!(out, "# {}", signing::detached_signature(key, &to_hex(&digest.finalize())))?;
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&partial, output)?;
+    Ok(summary)
+}
+
+pub fn write(root: &Path, spec: &HashSpec, output: &Path, signing_key: Option<&SecretKey>) -> io::Result<WriteSummary> {
+    let Listing { files, dirs, unlisted } = list_files(root)?;
+    // a manifest written into the tree would otherwise list the last one
+    let own = inside(root, output);
+    let files = files.into_iter().filter(|(rel, _, _)| Some(rel) != own.as_ref()).map(|(rel, size, mtime)| (rel, size, mtime, None)).collect();
+    let summary = WriteSummary { files: 0, bytes: 0, reused: 0, dirs_listed: dirs.len() as u64, dirs: 0, unreadable: unlisted };
+    write_listed(root, spec, output, signing_key, files, dirs, summary)
+}
+
+fn child(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+fn parent(rel: &str) -> &str {
+    rel.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+// Brings `old`, a manifest of `root`, up to date, listing only the
+// directories whose mtime changed since it was written: that is when entries
+// are added to, removed from or renamed in one. The files directly in one
+// that didn't change are taken as they were, digests and all, and files in
+// one that did keep theirs if their size and mtime are the same. Content
+// rewritten in place with its mtime put back goes unnoticed; a manifest
+// written from scratch doesn't miss that. `exclude` are paths relative to
+// `root` that aren't part of the tree (see `inside`).
+pub fn update(old: &Manifest, root: &Path, spec: &HashSpec, output: &Path, signing_key: Option<&SecretKey>, exclude: &[String]) -> io::Result<WriteSummary> {
+    fs::read_dir(root).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", root.display(), e)))?;
+    let listed = SystemTime::now();
+    let mut children: HashMap<&str, (Vec<&str>, Vec<&str>)> = HashMap::new();
+    for rel in old.entries.keys() {
+        children.entry(parent(rel)).or_default().0.push(rel);
+    }
+    for rel in old.dirs.keys().filter(|rel| !rel.is_empty()) {
+        children.entry(parent(rel)).or_default().1.push(rel);
+    }
+
+    let (mut files, mut dirs, mut unreadable) = (Vec::new(), Vec::new(), Vec::new());
+    let (mut reused, mut dirs_listed) = (0, 0);
+    let mut pending = vec![String::new()];
+    while let Some(dir) = pending.pop() {
+        let meta = match fs::metadata(root.join(&dir)) {
+            Ok(m) => m,
+            Err(e) => {
+                unreadable.push((dir, e));
+                continue;
+            }
+        };
+        let (old_files, old_dirs) = children.get(dir.as_str()).cloned().unwrap_or_default();
+        if old.dirs.get(&dir).is_some_and(|m| *m != 0 && *m == mtime_of(&meta)) {
+            for rel in old_files.into_iter().filter(|rel| !exclude.iter().any(|e| e == rel)) {
+                let entry = &old.entries[rel];
+                files.push((rel.to_string(), entry.size.unwrap_or(0), entry.mtime.unwrap_or(0), Some(entry.digests.clone())));
+                reused += 1;
+            }
+            pending.extend(old_dirs.into_iter().map(String::from));
+            dirs.push((dir, dir_mtime(&meta, listed)));
+            continue;
+        }
+        dirs_listed += 1;
+        let entries = match fs::read_dir(root.join(&dir)) {
+            Ok(entries) => entries,
+            Err(e) => {
+                unreadable.push((dir, e));
+                continue;
+            }
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    unreadable.push((dir.clone(), e));
+                    continue;
+                }
+            };
+            let rel = child(&dir, &entry.file_name().to_string_lossy());
+            let meta = match entry.file_type() {
+                Ok(t) if t.is_dir() => {
+                    pending.push(rel);
+                    continue;
+                }
+                Ok(t) if t.is_file() && !exclude.contains(&rel) => entry.metadata(),
+                Ok(_) => continue,
+                Err(e) => Err(e),
+            };
+            match meta {
+                Ok(meta) => {
+                    let (size, mtime) = (meta.len(), mtime_of(&meta));
+                    let known = old.entries.get(&rel).filter(|e| e.size == Some(size) && e.mtime == Some(mtime)).map(|e| e.digests.clone());
+                    reused += known.is_some() as u64;
+                    files.push((rel, size, mtime, known));
+                }
+                Err(e) => unreadable.push((rel, e)),
+            }
+        }
+        dirs.push((dir, dir_mtime(&meta, listed)));
+    }
+    let summary = WriteSummary { files: 0, bytes: 0, reused, dirs_listed, dirs: 0, unreadable };
+    write_listed(root, spec, output, signing_key, files, dirs, summary)
+}
+
+pub fn load(path: &Path) -> io::Result<Manifest> {
+    // what a signature would cover: every line above it
+    let digest = RefCell::new(Sha256::new());
+    let mut lines = BufReader::new(File::open(path)?)
+        .lines()
+        .inspect(|line| match line {
+            Ok(line) if !line.starts_with(SIGNATURE) => {
+                let mut digest = digest.borrow_mut();
+                digest.update(line.as_bytes());
+                digest.update(b"\n");
+            }
+            _ => {}
+        })
+        .peekable();
+    if lines.next().transpose()?.as_deref() != Some(MAGIC) {
+        return Err(invalid(1, "not a backup_auditor manifest"));
+    }
+    let root = match lines.next().transpose()? {
+        Some(l) if l.starts_with("# root ") => l["# root ".len()..].to_string(),
+        _ => return Err(invalid(2, "missing root")),
+    };
+    let algorithms = match lines.next().transpose()? {
+        Some(l) if l.starts_with("# algorithms ") => l["# algorithms ".len()..]
+            .split(',')
+            .map(|name| Algorithm::parse(name).ok_or_else(|| invalid(3, &format!("unknown hash algorithm {:?}", name))))
+            .collect::<io::Result<Vec<_>>>()?,
+        _ => return Err(invalid(3, "missing algorithms")),
+    };
+    let key_id = match lines.peek() {
+        Some(Ok(l)) if l.starts_with("# keyed ") => Some(l["# keyed ".len()..].to_string()),
+        _ => None,
+    };
+    let first = if key_id.is_some() {
+        lines.next();
+        5
+    } else {
+        4
+    };
+    let names: Vec<&'static str> = algorithms.iter().map(|a| if key_id.is_some() { a.keyed_name() } else { a.name() }).collect();
+    let mut entries = BTreeMap::new();
+    let mut dirs = BTreeMap::new();
+    let mut signature = None;
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        let n = i + first;
+        if signature.is_some() {
+            return Err(invalid(n, "lines after the signature"));
+        }
+        if let Some(s) = line.strip_prefix("# ").filter(|_| line.starts_with(SIGNATURE)) {
+            signature = Some(s.to_string());
+            continue;
+        }
+        if let Some(dir) = line.strip_prefix(DIR) {
+            match dir.split_once('\t') {
+                Some((mtime, rel)) => dirs.insert(unescape(rel), mtime.parse().map_err(|_| invalid(n, "invalid directory mtime"))?),
+                None => return Err(invalid(n, "expected a directory's MTIME and PATH")),
+            };
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        let (size, mtime, digests, rel) = match fields[..] {
+            [size, mtime, digests, rel] => (size, mtime, digests, rel),
+            _ => return Err(invalid(n, "expected SIZE, MTIME, DIGESTS and PATH")),
+        };
+        let values: Vec<&str> = digests.split(',').collect();
+        if values.len() != algorithms.len() {
+            return Err(invalid(n, "wrong number of digests"));
+        }
+        entries.insert(
+            unescape(rel),
+            Entry {
+                size: Some(size.parse().map_err(|_| invalid(n, "invalid size"))?),
+                mtime: Some(mtime.parse().map_err(|_| invalid(n, "invalid mtime"))?),
+                digests: Digests::new(names.iter().copied().zip(values.into_iter().map(String::from)).collect()),
+            },
+        );
+    }
+    let signed = signature.map(|s| (to_hex(&digest.take().finalize()), s));
+    Ok(Manifest { root, algorithms, key_id, entries, dirs, signed })
+}
+
+// A list in the format of md5sum, sha1sum, sha256sum and sha512sum, as
+// backup jobs often write next to what they copied:
+//
+//   DIGEST  PATH        (text mode)
+//   DIGEST *PATH        (binary mode)
+//   \DIGEST  PATH       (PATH with "\\" and "\n" escaped)
+//
+// loaded as a manifest of the tree the list was made in, named by `root`.
+// The algorithm is told by the length of the digests; lines starting with
+// '#' and blank lines are ignored.
+pub fn load_checksums(path: &Path, root: &str) -> io::Result<Manifest> {
+    let mut algorithm = None;
+    let mut entries = BTreeMap::new();
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let n = i + 1;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(rest) => (true, rest),
+            None => (false, line.as_str()),
+        };
+        let (digest, rel) = match line.split_once(' ') {
+            Some((digest, rest)) if rest.starts_with(' ') || rest.starts_with('*') => (digest.to_ascii_lowercase(), &rest[1..]),
+            _ => return Err(invalid(n, "expected DIGEST, two spaces (or a space and '*') and PATH")),
+        };
+        if !digest.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid(n, "digest is not hexadecimal"));
+        }
+        let this = match digest.len() {
+            40 => Algorithm::Sha1,
+            64 => Algorithm::Sha256,
+            128 => Algorithm::Sha512,
+            32 => Algorithm::Md5,
+            _ => return Err(invalid(n, "digest of unknown length")),
+        };
+        if *algorithm.get_or_insert(this) != this {
+            return Err(invalid(n, &format!("{} digest in a list of {} digests", this.name(), algorithm.unwrap().name())));
+        }
+        let rel = if escaped { unescape(rel) } else { rel.to_string() };
+        let rel = rel.trim_start_matches("./").to_string();
+        if rel.is_empty() || rel.starts_with('/') {
+            return Err(invalid(n, "path must be relative to the directory the list was made in"));
+        }
+        entries.insert(rel, Entry { size: None, mtime: None, digests: Digests::new(vec![(this.name(), digest)]) });
+    }
+    let algorithm = algorithm.ok_or_else(|| invalid(1, "no checksums"))?;
+    Ok(Manifest { root: root.to_string(), algorithms: vec![algorithm], key_id: None, entries, dirs: BTreeMap::new(), signed: None })
+}
+
+pub struct VerifySummary {
+    pub listed: u64,
+    pub checked: u64,
+}
+
+// Checks `dir` as a copy of the tree the manifest was made from: the manifest
+// stands in for the source, so findings and their IDs read as they would in a
+// normal audit of the original root against `dir`. `exclude` are paths
+// relative to `dir` that aren't part of the copy, like the manifest or report
+// when kept in it (see `inside`). Fails only when `dir` can't be read at all.
+pub fn verify(manifest: &Manifest, dir: &str, exclude: &[String], spec: &HashSpec, report: &Report) -> io::Result<VerifySummary> {
+    let Listing { files: mut on_disk, unlisted, .. } = list_files(Path::new(dir))?;
+    on_disk.retain(|(rel, _, _)| !exclude.contains(rel));
+    let src_path = |rel: &str| format!("{}/{}", manifest.root.trim_end_matches('/'), rel);
+    let tgt_path = |rel: &str| format!("{}/{}", dir.trim_end_matches('/'), rel);
+
+    let mut not_listed = Vec::new();
+    for (rel, reason) in unlisted {
+        report.record(Finding::Error { src: src_path(&rel), tgt: tgt_path(&rel), operation: LISTING, reason });
+        report.not_covered("read error", None);
+        not_listed.push(rel);
+    }
+    // what wasn't listed isn't known to be missing
+    let unknown = |rel: &str| not_listed.iter().any(|u| u.is_empty() || rel == u || rel.strip_prefix(u.as_str()).is_some_and(|r| r.starts_with('/')));
+
+    on_disk.par_iter().for_each(|(rel, size, _)| {
+        let (src, tgt) = (src_path(rel), tgt_path(rel));
+        let entry = match manifest.entries.get(rel) {
+            Some(e) => e,
+            None => {
+                report.record(Finding::MissingInSource { src, tgt, reason: io::Error::new(io::ErrorKind::NotFound, "not in manifest") });
+                return;
+            }
+        };
+        match entry.size {
+            Some(listed) if listed != *size => {
+                report.record(Finding::SizeMismatch { src, tgt, src_size: listed, tgt_size: *size });
+                report.not_covered("size differs from manifest", Some(listed));
+                return;
+            }
+            _ => {}
+        }
+        match File::open(&tgt).and_then(|f| hash::hash_file(spec, &f)) {
+            Ok(digests) if digests == entry.digests => {
+                report.covered(*size, [None, None]);
+                report.verified(&src, &tgt, &digests);
+            }
+            Ok(digests) => {
+                report.covered(*size, [None, None]);
+                report.record(Finding::HashMismatch { src, src_hash: entry.digests.clone(), tgt, tgt_hash: digests, damage: None });
+            }
+            Err(reason) => {
+                report.record(Finding::MissingInTarget { src, tgt, reason });
+                report.not_covered("unreadable in target", Some(*size));
+            }
+        }
+    });
+
+    let present: HashSet<&str> = on_disk.iter().map(|(rel, _, _)| rel.as_str()).collect();
+    for (rel, entry) in &manifest.entries {
+        if unknown(rel) && !present.contains(rel.as_str()) {
+            report.not_covered("read error", entry.size);
+        } else if !present.contains(rel.as_str()) {
+            report.record(Finding::MissingInTarget {
+                src: src_path(rel),
+                tgt: tgt_path(rel),
+                reason: io::Error::new(io::ErrorKind::NotFound, "listed in manifest"),
+            });
+            report.not_covered("missing in target", entry.size);
+        }
+    }
+    Ok(VerifySummary { listed: manifest.entries.len() as u64, checked: on_disk.len() as u64 })
+}
+
+// What a three-way audit calls a pair whose content differs, by which side
+// still matches the manifest's last known good digests.
+pub const SOURCE_CHANGED: &str = "source_changed";
+pub const TARGET_CORRUPTED: &str = "target_corrupted";
+pub const BOTH_CHANGED: &str = "both_changed";
+
+// A manifest of the source taken when source and target were known to match,
+// the third side of a three-way audit. The audit hashes with the manifest's
+// algorithms so digests compare directly.
+pub struct Baseline {
+    source_dir: String,
+    manifest: Manifest,
+}
+
+impl Baseline {
+    pub fn new(manifest: Manifest, source_dir: &str) -> Baseline {
+        Baseline { source_dir: source_dir.to_string(), manifest }
+    }
+
+    // None for files the manifest doesn't list: those stay plain mismatches.
+    pub fn classify(&self, src_path: &str, src: &Digests, tgt: &Digests) -> Option<&'static str> {
+        let entry = self.manifest.entries.get(&relative_key(&self.source_dir, src_path)?)?;
+        match (entry.digests == *src, entry.digests == *tgt) {
+            (false, true) => Some(SOURCE_CHANGED),
+            (true, false) => Some(TARGET_CORRUPTED),
+            (false, false) => Some(BOTH_CHANGED),
+            (true, true) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("backup_auditor-manifest-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        for path in ["plain", "back\\slash", "new\nline", "both\\n\n", "trailing\\", ""] {
+            let escaped = escape(path);
+            assert!(!escaped.contains('\n'));
+            assert_eq!(unescape(&escaped), path);
+        }
+        assert_eq!(escape("a\\b\nc"), "a\\\\b\\nc");
+    }
+
+    #[test]
+    fn written_manifests_load() {
+        let dir = temp_dir("load");
+        let root = dir.join("tree");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a"), "abc").unwrap();
+        fs::write(root.join("sub/new\nline"), "").unwrap();
+        let spec = HashSpec {
+            algorithms: vec![Algorithm::Sha256],
+            key: None,
+            s3_part_size: hash::DEFAULT_S3_PART_SIZE,
+            io_control: None,
+            read_limit: None,
+            read_progress: None,
+            skip_holes: false,
+            fadvise: false,
+            command: None,
+            sample: None,
+            buffer_size: hash::DEFAULT_BUFFER_SIZE,
+        };
+        let output = dir.join("manifest.txt");
+        let summary = write(&root, &spec, &output, None).unwrap();
+        assert_eq!((summary.files, summary.bytes, summary.dirs), (2, 3, 2));
+
+        let manifest = load(&output).unwrap();
+        assert_eq!(manifest.root, root.display().to_string());
+        assert_eq!(manifest.algorithms.iter().map(|a| a.name()).collect::<Vec<_>>(), vec!["sha256"]);
+        assert_eq!(manifest.entries.keys().collect::<Vec<_>>(), vec!["a", "sub/new\nline"]);
+        let a = &manifest.entries["a"];
+        assert_eq!(a.size, Some(3));
+        assert_eq!(a.digests.values().collect::<Vec<_>>(), vec!["ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"]);
+        assert_eq!(manifest.dirs.keys().collect::<Vec<_>>(), vec!["", "sub"]);
+        assert!(manifest.signed.is_none() && manifest.key_id.is_none());
+
+        let text = fs::read_to_string(&output).unwrap();
+        fs::write(&output, text.replace("ba7816bf", "ba7816bf,00")).unwrap();
+        assert!(load(&output).err().is_some_and(|e| e.to_string().contains("wrong number of digests")));
+        fs::write(&output, "# something else\n").unwrap();
+        assert!(load(&output).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn checksum_lists() {
+        let dir = temp_dir("sums");
+        let list = dir.join("SHA256SUMS");
+        let sha = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        fs::write(&list, format!("# made by sha256sum\n\n{0}  ./a\n{1} *bin/b\n\\{0}  new\\nline\n", sha, sha.to_uppercase())).unwrap();
+        let manifest = load_checksums(&list, "/src").unwrap();
+        assert_eq!(manifest.root, "/src");
+        assert_eq!(manifest.algorithms.iter().map(|a| a.name()).collect::<Vec<_>>(), vec!["sha256"]);
+        assert_eq!(manifest.entries.keys().collect::<Vec<_>>(), vec!["a", "bin/b", "new\nline"]);
+        assert_eq!(manifest.entries["bin/b"].digests.values().collect::<Vec<_>>(), vec![sha]);
+        assert!(manifest.entries["a"].size.is_none());
+
+        for bad in [format!("{}  a\n{}  b\n", sha, &sha[..40]), format!("{}  /abs\n", sha), format!("{} a\n", sha), String::from("xyz  a\n")] {
+            fs::write(&list, bad).unwrap();
+            assert!(load_checksums(&list, "/src").is_err());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::report::headline_kind;
+
+struct MergedFinding {
+    kind: &'static str,
+    text: String,
+    reported_by: Vec<usize>,
+}
+
+#[derive(Default)]
+struct MergedCoverage {
+    files_verified: u64,
+    files_total: u64,
+    // reason -> (files, other entries)
+    not_verified: BTreeMap<String, (u64, u64)>,
+}
+
+impl MergedCoverage {
+    fn add_line(&mut self, line: &str) {
+        if let Some(counts) = line.strip_prefix("Files verified: ") {
+            let counts = counts.split(' ').next().unwrap_or_default();
+            if let Some((verified, total)) = counts.split_once('/') {
+                self.files_verified += verified.parse::<u64>().unwrap_or(0);
+                self.files_total += total.parse::<u64>().unwrap_or(0);
+            }
+            return;
+        }
+        let (reason, counts) = match line.strip_prefix("  ").and_then(|l| l.rsplit_once(": ")) {
+            Some(parts) => parts,
+            None => return,
+        };
+        let number_before = |suffix: &str| {
+            counts
+                .split(", ")
+                .find_map(|part| part.split_once(suffix).map(|(n, _)| n.trim().parse::<u64>().unwrap_or(0)))
+                .unwrap_or(0)
+        };
+        let entry = self.not_verified.entry(reason.to_string()).or_default();
+        entry.0 += number_before(" files");
+        entry.1 += number_before(" other entries");
+    }
+}
+
+pub struct MergeSummary {
+    pub inputs: usize,
+    pub findings: usize,
+    pub duplicates: u64,
+}
+
+// Reads findings and coverage back out of text reports. Evidence lines, the
+// custody header, summaries and seals belong to the individual runs and are
+// not carried over; each input stays the evidence for its own run.
+pub fn merge_reports(inputs: &[String], output: &Path) -> io::Result<MergeSummary> {
+    let mut findings: Vec<MergedFinding> = Vec::new();
+    let mut by_text: BTreeMap<String, usize> = BTreeMap::new();
+    let mut coverage = MergedCoverage::default();
+    let mut duplicates = 0;
+
+    for (input, path) in inputs.iter().enumerate() {
+        let bytes = fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))?;
+        let text = String::from_utf8_lossy(&bytes);
+
+        let mut current: Option<(&'static str, String)> = None;
+        let mut in_coverage = false;
+        let mut finish = |current: &mut Option<(&'static str, String)>| {
+            if let Some((kind, text)) = current.take() {
+                match by_text.get(&text) {
+                    Some(&i) => {
+                        duplicates += 1;
+                        if !findings[i].reported_by.contains(&input) {
+                            findings[i].reported_by.push(input);
+                        }
+                    }
+                    None => {
+                        by_text.insert(text.clone(), findings.len());
+                        findings.push(MergedFinding { kind, text, reported_by: vec![input] });
+                    }
+                }
+            }
+        };
+        for line in text.lines() {
+            if let Some(kind) = headline_kind(line) {
+                finish(&mut current);
+                in_coverage = false;
+                current = Some((kind, format!("{}\n", line)));
+            } else if line.starts_with("== ") || line.starts_with("Verified ") || line.starts_with("Report sha256: ") {
+                finish(&mut current);
+                in_coverage = line == "== Coverage ==";
+            } else if let Some((_, text)) = current.as_mut() {
+                text.push_str(line);
+                text.push('\n');
+            } else if in_coverage {
+                coverage.add_line(line);
+            }
+        }
+        finish(&mut current);
+    }
+
+    let mut out = String::from("== Merged report ==\n");
+    for path in inputs {
+        out.push_str(&format!("Input: {:?}\n", path));
+    }
+    out.push_str("== Findings ==\n");
+    let mut counts: BTreeMap<&'static str, u64> = BTreeMap::new();
+    for f in &findings {
+        *counts.entry(f.kind).or_insert(0) += 1;
+        out.push_str(&f.text);
+        let sources: Vec<String> = f.reported_by.iter().map(|&i| format!("{:?}", inputs[i])).collect();
+        out.push_str(&format!("Reported by: {}\n", sources.join(", ")));
+    }
+    out.push_str(&format!(
+        "== Summary ==\nInputs: {}\nFiles verified (summed over inputs): {}/{}\n",
+        inputs.len(),
+        coverage.files_verified,
+        coverage.files_total,
+    ));
+    if !coverage.not_verified.is_empty() {
+        out.push_str("Not verified:\n");
+    }
+    for (reason, (files, other)) in &coverage.not_verified {
+        out.push_str(&format!("  {}: {} files", reason, files));
+        if *other > 0 {
+            out.push_str(&format!(", {} other entries", other));
+        }
+        out.push('\n');
+    }
+    out.push_str(&format!("Findings: {} ({} duplicates dropped)\n", findings.len(), duplicates));
+    for (kind, count) in &counts {
+        out.push_str(&format!("  {}: {}\n", kind, count));
+    }
+    fs::write(output, out)?;
+
+    Ok(MergeSummary { inputs: inputs.len(), findings: findings.len(), duplicates })
+}
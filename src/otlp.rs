@@ -0,0 +1,161 @@
+use std::sync::Mutex;
+use std::time::SystemTime;
+use serde_json::{json, Value};
+use crate::report::RunStats;
+
+// Each run is one trace: a root "audit" span with a child per stage. Metrics
+// are cumulative sums over the run, so a long --watch run exporting after
+// every batch yields counters that only go up.
+pub struct Exporter {
+    endpoint: String,
+    mode: &'static str,
+    run_id: String,
+    trace_id: String,
+    run_span_id: String,
+    started: SystemTime,
+    stages: Mutex<Vec<Stage>>,
+}
+
+struct Stage {
+    name: &'static str,
+    started: SystemTime,
+    finished: SystemTime,
+}
+
+fn nanos(t: SystemTime) -> String {
+    t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_nanos().to_string()
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+// Trace and span IDs only need to be unique, not secret.
+fn random_id(len: usize, salt: &str) -> String {
+    let seed = format!("{}\0{}\0{}\0{:?}", std::process::id(), nanos(SystemTime::now()), salt, std::thread::current().id());
+    blake3::hash(seed.as_bytes()).to_hex()[..len].to_string()
+}
+
+impl Exporter {
+    pub fn new(endpoint: &str, mode: &'static str, run_id: &str) -> Exporter {
+        Exporter {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            mode,
+            run_id: run_id.to_string(),
+            trace_id: random_id(32, "trace"),
+            run_span_id: random_id(16, "run"),
+            started: SystemTime::now(),
+            stages: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn stage(&self, name: &'static str, started: SystemTime) {
+        self.stages.lock().unwrap().push(Stage { name, started, finished: SystemTime::now() });
+    }
+
+    fn resource(&self) -> Value {
+        let host = gethostname::gethostname();
+        json!({ "attributes": [
+            attribute("service.name", "backup_auditor"),
+            attribute("service.version", env!("CARGO_PKG_VERSION")),
+            attribute("host.name", &host.to_string_lossy()),
+            attribute("backup_auditor.run_id", &self.run_id),
+        ] })
+    }
+
+    fn scope() -> Value {
+        json!({ "name": "backup_auditor", "version": env!("CARGO_PKG_VERSION") })
+    }
+
+    // Finished spans since the last export, plus the run span itself once the
+    // run is over.
+    fn traces(&self, source_dir: &str, target_dir: &str, finished: Option<SystemTime>) -> Value {
+        let stages: Vec<Stage> = self.stages.lock().unwrap().drain(..).collect();
+        let mut spans: Vec<Value> = stages
+            .iter()
+            .map(|s| {
+                json!({
+                    "traceId": self.trace_id,
+                    "spanId": random_id(16, s.name),
+                    "parentSpanId": self.run_span_id,
+                    "name": s.name,
+                    "kind": 1,
+                    "startTimeUnixNano": nanos(s.started),
+                    "endTimeUnixNano": nanos(s.finished),
+                })
+            })
+            .collect();
+        if let Some(finished) = finished {
+            spans.push(json!({
+                "traceId": self.trace_id,
+                "spanId": self.run_span_id,
+                "name": "audit",
+                "kind": 1,
+                "startTimeUnixNano": nanos(self.started),
+                "endTimeUnixNano": nanos(finished),
+                "attributes": [
+                    attribute("backup_auditor.mode", self.mode),
+                    attribute("backup_auditor.source", source_dir),
+                    attribute("backup_auditor.target", target_dir),
+                ],
+            }));
+        }
+        json!({ "resourceSpans": [{ "resource": self.resource(), "scopeSpans": [{ "scope": Exporter::scope(), "spans": spans }] }] })
+    }
+
+    fn metrics(&self, stats: &RunStats, now: SystemTime) -> Value {
+        let point = |value: u64, attributes: Vec<Value>| {
+            json!({
+                "asInt": value.to_string(),
+                "startTimeUnixNano": nanos(self.started),
+                "timeUnixNano": nanos(now),
+                "attributes": attributes,
+            })
+        };
+        let sum = |name: &str, unit: &str, points: Vec<Value>| {
+            json!({ "name": name, "unit": unit, "sum": { "aggregationTemporality": 2, "isMonotonic": true, "dataPoints": points } })
+        };
+        let findings = stats.findings.iter().map(|(kind, count)| point(*count, vec![attribute("kind", kind)])).collect();
+        let elapsed = now.duration_since(self.started).unwrap_or_default().as_secs_f64();
+        let metrics = vec![
+            sum("backup_auditor.files.verified", "{file}", vec![point(stats.files_verified, vec![])]),
+            sum("backup_auditor.bytes.verified", "By", vec![point(stats.bytes_verified, vec![])]),
+            sum("backup_auditor.files.not_verified", "{file}", vec![point(stats.files_not_verified, vec![])]),
+            sum("backup_auditor.findings", "{finding}", findings),
+            json!({ "name": "backup_auditor.run.duration", "unit": "s", "gauge": { "dataPoints": [{
+                "asDouble": elapsed,
+                "timeUnixNano": nanos(now),
+                "attributes": [attribute("backup_auditor.mode", self.mode)],
+            }] } }),
+        ];
+        json!({ "resourceMetrics": [{ "resource": self.resource(), "scopeMetrics": [{ "scope": Exporter::scope(), "metrics": metrics }] }] })
+    }
+
+    // OTLP/HTTP with JSON encoding, which every collector accepts on :4318.
+    // `finished` closes the run span; leave it None for interim exports.
+    pub fn export(&self, stats: &RunStats, source_dir: &str, target_dir: &str, finished: bool) -> Result<(), String> {
+        let now = SystemTime::now();
+        let traces = post(&self.endpoint, "/v1/traces", self.traces(source_dir, target_dir, finished.then_some(now)));
+        let metrics = post(&self.endpoint, "/v1/metrics", self.metrics(stats, now));
+        traces.and(metrics)
+    }
+}
+
+#[cfg(feature = "network")]
+fn post(endpoint: &str, path: &str, body: Value) -> Result<(), String> {
+    ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_secs(10))
+        .build()
+        .post(&format!("{}{}", endpoint, path))
+        .set("User-Agent", concat!("backup_auditor/", env!("CARGO_PKG_VERSION")))
+        .send_json(body)
+        .map(|_| ())
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+// The payloads are still built, so the exporter can be set up either way;
+// the CLI refuses --otlp-endpoint before it gets here.
+#[cfg(not(feature = "network"))]
+fn post(_endpoint: &str, path: &str, _body: Value) -> Result<(), String> {
+    Err(format!("{}: built without the \"network\" feature", path))
+}
@@ -0,0 +1,168 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+use indicatif::{HumanBytes, HumanDuration};
+use crate::report::Report;
+
+pub struct Progress {
+    pub total_files: u64,
+    pub total_bytes: u64,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    // bytes hashed so far on both sides, counted as they are read
+    bytes_read: Option<Arc<AtomicU64>>,
+    done: AtomicBool,
+    started: Instant,
+}
+
+impl Progress {
+    pub fn new(total_files: u64, total_bytes: u64, bytes_read: Option<Arc<AtomicU64>>) -> Progress {
+        Progress {
+            total_files,
+            total_bytes,
+            files_done: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+            bytes_read,
+            done: AtomicBool::new(false),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn files_done(&self) -> u64 {
+        self.files_done.load(Ordering::Relaxed)
+    }
+
+    // Whichever is further along: the bytes of the files finished, or half
+    // of those read so far (each file is read once per side), which include
+    // files still being hashed but not files finished without reading
+    // (--quick, unreadable, already hashed as another hard link).
+    pub fn bytes(&self) -> u64 {
+        let read = self.bytes_read.as_ref().map(|r| r.load(Ordering::Relaxed)).unwrap_or(0);
+        self.bytes_done.load(Ordering::Relaxed).max(read / 2)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::SeqCst)
+    }
+
+    pub fn file_done(&self, bytes: u64) {
+        self.files_done.fetch_add(1, Ordering::Relaxed);
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn finish(&self) {
+        self.done.store(true, Ordering::SeqCst);
+    }
+
+    // Bytes are the better measure of work left; trees of empty files fall
+    // back to the file count.
+    fn fraction(&self) -> f64 {
+        if self.total_bytes > 0 {
+            (self.bytes().min(self.total_bytes)) as f64 / self.total_bytes as f64
+        } else if self.total_files > 0 {
+            self.files_done.load(Ordering::Relaxed) as f64 / self.total_files as f64
+        } else {
+            1.0
+        }
+    }
+}
+
+// Hands out one of a fixed number of spinner bars to each file in flight.
+// Rayon may run a file's check on any thread, start another one on the same
+// thread while the first waits on nested work, or grow its pool, so bars are
+// claimed per file rather than per thread and released by the same file's
+// index. When all are taken the file simply goes without a bar.
+pub struct Slots {
+    busy: Vec<AtomicBool>,
+}
+
+impl Slots {
+    pub fn new(count: usize) -> Slots {
+        Slots { busy: (0..count).map(|_| AtomicBool::new(false)).collect() }
+    }
+
+    pub fn claim(&self) -> Option<usize> {
+        self.busy.iter().position(|b| b.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok())
+    }
+
+    pub fn release(&self, index: usize) {
+        self.busy[index].store(false, Ordering::Release);
+    }
+}
+
+// Rate limit for redrawing the bars: formatting the tally and sending a
+// redraw for every file costs CPU and, over SSH, bandwidth. Counters keep
+// counting in between; only what is shown lags by at most one interval.
+pub struct Refresh {
+    interval_ms: u64,
+    started: Instant,
+    next_ms: AtomicU64,
+}
+
+impl Refresh {
+    pub fn new(interval: Duration) -> Refresh {
+        Refresh { interval_ms: interval.as_millis() as u64, started: Instant::now(), next_ms: AtomicU64::new(0) }
+    }
+
+    // True for at most one caller per interval.
+    pub fn due(&self) -> bool {
+        let now = self.started.elapsed().as_millis() as u64;
+        let next = self.next_ms.load(Ordering::Relaxed);
+        now >= next && self.next_ms.compare_exchange(next, now + self.interval_ms, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    }
+}
+
+pub struct Milestones {
+    pub percent: u64,
+    pub interval: Duration,
+}
+
+// Plain log lines for non-interactive runs (cron, systemd, CI): one line every
+// `percent` of progress or every `interval`, whichever comes first.
+pub fn log_milestones(progress: Arc<Progress>, report: Arc<Report>, milestones: Milestones) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let step = milestones.percent.clamp(1, 100);
+        let mut next_percent = step;
+        let mut last_log = Instant::now();
+        while !progress.done.load(Ordering::SeqCst) {
+            thread::sleep(Duration::from_millis(250));
+            let percent = (progress.fraction() * 100.0) as u64;
+            if percent >= next_percent || last_log.elapsed() >= milestones.interval {
+                log_line(&progress, &report, false);
+                last_log = Instant::now();
+                while next_percent <= percent {
+                    next_percent += step;
+                }
+            }
+        }
+        log_line(&progress, &report, true);
+    })
+}
+
+fn log_line(progress: &Progress, report: &Report, finished: bool) {
+    let elapsed = progress.started.elapsed();
+    let bytes_done = progress.bytes().min(progress.total_bytes);
+    let rate = bytes_done as f64 / elapsed.as_secs_f64().max(0.001);
+    let fraction = progress.fraction();
+    let eta = if finished {
+        format!("finished in {}", HumanDuration(elapsed))
+    } else if rate > 0.0 {
+        let left = progress.total_bytes.saturating_sub(bytes_done) as f64 / rate;
+        format!("eta {}", HumanDuration(Duration::from_secs_f64(left)))
+    } else {
+        String::from("eta unknown")
+    };
+    println!(
+        "[{}] {:.0}% files {}/{} bytes {}/{} {}/s findings {} {}",
+        humantime::format_rfc3339_seconds(SystemTime::now()),
+        fraction * 100.0,
+        progress.files_done.load(Ordering::Relaxed),
+        progress.total_files,
+        HumanBytes(bytes_done),
+        HumanBytes(progress.total_bytes),
+        HumanBytes(rate as u64),
+        report.findings_count(),
+        eta,
+    );
+}
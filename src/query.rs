@@ -0,0 +1,165 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::Path;
+use indicatif::HumanBytes;
+use serde_json::Value;
+use crate::sqlite::{self, quote};
+
+// Which findings of a --format sqlite database the query subcommand looks at.
+#[derive(Default)]
+pub struct Filter {
+    // a run's ID, else the last run started
+    pub run: Option<String>,
+    // any of these kinds; all when empty
+    pub kinds: Vec<String>,
+    // at or below this path relative to the root
+    pub under: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+}
+
+pub struct Found {
+    pub kind: String,
+    pub side: String,
+    pub rel: String,
+    pub size: Option<u64>,
+}
+
+// How --by groups findings.
+#[derive(Clone, Copy)]
+pub enum By {
+    Kind,
+    // the first N components of the directory a finding is in
+    Directory(usize),
+    Size,
+}
+
+// Upper bounds of the size ranges --by size counts findings in.
+const SIZE_RANGES: [u64; 4] = [1 << 10, 1 << 20, 100 << 20, 1 << 30];
+
+#[derive(Default)]
+pub struct Group {
+    pub findings: u64,
+    // of the findings whose size is known
+    pub bytes: u64,
+}
+
+pub fn runs(db: &Path) -> io::Result<Vec<Value>> {
+    sqlite::schema_version(db)?;
+    sqlite::query(
+        db,
+        "SELECT runs.*, (SELECT count(*) FROM findings WHERE findings.run_id = runs.run_id) AS findings FROM runs ORDER BY started, rowid",
+    )
+}
+
+// The run `filter` picks and its findings that match it, ordered by path.
+pub fn findings(db: &Path, filter: &Filter) -> io::Result<(String, Vec<Found>)> {
+    if sqlite::schema_version(db)?.is_none() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} holds no runs", db)));
+    }
+    let run = match &filter.run {
+        Some(run) => run.clone(),
+        None => sqlite::query(db, "SELECT run_id FROM runs ORDER BY started DESC, rowid DESC LIMIT 1")?
+            .first()
+            .and_then(|r| r["run_id"].as_str().map(String::from))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{:?} holds no runs", db)))?,
+    };
+    if sqlite::query(db, &format!("SELECT run_id FROM runs WHERE run_id = {}", quote(&run)))?.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{:?} holds no run {}", db, run)));
+    }
+    let mut conditions = vec![format!("run_id = {}", quote(&run))];
+    if !filter.kinds.is_empty() {
+        conditions.push(format!("kind IN ({})", filter.kinds.iter().map(|k| quote(k)).collect::<Vec<_>>().join(", ")));
+    }
+    if let Some(dir) = filter.under.as_deref().map(|d| d.trim_matches('/')).filter(|d| !d.is_empty()) {
+        // not LIKE, which would take _ and % in names for wildcards
+        conditions.push(format!("(rel = {0} OR substr(rel, 1, {1}) = {2})", quote(dir), dir.len() + 1, quote(&format!("{}/", dir))));
+    }
+    if let Some(min) = filter.min_size {
+        conditions.push(format!("size >= {}", min));
+    }
+    if let Some(max) = filter.max_size {
+        conditions.push(format!("size <= {}", max));
+    }
+    let rows = sqlite::query(db, &format!("SELECT kind, side, rel, size FROM findings WHERE {} ORDER BY rel, kind", conditions.join(" AND ")))?;
+    let found = rows
+        .iter()
+        .map(|r| Found {
+            kind: r["kind"].as_str().unwrap_or_default().to_string(),
+            side: r["side"].as_str().unwrap_or_default().to_string(),
+            rel: r["rel"].as_str().unwrap_or_default().to_string(),
+            size: r["size"].as_u64(),
+        })
+        .collect();
+    Ok((run, found))
+}
+
+fn size_range(size: Option<u64>) -> String {
+    let size = match size {
+        Some(s) => s,
+        None => return String::from("unknown size"),
+    };
+    match SIZE_RANGES.iter().position(|bound| size < *bound) {
+        Some(0) => format!("under {}", HumanBytes(SIZE_RANGES[0])),
+        Some(n) => format!("{} to {}", HumanBytes(SIZE_RANGES[n - 1]), HumanBytes(SIZE_RANGES[n])),
+        None => format!("{} and over", HumanBytes(SIZE_RANGES[SIZE_RANGES.len() - 1])),
+    }
+}
+
+// Findings and their bytes per group, ordered by group; size ranges from the
+// smallest up, sizes unknown last.
+pub fn summarize(found: &[Found], by: By) -> Vec<(String, Group)> {
+    let mut groups: BTreeMap<(usize, String), Group> = BTreeMap::new();
+    for f in found {
+        let key = match by {
+            By::Kind => (0, f.kind.clone()),
+            By::Directory(depth) => {
+                let parts: Vec<&str> = f.rel.split('/').collect();
+                let dir = parts[..(parts.len() - 1).min(depth)].join("/");
+                (0, if dir.is_empty() { String::from(".") } else { dir })
+            }
+            By::Size => (f.size.map(|s| SIZE_RANGES.iter().filter(|bound| s >= **bound).count()).unwrap_or(SIZE_RANGES.len() + 1), size_range(f.size)),
+        };
+        let group = groups.entry(key).or_default();
+        group.findings += 1;
+        group.bytes += f.size.unwrap_or(0);
+    }
+    groups.into_iter().map(|((_, name), group)| (name, group)).collect()
+}
+
+// The source paths among `found`, once each, as rsync's --files-from takes
+// them: relative to the source root. Findings about the target alone have
+// nothing in the source to copy and are left out.
+pub fn files_from(found: &[Found]) -> String {
+    let paths: BTreeSet<&str> = found.iter().filter(|f| f.side == "src" && !f.rel.is_empty()).map(|f| f.rel.as_str()).collect();
+    paths.into_iter().map(|p| format!("{}\n", p)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn found(kind: &str, side: &str, rel: &str, size: Option<u64>) -> Found {
+        Found { kind: kind.to_string(), side: side.to_string(), rel: rel.to_string(), size }
+    }
+
+    #[test]
+    fn groups() {
+        let found = [
+            found("hash_mismatch", "src", "a/b/c", Some(5 << 20)),
+            found("missing_in_target", "src", "a/x", Some(10)),
+            found("missing_in_target", "src", "top", None),
+            found("missing_in_source", "tgt", "a/b/d", Some(2 << 30)),
+        ];
+        let names = |by| summarize(&found, by).into_iter().map(|(name, g)| (name, g.findings, g.bytes)).collect::<Vec<_>>();
+        assert_eq!(names(By::Kind)[1], (String::from("missing_in_source"), 1, 2 << 30));
+        assert_eq!(
+            names(By::Directory(1)),
+            vec![(String::from("."), 1, 0), (String::from("a"), 3, (5 << 20) + 10 + (2 << 30))]
+        );
+        assert_eq!(names(By::Directory(2))[2], (String::from("a/b"), 2, (5 << 20) + (2 << 30)));
+        let sizes: Vec<String> = names(By::Size).into_iter().map(|(name, ..)| name).collect();
+        assert_eq!(sizes, vec!["under 1.00KiB", "1.00MiB to 100.00MiB", "1.00GiB and over", "unknown size"]);
+        assert_eq!(files_from(&found), "a/b/c\na/x\ntop\n");
+    }
+}
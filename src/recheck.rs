@@ -0,0 +1,62 @@
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use serde_json::Value;
+use crate::ack::relative_key;
+use crate::report;
+
+// What a previous run's report (--format json) left to look at again once its
+// findings have been seen to.
+pub struct Recheck {
+    // relative to the roots, each once
+    pub paths: Vec<PathBuf>,
+    pub findings: u64,
+    // about paths under neither root: a report of some other pair of trees
+    pub elsewhere: u64,
+    // gone from both sides since, e.g. a target-only entry deleted
+    pub resolved: u64,
+}
+
+fn gone(path: &Path) -> bool {
+    matches!(fs::symlink_metadata(path), Err(e) if e.kind() == io::ErrorKind::NotFound)
+}
+
+// The entries the report has findings about, informational ones aside. Each
+// finding names its entry by a source or target path, which is taken
+// relative to the matching root given now.
+pub fn load(report_file: &Path, source_dir: &str, target_dir: &str) -> io::Result<Recheck> {
+    let mut paths = BTreeSet::new();
+    let mut recheck = Recheck { paths: Vec::new(), findings: 0, elsewhere: 0, resolved: 0 };
+    for (i, line) in BufReader::new(File::open(report_file)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object: Value = serde_json::from_str(&line).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: not JSON; only reports written with --format json can be rechecked", i + 1))
+        })?;
+        if object["type"] != "finding" || object["kind"].as_str().map(report::is_informational).unwrap_or(true) {
+            continue;
+        }
+        recheck.findings += 1;
+        let root = match object["side"].as_str() {
+            Some("tgt") => target_dir,
+            _ => source_dir,
+        };
+        match object["path"].as_str().and_then(|path| relative_key(root, path)).filter(|rel| !rel.is_empty()) {
+            Some(rel) => {
+                paths.insert(PathBuf::from(rel));
+            }
+            None => recheck.elsewhere += 1,
+        }
+    }
+    for rel in paths {
+        if gone(&Path::new(source_dir).join(&rel)) && gone(&Path::new(target_dir).join(&rel)) {
+            recheck.resolved += 1;
+        } else {
+            recheck.paths.push(rel);
+        }
+    }
+    Ok(recheck)
+}
@@ -0,0 +1,57 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+use crate::hash::Algorithm;
+use crate::index::EntryKind;
+
+// One entry of a remote target's listing.
+pub struct Object {
+    // relative to the URL the target was given as
+    pub key: String,
+    pub size: u64,
+    // as the store reports it, quotes stripped
+    pub etag: Option<String>,
+    pub kind: EntryKind,
+}
+
+// A target that isn't a local directory but a URL, audited from a listing of
+// it and, for a full comparison, by streaming content from it. Paths handed
+// to `open` are target paths as the walk builds them: the URL joined with the
+// path relative to the source root.
+pub trait Remote: Send + Sync {
+    fn list(&self) -> io::Result<Vec<Object>>;
+    fn open(&self, tgt_path: &str) -> io::Result<Box<dyn Read + Send>>;
+
+    // A digest of the whole object the store keeps itself, in hex, for a
+    // comparison that doesn't download it. None when there is none the
+    // source can be hashed to match.
+    fn checksum(&self, _tgt_path: &str) -> io::Result<Option<(Algorithm, String)>> {
+        Ok(None)
+    }
+}
+
+pub struct Options {
+    // for a store that isn't where an s3:// URL says by default, e.g. MinIO
+    pub s3_endpoint: Option<String>,
+    pub s3_region: Option<String>,
+    // the ssh client and its options, split at whitespace
+    pub ssh_command: String,
+    // connections to keep open at most, one per file read at once
+    pub connections: usize,
+}
+
+// The scheme of a target given as a URL, None for a local path.
+pub fn scheme(target: &str) -> Option<&str> {
+    target.split_once("://").map(|(scheme, _)| scheme).filter(|s| !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+pub fn connect(url: &str, options: &Options) -> Result<Arc<dyn Remote>, String> {
+    match scheme(url) {
+        #[cfg(feature = "network")]
+        Some("s3") => Ok(Arc::new(crate::s3::S3::new(url, options)?)),
+        #[cfg(not(feature = "network"))]
+        Some("s3") => Err(String::from("s3:// targets need a build with the \"network\" feature")),
+        Some("sftp") => Ok(Arc::new(crate::sftp::Sftp::new(url, options)?)),
+        Some(other) => Err(format!("{}:// targets are not supported; s3:// and sftp:// are", other)),
+        None => Err(format!("{:?} is not a URL", url)),
+    }
+}
@@ -0,0 +1,123 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use crate::report::Finding;
+
+// What it takes to bring one target entry back in line with the source,
+// planned from a finding once the audit is done. Only Replace and Delete
+// remove anything from the target.
+pub enum Action {
+    // nothing (readable) at the target path
+    Copy { src: String, tgt: String },
+    // same type of entry, different content
+    Overwrite { src: String, tgt: String },
+    // a different type of entry at the target path
+    Replace { src: String, tgt: String },
+    // only in the target
+    Delete { tgt: String },
+}
+
+impl Action {
+    // Findings that are acknowledged, expected or informational plan nothing,
+    // nor do differences only in metadata.
+    pub fn for_finding(finding: &Finding) -> Option<Action> {
+        match finding {
+            Finding::MissingInTarget { src, tgt, .. } => Some(Action::Copy { src: src.clone(), tgt: tgt.clone() }),
+            Finding::HashMismatch { src, tgt, .. } | Finding::BaselineMismatch { src, tgt, .. } | Finding::SizeMismatch { src, tgt, .. } => {
+                Some(Action::Overwrite { src: src.clone(), tgt: tgt.clone() })
+            }
+            Finding::TypeMismatch { src, tgt } => Some(Action::Replace { src: src.clone(), tgt: tgt.clone() }),
+            Finding::MissingInSource { tgt, reason, .. } if reason.kind() == io::ErrorKind::NotFound => Some(Action::Delete { tgt: tgt.clone() }),
+            _ => None,
+        }
+    }
+
+    pub fn target(&self) -> &str {
+        match self {
+            Action::Copy { tgt, .. } | Action::Overwrite { tgt, .. } | Action::Replace { tgt, .. } | Action::Delete { tgt } => tgt,
+        }
+    }
+
+    pub fn deletes(&self) -> bool {
+        matches!(self, Action::Replace { .. } | Action::Delete { .. })
+    }
+
+    pub fn apply(&self) -> io::Result<()> {
+        match self {
+            Action::Copy { src, tgt } | Action::Overwrite { src, tgt } => copy_entry(Path::new(src), Path::new(tgt)),
+            Action::Replace { src, tgt } => {
+                remove_entry(Path::new(tgt))?;
+                copy_entry(Path::new(src), Path::new(tgt))
+            }
+            Action::Delete { tgt } => remove_entry(Path::new(tgt)),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Copy { src, tgt } => write!(f, "copy {:?} to {:?}", src, tgt),
+            Action::Overwrite { src, tgt } => write!(f, "overwrite {:?} with {:?}", tgt, src),
+            Action::Replace { src, tgt } => write!(f, "delete {:?} and copy {:?} in its place", tgt, src),
+            Action::Delete { tgt } => write!(f, "delete {:?}", tgt),
+        }
+    }
+}
+
+// Files are copied next to the target and renamed over it, so an interrupted
+// repair never leaves a half-written file under the real name. Directories
+// are created empty: the files in them have findings of their own.
+fn copy_entry(src: &Path, tgt: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(src)?;
+    if let Some(parent) = tgt.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if meta.is_dir() {
+        fs::create_dir_all(tgt)?;
+        return fs::set_permissions(tgt, meta.permissions());
+    }
+    if meta.file_type().is_symlink() {
+        return copy_link(src, tgt);
+    }
+    if !meta.is_file() {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "special files are not copied"));
+    }
+    let name = tgt.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "target has no file name"))?;
+    let partial = tgt.with_file_name(format!(".{}.backup_auditor-repair", name.to_string_lossy()));
+    let copied = fs::copy(src, &partial).and_then(|_| {
+        let out = File::options().write(true).open(&partial)?;
+        out.set_modified(meta.modified()?)?;
+        out.sync_all()
+    });
+    match copied.and_then(|_| fs::rename(&partial, tgt)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(unix)]
+fn copy_link(src: &Path, tgt: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(tgt).is_ok() {
+        fs::remove_file(tgt)?;
+    }
+    std::os::unix::fs::symlink(fs::read_link(src)?, tgt)
+}
+
+#[cfg(not(unix))]
+fn copy_link(_src: &Path, _tgt: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "symlinks are only copied on unix"))
+}
+
+fn remove_entry(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(m) if m.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,1536 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use hmac::{Hmac, Mac};
+use indicatif::HumanBytes;
+use serde_json::{json, Map, Value};
+use sha2::{Sha256, Digest};
+use crate::ack::{self, Acks};
+use crate::damage::Damage;
+use crate::hash::Digests;
+use crate::history::{self, History};
+use crate::manifest;
+use crate::scratch::{Scratch, Spill};
+use crate::signing::{PublicKey, SecretKey};
+use crate::sqlite;
+
+pub enum Finding {
+    MissingInTarget { src: String, tgt: String, reason: io::Error },
+    MissingInSource { src: String, tgt: String, reason: io::Error },
+    MissingInBoth { src: String, tgt: String, src_reason: io::Error, tgt_reason: io::Error },
+    // `damage` when asked to analyze mismatches of files of the same size
+    HashMismatch { src: String, src_hash: Digests, tgt: String, tgt_hash: Digests, damage: Option<Damage> },
+    // a hash mismatch told apart by a baseline manifest; `verdict` is its kind
+    BaselineMismatch { src: String, src_hash: Digests, tgt: String, tgt_hash: Digests, damage: Option<Damage>, verdict: &'static str },
+    TypeMismatch { src: String, tgt: String },
+    MetadataMismatch { src: String, tgt: String, field: &'static str, src_value: String, tgt_value: String },
+    PathTooLong { side: &'static str, path: String, reason: io::Error },
+    Synthetic { index: u64, count: u64, src: String, tgt: String },
+    BudgetExceeded { src: String, tgt: String, reason: String },
+    Skipped { src: String, reason: String },
+    ExpectedDifference { src: String, tgt: String, pattern: String },
+    RuleViolation { src: String, tgt: String, rule: String, detail: String },
+    SizeMismatch { src: String, tgt: String, src_size: u64, tgt_size: u64 },
+    Acknowledged { kind: &'static str, path: String, note: String },
+    // raised in `seen` of the last `runs` runs in the history, and not in
+    // some run after one that raised it
+    Flaky { kind: &'static str, side: &'static str, path: String, seen: usize, runs: usize },
+    LowFreeSpace { tgt: String, free: String, threshold: String },
+    EntryCountMismatch { src: String, tgt: String, src_count: u64, tgt_count: u64 },
+    // `check` names what the backup job's log claims that the audit contradicts
+    BackupLogMismatch { tgt: String, log: String, check: &'static str, detail: String },
+    // `other` is the source path first seen with the inode; `check` is "split"
+    // for source links copied apart, "merged" for target links the source lacks
+    HardLinkMismatch { src: String, tgt: String, other: String, check: &'static str },
+    // informational, next to the hash mismatch of a text file stored
+    // differently in the target; `aspect` is encoding, bom or line_endings
+    EncodingDifference { src: String, tgt: String, aspect: &'static str, src_value: String, tgt_value: String },
+    // a read that failed partway, after the entry was found; `tgt` is empty
+    // when the source walk failed before a pair was formed
+    Error { src: String, tgt: String, operation: &'static str, reason: io::Error },
+}
+
+impl Finding {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Finding::MissingInTarget { .. } => "missing_in_target",
+            Finding::MissingInSource { .. } => "missing_in_source",
+            Finding::MissingInBoth { .. } => "missing_in_both",
+            Finding::HashMismatch { .. } => "hash_mismatch",
+            Finding::BaselineMismatch { verdict, .. } => verdict,
+            Finding::TypeMismatch { .. } => "type_mismatch",
+            Finding::MetadataMismatch { .. } => "metadata_mismatch",
+            Finding::PathTooLong { .. } => "path_too_long",
+            Finding::Synthetic { .. } => "synthetic",
+            Finding::BudgetExceeded { .. } => "budget_exceeded",
+            Finding::Skipped { .. } => "skipped",
+            Finding::ExpectedDifference { .. } => "expected_difference",
+            Finding::RuleViolation { .. } => "rule_violation",
+            Finding::SizeMismatch { .. } => "size_mismatch",
+            Finding::Acknowledged { .. } => "acknowledged",
+            Finding::Flaky { .. } => "flaky",
+            Finding::LowFreeSpace { .. } => "low_free_space",
+            Finding::EntryCountMismatch { .. } => "entry_count_mismatch",
+            Finding::BackupLogMismatch { .. } => "backup_log_mismatch",
+            Finding::HardLinkMismatch { .. } => "hard_link_mismatch",
+            Finding::EncodingDifference { .. } => "encoding_difference",
+            Finding::Error { .. } => "error",
+        }
+    }
+}
+
+const KINDS: &[&str] = &[
+    "missing_in_target",
+    "missing_in_source",
+    "missing_in_both",
+    "hash_mismatch",
+    "type_mismatch",
+    "metadata_mismatch",
+    "path_too_long",
+    "synthetic",
+    "budget_exceeded",
+    "skipped",
+    "expected_difference",
+    "rule_violation",
+    "size_mismatch",
+    "acknowledged",
+    "flaky",
+    "low_free_space",
+    "entry_count_mismatch",
+    "backup_log_mismatch",
+    "error",
+    "source_changed",
+    "target_corrupted",
+    "both_changed",
+    "hard_link_mismatch",
+    "encoding_difference",
+];
+
+// Findings that don't mean the target differs or couldn't be read.
+pub fn is_informational(kind: &str) -> bool {
+    matches!(kind, "skipped" | "expected_difference" | "acknowledged" | "flaky" | "low_free_space" | "encoding_difference")
+}
+
+pub fn is_kind(name: &str) -> bool {
+    KINDS.contains(&name)
+}
+
+impl Finding {
+    // The entry a finding is about, and which side's path names it.
+    pub fn subject(&self) -> (&'static str, &str) {
+        match self {
+            Finding::MissingInTarget { src, .. }
+            | Finding::MissingInSource { src, .. }
+            | Finding::MissingInBoth { src, .. }
+            | Finding::HashMismatch { src, .. }
+            | Finding::BaselineMismatch { src, .. }
+            | Finding::TypeMismatch { src, .. }
+            | Finding::MetadataMismatch { src, .. }
+            | Finding::Synthetic { src, .. }
+            | Finding::BudgetExceeded { src, .. }
+            | Finding::Skipped { src, .. }
+            | Finding::ExpectedDifference { src, .. }
+            | Finding::RuleViolation { src, .. }
+            | Finding::SizeMismatch { src, .. }
+            | Finding::EntryCountMismatch { src, .. }
+            | Finding::Error { src, .. }
+            | Finding::HardLinkMismatch { src, .. }
+            | Finding::EncodingDifference { src, .. } => ("src", src),
+            Finding::PathTooLong { side, path, .. } => (side, path),
+            Finding::LowFreeSpace { tgt, .. } | Finding::BackupLogMismatch { tgt, .. } => ("tgt", tgt),
+            Finding::Acknowledged { path, .. } => ("src", path),
+            Finding::Flaky { side, path, .. } => (side, path),
+        }
+    }
+
+    // What tells apart findings of one kind about the same path. Values that
+    // change from run to run (digests, sizes, error text) are left out so an
+    // unchanged discrepancy keeps its ID.
+    fn detail(&self) -> &str {
+        match self {
+            Finding::MetadataMismatch { field, .. } => field,
+            Finding::EncodingDifference { aspect, .. } => aspect,
+            Finding::PathTooLong { side, .. } => side,
+            Finding::Skipped { reason, .. } => reason,
+            Finding::ExpectedDifference { pattern, .. } => pattern,
+            Finding::RuleViolation { rule, .. } => rule,
+            Finding::BackupLogMismatch { check, .. } | Finding::HardLinkMismatch { check, .. } => check,
+            Finding::Error { operation, .. } => operation,
+            _ => "",
+        }
+    }
+
+    // The values a template can refer to as {name}.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("kind", self.kind().to_string())];
+        match self {
+            Finding::MissingInTarget { src, tgt, reason }
+            | Finding::MissingInSource { src, tgt, reason } => {
+                fields.extend([("src", src.clone()), ("tgt", tgt.clone()), ("reason", reason.to_string())]);
+            }
+            Finding::MissingInBoth { src, tgt, src_reason, tgt_reason } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("src_reason", src_reason.to_string()),
+                ("tgt_reason", tgt_reason.to_string()),
+            ]),
+            Finding::HashMismatch { src, src_hash, tgt, tgt_hash, damage } | Finding::BaselineMismatch { src, src_hash, tgt, tgt_hash, damage, .. } => {
+                fields.extend([
+                    ("src", src.clone()),
+                    ("tgt", tgt.clone()),
+                    ("algorithms", src_hash.names()),
+                    ("src_hash", src_hash.to_string()),
+                    ("tgt_hash", tgt_hash.to_string()),
+                ]);
+                fields.extend(damage.as_ref().map(|d| ("damage", d.to_string())));
+            }
+            Finding::TypeMismatch { src, tgt } => fields.extend([("src", src.clone()), ("tgt", tgt.clone())]),
+            Finding::SizeMismatch { src, tgt, src_size, tgt_size } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("src_size", src_size.to_string()),
+                ("tgt_size", tgt_size.to_string()),
+            ]),
+            Finding::EntryCountMismatch { src, tgt, src_count, tgt_count } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("src_count", src_count.to_string()),
+                ("tgt_count", tgt_count.to_string()),
+            ]),
+            Finding::MetadataMismatch { src, tgt, field, src_value, tgt_value } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("field", field.to_string()),
+                ("src_value", src_value.clone()),
+                ("tgt_value", tgt_value.clone()),
+            ]),
+            Finding::PathTooLong { side, path, reason } => {
+                fields.extend([("side", side.to_string()), ("path", path.clone()), ("reason", reason.to_string())]);
+            }
+            Finding::Synthetic { index, count, src, tgt } => fields.extend([
+                ("index", index.to_string()),
+                ("count", count.to_string()),
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+            ]),
+            Finding::BudgetExceeded { src, tgt, reason } => {
+                fields.extend([("src", src.clone()), ("tgt", tgt.clone()), ("reason", reason.clone())]);
+            }
+            Finding::Skipped { src, reason } => fields.extend([("src", src.clone()), ("reason", reason.clone())]),
+            Finding::ExpectedDifference { src, tgt, pattern } => {
+                fields.extend([("src", src.clone()), ("tgt", tgt.clone()), ("pattern", pattern.clone())]);
+            }
+            Finding::RuleViolation { src, tgt, rule, detail } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("rule", rule.clone()),
+                ("reason", detail.clone()),
+            ]),
+            Finding::LowFreeSpace { tgt, free, threshold } => {
+                fields.extend([("tgt", tgt.clone()), ("free", free.clone()), ("threshold", threshold.clone())]);
+            }
+            Finding::Error { src, tgt, operation, reason } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("operation", operation.to_string()),
+                ("reason", reason.to_string()),
+            ]),
+            Finding::EncodingDifference { src, tgt, aspect, src_value, tgt_value } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("aspect", aspect.to_string()),
+                ("src_value", src_value.clone()),
+                ("tgt_value", tgt_value.clone()),
+            ]),
+            Finding::HardLinkMismatch { src, tgt, other, check } => fields.extend([
+                ("src", src.clone()),
+                ("tgt", tgt.clone()),
+                ("other", other.clone()),
+                ("check", check.to_string()),
+            ]),
+            Finding::BackupLogMismatch { tgt, log, check, detail } => fields.extend([
+                ("tgt", tgt.clone()),
+                ("log", log.clone()),
+                ("check", check.to_string()),
+                ("reason", detail.clone()),
+            ]),
+            Finding::Acknowledged { kind, path, note } => {
+                fields.extend([("acknowledged_kind", kind.to_string()), ("src", path.clone()), ("note", note.clone())]);
+            }
+            Finding::Flaky { kind, side, path, seen, runs } => fields.extend([
+                ("flaky_kind", kind.to_string()),
+                (side, path.clone()),
+                ("seen", seen.to_string()),
+                ("runs", runs.to_string()),
+            ]),
+        }
+        fields
+    }
+}
+
+impl Finding {
+    // One JSON object per finding: the template fields, with digests, sizes and
+    // counts as structured values rather than their text form.
+    fn to_json(&self, id: &str, run_id: &str) -> Value {
+        let (side, path) = self.subject();
+        let mut object = Map::new();
+        object.insert("type".into(), json!("finding"));
+        object.insert("id".into(), json!(id));
+        object.insert("run_id".into(), json!(run_id));
+        object.insert("path".into(), json!(path));
+        object.insert("side".into(), json!(side));
+        for (name, value) in self.fields() {
+            object.insert(name.into(), json!(value));
+        }
+        let digests = |d: &Digests| Value::Object(d.pairs().map(|(name, hex)| (name.to_string(), json!(hex))).collect());
+        match self {
+            Finding::HashMismatch { src_hash, tgt_hash, damage, .. } | Finding::BaselineMismatch { src_hash, tgt_hash, damage, .. } => {
+                object.remove("algorithms");
+                object.insert("src_hash".into(), digests(src_hash));
+                object.insert("tgt_hash".into(), digests(tgt_hash));
+                if let Some(d) = damage {
+                    object.insert("damage".into(), d.to_json());
+                }
+            }
+            Finding::SizeMismatch { src_size, tgt_size, .. } => {
+                object.insert("src_size".into(), json!(src_size));
+                object.insert("tgt_size".into(), json!(tgt_size));
+            }
+            Finding::EntryCountMismatch { src_count, tgt_count, .. } => {
+                object.insert("src_count".into(), json!(src_count));
+                object.insert("tgt_count".into(), json!(tgt_count));
+            }
+            Finding::Synthetic { index, count, .. } => {
+                object.insert("index".into(), json!(index));
+                object.insert("count".into(), json!(count));
+            }
+            _ => {}
+        }
+        Value::Object(object)
+    }
+
+    // One row under CSV_HEADER. Whatever doesn't have a column of its own goes
+    // into `detail` as name=value pairs.
+    fn to_csv(&self, id: &str) -> String {
+        let fields = self.fields();
+        let field = |name: &str| fields.iter().find(|(n, _)| *n == name).map(|(_, v)| v.as_str()).unwrap_or("");
+        let (src, tgt) = match self {
+            Finding::PathTooLong { side: "tgt", path, .. } => ("", path.as_str()),
+            Finding::PathTooLong { path, .. } => (path.as_str(), ""),
+            _ => (field("src"), field("tgt")),
+        };
+        let columns = ["kind", "src", "tgt", "src_hash", "tgt_hash", "src_size", "tgt_size", "algorithms", "side", "path"];
+        let detail: Vec<String> = fields.iter().filter(|(n, _)| !columns.contains(n)).map(|(n, v)| format!("{}={}", n, v)).collect();
+        let row = [id, self.kind(), src, tgt, field("src_hash"), field("tgt_hash"), field("src_size"), field("tgt_size"), &detail.join("; ")];
+        let mut line = row.iter().map(|v| csv_escape(v)).collect::<Vec<_>>().join(",");
+        line.push('\n');
+        line
+    }
+}
+
+impl Finding {
+    // A row under ID, source, target and detail, the detail as in to_csv().
+    fn to_html_row(&self, id: &str) -> String {
+        let fields = self.fields();
+        let field = |name: &str| fields.iter().find(|(n, _)| *n == name).map(|(_, v)| v.as_str()).unwrap_or("");
+        let (src, tgt) = match self {
+            Finding::PathTooLong { side: "tgt", path, .. } => ("", path.as_str()),
+            Finding::PathTooLong { path, .. } => (path.as_str(), ""),
+            _ => (field("src"), field("tgt")),
+        };
+        let columns = ["kind", "src", "tgt", "side", "path"];
+        let detail: Vec<String> = fields.iter().filter(|(n, _)| !columns.contains(n)).map(|(n, v)| format!("{}={}", n, v)).collect();
+        format!("<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n", html_escape(id), html_escape(src), html_escape(tgt), html_escape(&detail.join("; ")))
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+const HTML_STYLE: &str = "body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;width:100%}\
+th,td{border:1px solid #ccc;padding:4px 8px;text-align:left;vertical-align:top;word-break:break-all}\
+th{background:#eee}thead th{cursor:pointer}summary{font-weight:bold;margin:1em 0 .5em;cursor:pointer}pre{background:#f6f6f6;padding:1em}";
+
+// A click on a column header sorts the table by it, a second one reverses.
+const HTML_SCRIPT: &str = "document.querySelectorAll('thead th').forEach(function(th){th.onclick=function(){\
+var b=th.closest('table').tBodies[0],i=th.cellIndex,d=th.dataset.desc=th.dataset.desc?'':'1';\
+Array.from(b.rows).sort(function(x,y){var c=x.cells[i].textContent.localeCompare(y.cells[i].textContent,undefined,{numeric:true});return d?-c:c;})\
+.forEach(function(r){b.appendChild(r);});};});";
+
+const CSV_HEADER: &str = "id,type,source_path,target_path,source_hash,target_hash,source_size,target_size,detail\n";
+
+pub fn csv_escape(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    // JSON Lines: a "finding" object per line and a "summary" object last,
+    // so a partial file from an interrupted run still parses line by line.
+    Json,
+    // A header and one row per finding, for spreadsheets; no summary.
+    Csv,
+    // One self-contained page, written at the end: the run summary, a
+    // collapsible sortable table per kind of finding and the coverage.
+    Html,
+    // Rows in a SQLite database (see sqlite.rs) that runs are added to,
+    // committed in batches as they come.
+    Sqlite,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            "html" => Some(Format::Html),
+            "sqlite" => Some(Format::Sqlite),
+            _ => None,
+        }
+    }
+}
+
+// Site-specific wording for findings (translations, ticket links): a
+// `<kind>.txt` file per kind using {name} placeholders for the finding's
+// fields. Kinds without a file keep the built-in text.
+#[derive(Default)]
+pub struct Templates(BTreeMap<&'static str, String>);
+
+impl Templates {
+    pub fn load(dir: &Path) -> io::Result<Templates> {
+        let mut templates = BTreeMap::new();
+        for kind in KINDS {
+            match fs::read_to_string(dir.join(format!("{}.txt", kind))) {
+                Ok(t) => {
+                    templates.insert(*kind, t);
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if templates.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "no <kind>.txt templates found"));
+        }
+        Ok(Templates(templates))
+    }
+
+    fn render(&self, finding: &Finding, id: &str) -> String {
+        let template = match self.0.get(finding.kind()) {
+            Some(t) => t,
+            None => return format!("{}ID:{}\n", finding, id),
+        };
+        let mut text = template.replace("{id}", id);
+        for (name, value) in finding.fields() {
+            text = text.replace(&format!("{{{}}}", name), &value);
+        }
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+        text
+    }
+}
+
+#[derive(Default)]
+pub struct Tally {
+    pub mismatches: u64,
+    pub missing: u64,
+    pub errors: u64,
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "✗ {} mismatches · {} missing · {} errors", self.mismatches, self.missing, self.errors)
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::MissingInTarget { src, tgt, reason } => {
+                write!(f, "Found missing file in target\nsrc={:?}\ntgt={:?}\nReason:{:?}\n", src, tgt, reason)
+            }
+            Finding::MissingInSource { src, tgt, reason } => {
+                write!(f, "Found missing file in source\nsrc={:?}\ntgt={:?}\nReason:{:?}\n", src, tgt, reason)
+            }
+            Finding::MissingInBoth { src, tgt, src_reason, tgt_reason } => {
+                write!(f, "Found missing file in source and target\nsrc={:?}\ntgt={:?}\nSrcReason:{:?}\nTgtReason:{:?}\n", src, tgt, src_reason, tgt_reason)
+            }
+            Finding::HashMismatch { src, src_hash, tgt, tgt_hash, damage } => {
+                write!(f, "Found mismatched {} hashes:\nsrc={:?}\n", src_hash.names(), src)?;
+                for d in src_hash.values() {
+                    writeln!(f, "{}", d)?;
+                }
+                writeln!(f, "tgt={:?}", tgt)?;
+                for d in tgt_hash.values() {
+                    writeln!(f, "{}", d)?;
+                }
+                if let Some(d) = damage {
+                    writeln!(f, "Damage: {}", d)?;
+                }
+                Ok(())
+            }
+            Finding::BaselineMismatch { src, src_hash, tgt, tgt_hash, damage, verdict } => {
+                match *verdict {
+                    manifest::SOURCE_CHANGED => writeln!(f, "Found source changed since the baseline manifest (target still matches it)")?,
+                    manifest::TARGET_CORRUPTED => writeln!(f, "Found target corrupted relative to the baseline manifest (source still matches it)")?,
+                    _ => writeln!(f, "Found source and target both changed since the baseline manifest")?,
+                }
+                writeln!(f, "src={:?}", src)?;
+                for d in src_hash.values() {
+                    writeln!(f, "{}", d)?;
+                }
+                writeln!(f, "tgt={:?}", tgt)?;
+                for d in tgt_hash.values() {
+                    writeln!(f, "{}", d)?;
+                }
+                if let Some(d) = damage {
+                    writeln!(f, "Damage: {}", d)?;
+                }
+                Ok(())
+            }
+            Finding::TypeMismatch { src, tgt } => {
+                write!(f, "Found mismatched file types\nsrc={:?}\ntgt={:?}\n", src, tgt)
+            }
+            Finding::SizeMismatch { src, tgt, src_size, tgt_size } => {
+                write!(f, "Found mismatched file sizes\nsrc={:?}\n{}\ntgt={:?}\n{}\n", src, src_size, tgt, tgt_size)
+            }
+            Finding::EntryCountMismatch { src, tgt, src_count, tgt_count } => {
+                write!(f, "Found mismatched directory entry counts\nsrc={:?}\n{}\ntgt={:?}\n{}\n", src, src_count, tgt, tgt_count)
+            }
+            Finding::MetadataMismatch { src, tgt, field, src_value, tgt_value } => {
+                write!(f, "Found mismatched {}\nsrc={:?}\n{}\ntgt={:?}\n{}\n", field, src, src_value, tgt, tgt_value)
+            }
+            Finding::PathTooLong { side, path, reason } => {
+                write!(f, "Found path too long for the OS\n{}={:?}\nReason:{:?}\n", side, path, reason)
+            }
+            Finding::BudgetExceeded { src, tgt, reason } => {
+                write!(f, "Found file exceeding the comparison budget\nsrc={:?}\ntgt={:?}\nReason:{}\n", src, tgt, reason)
+            }
+            Finding::Skipped { src, reason } => {
+                write!(f, "Skipped\nsrc={:?}\nReason:{}\n", src, reason)
+            }
+            Finding::ExpectedDifference { src, tgt, pattern } => {
+                write!(f, "Found expected difference (informational, matches --expect-different {:?})\nsrc={:?}\ntgt={:?}\n", pattern, src, tgt)
+            }
+            Finding::RuleViolation { src, tgt, rule, detail } => {
+                write!(f, "Found rule violation (rule {})\nsrc={:?}\ntgt={:?}\nReason:{}\n", rule, src, tgt, detail)
+            }
+            Finding::LowFreeSpace { tgt, free, threshold } => {
+                write!(f, "Found low free space on the target (below --min-free-space {})\ntgt={:?}\nFree:{}\n", threshold, tgt, free)
+            }
+            Finding::Error { src, tgt, operation, reason } => {
+                write!(f, "Found error while {}\nsrc={:?}\ntgt={:?}\nReason:{:?}\n", operation, src, tgt, reason)
+            }
+            Finding::EncodingDifference { src, tgt, aspect, src_value, tgt_value } => {
+                let headline = match *aspect {
+                    "encoding" => "Found text encoding difference",
+                    "bom" => "Found byte order mark difference",
+                    _ => "Found line ending difference",
+                };
+                write!(f, "{} (informational, the target copy's text may have been converted)\nsrc={:?}\n{}\ntgt={:?}\n{}\n", headline, src, src_value, tgt, tgt_value)
+            }
+            Finding::HardLinkMismatch { src, tgt, other, check: "split" } => {
+                write!(f, "Found hard link not preserved in target\nsrc={:?}\ntgt={:?}\nLinked in the source to {:?}, but a separate file in the target\n", src, tgt, other)
+            }
+            Finding::HardLinkMismatch { src, tgt, other, .. } => {
+                write!(f, "Found hard link in target not in source\nsrc={:?}\ntgt={:?}\nLinked in the target to the copy of {:?}, but a separate file in the source\n", src, tgt, other)
+            }
+            Finding::BackupLogMismatch { tgt, log, detail, .. } => {
+                write!(f, "Found backup log contradicting the audit\ntgt={:?}\nLog:{:?}\nReason:{}\n", tgt, log, detail)
+            }
+            Finding::Acknowledged { kind, path, note } => {
+                write!(f, "Acknowledged {} (known, not raised again)\nsrc={:?}\nNote:{}\n", kind, path, note)
+            }
+            Finding::Flaky { kind, side, path, seen, runs } => {
+                write!(f, "Flaky {} (raised in {} of the last {} runs, not every time; not raised this run)\n{}={:?}\n", kind, seen, runs, side, path)
+            }
+            Finding::Synthetic { index, count, src, tgt } => {
+                write!(f, "Found SYNTHETIC finding {}/{} (injected by --inject-findings, not a real discrepancy)\nsrc={:?}\ntgt={:?}\n", index, count, src, tgt)
+            }
+        }
+    }
+}
+
+// Names the side a missing-file headline is about after its label, keeping
+// the headline itself so headline_kind() still reads it: "Found missing file
+// in target (copy B)". Templated text is left alone.
+fn label_headline(finding: &Finding, text: String, (src, tgt): &(String, String)) -> String {
+    let (headline, label) = match finding {
+        Finding::MissingInTarget { .. } => ("Found missing file in target", tgt.clone()),
+        Finding::MissingInSource { .. } => ("Found missing file in source", src.clone()),
+        Finding::MissingInBoth { .. } => ("Found missing file in source and target", format!("{} and {}", src, tgt)),
+        _ => return text,
+    };
+    match text.strip_prefix(headline) {
+        Some(rest) if rest.starts_with('\n') => format!("{} ({}){}", headline, label, rest),
+        _ => text,
+    }
+}
+
+// Maps the first line of a finding as written above back to its kind, for
+// tools that read reports (merge-reports). None for any other line.
+pub fn headline_kind(line: &str) -> Option<&'static str> {
+    const HEADLINES: &[(&str, &str)] = &[
+        ("Found missing file in target", "missing_in_target"),
+        ("Found missing file in source and target", "missing_in_both"),
+        ("Found missing file in source", "missing_in_source"),
+        ("Found mismatched file types", "type_mismatch"),
+        ("Found mismatched file sizes", "size_mismatch"),
+        ("Found mismatched directory entry counts", "entry_count_mismatch"),
+        ("Found path too long for the OS", "path_too_long"),
+        ("Found file exceeding the comparison budget", "budget_exceeded"),
+        ("Found expected difference", "expected_difference"),
+        ("Found rule violation", "rule_violation"),
+        ("Found SYNTHETIC finding", "synthetic"),
+        ("Found low free space on the target", "low_free_space"),
+        ("Found backup log contradicting the audit", "backup_log_mismatch"),
+        ("Found error while ", "error"),
+        ("Found hard link not preserved in target", "hard_link_mismatch"),
+        ("Found text encoding difference", "encoding_difference"),
+        ("Found byte order mark difference", "encoding_difference"),
+        ("Found line ending difference", "encoding_difference"),
+        ("Found hard link in target not in source", "hard_link_mismatch"),
+        ("Found source changed since the baseline manifest", "source_changed"),
+        ("Found target corrupted relative to the baseline manifest", "target_corrupted"),
+        ("Found source and target both changed since the baseline manifest", "both_changed"),
+        ("Acknowledged ", "acknowledged"),
+        ("Flaky ", "flaky"),
+    ];
+    if line == "Skipped" {
+        return Some("skipped");
+    }
+    if let Some((_, kind)) = HEADLINES.iter().find(|(h, _)| line.starts_with(h)) {
+        return Some(kind);
+    }
+    match line.strip_prefix("Found mismatched ") {
+        Some(rest) if rest.ends_with(" hashes:") => Some("hash_mismatch"),
+        Some(_) => Some("metadata_mismatch"),
+        None => None,
+    }
+}
+
+// Chain-of-custody profile: the report becomes a single self-contained evidence
+// file with a header, a line per verified file and a sealed summary.
+pub struct Custody {
+    pub operator: String,
+    // secret for an HMAC of the seal
+    pub key: Option<Vec<u8>>,
+    // signs the seal for anyone with the public key to check (check_seals)
+    pub signing_key: Option<SecretKey>,
+}
+
+pub struct RunInfo<'a> {
+    pub source_dir: &'a str,
+    pub target_dir: &'a str,
+    pub command_line: &'a [String],
+    pub filesystems: &'a str,
+}
+
+pub struct RunStats {
+    pub files_verified: u64,
+    pub bytes_verified: u64,
+    pub files_not_verified: u64,
+    pub findings: BTreeMap<&'static str, u64>,
+}
+
+pub struct ReportOptions {
+    pub custody: Option<Custody>,
+    pub append_only: bool,
+    pub templates: Templates,
+    pub max_findings_per_kind: Option<u64>,
+    pub acks: Acks,
+    // earlier runs' findings, to tell flaky ones by
+    pub history: Option<History>,
+    pub format: Format,
+    pub fail_fast: bool,
+    // what to call the source and target roots, when they aren't a live
+    // source and its backup (two copies compared with each other)
+    pub labels: Option<(String, String)>,
+    // where --format html keeps its table rows until the page is written
+    pub scratch: Option<Arc<Scratch>>,
+}
+
+pub struct Report {
+    custody: Option<Custody>,
+    templates: Templates,
+    format: Format,
+    append_only: bool,
+    max_findings_per_kind: Option<u64>,
+    acks: Acks,
+    history: Option<History>,
+    run_id: String,
+    started: SystemTime,
+    fail_fast: bool,
+    // set by the first finding that isn't informational, with fail_fast, and
+    // by the first write to the report that fails
+    stopped: AtomicBool,
+    observer: Option<Observer>,
+    labels: Option<(String, String)>,
+    scratch: Option<Arc<Scratch>>,
+    state: Mutex<ReportState>,
+}
+
+type Observer = Box<dyn Fn(&Finding, &str) + Send + Sync>;
+
+// Where the report goes: a file, or the database with --format sqlite.
+enum Output {
+    File(File),
+    Database(sqlite::Writer),
+}
+
+struct ReportState {
+    out: Output,
+    digest: Sha256,
+    counts: BTreeMap<&'static str, u64>,
+    verified: u64,
+    roots: Option<(String, String)>,
+    coverage: Coverage,
+    storage: Option<Storage>,
+    // --format html: table rows by kind, and the header's filesystems,
+    // until finish() writes the page
+    html_rows: BTreeMap<&'static str, HtmlRows>,
+    filesystems: String,
+    // the first write that failed (a full disk, a closed pipe); nothing is
+    // written after it and finish() returns it
+    failed: Option<io::Error>,
+    // with a history, the IDs of the findings raised, flaky ones included,
+    // for the history to have this run too
+    raised: BTreeSet<String>,
+}
+
+// One kind's rows, spilled to scratch space as they come and held in memory
+// once that fails (no scratch space, or its quota used up).
+#[derive(Default)]
+struct HtmlRows {
+    spill: Option<Spill>,
+    held: Vec<String>,
+}
+
+impl HtmlRows {
+    fn push(&mut self, scratch: Option<&Scratch>, kind: &str, row: String) {
+        let scratch = match scratch {
+            Some(s) if self.held.is_empty() => s,
+            _ => return self.held.push(row),
+        };
+        let spilled = match &mut self.spill {
+            Some(spill) => scratch.write(spill, row.as_bytes()),
+            None => scratch.spill(&format!("html-rows-{}", kind)).and_then(|spill| scratch.write(self.spill.insert(spill), row.as_bytes())),
+        };
+        if spilled.is_err() {
+            self.held.push(row);
+        }
+    }
+
+    fn concat(&mut self) -> String {
+        let mut rows = self.spill.as_mut().and_then(|s| s.read_back().ok()).unwrap_or_default();
+        rows.push_str(&self.held.concat());
+        rows
+    }
+}
+
+// How much disk the compared target files and the target dataset take up for
+// the data they hold (--storage-efficiency).
+#[derive(Default)]
+struct Storage {
+    files: u64,
+    logical: u64,
+    allocated: u64,
+    dataset: Option<Result<String, String>>,
+}
+
+impl Storage {
+    fn ratio(&self) -> f64 {
+        if self.allocated == 0 { 1.0 } else { self.logical as f64 / self.allocated as f64 }
+    }
+
+    fn section(&self) -> String {
+        let mut s = format!(
+            "== Storage efficiency ==\nTarget files compared: {} files, {} of data in {} allocated ({:.2}x)\n",
+            self.files,
+            HumanBytes(self.logical),
+            HumanBytes(self.allocated),
+            self.ratio(),
+        );
+        match &self.dataset {
+            Some(Ok(line)) => s.push_str(&format!("Target dataset: {}\n", line)),
+            Some(Err(e)) => s.push_str(&format!("Target dataset: unavailable ({})\n", e)),
+            None => {}
+        }
+        s
+    }
+}
+
+#[derive(Default)]
+struct Uncovered {
+    files: u64,
+    bytes: u64,
+    other: u64,
+}
+
+// Accounts for every regular file in the source: either its content was
+// compared or it lands under the reason it wasn't. Non-file entries that stop
+// a check (pruned subtrees, unreadable directories) are counted alongside.
+#[derive(Default)]
+struct Coverage {
+    files: u64,
+    bytes: u64,
+    not_verified: BTreeMap<String, Uncovered>,
+    linked: [LinkedReads; 2],
+    // verified targets that share extents with their source (--detect-clones)
+    cloned_files: u64,
+    cloned_bytes: u64,
+    // files by the method a --method-policy picked for them
+    methods: BTreeMap<&'static str, u64>,
+}
+
+// Hard links to an inode already verified under another name: the bytes count
+// as verified again (the path is covered) but were not new data on the disk.
+// Only multiply-linked inodes are remembered, which keeps the set small.
+#[derive(Default)]
+struct LinkedReads {
+    seen: HashSet<(u64, u64)>,
+    repeat_files: u64,
+    repeat_bytes: u64,
+}
+
+impl LinkedReads {
+    fn read(&mut self, identity: Option<(u64, u64)>, bytes: u64) {
+        if let Some(id) = identity {
+            if !self.seen.insert(id) {
+                self.repeat_files += 1;
+                self.repeat_bytes += bytes;
+            }
+        }
+    }
+}
+
+impl Coverage {
+    fn section(&self) -> String {
+        let total_files = self.files + self.not_verified.values().map(|u| u.files).sum::<u64>();
+        let total_bytes = self.bytes + self.not_verified.values().map(|u| u.bytes).sum::<u64>();
+        let percent = |part: u64, whole: u64| if whole == 0 { 100.0 } else { part as f64 * 100.0 / whole as f64 };
+        let mut s = format!(
+            "== Coverage ==\nFiles verified: {}/{} ({:.2}%)\nBytes verified: {}/{} ({:.2}%)\n",
+            self.files,
+            total_files,
+            percent(self.files, total_files),
+            HumanBytes(self.bytes),
+            HumanBytes(total_bytes),
+            percent(self.bytes, total_bytes),
+        );
+        let [src, tgt] = &self.linked;
+        if src.repeat_files + tgt.repeat_files > 0 {
+            s.push_str(&format!(
+                "Unique bytes verified: source {} ({} hard-linked copies), target {} ({} hard-linked copies)\n",
+                HumanBytes(self.bytes.saturating_sub(src.repeat_bytes)),
+                src.repeat_files,
+                HumanBytes(self.bytes.saturating_sub(tgt.repeat_bytes)),
+                tgt.repeat_files,
+            ));
+        }
+        if self.cloned_files > 0 {
+            s.push_str(&format!(
+                "Cloned copies: {} target files share {} of extents with their source (reflinks, not independent copies)\n",
+                self.cloned_files,
+                HumanBytes(self.cloned_bytes),
+            ));
+        }
+        if !self.methods.is_empty() {
+            let methods: Vec<String> = self.methods.iter().map(|(method, files)| format!("{} {}", method, files)).collect();
+            s.push_str(&format!("Files by method: {}\n", methods.join(", ")));
+        }
+        if !self.not_verified.is_empty() {
+            s.push_str("Not verified:\n");
+        }
+        for (reason, u) in &self.not_verified {
+            s.push_str(&format!("  {}: {} files ({})", reason, u.files, HumanBytes(u.bytes)));
+            if u.other > 0 {
+                s.push_str(&format!(", {} other entries", u.other));
+            }
+            s.push('\n');
+        }
+        s
+    }
+}
+
+impl ReportState {
+    fn write(&mut self, s: &str) {
+        if self.failed.is_some() {
+            return;
+        }
+        self.digest.update(s.as_bytes());
+        if let Output::File(out) = &mut self.out {
+            if let Err(e) = out.write_all(s.as_bytes()) {
+                self.failed = Some(e);
+            }
+        }
+    }
+
+    // Like write(), for the database.
+    fn database(&mut self, f: impl FnOnce(&mut sqlite::Writer) -> io::Result<()>) {
+        if let (None, Output::Database(db)) = (&self.failed, &mut self.out) {
+            if let Err(e) = f(db) {
+                self.failed = Some(e);
+            }
+        }
+    }
+
+    // Flushes the report to stable storage. Pipes, terminals and devices can't
+    // be, nor can files on some WORM and FUSE filesystems, and they all say so
+    // with EINVAL or ENOTSUP rather than losing anything.
+    fn sync(&mut self) -> io::Result<()> {
+        if let Some(e) = self.failed.take() {
+            return Err(e);
+        }
+        let out = match &mut self.out {
+            Output::File(out) => out,
+            Output::Database(_) => return Ok(()),
+        };
+        match out.sync_all() {
+            Err(e) if matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported) => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl Report {
+    // In append-only mode the file is opened with O_APPEND and never truncated,
+    // so every write lands strictly after the existing content (WORM media).
+    pub fn create(path: &str, options: ReportOptions) -> io::Result<Report> {
+        let ReportOptions { custody, append_only, templates, max_findings_per_kind, acks, history, format, fail_fast, labels, scratch } = options;
+        let out = if format == Format::Sqlite {
+            Output::Database(sqlite::Writer::open(Path::new(path))?)
+        } else {
+            let mut out = if append_only {
+                OpenOptions::new().append(true).create(true).open(path)?
+            } else {
+                File::create(path)?
+            };
+            // appended runs share the first run's header
+            if format == Format::Csv && out.metadata()?.len() == 0 {
+                out.write_all(CSV_HEADER.as_bytes())?;
+            }
+            Output::File(out)
+        };
+        Ok(Report {
+            custody,
+            templates,
+            format,
+            append_only,
+            max_findings_per_kind,
+            acks,
+            history,
+            run_id: new_run_id(),
+            started: SystemTime::now(),
+            fail_fast,
+            stopped: AtomicBool::new(false),
+            observer: None,
+            labels,
+            scratch,
+            state: Mutex::new(ReportState {
+                out,
+                digest: Sha256::new(),
+                counts: BTreeMap::new(),
+                verified: 0,
+                roots: None,
+                coverage: Coverage::default(),
+                storage: None,
+                html_rows: BTreeMap::new(),
+                filesystems: String::new(),
+                failed: None,
+                raised: BTreeSet::new(),
+            }),
+        })
+    }
+
+    // Called with every finding and its ID once it is recorded, listed or not.
+    // Runs on the recording thread, after the report lock is released.
+    pub fn observe(&mut self, observer: impl Fn(&Finding, &str) + Send + Sync + 'static) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    // Names this run in the report, its JSON findings and exported telemetry,
+    // so an alert or a metrics series can be traced back to one report.
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    pub fn header(&self, run: &RunInfo) {
+        let mut state = self.state.lock().unwrap();
+        state.roots = Some((run.source_dir.to_string(), run.target_dir.to_string()));
+        state.filesystems = run.filesystems.to_string();
+        let started = humantime::format_rfc3339_seconds(self.started).to_string();
+        state.database(|db| db.start_run(&self.run_id, run.source_dir, run.target_dir, &started));
+        drop(state);
+        if self.format != Format::Text {
+            return;
+        }
+        let labels = match &self.labels {
+            Some((src, tgt)) => format!("Labels: src is {:?}, tgt is {:?}\n", src, tgt),
+            None => String::new(),
+        };
+        let custody = match &self.custody {
+            Some(c) => c,
+            None => {
+                self.state.lock().unwrap().write(&format!("Run: {}\n{}{}", self.run_id, labels, run.filesystems));
+                return;
+            }
+        };
+        let host = gethostname::gethostname();
+        let header = format!(
+            "== Chain of custody ==\nTool: Backup Auditor v{}\nRun: {}\nOperator: {}\nHost: {}\nStarted: {}\nSource: {:?}\nTarget: {:?}\nCommand: {:?}\n{}{}== Evidence ==\n",
+            env!("CARGO_PKG_VERSION"),
+            self.run_id,
+            custody.operator,
+            host.to_string_lossy(),
+            humantime::format_rfc3339_seconds(self.started),
+            run.source_dir,
+            run.target_dir,
+            run.command_line.join(" "),
+            labels,
+            run.filesystems,
+        );
+        self.state.lock().unwrap().write(&header);
+    }
+
+    pub fn record(&self, mut finding: Finding) {
+        let _span = tracing::info_span!("report", kind = finding.kind()).entered();
+        let mut state = self.state.lock().unwrap();
+        let (side, path) = finding.subject();
+        let root = state.roots.as_ref().map(|(source_dir, target_dir)| if side == "tgt" { target_dir } else { source_dir });
+        let rel = root.and_then(|r| ack::relative_key(r, path)).unwrap_or_else(|| path.to_string());
+        let id = finding_id(finding.kind(), &rel, finding.detail());
+        if let Some(note) = self.acks.note(finding.kind(), &rel, &id) {
+            finding = Finding::Acknowledged { kind: finding.kind(), path: path.to_string(), note: note.to_string() };
+        }
+        if let Some(history) = self.history.as_ref().filter(|_| !is_informational(finding.kind())) {
+            state.raised.insert(id.clone());
+            if let history::Record::Flaky { seen, runs } = history.record(&id) {
+                let (side, path) = finding.subject();
+                finding = Finding::Flaky { kind: finding.kind(), side, path: path.to_string(), seen, runs };
+            }
+        }
+        if self.fail_fast && !is_informational(finding.kind()) {
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+        let count = state.counts.entry(finding.kind()).or_insert(0);
+        *count += 1;
+        if !self.max_findings_per_kind.map(|m| *count > m).unwrap_or(false) {
+            match self.format {
+                Format::Text => {
+                    let text = self.templates.render(&finding, &id);
+                    state.write(&match &self.labels {
+                        Some(labels) => label_headline(&finding, text, labels),
+                        None => text,
+                    })
+                }
+                Format::Json => {
+                    let mut object = finding.to_json(&id, &self.run_id);
+                    if let (Some((src, tgt)), Value::Object(o)) = (&self.labels, &mut object) {
+                        o.insert("src_label".into(), json!(src));
+                        o.insert("tgt_label".into(), json!(tgt));
+                    }
+                    state.write(&format!("{}\n", object))
+                }
+                Format::Csv => state.write(&finding.to_csv(&id)),
+                Format::Html => {
+                    let row = finding.to_html_row(&id);
+                    state.html_rows.entry(finding.kind()).or_default().push(self.scratch.as_deref(), finding.kind(), row);
+                }
+                Format::Sqlite => {
+                    let (side, path) = finding.subject();
+                    let fields = finding.fields();
+                    let size = fields
+                        .iter()
+                        .find(|(n, _)| *n == "src_size")
+                        .and_then(|(_, v)| v.parse().ok())
+                        .or_else(|| fs::symlink_metadata(path).ok().filter(|m| m.is_file()).map(|m| m.len()));
+                    let object = finding.to_json(&id, &self.run_id);
+                    let row = sqlite::Row { run_id: &self.run_id, id: &id, kind: finding.kind(), side, path, rel: &rel, size, object: &object };
+                    state.database(|db| db.insert(&row));
+                }
+            }
+        }
+        // no point auditing on into a report that can't be written
+        if state.failed.is_some() {
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+        drop(state);
+        if let Some(observer) = &self.observer {
+            observer(&finding, &id);
+        }
+    }
+
+    // With fail_fast, or once the report can't be written, whether the audit
+    // should stop feeding in more entries.
+    // The IDs of the findings raised so far, when there is a history.
+    pub fn raised(&self) -> BTreeSet<String> {
+        self.state.lock().unwrap().raised.clone()
+    }
+
+    pub fn stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    pub fn tally(&self) -> Tally {
+        tally_of(&self.state.lock().unwrap().counts)
+    }
+
+    pub fn stats(&self) -> RunStats {
+        let state = self.state.lock().unwrap();
+        RunStats {
+            files_verified: state.coverage.files,
+            bytes_verified: state.coverage.bytes,
+            files_not_verified: state.coverage.not_verified.values().map(|u| u.files).sum(),
+            findings: state.counts.clone(),
+        }
+    }
+
+    // `links` identifies each side's inode when it has other hard links.
+    pub fn covered(&self, bytes: u64, links: [Option<(u64, u64)>; 2]) {
+        let mut state = self.state.lock().unwrap();
+        state.coverage.files += 1;
+        state.coverage.bytes += bytes;
+        for (linked, identity) in state.coverage.linked.iter_mut().zip(links) {
+            linked.read(identity, bytes);
+        }
+        state.database(sqlite::Writer::commit_if_due);
+    }
+
+    // `shared` is how much of a verified target's data is physically the
+    // source's; zero means an independent copy.
+    pub fn cloned(&self, shared: u64) {
+        if shared > 0 {
+            let mut state = self.state.lock().unwrap();
+            state.coverage.cloned_files += 1;
+            state.coverage.cloned_bytes += shared;
+        }
+    }
+
+    // A compared target file's length and the space it takes on disk.
+    pub fn stored(&self, logical: u64, allocated: u64) {
+        let mut state = self.state.lock().unwrap();
+        let storage = state.storage.get_or_insert_with(Storage::default);
+        storage.files += 1;
+        storage.logical += logical;
+        storage.allocated += allocated;
+    }
+
+    // The target filesystem's own compression and dedup figures, or why
+    // there are none.
+    pub fn dataset_efficiency(&self, figures: Result<String, String>) {
+        self.state.lock().unwrap().storage.get_or_insert_with(Storage::default).dataset = Some(figures);
+    }
+
+    // `file_bytes` is the size for a regular file, None for anything else.
+    pub fn not_covered(&self, reason: &str, file_bytes: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        let u = state.coverage.not_verified.entry(reason.to_string()).or_default();
+        match file_bytes {
+            Some(bytes) => {
+                u.files += 1;
+                u.bytes += bytes;
+            }
+            None => u.other += 1,
+        }
+    }
+
+    pub fn findings_count(&self) -> u64 {
+        self.state.lock().unwrap().counts.values().sum()
+    }
+
+    // The method picked for a file, before it is compared by it.
+    pub fn method(&self, src: &str, method: &'static str) {
+        let mut state = self.state.lock().unwrap();
+        *state.coverage.methods.entry(method).or_insert(0) += 1;
+        match self.format {
+            Format::Text => state.write(&format!("Method {} src={:?}\n", method, src)),
+            Format::Json => state.write(&format!("{}\n", json!({ "type": "method", "run_id": self.run_id, "path": src, "method": method }))),
+            Format::Csv | Format::Html | Format::Sqlite => {}
+        }
+    }
+
+    pub fn verified(&self, src: &str, tgt: &str, digests: &Digests) {
+        let mut state = self.state.lock().unwrap();
+        state.verified += 1;
+        if self.custody.is_some() {
+            state.write(&format!("Verified {} src={:?} tgt={:?}\n", digests, src, tgt));
+        }
+    }
+
+    fn html_page(&self, state: &mut ReportState) -> String {
+        let (source, target) = state.roots.clone().unwrap_or_default();
+        let mut summary = vec![("Run", self.run_id.clone()), ("Source", source), ("Target", target)];
+        if let Some((src, tgt)) = &self.labels {
+            summary.push(("Labels", format!("source is {}, target is {}", src, tgt)));
+        }
+        summary.extend([
+            ("Started", humantime::format_rfc3339_seconds(self.started).to_string()),
+            ("Finished", humantime::format_rfc3339_seconds(SystemTime::now()).to_string()),
+            ("Files verified", state.coverage.files.to_string()),
+            ("Result", tally_of(&state.counts).to_string()),
+        ]);
+        let mut page = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>Backup audit {}</title><style>{}</style></head><body>\n<h1>Backup audit</h1>\n<table>\n",
+            html_escape(&self.run_id),
+            HTML_STYLE,
+        );
+        for (name, value) in summary {
+            page.push_str(&format!("<tr><th>{}</th><td>{}</td></tr>\n", name, html_escape(&value)));
+        }
+        page.push_str("</table>\n<h2>Findings</h2>\n");
+        if state.counts.is_empty() {
+            page.push_str("<p>No findings.</p>\n");
+        }
+        // informational kinds start collapsed
+        for (kind, count) in &state.counts {
+            let rows = state.html_rows.get_mut(kind).map(HtmlRows::concat).unwrap_or_default();
+            page.push_str(&format!(
+                "<details{}><summary>{} ({})</summary>\n<table><thead><tr><th>ID</th><th>Source</th><th>Target</th><th>Detail</th></tr></thead><tbody>\n{}</tbody></table>\n",
+                if is_informational(kind) { "" } else { " open" },
+                kind,
+                count,
+                rows,
+            ));
+            if let Some(max) = self.max_findings_per_kind.filter(|max| count > max) {
+                page.push_str(&format!("<p>{} listed, and {} more not listed.</p>\n", max, count - max));
+            }
+            page.push_str("</details>\n");
+        }
+        if self.stopped() {
+            page.push_str("<p>Stopped at the first finding (--fail-fast); entries after it were not audited.</p>\n");
+        }
+        let mut sections = vec![state.coverage.section()];
+        sections.extend(state.storage.as_ref().map(Storage::section));
+        for section in sections {
+            let (title, body) = section.split_once('\n').unwrap_or_default();
+            page.push_str(&format!("<h2>{}</h2>\n<pre>{}</pre>\n", html_escape(title.trim_matches(|c| c == '=' || c == ' ')), html_escape(body)));
+        }
+        page.push_str(&format!("<h2>Filesystems</h2>\n<pre>{}</pre>\n", html_escape(state.filesystems.trim_start_matches("== Filesystems ==\n"))));
+        page.push_str(&format!("<script>{}</script>\n</body></html>\n", HTML_SCRIPT));
+        page
+    }
+
+    // A row of type summary under the run's ID, closing each appended run's
+    // rows with its identity and counts, in detail as in to_csv().
+    fn csv_summary(&self, state: &ReportState) -> String {
+        let (source, target) = state.roots.clone().unwrap_or_default();
+        let mut detail = vec![
+            format!("started={}", humantime::format_rfc3339_seconds(self.started)),
+            format!("finished={}", humantime::format_rfc3339_seconds(SystemTime::now())),
+            format!("files_verified={}", state.verified),
+            format!("findings={}", state.counts.values().sum::<u64>()),
+        ];
+        detail.extend(state.counts.iter().map(|(kind, count)| format!("{}={}", kind, count)));
+        let row = [self.run_id.as_str(), "summary", &source, &target, "", "", "", "", &detail.join("; ")];
+        let mut line = row.iter().map(|v| csv_escape(v)).collect::<Vec<_>>().join(",");
+        line.push('\n');
+        line
+    }
+
+    fn json_summary(&self, state: &ReportState) -> Value {
+        let coverage = &state.coverage;
+        let not_verified: Map<String, Value> = coverage
+            .not_verified
+            .iter()
+            .map(|(reason, u)| (reason.clone(), json!({ "files": u.files, "bytes": u.bytes, "other": u.other })))
+            .collect();
+        let not_listed: BTreeMap<&str, u64> = match self.max_findings_per_kind {
+            Some(max) => state.counts.iter().filter(|(_, count)| **count > max).map(|(kind, count)| (*kind, count - max)).collect(),
+            None => BTreeMap::new(),
+        };
+        let [src, tgt] = &coverage.linked;
+        let (source, target) = state.roots.clone().unwrap_or_default();
+        let storage = state.storage.as_ref().map(|s| {
+            json!({
+                "files": s.files,
+                "logical_bytes": s.logical,
+                "allocated_bytes": s.allocated,
+                "ratio": s.ratio(),
+                "dataset": match &s.dataset {
+                    Some(Ok(line)) => json!(line),
+                    Some(Err(e)) => json!({ "unavailable": e }),
+                    None => Value::Null,
+                },
+            })
+        });
+        json!({
+            "type": "summary",
+            "run_id": self.run_id,
+            "source": source,
+            "target": target,
+            "started": humantime::format_rfc3339_seconds(self.started).to_string(),
+            "finished": humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            "files_verified": coverage.files,
+            "bytes_verified": coverage.bytes,
+            "unique_bytes_verified": {
+                "source": coverage.bytes.saturating_sub(src.repeat_bytes),
+                "target": coverage.bytes.saturating_sub(tgt.repeat_bytes),
+            },
+            "cloned": { "files": coverage.cloned_files, "bytes": coverage.cloned_bytes },
+            "methods": coverage.methods,
+            "not_verified": not_verified,
+            "findings": state.counts,
+            "findings_not_listed": not_listed,
+            "stopped_early": self.stopped(),
+            "storage": storage,
+        })
+    }
+
+    // The summary repeats the run's identity so it can be read on its own when
+    // several append-only runs share one file. Fails with the first write to
+    // the report that did.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if self.format == Format::Json {
+            let summary = self.json_summary(&state);
+            state.write(&format!("{}\n", summary));
+            return state.sync();
+        }
+        if self.format == Format::Csv {
+            if self.append_only {
+                let row = self.csv_summary(&state);
+                state.write(&row);
+            }
+            return state.sync();
+        }
+        if self.format == Format::Html {
+            let page = self.html_page(&mut state);
+            state.write(&page);
+            return state.sync();
+        }
+        if self.format == Format::Sqlite {
+            let finished = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+            let (files, bytes, stopped) = (state.coverage.files, state.coverage.bytes, self.stopped());
+            state.database(|db| db.finish(&self.run_id, &finished, files, bytes, stopped));
+            return state.failed.take().map_or(Ok(()), Err);
+        }
+        if let Some(max) = self.max_findings_per_kind {
+            let truncated: Vec<String> = state
+                .counts
+                .iter()
+                .filter(|(_, count)| **count > max)
+                .map(|(kind, count)| format!("Truncated {} findings: {} listed, and {} more not listed\n", kind, max, count - max))
+                .collect();
+            for line in truncated {
+                state.write(&line);
+            }
+        }
+        if self.stopped() {
+            state.write("Stopped at the first finding (--fail-fast); entries after it were not audited\n");
+        }
+        let coverage = state.coverage.section();
+        state.write(&coverage);
+        if let Some(storage) = state.storage.as_ref().map(Storage::section) {
+            state.write(&storage);
+        }
+        if self.custody.is_none() && !self.append_only {
+            return state.failed.take().map_or(Ok(()), Err);
+        }
+        let mut summary = String::from("== Summary ==\n");
+        if let Some((source_dir, target_dir)) = &state.roots {
+            summary.push_str(&format!("Source: {:?}\nTarget: {:?}\n", source_dir, target_dir));
+        }
+        summary.push_str(&format!(
+            "Started: {}\nFinished: {}\nFiles verified: {}\n",
+            humantime::format_rfc3339_seconds(self.started),
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            state.verified,
+        ));
+        let total: u64 = state.counts.values().sum();
+        summary.push_str(&format!("Findings: {}\n", total));
+        for (kind, count) in &state.counts {
+            summary.push_str(&format!("  {}: {}\n", kind, count));
+        }
+        state.write(&summary);
+
+        let custody = match &self.custody {
+            Some(c) => c,
+            None => return state.sync(),
+        };
+
+        // The seal covers every byte written above it, so any edit to the
+        // evidence or summary is detectable by re-hashing the file. Whoever
+        // edits it can re-hash it too; the MAC over the digest they can't
+        // redo without the key, but checking it takes that same key. The
+        // signature they can't redo either, and anyone with the public key
+        // can check it.
+        let digest = to_hex(&state.digest.clone().finalize());
+        let mut seal = format!("{}{}\n", SEAL_DIGEST, digest);
+        if let Some(key) = &custody.key {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+            mac.update(digest.as_bytes());
+            seal.push_str(&format!("{}{}\n", SEAL_MAC, to_hex(&mac.finalize().into_bytes())));
+        }
+        if let Some(key) = &custody.signing_key {
+            seal.push_str(&format!("{}{}: {}\n", SEAL_SIGNATURE, key.public_key().id(), key.sign(digest.as_bytes())));
+        }
+        // the seal is only worth writing under a complete report
+        if let Some(e) = state.failed.take() {
+            return Err(e);
+        }
+        state.write(&seal);
+        state.sync()
+    }
+}
+
+const SEAL_DIGEST: &str = "Report sha256: ";
+const SEAL_MAC: &str = "MAC hmac-sha256: ";
+// followed by the key's id, ": " and the signature of the digest
+const SEAL_SIGNATURE: &str = "Signature ed25519 ";
+
+struct PendingSeal {
+    claimed: String,
+    computed: String,
+    signature: Option<(String, String)>,
+}
+
+impl PendingSeal {
+    fn check(self, key: Option<&PublicKey>) -> Result<(), String> {
+        if self.claimed != self.computed {
+            return Err(format!("the report sha256 is {} but what it seals hashes to {}; it was changed after sealing", self.claimed, self.computed));
+        }
+        let key = match key {
+            Some(k) => k,
+            None => return Ok(()),
+        };
+        match self.signature {
+            None => Err(String::from("not signed")),
+            Some((id, _)) if id != key.id() => Err(format!("signed with key {}, not with {}", id, key.id())),
+            Some((_, signature)) if !key.verify(self.claimed.as_bytes(), &signature) => Err(String::from("the signature doesn't match")),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+// Checks every chain-of-custody seal in a text report, one per run when
+// runs were appended to it: that each report sha256 is that of what was
+// written above it since the previous seal, and with `key`, that the seal
+// is signed with it. A result per seal, in order.
+pub fn check_seals(report: &[u8], key: Option<&PublicKey>) -> Vec<Result<(), String>> {
+    let mut results = Vec::new();
+    let mut digest = Sha256::new();
+    let mut pending: Option<PendingSeal> = None;
+    for line in report.split_inclusive(|b| *b == b'\n') {
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim_end_matches('\n');
+        if let Some(claimed) = text.strip_prefix(SEAL_DIGEST) {
+            if let Some(seal) = pending.take() {
+                results.push(seal.check(key));
+            }
+            let computed = to_hex(&std::mem::take(&mut digest).finalize());
+            pending = Some(PendingSeal { claimed: claimed.to_string(), computed, signature: None });
+            continue;
+        }
+        if let Some(seal) = pending.as_mut() {
+            if text.starts_with(SEAL_MAC) {
+                continue;
+            }
+            if let Some((id, signature)) = text.strip_prefix(SEAL_SIGNATURE).and_then(|s| s.split_once(": ")) {
+                seal.signature = Some((id.to_string(), signature.to_string()));
+                continue;
+            }
+            results.push(pending.take().unwrap().check(key));
+        }
+        digest.update(line);
+    }
+    if let Some(seal) = pending {
+        results.push(seal.check(key));
+    }
+    results
+}
+
+pub fn tally_of(counts: &BTreeMap<&'static str, u64>) -> Tally {
+    let mut tally = Tally::default();
+    for (kind, count) in counts {
+        match *kind {
+            "hash_mismatch" | "type_mismatch" | "metadata_mismatch" | "size_mismatch" | "entry_count_mismatch" | "backup_log_mismatch" | "source_changed" | "target_corrupted" | "both_changed" | "hard_link_mismatch" | "rule_violation" | "synthetic" => tally.mismatches += count,
+            "missing_in_target" | "missing_in_source" | "missing_in_both" => tally.missing += count,
+            kind if is_informational(kind) => {}
+            _ => tally.errors += count,
+        }
+    }
+    tally
+}
+
+// Deterministic across runs, hosts and output formats: the same discrepancy
+// on the same root-relative path always gets the same ID.
+pub fn finding_id(kind: &str, rel_path: &str, detail: &str) -> String {
+    let digest = Sha256::digest(format!("{}\0{}\0{}", kind, rel_path, detail).as_bytes());
+    format!("F-{}", &to_hex(&digest)[..16])
+}
+
+// A random (version 4) UUID. Unique is all it needs to be, so the host,
+// process and clock are hashed rather than pulling in a random source.
+fn new_run_id() -> String {
+    let seed = format!("{:?}\0{}\0{:?}", gethostname::gethostname(), std::process::id(), SystemTime::now());
+    let mut bytes = *blake3::hash(seed.as_bytes()).as_bytes();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = to_hex(&bytes[..16]);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Acks and histories name findings by these, so they must not change
+    // from one version to the next.
+    #[test]
+    fn finding_ids_are_stable() {
+        assert_eq!(finding_id("type_mismatch", "a/b", ""), "F-3b11ff96711a54b1");
+        assert_eq!(finding_id("metadata_mismatch", "a/b", "mode"), "F-f018c55b0df2859d");
+        assert_ne!(finding_id("metadata_mismatch", "a/b", "uid"), finding_id("metadata_mismatch", "a/b", "mode"));
+    }
+
+    // The same finding under roots elsewhere gets the same ID.
+    #[test]
+    fn ids_are_relative_to_the_roots() {
+        let dir = std::env::temp_dir().join(format!("backup_auditor-report-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut ids = Vec::new();
+        for (n, (source_dir, target_dir)) in [("/one/src", "/one/tgt"), ("/two/src/", "/elsewhere/tgt")].into_iter().enumerate() {
+            let options = ReportOptions {
+                custody: None,
+                append_only: false,
+                templates: Templates::default(),
+                max_findings_per_kind: None,
+                acks: Acks::default(),
+                history: None,
+                format: Format::Json,
+                fail_fast: false,
+                labels: None,
+                scratch: None,
+            };
+            let mut report = Report::create(&dir.join(format!("{}.json", n)).to_string_lossy(), options).unwrap();
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let record = seen.clone();
+            report.observe(move |_, id| record.lock().unwrap().push(id.to_string()));
+            report.header(&RunInfo { source_dir, target_dir, command_line: &[], filesystems: "" });
+            let (src, tgt) = (format!("{}/a/b", source_dir.trim_end_matches('/')), format!("{}/a/b", target_dir));
+            report.record(Finding::TypeMismatch { src, tgt });
+            report.finish().unwrap();
+            ids.push(seen.lock().unwrap().clone());
+        }
+        assert_eq!(ids[0], vec![String::from("F-3b11ff96711a54b1")]);
+        assert_eq!(ids[0], ids[1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
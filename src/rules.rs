@@ -0,0 +1,105 @@
+use std::path::{Path, PathBuf};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+pub enum Check {
+    // present in the target in any form
+    Exists,
+    // target size within this many percent of the source
+    SizeWithin(f64),
+}
+
+pub struct Rule {
+    pub name: String,
+    pattern: String,
+    check: Check,
+}
+
+impl Rule {
+    // Err describes the violation.
+    pub fn evaluate(&self, src_len: u64, tgt_len: u64) -> Result<(), String> {
+        match self.check {
+            Check::Exists => Ok(()),
+            Check::SizeWithin(percent) => {
+                let allowed = src_len as f64 * percent / 100.0;
+                if (tgt_len as f64 - src_len as f64).abs() <= allowed {
+                    Ok(())
+                } else {
+                    Err(format!("size {} is not within {}% of source size {}", tgt_len, percent, src_len))
+                }
+            }
+        }
+    }
+}
+
+// One rule per line: NAME GLOB CHECK, where CHECK is "exists" or
+// "size-within N%". Blank lines and lines starting with # are ignored.
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, String> {
+    let mut rules = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", n + 1, msg);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let check = match fields.get(2..) {
+            Some(["exists"]) => Check::Exists,
+            Some(["size-within", p]) => match p.strip_suffix('%').and_then(|p| p.parse::<f64>().ok()) {
+                Some(p) if p >= 0.0 => Check::SizeWithin(p),
+                _ => return Err(err("size-within takes a percentage like 10%")),
+            },
+            _ => return Err(err("expected NAME GLOB exists|size-within N%")),
+        };
+        rules.push(Rule { name: fields[0].to_string(), pattern: fields[1].to_string(), check });
+    }
+    Ok(rules)
+}
+
+fn glob_set<'a>(patterns: impl Iterator<Item = &'a str>) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).map_err(|e| e.to_string())?);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+// Per-path exceptions to "the target must be identical". Globs are matched
+// against paths relative to the source root; `*` also crosses directories.
+#[derive(Default)]
+pub struct Rules {
+    root: PathBuf,
+    expect_different: Vec<String>,
+    expect_different_set: GlobSet,
+    rules: Vec<Rule>,
+    rules_set: GlobSet,
+}
+
+impl Rules {
+    pub fn new(root: &str, expect_different: Vec<String>, rules: Vec<Rule>) -> Result<Rules, String> {
+        Ok(Rules {
+            root: PathBuf::from(root),
+            expect_different_set: glob_set(expect_different.iter().map(String::as_str))?,
+            expect_different,
+            rules_set: glob_set(rules.iter().map(|r| r.pattern.as_str()))?,
+            rules,
+        })
+    }
+
+    fn relative<'a>(&self, src_path: &'a str) -> &'a Path {
+        let path = Path::new(src_path);
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+
+    // The --expect-different pattern covering this source path, if any.
+    pub fn expected_difference(&self, src_path: &str) -> Option<&str> {
+        let matched = self.expect_different_set.matches(self.relative(src_path));
+        matched.first().map(|&i| self.expect_different[i].as_str())
+    }
+
+    // The first rule in file order covering this source path. A ruled file is
+    // judged by its rule instead of by content.
+    pub fn rule_for(&self, src_path: &str) -> Option<&Rule> {
+        let matched = self.rules_set.matches(self.relative(src_path));
+        matched.into_iter().min().map(|i| &self.rules[i])
+    }
+}
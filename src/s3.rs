@@ -0,0 +1,284 @@
+use std::env;
+use std::io::{self, Read};
+use std::time::{Duration, SystemTime};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use crate::hash::Algorithm;
+use crate::index::EntryKind;
+use crate::remote::{Object, Options, Remote};
+use crate::report::to_hex;
+
+// SHA-256 of an empty request body, which every GET has.
+const EMPTY_SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+// A bucket, or a prefix in one, on S3 or a store speaking its API (MinIO,
+// Ceph RGW, ...). Credentials come from the usual AWS_* environment variables;
+// without them requests go unsigned, which is enough for public buckets.
+pub struct S3 {
+    // s3://bucket/prefix as given
+    url: String,
+    prefix: String,
+    // scheme and authority of the endpoint
+    origin: String,
+    host: String,
+    // the bucket's path on the endpoint: "/bucket" for a custom endpoint,
+    // empty when the bucket is in the host name
+    bucket_path: String,
+    region: String,
+    credentials: Option<Credentials>,
+    agent: ureq::Agent,
+}
+
+// RFC 3986 unreserved characters stay, everything else is percent-encoded;
+// SigV4 signs exactly this form.
+fn uri_encode(s: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(b as char),
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// The text of the first <name> element, entities decoded.
+fn tag(xml: &str, name: &str) -> Option<String> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let len = xml[start..].find(&format!("</{}>", name))?;
+    Some(xml_unescape(&xml[start..start + len]))
+}
+
+fn xml_unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let entity = rest.find(';').map(|end| &rest[1..end]);
+        let decoded = match entity {
+            Some("amp") => Some('&'),
+            Some("lt") => Some('<'),
+            Some("gt") => Some('>'),
+            Some("quot") => Some('"'),
+            Some("apos") => Some('\''),
+            Some(e) if e.starts_with("#x") => u32::from_str_radix(&e[2..], 16).ok().and_then(char::from_u32),
+            Some(e) if e.starts_with('#') => e[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match (decoded, entity) {
+            (Some(c), Some(e)) => {
+                out.push(c);
+                rest = &rest[e.len() + 2..];
+            }
+            _ => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+impl S3 {
+    pub fn new(url: &str, options: &Options) -> Result<S3, String> {
+        let path = url.strip_prefix("s3://").ok_or_else(|| format!("{:?} is not an s3:// URL", url))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if bucket.is_empty() {
+            return Err(format!("{:?} names no bucket", url));
+        }
+        let region = options
+            .s3_region
+            .clone()
+            .or_else(|| env::var("AWS_REGION").ok())
+            .or_else(|| env::var("AWS_DEFAULT_REGION").ok())
+            .unwrap_or_else(|| String::from("us-east-1"));
+        let custom = options.s3_endpoint.clone().or_else(|| env::var("AWS_ENDPOINT_URL_S3").ok()).or_else(|| env::var("AWS_ENDPOINT_URL").ok());
+        let (origin, bucket_path) = match custom {
+            Some(e) => (e.trim_end_matches('/').to_string(), format!("/{}", uri_encode(bucket, false))),
+            // dots in the bucket name would break the certificate's wildcard
+            None if bucket.contains('.') => (format!("https://s3.{}.amazonaws.com", region), format!("/{}", bucket)),
+            None => (format!("https://{}.s3.{}.amazonaws.com", bucket, region), String::new()),
+        };
+        let host = match origin.split_once("://") {
+            Some(("http" | "https", host)) if !host.is_empty() && !host.contains('/') => host.to_string(),
+            _ => return Err(format!("Invalid S3 endpoint {:?}: expected http(s)://HOST[:PORT]", origin)),
+        };
+        let credentials = match (env::var("AWS_ACCESS_KEY_ID"), env::var("AWS_SECRET_ACCESS_KEY")) {
+            (Ok(access_key), Ok(secret_key)) => Some(Credentials { access_key, secret_key, session_token: env::var("AWS_SESSION_TOKEN").ok() }),
+            _ => None,
+        };
+        Ok(S3 {
+            url: url.trim_end_matches('/').to_string(),
+            prefix: prefix.trim_matches('/').to_string(),
+            origin,
+            host,
+            bucket_path,
+            region,
+            credentials,
+            agent: ureq::AgentBuilder::new().timeout_connect(Duration::from_secs(30)).timeout_read(Duration::from_secs(300)).build(),
+        })
+    }
+
+    // The object key for a target path.
+    fn key(&self, tgt_path: &str) -> io::Result<String> {
+        let rel = tgt_path
+            .strip_prefix(&self.url)
+            .map(|r| r.trim_start_matches('/'))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not under {}", tgt_path, self.url)))?;
+        Ok(match (self.prefix.as_str(), rel) {
+            ("", rel) => rel.to_string(),
+            (prefix, rel) => format!("{}/{}", prefix, rel),
+        })
+    }
+
+    fn get(&self, key: &str, query: &[(&str, &str)]) -> io::Result<ureq::Response> {
+        self.request("GET", key, query, &[])
+    }
+
+    // A signed (AWS Signature Version 4) request for `key` in the bucket, or
+    // for the bucket itself with an empty key. `extra` are x-amz-* headers to
+    // send and sign along with the usual ones.
+    fn request(&self, method: &str, key: &str, query: &[(&str, &str)], extra: &[(&str, &str)]) -> io::Result<ureq::Response> {
+        let path = match key {
+            "" if self.bucket_path.is_empty() => String::from("/"),
+            "" => self.bucket_path.clone(),
+            _ => format!("{}/{}", self.bucket_path, uri_encode(key, true)),
+        };
+        let mut pairs: Vec<(String, String)> = query.iter().map(|(k, v)| (uri_encode(k, false), uri_encode(v, false))).collect();
+        pairs.sort();
+        let query = pairs.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+
+        let mut request = self.agent.request(method, &format!("{}{}{}{}", self.origin, path, if query.is_empty() { "" } else { "?" }, query));
+        if let Some(credentials) = &self.credentials {
+            let stamp: String = humantime::format_rfc3339_seconds(SystemTime::now()).to_string().chars().filter(|c| *c != '-' && *c != ':').collect();
+            let date = &stamp[..8];
+            let mut headers = vec![("host", self.host.as_str()), ("x-amz-content-sha256", EMPTY_SHA256), ("x-amz-date", stamp.as_str())];
+            if let Some(token) = &credentials.session_token {
+                headers.push(("x-amz-security-token", token));
+            }
+            headers.extend_from_slice(extra);
+            // signed in order of their names
+            headers.sort();
+            let signed_headers = headers.iter().map(|(n, _)| *n).collect::<Vec<_>>().join(";");
+            let canonical_headers: String = headers.iter().map(|(n, v)| format!("{}:{}\n", n, v.trim())).collect();
+            let canonical = format!("{}\n{}\n{}\n{}\n{}\n{}", method, path, query, canonical_headers, signed_headers, EMPTY_SHA256);
+            let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+            let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", stamp, scope, to_hex(&Sha256::digest(canonical.as_bytes())));
+            let key = ["s3", "aws4_request"].iter().fold(hmac(&hmac(format!("AWS4{}", credentials.secret_key).as_bytes(), date), &self.region), |k, part| hmac(&k, part));
+            let authorization = format!(
+                "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                credentials.access_key,
+                scope,
+                signed_headers,
+                to_hex(&hmac(&key, &to_sign))
+            );
+            for (name, value) in headers.iter().filter(|(n, _)| *n != "host") {
+                request = request.set(name, value);
+            }
+            request = request.set("Authorization", &authorization);
+        } else {
+            for (name, value) in extra {
+                request = request.set(name, value);
+            }
+        }
+        match request.set("User-Agent", concat!("backup_auditor/", env!("CARGO_PKG_VERSION"))).call() {
+            Ok(response) => Ok(response),
+            Err(ureq::Error::Status(status, response)) => {
+                let moved = response.header("x-amz-bucket-region").filter(|r| *r != self.region).map(|r| format!(" (the bucket is in {}; pass --s3-region {})", r, r));
+                let body = response.into_string().unwrap_or_default();
+                let message = match (tag(&body, "Code"), tag(&body, "Message")) {
+                    (Some(code), Some(message)) => format!("{}: {}", code, message),
+                    (Some(code), None) => code,
+                    _ => format!("HTTP {}", status),
+                };
+                let kind = match status {
+                    404 => io::ErrorKind::NotFound,
+                    401 | 403 => io::ErrorKind::PermissionDenied,
+                    _ => io::ErrorKind::Other,
+                };
+                Err(io::Error::new(kind, format!("{}{}", message, moved.unwrap_or_default())))
+            }
+            Err(e) => Err(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+impl Remote for S3 {
+    // ListObjectsV2, a thousand keys per page.
+    fn list(&self) -> io::Result<Vec<Object>> {
+        let prefix = if self.prefix.is_empty() { String::new() } else { format!("{}/", self.prefix) };
+        let mut objects = Vec::new();
+        let mut token: Option<String> = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", prefix.as_str())];
+            if let Some(t) = &token {
+                query.push(("continuation-token", t));
+            }
+            let body = self.get("", &query)?.into_string()?;
+            for contents in body.split("<Contents>").skip(1) {
+                let contents = contents.split("</Contents>").next().unwrap_or_default();
+                let key = tag(contents, "Key").ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "listed object without a key"))?;
+                let size = tag(contents, "Size").and_then(|s| s.parse().ok()).unwrap_or(0);
+                match key.strip_prefix(&prefix) {
+                    // keys ending in / are placeholders for directories
+                    Some(rel) if !rel.is_empty() => objects.push(Object {
+                        key: rel.trim_end_matches('/').to_string(),
+                        size,
+                        etag: tag(contents, "ETag").map(|e| e.trim_matches('"').to_string()),
+                        kind: if rel.ends_with('/') { EntryKind::Dir } else { EntryKind::File },
+                    }),
+                    _ => {}
+                }
+            }
+            token = match tag(&body, "IsTruncated").as_deref() {
+                Some("true") => Some(tag(&body, "NextContinuationToken").ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated listing without a continuation token"))?),
+                _ => break,
+            };
+        }
+        Ok(objects)
+    }
+
+    fn open(&self, tgt_path: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(self.get(&self.key(tgt_path)?, &[])?.into_reader())
+    }
+
+    // HeadObject in checksum mode, which answers with the checksum the object
+    // was uploaded with, if any. A multipart upload's is a checksum of its
+    // parts' checksums unless its type is FULL_OBJECT, and the source can't be
+    // split the same way without knowing the part sizes, so it's left out.
+    fn checksum(&self, tgt_path: &str) -> io::Result<Option<(Algorithm, String)>> {
+        let response = self.request("HEAD", &self.key(tgt_path)?, &[], &[("x-amz-checksum-mode", "ENABLED")])?;
+        if response.header("x-amz-checksum-type") == Some("COMPOSITE") {
+            return Ok(None);
+        }
+        let stored = [
+            ("x-amz-checksum-sha256", Algorithm::Sha256, 32),
+            ("x-amz-checksum-crc64nvme", Algorithm::Crc64Nvme, 8),
+            ("x-amz-checksum-crc32c", Algorithm::Crc32c, 4),
+        ]
+        .into_iter()
+        .find_map(|(header, algorithm, len)| {
+            // composite ones end in -PARTS, which isn't base64
+            let value = response.header(header)?;
+            STANDARD.decode(value).ok().filter(|d| d.len() == len).map(|d| (algorithm, to_hex(&d)))
+        });
+        Ok(stored)
+    }
+}
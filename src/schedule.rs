@@ -0,0 +1,55 @@
+use std::fmt;
+
+// When scheduled audits run: as a launchd agent with install-schedule on
+// macOS, as a Windows service with the service subcommand.
+pub struct Schedule {
+    pub hour: u8,
+    pub minute: u8,
+    // 0 and 7 are both Sunday, as launchd takes them; None runs daily
+    pub weekday: Option<u8>,
+}
+
+impl Schedule {
+    // "HH:MM", 24-hour
+    pub fn parse_time(at: &str) -> Option<(u8, u8)> {
+        let (h, m) = at.split_once(':')?;
+        if m.len() != 2 {
+            return None;
+        }
+        let (h, m): (u8, u8) = (h.parse().ok()?, m.parse().ok()?);
+        (h < 24 && m < 60).then_some((h, m))
+    }
+
+    // Whether a run is due in this minute of local time, `weekday` counting
+    // from 0 for Sunday.
+    pub fn due(&self, weekday: u8, hour: u8, minute: u8) -> bool {
+        (hour, minute) == (self.hour, self.minute) && self.weekday.map(|d| d % 7 == weekday).unwrap_or(true)
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.weekday {
+            Some(d) => write!(f, "weekly on day {}", d)?,
+            None => write!(f, "daily")?,
+        }
+        write!(f, " at {:02}:{:02}", self.hour, self.minute)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_and_days() {
+        assert_eq!(Schedule::parse_time("02:30"), Some((2, 30)));
+        assert_eq!(Schedule::parse_time("24:00"), None);
+        assert_eq!(Schedule::parse_time("2:3"), None);
+        let sundays = Schedule { hour: 2, minute: 30, weekday: Some(7) };
+        assert!(sundays.due(0, 2, 30));
+        assert!(!sundays.due(1, 2, 30));
+        assert!(Schedule { weekday: None, ..sundays }.due(3, 2, 30));
+        assert_eq!(sundays.to_string(), "weekly on day 7 at 02:30");
+    }
+}
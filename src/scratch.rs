@@ -0,0 +1,100 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+// Where one run keeps its temporary data: a directory of its own under
+// --scratch-dir, made on first use. It is removed when the run succeeds and
+// kept when it doesn't, with a note of which run it belonged to, so what was
+// there at the time can be looked at.
+pub struct Scratch {
+    // the run's directory, named up front so the walk can leave it out
+    path: PathBuf,
+    // bytes all spills together may take up
+    quota: Option<u64>,
+    used: AtomicU64,
+    dir: Mutex<Option<PathBuf>>,
+    command_line: Vec<String>,
+}
+
+const PREFIX: &str = "backup_auditor-";
+
+impl Scratch {
+    pub fn new(parent: &Path, quota: Option<u64>, command_line: &[String]) -> Scratch {
+        let started = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis();
+        let path = parent.join(format!("{}{}-{}", PREFIX, std::process::id(), started));
+        Scratch { path, quota, used: AtomicU64::new(0), dir: Mutex::new(None), command_line: command_line.to_vec() }
+    }
+
+    // Where the run's directory is or will be made.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // The run's directory, if anything has been put in it yet.
+    pub fn dir(&self) -> Option<PathBuf> {
+        self.dir.lock().unwrap().clone()
+    }
+
+    fn create_dir(&self) -> io::Result<PathBuf> {
+        let mut dir = self.dir.lock().unwrap();
+        if let Some(d) = &*dir {
+            return Ok(d.clone());
+        }
+        let now = SystemTime::now();
+        let path = self.path.clone();
+        fs::create_dir_all(&path)?;
+        fs::write(
+            path.join("run.txt"),
+            format!("started {}\npid {}\ncommand line {:?}\n", humantime::format_rfc3339_seconds(now), std::process::id(), self.command_line),
+        )?;
+        *dir = Some(path.clone());
+        Ok(path)
+    }
+
+    // A new file in the run's directory, for data that would otherwise pile
+    // up in memory.
+    pub fn spill(&self, name: &str) -> io::Result<Spill> {
+        let path = self.create_dir()?.join(name);
+        let file = File::options().read(true).write(true).create_new(true).open(&path)?;
+        Ok(Spill { out: BufWriter::new(file) })
+    }
+
+    pub fn write(&self, spill: &mut Spill, data: &[u8]) -> io::Result<()> {
+        let len = data.len() as u64;
+        let used = self.used.fetch_add(len, Ordering::Relaxed) + len;
+        if let Some(quota) = self.quota.filter(|q| used > *q) {
+            self.used.fetch_sub(len, Ordering::Relaxed);
+            return Err(io::Error::new(io::ErrorKind::StorageFull, format!("scratch quota of {} bytes used up", quota)));
+        }
+        spill.out.write_all(data)
+    }
+
+    // Removes the run's directory; the caller keeps it instead when the run
+    // failed.
+    pub fn remove(&self) -> io::Result<()> {
+        match self.dir.lock().unwrap().take() {
+            Some(dir) => fs::remove_dir_all(dir),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Spill {
+    out: BufWriter<File>,
+}
+
+impl Spill {
+    // Everything written so far; the file stays for diagnosis until the
+    // scratch directory is removed.
+    pub fn read_back(&mut self) -> io::Result<String> {
+        self.out.flush()?;
+        let file = self.out.get_mut();
+        file.seek(SeekFrom::Start(0))?;
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        Ok(text)
+    }
+}
@@ -0,0 +1,407 @@
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use crate::index::EntryKind;
+use crate::remote::{Object, Options, Remote};
+
+// SFTP version 3 (draft-ietf-secsh-filexfer-02), which every server speaks,
+// and only the requests an audit needs.
+const FXP_INIT: u8 = 1;
+const FXP_VERSION: u8 = 2;
+const FXP_OPEN: u8 = 3;
+const FXP_CLOSE: u8 = 4;
+const FXP_READ: u8 = 5;
+const FXP_OPENDIR: u8 = 11;
+const FXP_READDIR: u8 = 12;
+const FXP_STATUS: u8 = 101;
+const FXP_HANDLE: u8 = 102;
+const FXP_DATA: u8 = 103;
+const FXP_NAME: u8 = 104;
+
+const FX_OK: u32 = 0;
+const FX_EOF: u32 = 1;
+const FX_NO_SUCH_FILE: u32 = 2;
+const FX_PERMISSION_DENIED: u32 = 3;
+
+const FXF_READ: u32 = 1;
+
+const ATTR_SIZE: u32 = 0x1;
+const ATTR_UIDGID: u32 = 0x2;
+const ATTR_PERMISSIONS: u32 = 0x4;
+const ATTR_ACMODTIME: u32 = 0x8;
+const ATTR_EXTENDED: u32 = 0x8000_0000;
+
+// What servers are guaranteed to answer in full; larger reads come back short.
+const READ_SIZE: u32 = 32 * 1024;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("SFTP: {}", msg))
+}
+
+// Whether a session is still usable after a request failed with `e`: a
+// server refusing it is, a connection lost or replies out of step are not.
+fn in_step(e: &io::Error) -> bool {
+    !matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe)
+}
+
+// A reply's payload, read front to back.
+struct Payload {
+    data: Vec<u8>,
+    at: usize,
+}
+
+impl Payload {
+    fn take(&mut self, n: usize) -> io::Result<&[u8]> {
+        let bytes = self.data.get(self.at..self.at + n).ok_or_else(|| invalid("reply cut short"))?;
+        self.at += n;
+        Ok(bytes)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    // ATTRS: the size and the file type are all that's kept.
+    fn attrs(&mut self) -> io::Result<(u64, Option<u32>)> {
+        let flags = self.u32()?;
+        let size = if flags & ATTR_SIZE != 0 { self.u64()? } else { 0 };
+        if flags & ATTR_UIDGID != 0 {
+            self.take(8)?;
+        }
+        let permissions = if flags & ATTR_PERMISSIONS != 0 { Some(self.u32()?) } else { None };
+        if flags & ATTR_ACMODTIME != 0 {
+            self.take(8)?;
+        }
+        if flags & ATTR_EXTENDED != 0 {
+            for _ in 0..self.u32()? {
+                self.string()?;
+                self.string()?;
+            }
+        }
+        Ok((size, permissions))
+    }
+}
+
+fn put_string(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u32).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+// One SSH connection running the sftp subsystem. Requests are sent one at a
+// time and answered in order, so every reply is for the last request.
+struct Session {
+    child: Child,
+    to_server: BufWriter<ChildStdin>,
+    from_server: BufReader<ChildStdout>,
+    next_id: u32,
+}
+
+impl Session {
+    fn connect(ssh: &[String], destination: &str, port: Option<&str>) -> io::Result<Session> {
+        let (program, args) = ssh.split_first().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty --ssh-command"))?;
+        let mut command = Command::new(program);
+        command.args(args).arg("-o").arg("BatchMode=yes");
+        if let Some(port) = port {
+            command.arg("-p").arg(port);
+        }
+        let mut child = command.arg("-s").arg(destination).arg("sftp").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().map_err(|e| io::Error::new(e.kind(), format!("{}: {}", program, e)))?;
+        let mut session = Session {
+            to_server: BufWriter::new(child.stdin.take().unwrap()),
+            from_server: BufReader::new(child.stdout.take().unwrap()),
+            child,
+            next_id: 0,
+        };
+        session.send(FXP_INIT, &3u32.to_be_bytes())?;
+        match session.receive() {
+            Ok((FXP_VERSION, _)) => Ok(session),
+            Ok(_) => Err(invalid("unexpected reply to INIT")),
+            // ssh has said why on stderr by now
+            Err(e) => Err(io::Error::new(e.kind(), format!("no SFTP server answered on {} ({})", destination, e))),
+        }
+    }
+
+    fn send(&mut self, kind: u8, body: &[u8]) -> io::Result<()> {
+        self.to_server.write_all(&(body.len() as u32 + 1).to_be_bytes())?;
+        self.to_server.write_all(&[kind])?;
+        self.to_server.write_all(body)?;
+        self.to_server.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<(u8, Payload)> {
+        let mut len = [0; 4];
+        self.from_server.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 || len > 1 << 24 {
+            return Err(invalid("bad packet length"));
+        }
+        let mut data = vec![0; len];
+        self.from_server.read_exact(&mut data)?;
+        Ok((data[0], Payload { data, at: 1 }))
+    }
+
+    // Sends a request carrying `body` after its id and returns the reply
+    // after its id; a STATUS reply other than OK or EOF is an error.
+    fn request(&mut self, kind: u8, body: &[u8]) -> io::Result<(u8, Payload)> {
+        self.next_id = self.next_id.wrapping_add(1);
+        let mut packet = self.next_id.to_be_bytes().to_vec();
+        packet.extend_from_slice(body);
+        self.send(kind, &packet)?;
+        let (reply, mut payload) = self.receive()?;
+        if payload.u32()? != self.next_id {
+            return Err(invalid("reply to another request"));
+        }
+        if reply == FXP_STATUS {
+            let code = payload.u32()?;
+            if code != FX_OK && code != FX_EOF {
+                let message = String::from_utf8_lossy(&payload.string().unwrap_or_default()).into_owned();
+                let kind = match code {
+                    FX_NO_SUCH_FILE => io::ErrorKind::NotFound,
+                    FX_PERMISSION_DENIED => io::ErrorKind::PermissionDenied,
+                    _ => io::ErrorKind::Other,
+                };
+                return Err(io::Error::new(kind, message));
+            }
+        }
+        Ok((reply, payload))
+    }
+
+    fn handle(&mut self, kind: u8, path: &str, flags: Option<u32>) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        put_string(&mut body, path.as_bytes());
+        if let Some(flags) = flags {
+            body.extend_from_slice(&flags.to_be_bytes());
+            // no attributes
+            body.extend_from_slice(&0u32.to_be_bytes());
+        }
+        match self.request(kind, &body)? {
+            (FXP_HANDLE, mut payload) => payload.string(),
+            _ => Err(invalid("expected a handle")),
+        }
+    }
+
+    fn close(&mut self, handle: &[u8]) -> io::Result<()> {
+        let mut body = Vec::new();
+        put_string(&mut body, handle);
+        self.request(FXP_CLOSE, &body).map(|_| ())
+    }
+
+    // Names, sizes and types of a directory's entries, . and .. left out.
+    fn read_dir(&mut self, path: &str) -> io::Result<Vec<(String, u64, EntryKind)>> {
+        let handle = self.handle(FXP_OPENDIR, path, None)?;
+        let mut entries = Vec::new();
+        let mut body = Vec::new();
+        put_string(&mut body, &handle);
+        // a STATUS of EOF once every entry has been sent
+        while let (FXP_NAME, mut payload) = self.request(FXP_READDIR, &body)? {
+            for _ in 0..payload.u32()? {
+                let name = String::from_utf8_lossy(&payload.string()?).into_owned();
+                payload.string()?;
+                let (size, permissions) = payload.attrs()?;
+                let kind = match permissions.map(|p| p & 0o170000) {
+                    Some(0o040000) => EntryKind::Dir,
+                    Some(0o100000) => EntryKind::File,
+                    _ => EntryKind::Other,
+                };
+                if name != "." && name != ".." {
+                    entries.push((name, size, kind));
+                }
+            }
+        }
+        self.close(&handle)?;
+        Ok(entries)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// Up to `size` sessions, opened as they are first needed and reused after,
+// so each worker reading at once has its own connection.
+struct Pool {
+    ssh: Vec<String>,
+    destination: String,
+    port: Option<String>,
+    size: usize,
+    // idle sessions, and how many there are in all
+    sessions: Mutex<(Vec<Session>, usize)>,
+    returned: Condvar,
+}
+
+impl Pool {
+    fn take(&self) -> io::Result<Session> {
+        let mut sessions = self.sessions.lock().unwrap();
+        loop {
+            if let Some(session) = sessions.0.pop() {
+                return Ok(session);
+            }
+            if sessions.1 < self.size {
+                sessions.1 += 1;
+                drop(sessions);
+                let connected = Session::connect(&self.ssh, &self.destination, self.port.as_deref());
+                if connected.is_err() {
+                    self.sessions.lock().unwrap().1 -= 1;
+                }
+                return connected;
+            }
+            sessions = self.returned.wait(sessions).unwrap();
+        }
+    }
+
+    // A session that failed mid-request is dropped rather than returned, its
+    // replies no longer in step with its requests.
+    fn give_back(&self, session: Session, healthy: bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if healthy {
+            sessions.0.push(session);
+        } else {
+            sessions.1 -= 1;
+        }
+        self.returned.notify_one();
+    }
+}
+
+// A directory on a server reachable over SSH, read through the ssh client
+// installed on this machine, with its keys, agent and ~/.ssh/config.
+pub struct Sftp {
+    // sftp://[user@]host[:port]/path as given
+    url: String,
+    // absolute, or relative to the login directory for /~/path
+    root: String,
+    pool: Arc<Pool>,
+}
+
+impl Sftp {
+    pub fn new(url: &str, options: &Options) -> Result<Sftp, String> {
+        let rest = url.strip_prefix("sftp://").ok_or_else(|| format!("{:?} is not an sftp:// URL", url))?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (destination, port) = match authority.rsplit_once(':') {
+            Some((d, p)) if p.parse::<u16>().is_ok() => (d, Some(p.to_string())),
+            _ => (authority, None),
+        };
+        if destination.is_empty() || destination.ends_with('@') {
+            return Err(format!("{:?} names no host", url));
+        }
+        let root = match path.strip_prefix("/~").unwrap_or(path) {
+            "" | "/" if path != "/" => String::from("."),
+            "/" => String::from("/"),
+            p if path.starts_with("/~") => p.trim_matches('/').to_string(),
+            p => p.trim_end_matches('/').to_string(),
+        };
+        let ssh = options.ssh_command.split_whitespace().map(String::from).collect();
+        Ok(Sftp {
+            url: url.trim_end_matches('/').to_string(),
+            root,
+            pool: Arc::new(Pool {
+                ssh,
+                destination: destination.to_string(),
+                port,
+                size: options.connections.max(1),
+                sessions: Mutex::new((Vec::new(), 0)),
+                returned: Condvar::new(),
+            }),
+        })
+    }
+
+    fn server_path(&self, rel: &str) -> String {
+        match (self.root.as_str(), rel) {
+            (root, "") => root.to_string(),
+            ("/", rel) => format!("/{}", rel),
+            (root, rel) => format!("{}/{}", root, rel),
+        }
+    }
+}
+
+impl Remote for Sftp {
+    // Walks the tree depth first on one session. Symlinks are listed, not
+    // followed.
+    fn list(&self) -> io::Result<Vec<Object>> {
+        let mut session = self.pool.take()?;
+        let mut objects = Vec::new();
+        let mut pending = vec![String::new()];
+        let mut listed = Ok(());
+        while let Some(dir) = pending.pop() {
+            let entries = match session.read_dir(&self.server_path(&dir)) {
+                Ok(e) => e,
+                Err(e) => {
+                    listed = Err(io::Error::new(e.kind(), format!("{}: {}", self.server_path(&dir), e)));
+                    break;
+                }
+            };
+            for (name, size, kind) in entries {
+                let key = if dir.is_empty() { name } else { format!("{}/{}", dir, name) };
+                if kind == EntryKind::Dir {
+                    pending.push(key.clone());
+                }
+                objects.push(Object { key, size, etag: None, kind });
+            }
+        }
+        self.pool.give_back(session, listed.as_ref().map_or_else(in_step, |_| true));
+        listed.map(|_| objects)
+    }
+
+    fn open(&self, tgt_path: &str) -> io::Result<Box<dyn Read + Send>> {
+        let rel = tgt_path
+            .strip_prefix(&self.url)
+            .map(|r| r.trim_start_matches('/'))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?} is not under {}", tgt_path, self.url)))?;
+        let mut session = self.pool.take()?;
+        match session.handle(FXP_OPEN, &self.server_path(rel), Some(FXF_READ)) {
+            Ok(handle) => Ok(Box::new(RemoteFile { pool: self.pool.clone(), session: Some(session), handle, offset: 0, healthy: true })),
+            Err(e) => {
+                self.pool.give_back(session, in_step(&e));
+                Err(e)
+            }
+        }
+    }
+}
+
+// An open file, holding its session until dropped.
+struct RemoteFile {
+    pool: Arc<Pool>,
+    session: Option<Session>,
+    handle: Vec<u8>,
+    offset: u64,
+    healthy: bool,
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let session = self.session.as_mut().expect("session held until drop");
+        let mut body = Vec::new();
+        put_string(&mut body, &self.handle);
+        body.extend_from_slice(&self.offset.to_be_bytes());
+        body.extend_from_slice(&(buf.len().min(READ_SIZE as usize) as u32).to_be_bytes());
+        let data = match session.request(FXP_READ, &body) {
+            Ok((FXP_DATA, mut payload)) => payload.string(),
+            Ok(_) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        };
+        let data = data.inspect_err(|e| self.healthy = in_step(e))?;
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+impl Drop for RemoteFile {
+    fn drop(&mut self) {
+        if let Some(mut session) = self.session.take() {
+            let closed = self.healthy && session.close(&self.handle).is_ok();
+            self.pool.give_back(session, closed);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use sha2::{Digest, Sha256};
+use crate::report::to_hex;
+
+// Ed25519 signatures, for what others have to be able to check without being
+// able to forge: the secret key stays with whoever signs, and anyone with the
+// public key can check. Key files are text, a line saying what they hold and
+// the key in base64:
+//
+//   backup_auditor ed25519 secret key
+//   BASE64 (PKCS#8)
+//
+//   backup_auditor ed25519 public key
+//   BASE64 (32 bytes)
+const SECRET_MAGIC: &str = "backup_auditor ed25519 secret key";
+const PUBLIC_MAGIC: &str = "backup_auditor ed25519 public key";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+// The base64 line of a key file whose first line is `magic`.
+fn key_line(text: &str, magic: &str) -> io::Result<Vec<u8>> {
+    let mut lines = text.lines();
+    if lines.next() != Some(magic) {
+        return Err(invalid(&format!("not a file starting {:?}", magic)));
+    }
+    let line = lines.next().ok_or_else(|| invalid("no key after the first line"))?;
+    STANDARD.decode(line.trim()).map_err(|e| invalid(&format!("key is not base64: {}", e)))
+}
+
+pub struct SecretKey(Ed25519KeyPair);
+
+impl SecretKey {
+    pub fn load(path: &Path) -> io::Result<SecretKey> {
+        let pkcs8 = key_line(&fs::read_to_string(path)?, SECRET_MAGIC)?;
+        Ed25519KeyPair::from_pkcs8(&pkcs8).map(SecretKey).map_err(|e| invalid(&format!("not an ed25519 key: {}", e)))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut key = [0; 32];
+        key.copy_from_slice(self.0.public_key().as_ref());
+        PublicKey(key)
+    }
+
+    // The signature of `message`, in base64.
+    pub fn sign(&self, message: &[u8]) -> String {
+        STANDARD.encode(self.0.sign(message))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; 32]);
+
+impl PublicKey {
+    pub fn load(path: &Path) -> io::Result<PublicKey> {
+        PublicKey::parse(&fs::read_to_string(path)?)
+    }
+
+    // A public key file's contents.
+    pub fn parse(text: &str) -> io::Result<PublicKey> {
+        let key = key_line(text, PUBLIC_MAGIC)?;
+        key.try_into().map(PublicKey).map_err(|_| invalid("an ed25519 public key is 32 bytes"))
+    }
+
+    // Just the base64 line of one, as built in for checking releases.
+    pub fn from_base64(line: &str) -> io::Result<PublicKey> {
+        PublicKey::parse(&format!("{}\n{}\n", PUBLIC_MAGIC, line))
+    }
+
+    // Names the key in what it signed, so a signature is checked against the
+    // key it claims to be made with.
+    pub fn id(&self) -> String {
+        to_hex(&Sha256::digest(self.0)[..8])
+    }
+
+    // Whether `signature`, in base64, is this key's of `message`.
+    pub fn verify(&self, message: &[u8], signature: &str) -> bool {
+        match STANDARD.decode(signature.trim()) {
+            Ok(sig) => UnparsedPublicKey::new(&signature::ED25519, self.0).verify(message, &sig).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    pub fn to_file(self) -> String {
+        format!("{}\n{}\n", PUBLIC_MAGIC, STANDARD.encode(self.0))
+    }
+}
+
+// A detached signature of some content, as the sign subcommand writes next
+// to a file and a release carries next to each binary:
+//
+//   Signature ed25519 KEYID: BASE64
+//
+// over the content's sha256 in hex, so a large file needn't be held in
+// memory to be signed.
+const DETACHED: &str = "Signature ed25519 ";
+
+pub fn detached_signature(key: &SecretKey, sha256_hex: &str) -> String {
+    format!("{}{}: {}\n", DETACHED, key.public_key().id(), key.sign(sha256_hex.as_bytes()))
+}
+
+// Whether `signature`, a detached one, is `key`'s of content with that
+// sha256; the error says why not.
+pub fn check_detached(key: &PublicKey, sha256_hex: &str, signature: &str) -> Result<(), String> {
+    let (id, signature) = signature.trim().strip_prefix(DETACHED).and_then(|s| s.split_once(": ")).ok_or("not an ed25519 signature")?;
+    if id != key.id() {
+        return Err(format!("signed with key {}, not with {}", id, key.id()));
+    }
+    match key.verify(sha256_hex.as_bytes(), signature) {
+        true => Ok(()),
+        false => Err(String::from("the signature doesn't match")),
+    }
+}
+
+// Makes a new key pair: the secret key in `secret`, readable only by its
+// owner, and the public key in `public`. Neither file is overwritten.
+pub fn generate(secret: &Path, public: &Path) -> io::Result<PublicKey> {
+    let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).map_err(|_| io::Error::other("no random numbers to make a key from"))?;
+    let key = SecretKey(Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).map_err(|e| io::Error::other(e.to_string()))?);
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(secret)?.write_all(format!("{}\n{}\n", SECRET_MAGIC, STANDARD.encode(pkcs8.as_ref())).as_bytes())?;
+    let public_key = key.public_key();
+    OpenOptions::new().write(true).create_new(true).open(public)?.write_all(public_key.to_file().as_bytes())?;
+    Ok(public_key)
+}
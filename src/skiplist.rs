@@ -0,0 +1,76 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use crate::ack::relative_key;
+use crate::audit;
+use crate::report::Finding;
+
+#[derive(Clone, Copy)]
+pub enum SkipListFormat {
+    // --exclude-from: one pattern per line, anchored at the transfer root
+    Rsync,
+    // --exclude-from / --patterns-from: pf: path prefixes, absolute
+    Borg,
+}
+
+impl SkipListFormat {
+    pub fn parse(name: &str) -> Option<SkipListFormat> {
+        match name {
+            "rsync" => Some(SkipListFormat::Rsync),
+            "borg" => Some(SkipListFormat::Borg),
+            _ => None,
+        }
+    }
+}
+
+// The source path of a finding that says the source couldn't be read
+// (permissions, I/O errors, over-long names). Entries that simply aren't there
+// are left out: the backup tool won't trip over those.
+pub fn unreadable_source(finding: &Finding) -> Option<&str> {
+    match finding {
+        Finding::MissingInSource { src, reason, .. } if reason.kind() != io::ErrorKind::NotFound => Some(src),
+        Finding::MissingInBoth { src, src_reason, .. } if src_reason.kind() != io::ErrorKind::NotFound => Some(src),
+        Finding::PathTooLong { side: "src", path, .. } => Some(path),
+        Finding::Error { src, operation: audit::LISTING, .. } => Some(src),
+        _ => None,
+    }
+}
+
+// rsync treats a pattern with any of these as a wildcard; escaped, they match
+// themselves.
+fn rsync_escape(rel: &str) -> String {
+    let mut out = String::with_capacity(rel.len());
+    for c in rel.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+// Writes `paths` (under `source_dir`) for the backup tool to skip. Returns how
+// many were left out because the format can't express them: names with a
+// newline for both, and for borg names ending in whitespace, which it strips
+// from pattern lines.
+pub fn write(file: &Path, format: SkipListFormat, source_dir: &str, paths: &[String]) -> io::Result<usize> {
+    let mut out = BufWriter::new(File::create(file)?);
+    let root = Path::new(source_dir).canonicalize().unwrap_or_else(|_| source_dir.into());
+    let mut left_out = 0;
+    for path in paths {
+        let rel = match relative_key(source_dir, path) {
+            Some(rel) if !rel.is_empty() && !rel.contains(['\n', '\r']) => rel,
+            _ => {
+                left_out += 1;
+                continue;
+            }
+        };
+        match format {
+            SkipListFormat::Rsync => writeln!(out, "/{}", rsync_escape(&rel))?,
+            SkipListFormat::Borg if rel.trim_end() != rel => left_out += 1,
+            SkipListFormat::Borg => writeln!(out, "pf:{}", root.join(&rel).display())?,
+        }
+    }
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(left_out)
+}
@@ -0,0 +1,243 @@
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::time::{Duration, Instant};
+use serde_json::Value;
+
+// Results kept in a SQLite database (--format sqlite), written and read with
+// the sqlite3 command-line tool. Each run adds a row to `runs` when it starts
+// and fills in the rest when it finishes, so a run without `finished` was
+// interrupted; its findings up to the last committed batch are there all the
+// same. The database is in WAL mode, so a crash at any point leaves it as it
+// was after that batch.
+pub const SCHEMA_VERSION: i64 = 1;
+
+// findings per transaction, and how long one is left open for at most
+const BATCH_FINDINGS: usize = 500;
+const BATCH_AGE: Duration = Duration::from_secs(2);
+
+const SCHEMA: &str = "
+BEGIN;
+CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL);
+INSERT INTO schema_version SELECT 1 WHERE NOT EXISTS (SELECT * FROM schema_version);
+CREATE TABLE IF NOT EXISTS runs (
+    run_id TEXT PRIMARY KEY,
+    source TEXT NOT NULL,
+    target TEXT NOT NULL,
+    started TEXT NOT NULL,
+    finished TEXT,
+    files_verified INTEGER,
+    bytes_verified INTEGER,
+    stopped_early INTEGER
+);
+CREATE TABLE IF NOT EXISTS findings (
+    run_id TEXT NOT NULL REFERENCES runs (run_id),
+    id TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    side TEXT NOT NULL,
+    path TEXT NOT NULL,
+    -- path relative to the root of its side
+    rel TEXT NOT NULL,
+    -- of the file the finding is about, where it could be found out
+    size INTEGER,
+    -- the finding as --format json writes it
+    object TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS findings_by_run ON findings (run_id, kind);
+COMMIT;
+";
+
+// A finding as it goes into the database.
+pub struct Row<'a> {
+    pub run_id: &'a str,
+    pub id: &'a str,
+    pub kind: &'a str,
+    pub side: &'a str,
+    pub path: &'a str,
+    pub rel: &'a str,
+    pub size: Option<u64>,
+    pub object: &'a Value,
+}
+
+pub struct Writer {
+    child: Child,
+    stdin: Option<BufWriter<ChildStdin>>,
+    // findings in the open transaction and when it was begun
+    batch: usize,
+    begun: Option<Instant>,
+}
+
+// As an SQL string literal.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+fn integer(n: Option<u64>) -> String {
+    n.map(|n| n.to_string()).unwrap_or_else(|| String::from("NULL"))
+}
+
+// Runs one statement and returns its rows as JSON objects by column name.
+pub fn query(path: &Path, sql: &str) -> io::Result<Vec<Value>> {
+    let out = Command::new("sqlite3")
+        .args(["-batch", "-bail", "-readonly", "-json"])
+        .arg(path)
+        .arg(sql)
+        .output()
+        .map_err(|e| io::Error::new(e.kind(), format!("sqlite3: {}", e)))?;
+    if !out.status.success() {
+        return Err(io::Error::other(format!("sqlite3 failed: {}", String::from_utf8_lossy(&out.stderr).trim())));
+    }
+    // nothing at all when there are no rows
+    if out.stdout.iter().all(u8::is_ascii_whitespace) {
+        return Ok(Vec::new());
+    }
+    serde_json::from_slice(&out.stdout).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("sqlite3 printed something other than JSON: {}", e)))
+}
+
+// The schema version of the database at `path`; None when it has no tables
+// yet. One of something else, or of a later version, isn't written to.
+pub fn schema_version(path: &Path) -> io::Result<Option<i64>> {
+    if path.metadata().map(|m| m.len() == 0).unwrap_or(true) {
+        return Ok(None);
+    }
+    let tables = query(path, "SELECT name FROM sqlite_master WHERE type = 'table'")?;
+    if tables.is_empty() {
+        return Ok(None);
+    }
+    if !tables.iter().any(|t| t["name"] == "schema_version") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{:?} is not a backup_auditor results database", path)));
+    }
+    let rows = query(path, "SELECT max(version) AS version FROM schema_version")?;
+    match rows.first().and_then(|r| r["version"].as_i64()) {
+        Some(v) if v > SCHEMA_VERSION => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{:?} has schema version {}, newer than the {} this version knows", path, v, SCHEMA_VERSION),
+        )),
+        v => Ok(v),
+    }
+}
+
+impl Writer {
+    pub fn open(path: &Path) -> io::Result<Writer> {
+        schema_version(path)?;
+        let mut child = Command::new("sqlite3")
+            .args(["-batch", "-bail"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("sqlite3: {}", e)))?;
+        let stdin = child.stdin.take().map(BufWriter::new);
+        let mut writer = Writer { child, stdin, batch: 0, begun: None };
+        // FULL makes each commit durable, not just consistent
+        writer.send("PRAGMA journal_mode = WAL;\nPRAGMA synchronous = FULL;\nPRAGMA busy_timeout = 10000;\n")?;
+        writer.send(SCHEMA)?;
+        writer.flush()?;
+        Ok(writer)
+    }
+
+    fn send(&mut self, sql: &str) -> io::Result<()> {
+        let result = match &mut self.stdin {
+            Some(stdin) => stdin.write_all(sql.as_bytes()),
+            None => Err(io::Error::from(io::ErrorKind::BrokenPipe)),
+        };
+        result.map_err(|e| self.failed(e))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let result = match &mut self.stdin {
+            Some(stdin) => stdin.flush(),
+            None => Ok(()),
+        };
+        result.map_err(|e| self.failed(e))
+    }
+
+    // sqlite3 stops at the first statement that fails, which closes the pipe;
+    // what it said about it explains that better than the broken pipe does.
+    fn failed(&mut self, e: io::Error) -> io::Error {
+        self.stdin = None;
+        let mut stderr = String::new();
+        if let Some(mut err) = self.child.stderr.take() {
+            let _ = err.read_to_string(&mut stderr);
+        }
+        let _ = self.child.wait();
+        match stderr.trim() {
+            "" => e,
+            said => io::Error::other(format!("sqlite3 failed: {}", said)),
+        }
+    }
+
+    pub fn start_run(&mut self, run_id: &str, source: &str, target: &str, started: &str) -> io::Result<()> {
+        self.send(&format!(
+            "INSERT INTO runs (run_id, source, target, started) VALUES ({}, {}, {}, {});\n",
+            quote(run_id),
+            quote(source),
+            quote(target),
+            quote(started),
+        ))?;
+        self.flush()
+    }
+
+    pub fn insert(&mut self, row: &Row) -> io::Result<()> {
+        if self.begun.is_none() {
+            self.send("BEGIN;\n")?;
+            self.begun = Some(Instant::now());
+        }
+        self.send(&format!(
+            "INSERT INTO findings VALUES ({}, {}, {}, {}, {}, {}, {}, {});\n",
+            quote(row.run_id),
+            quote(row.id),
+            quote(row.kind),
+            quote(row.side),
+            quote(row.path),
+            quote(row.rel),
+            integer(row.size),
+            quote(&row.object.to_string()),
+        ))?;
+        self.batch += 1;
+        self.commit_if_due()
+    }
+
+    // Commits the open transaction once it is full or has been open a while,
+    // so findings don't wait long to be queryable from elsewhere.
+    pub fn commit_if_due(&mut self) -> io::Result<()> {
+        match self.begun {
+            Some(begun) if self.batch >= BATCH_FINDINGS || begun.elapsed() >= BATCH_AGE => self.commit(),
+            _ => Ok(()),
+        }
+    }
+
+    fn commit(&mut self) -> io::Result<()> {
+        if self.begun.take().is_some() {
+            self.batch = 0;
+            self.send("COMMIT;\n")?;
+        }
+        self.flush()
+    }
+
+    // Commits the last batch, completes the run's row and waits for sqlite3
+    // to have written it all.
+    pub fn finish(&mut self, run_id: &str, finished: &str, files_verified: u64, bytes_verified: u64, stopped_early: bool) -> io::Result<()> {
+        self.commit()?;
+        self.send(&format!(
+            "UPDATE runs SET finished = {}, files_verified = {}, bytes_verified = {}, stopped_early = {} WHERE run_id = {};\n",
+            quote(finished),
+            files_verified,
+            bytes_verified,
+            stopped_early as u8,
+            quote(run_id),
+        ))?;
+        self.send("PRAGMA wal_checkpoint(TRUNCATE);\n")?;
+        self.flush()?;
+        self.stdin = None;
+        let mut stderr = String::new();
+        if let Some(mut err) = self.child.stderr.take() {
+            err.read_to_string(&mut stderr)?;
+        }
+        match self.child.wait()? {
+            status if status.success() => Ok(()),
+            status => Err(io::Error::other(format!("sqlite3 failed ({}): {}", status, stderr.trim()))),
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs;
+use std::io;
+use std::time::{Duration, SystemTime};
+use globset::{Glob, GlobMatcher};
+use crate::ack::relative_key;
+use crate::cache::VerifyCache;
+use crate::hash::{HashSpec, Sample};
+
+// How one file pair is verified, picked per file by the policies.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    // every byte of both sides
+    Full,
+    // the --sample ranges of both sides
+    Sample,
+    // size and modification time only
+    Quick,
+}
+
+impl Method {
+    pub fn parse(name: &str) -> Option<Method> {
+        match name {
+            "full" => Some(Method::Full),
+            "sample" => Some(Method::Sample),
+            "quick" => Some(Method::Quick),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Method::Full => "full",
+            Method::Sample => "sample",
+            Method::Quick => "quick",
+        }
+    }
+}
+
+enum Condition {
+    // matched against the path relative to the source root; `*` also crosses
+    // directories
+    Path(GlobMatcher),
+    SizeAtLeast(u64),
+    SizeBelow(u64),
+    // the source not modified for at least this long
+    Cold(Duration),
+    // hashed in full this recently and unchanged since, per --verify-cache
+    VerifiedWithin(Duration),
+}
+
+pub struct Policy {
+    method: Method,
+    conditions: Vec<Condition>,
+}
+
+// What Method::Sample reads without --sample.
+pub const DEFAULT_SAMPLE: Sample = Sample { block: 1 << 20, stride: 64 << 20 };
+
+// Used by --adaptive: small files are cheap enough to always read in full,
+// pairs recently verified and unchanged since are left at that, and huge
+// files nobody has touched in a month are sampled.
+pub const DEFAULT_POLICIES: &str = "\
+full size<64M
+quick verified-within=7d
+sample size>=1G cold=30d
+full
+";
+
+// One policy per line: METHOD (full, sample or quick) and any of path=GLOB,
+// size>=SIZE, size<SIZE, cold=DURATION and verified-within=DURATION, all of
+// which must hold for it to apply. Blank lines and lines starting with # are
+// ignored.
+pub fn parse_policies(text: &str, parse_size: impl Fn(&str) -> Result<u64, String>) -> Result<Vec<Policy>, String> {
+    let mut policies = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |msg: &str| format!("line {}: {}", n + 1, msg);
+        let mut fields = line.split_whitespace();
+        let method = fields.next().and_then(Method::parse).ok_or_else(|| err("expected full, sample or quick first"))?;
+        let duration = |d: &str| humantime::parse_duration(d).map_err(|e| err(&format!("{:?}: {}", d, e)));
+        let conditions = fields
+            .map(|condition| {
+                if let Some(glob) = condition.strip_prefix("path=") {
+                    return Glob::new(glob).map(|g| Condition::Path(g.compile_matcher())).map_err(|e| err(&e.to_string()));
+                }
+                if let Some(size) = condition.strip_prefix("size>=") {
+                    return parse_size(size).map(Condition::SizeAtLeast).map_err(|e| err(&e));
+                }
+                if let Some(size) = condition.strip_prefix("size<") {
+                    return parse_size(size).map(Condition::SizeBelow).map_err(|e| err(&e));
+                }
+                if let Some(d) = condition.strip_prefix("cold=") {
+                    return duration(d).map(Condition::Cold);
+                }
+                if let Some(d) = condition.strip_prefix("verified-within=") {
+                    return duration(d).map(Condition::VerifiedWithin);
+                }
+                Err(err(&format!("unknown condition {:?}; expected path=, size>=, size<, cold= or verified-within=", condition)))
+            })
+            .collect::<Result<_, _>>()?;
+        policies.push(Policy { method, conditions });
+    }
+    Ok(policies)
+}
+
+// Picks the method for each file from the first policy that applies to it,
+// the full hash when none does, and keeps the cache of pairs verified in full.
+pub struct Strategy {
+    source_dir: String,
+    policies: Vec<Policy>,
+    cache: Option<VerifyCache>,
+    // what Method::Sample hashes with
+    pub sampled: HashSpec,
+}
+
+impl Strategy {
+    pub fn new(source_dir: &str, policies: Vec<Policy>, cache: Option<VerifyCache>, sampled: HashSpec) -> Strategy {
+        Strategy { source_dir: source_dir.to_string(), policies, cache, sampled }
+    }
+
+    fn applies(&self, condition: &Condition, rel: &str, src: &fs::Metadata, tgt: &fs::Metadata) -> bool {
+        match condition {
+            Condition::Path(glob) => glob.is_match(rel),
+            Condition::SizeAtLeast(size) => src.len() >= *size,
+            Condition::SizeBelow(size) => src.len() < *size,
+            Condition::Cold(age) => src.modified().ok().and_then(|t| SystemTime::now().duration_since(t).ok()).is_some_and(|a| a >= *age),
+            Condition::VerifiedWithin(age) => self.cache.as_ref().and_then(|c| c.verified_ago(rel, src, tgt)).is_some_and(|a| a <= *age),
+        }
+    }
+
+    pub fn choose(&self, src_path: &str, src: &fs::Metadata, tgt: &fs::Metadata) -> Method {
+        let rel = relative_key(&self.source_dir, src_path).unwrap_or_default();
+        self.policies
+            .iter()
+            .find(|p| p.conditions.iter().all(|c| self.applies(c, &rel, src, tgt)))
+            .map(|p| p.method)
+            .unwrap_or(Method::Full)
+    }
+
+    // A pair whose full hashes matched; the cache remembers it as it is now.
+    pub fn verified(&self, src_path: &str, src: &fs::Metadata, tgt: &fs::Metadata) {
+        if let (Some(cache), Some(rel)) = (&self.cache, relative_key(&self.source_dir, src_path)) {
+            cache.record(&rel, src, tgt);
+        }
+    }
+
+    // A pair found to differ is verified in full again before it's trusted.
+    pub fn failed(&self, src_path: &str) {
+        if let (Some(cache), Some(rel)) = (&self.cache, relative_key(&self.source_dir, src_path)) {
+            cache.forget(&rel);
+        }
+    }
+
+    pub fn save_cache(&self) -> Option<io::Result<usize>> {
+        self.cache.as_ref().map(VerifyCache::save)
+    }
+}
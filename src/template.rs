@@ -0,0 +1,64 @@
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+// A saved audit for --template: its command line arguments, an option and
+// its value per line, with {{name}} placeholders so one file serves many
+// datasets and machines:
+//
+//   # nightly audit of one dataset
+//   -s /tank/{{dataset}}
+//   -t /backup/{{hostname}}/{{dataset}}
+//   -o /var/log/audit/{{dataset}}-{{date}}.txt
+//   --check-metadata
+//
+// A line is split at its first run of whitespace only, so values may contain
+// spaces; a line not starting with '-' is one positional argument. Blank
+// lines and lines starting with '#' are ignored.
+
+// The variables every template has; --var can override them.
+pub fn builtin_vars() -> BTreeMap<String, String> {
+    let now = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    BTreeMap::from([
+        // UTC, like the report's timestamps
+        ("date".to_string(), now[..10].to_string()),
+        ("hostname".to_string(), gethostname::gethostname().to_string_lossy().into_owned()),
+    ])
+}
+
+// Replaces each {{name}} in `text`; a name with no value is an error rather
+// than an empty path component.
+pub fn substitute(text: &str, vars: &BTreeMap<String, String>) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").ok_or_else(|| format!("unclosed {{{{ in {:?}", text))?;
+        let name = rest[start + 2..start + end].trim();
+        match vars.get(name) {
+            Some(value) => out.push_str(value),
+            None => return Err(format!("no value for {{{{{}}}}}; pass --var {}=VALUE", name, name)),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+pub fn arguments(text: &str, vars: &BTreeMap<String, String>) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = substitute(line, vars).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        match line.split_once(char::is_whitespace) {
+            Some((option, value)) if line.starts_with('-') => {
+                args.push(option.to_string());
+                args.push(value.trim_start().to_string());
+            }
+            _ => args.push(line),
+        }
+    }
+    Ok(args)
+}
@@ -0,0 +1,134 @@
+use std::io::{self, Read};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+// Reads measured between limit adjustments.
+const WINDOW: u32 = 64;
+
+struct State {
+    limit: usize,
+    in_flight: usize,
+    // exponentially weighted read latency, in seconds
+    latency: f64,
+    samples: u32,
+    lowest_limit: usize,
+}
+
+// Keeps the read latency seen on one root under a target by adjusting how many
+// files are hashed from it at once: one more per window spent under target,
+// a quarter fewer per window over it (AIMD, as TCP does for the same tradeoff).
+// SSDs end up near the worker count, a single spinning disk near one.
+pub struct LatencyController {
+    target: Duration,
+    max: usize,
+    state: Mutex<State>,
+    freed: Condvar,
+}
+
+pub struct Permit<'a>(&'a LatencyController);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.0.state.lock().unwrap().in_flight -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+impl LatencyController {
+    pub fn new(target: Duration, max: usize) -> LatencyController {
+        let max = max.max(1);
+        LatencyController {
+            target,
+            max,
+            state: Mutex::new(State { limit: max.min(2), in_flight: 0, latency: 0.0, samples: 0, lowest_limit: max }),
+            freed: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.in_flight >= state.limit {
+            state = self.freed.wait(state).unwrap();
+        }
+        state.in_flight += 1;
+        Permit(self)
+    }
+
+    fn record(&self, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.latency = if state.samples == 0 && state.latency == 0.0 {
+            latency.as_secs_f64()
+        } else {
+            state.latency * 0.9 + latency.as_secs_f64() * 0.1
+        };
+        state.samples += 1;
+        if state.samples < WINDOW {
+            return;
+        }
+        state.samples = 0;
+        if state.latency > self.target.as_secs_f64() {
+            state.limit = (state.limit * 3 / 4).max(1);
+            state.lowest_limit = state.lowest_limit.min(state.limit);
+        } else if state.limit < self.max {
+            state.limit += 1;
+            self.freed.notify_one();
+        }
+    }
+
+    // Current limit, lowest limit reached, smoothed latency.
+    pub fn status(&self) -> (usize, usize, Duration) {
+        let state = self.state.lock().unwrap();
+        (state.limit, state.lowest_limit.min(state.limit), Duration::from_secs_f64(state.latency))
+    }
+}
+
+pub struct Timed<'a, R> {
+    pub inner: R,
+    pub controller: &'a LatencyController,
+}
+
+impl<R: Read> Read for Timed<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let n = self.inner.read(buf)?;
+        self.controller.record(started.elapsed());
+        Ok(n)
+    }
+}
+
+// A fixed cap on files read at once across both roots, for disks that seek
+// badly under parallel reads whatever the latency says.
+pub struct ReadLimit {
+    max: usize,
+    in_flight: Mutex<usize>,
+    freed: Condvar,
+}
+
+pub struct ReadSlot<'a>(&'a ReadLimit);
+
+impl Drop for ReadSlot<'_> {
+    fn drop(&mut self) {
+        *self.0.in_flight.lock().unwrap() -= 1;
+        self.0.freed.notify_one();
+    }
+}
+
+impl ReadLimit {
+    pub fn new(max: usize) -> ReadLimit {
+        ReadLimit { max: max.max(1), in_flight: Mutex::new(0), freed: Condvar::new() }
+    }
+
+    pub fn acquire(&self) -> ReadSlot<'_> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        while *in_flight >= self.max {
+            in_flight = self.freed.wait(in_flight).unwrap();
+        }
+        *in_flight += 1;
+        ReadSlot(self)
+    }
+}
+
+pub struct IoControl {
+    pub src: LatencyController,
+    pub tgt: LatencyController,
+}
@@ -0,0 +1,229 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use serde_json::Value;
+use crate::ack::relative_key;
+
+// The request that opens a ticket, for --ticket-template: a request line, the
+// headers, a blank line and the body, with {name} placeholders for the group
+// the ticket is about and {env:NAME} for secrets kept out of the file:
+//
+//   POST https://gitlab.example.com/api/v4/projects/42/issues
+//   PRIVATE-TOKEN: {env:GITLAB_TOKEN}
+//   Content-Type: application/json
+//
+//   {"title": "{title}", "description": "{description}", "labels": "backup"}
+//
+// Values in the body are escaped as in a JSON string, quotes left to the
+// template, since that is what issue trackers' APIs take. The names are
+// title, description, group, count, run_id, source and target.
+pub struct Template {
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+// differing files it takes to open a ticket unless told otherwise
+pub const DEFAULT_MAX_MISMATCHES: u64 = 100;
+
+// Findings that merit a ticket of their own, keyed so a later run can tell
+// it's the same problem.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Group {
+    // "missing-subtree:REL" or "mismatches"
+    pub key: String,
+    pub title: String,
+    pub description: String,
+    pub count: u64,
+}
+
+impl Template {
+    pub fn load(path: &Path) -> io::Result<Template> {
+        Template::parse(&fs::read_to_string(path)?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Template, String> {
+        let (head, body) = text.split_once("\n\n").or_else(|| text.split_once("\r\n\r\n")).unwrap_or((text, ""));
+        let mut lines = head.lines();
+        let (method, url) = lines.next().and_then(|l| l.trim().split_once(' ')).ok_or("the first line must be METHOD URL")?;
+        let mut headers = Vec::new();
+        for (n, line) in lines.enumerate() {
+            match line.split_once(':') {
+                Some((name, value)) if !name.trim().is_empty() => headers.push((name.trim().to_string(), value.trim().to_string())),
+                _ => return Err(format!("line {}: expected NAME: VALUE", n + 2)),
+            }
+        }
+        Ok(Template { method: method.to_string(), url: url.trim().to_string(), headers, body: body.to_string() })
+    }
+
+    // Each {env:NAME} must be set now rather than found missing when the
+    // first ticket is due.
+    pub fn check_env(&self) -> Result<(), String> {
+        let texts = [&self.url, &self.body].into_iter().chain(self.headers.iter().map(|(_, v)| v));
+        for text in texts {
+            fill(text, &BTreeMap::new(), false)?;
+        }
+        Ok(())
+    }
+}
+
+// Replaces {name} and {env:NAME}; anything else in braces, like the JSON of
+// a body, is left as it is.
+fn fill(text: &str, values: &BTreeMap<&str, String>, json: bool) -> Result<String, String> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let name = rest[1..].find('}').map(|end| &rest[1..end + 1]).filter(|n| n.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':'));
+        let value = match name {
+            Some(n) if n.starts_with("env:") => Some(env::var(&n[4..]).map_err(|_| format!("${} is not set", &n[4..]))?),
+            Some(n) => values.get(n).cloned(),
+            None => None,
+        };
+        match (name, value) {
+            (Some(n), Some(v)) => {
+                out.push_str(&if json { escape_json(&v) } else { v });
+                rest = &rest[n.len() + 2..];
+            }
+            _ => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+// As inside a JSON string, without the quotes.
+fn escape_json(s: &str) -> String {
+    let quoted = Value::from(s).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+// The groups among a run's findings: a directory missing from the target with
+// everything in it, reported once at its topmost missing directory, and more
+// than `max_mismatches` files differing in content or metadata. Findings the
+// history calls flaky aren't raised as missing, so they open no ticket.
+pub fn groups(source_dir: &str, target_dir: &str, missing_in_target: &[String], mismatches: u64, max_mismatches: u64) -> Vec<Group> {
+    let mut subtrees: BTreeMap<String, u64> = BTreeMap::new();
+    for src in missing_in_target {
+        let rel = match relative_key(source_dir, src) {
+            Some(rel) if !rel.is_empty() => rel,
+            _ => continue,
+        };
+        // the topmost directory above the entry that the target lacks, or the
+        // entry itself when it's a directory
+        let parts: Vec<&str> = rel.split('/').collect();
+        let missing = (1..parts.len())
+            .map(|n| parts[..n].join("/"))
+            .find(|dir| fs::symlink_metadata(Path::new(target_dir).join(dir)).is_err())
+            .or_else(|| Path::new(src).is_dir().then(|| rel.clone()));
+        if let Some(dir) = missing {
+            *subtrees.entry(dir).or_insert(0) += 1;
+        }
+    }
+    let mut groups: Vec<Group> = subtrees
+        .into_iter()
+        .map(|(dir, count)| Group {
+            key: format!("missing-subtree:{}", dir),
+            title: format!("Backup is missing {}/", dir),
+            description: format!("The directory {:?} of {:?} is missing from the backup in {:?}, with {} entries reported missing under it.", dir, source_dir, target_dir, count),
+            count,
+        })
+        .collect();
+    if mismatches > max_mismatches {
+        groups.push(Group {
+            key: String::from("mismatches"),
+            title: format!("Backup differs from the source in {} files", mismatches),
+            description: format!("{} files in {:?} differ from their backup in {:?}, more than the {} allowed before a ticket is opened.", mismatches, source_dir, target_dir, max_mismatches),
+            count: mismatches,
+        });
+    }
+    groups
+}
+
+// The ticket a tracker's reply names: Jira's key, GitLab's web_url, GitHub's
+// html_url, or failing those a url or id.
+fn reference(reply: &Value) -> Option<String> {
+    ["key", "web_url", "html_url", "url", "self"]
+        .iter()
+        .find_map(|name| reply[name].as_str().map(String::from))
+        .or_else(|| reply["id"].as_u64().map(|id| id.to_string()))
+        .or_else(|| reply["iid"].as_u64().map(|id| id.to_string()))
+}
+
+// Opens a ticket for `group`; returns what the tracker calls it.
+pub fn open(template: &Template, group: &Group, run_id: &str, source_dir: &str, target_dir: &str) -> Result<String, String> {
+    let values = BTreeMap::from([
+        ("title", group.title.clone()),
+        ("description", group.description.clone()),
+        ("group", group.key.clone()),
+        ("count", group.count.to_string()),
+        ("run_id", run_id.to_string()),
+        ("source", source_dir.to_string()),
+        ("target", target_dir.to_string()),
+    ]);
+    let url = fill(&template.url, &values, false)?;
+    let headers = template.headers.iter().map(|(name, value)| Ok((name.as_str(), fill(value, &values, false)?))).collect::<Result<Vec<_>, String>>()?;
+    let body = fill(&template.body, &values, true)?;
+    let reply = send(&template.method, &url, &headers, &body)?;
+    Ok(reference(&reply).unwrap_or_else(|| String::from("opened, reply named no ticket")))
+}
+
+#[cfg(feature = "network")]
+fn send(method: &str, url: &str, headers: &[(&str, String)], body: &str) -> Result<Value, String> {
+    let mut request = ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_secs(30))
+        .build()
+        .request(method, url)
+        .set("User-Agent", concat!("backup_auditor/", env!("CARGO_PKG_VERSION")));
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    let response = match request.send_string(body) {
+        Ok(r) => r,
+        Err(ureq::Error::Status(status, r)) => return Err(format!("HTTP {}: {}", status, r.into_string().unwrap_or_default().trim())),
+        Err(e) => return Err(e.to_string()),
+    };
+    let text = response.into_string().map_err(|e| e.to_string())?;
+    Ok(serde_json::from_str(&text).unwrap_or(Value::Null))
+}
+
+// The CLI refuses --ticket-template before it gets here.
+#[cfg(not(feature = "network"))]
+fn send(_method: &str, _url: &str, _headers: &[(&str, String)], _body: &str) -> Result<Value, String> {
+    Err(String::from("built without the \"network\" feature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn template_and_placeholders() {
+        let template = Template::parse("POST https://t.example/issues\nContent-Type: application/json\n\n{\"title\": \"{title}\", \"n\": {count}}\n").unwrap();
+        assert_eq!((template.method.as_str(), template.url.as_str()), ("POST", "https://t.example/issues"));
+        assert_eq!(template.headers, vec![(String::from("Content-Type"), String::from("application/json"))]);
+        let values = BTreeMap::from([("title", String::from("a \"b\"\n")), ("count", String::from("3"))]);
+        assert_eq!(fill(&template.body, &values, true).unwrap(), "{\"title\": \"a \\\"b\\\"\\n\", \"n\": 3}\n");
+        assert!(fill("{env:BACKUP_AUDITOR_TEST_UNSET}", &values, false).is_err());
+    }
+
+    #[test]
+    fn missing_subtrees_group_at_the_top() {
+        let dir = std::env::temp_dir().join(format!("backup_auditor-ticket-{}", std::process::id()));
+        let (src, tgt) = (dir.join("s"), dir.join("t"));
+        fs::create_dir_all(src.join("a/b")).unwrap();
+        fs::create_dir_all(tgt.join("c")).unwrap();
+        let (src, tgt) = (src.display().to_string(), tgt.display().to_string());
+        let missing: Vec<String> = ["a/b/x", "a/y", "c/z"].iter().map(|rel| format!("{}/{}", src, rel)).collect();
+        let groups = groups(&src, &tgt, &missing, 5, 4);
+        assert_eq!(groups.iter().map(|g| (g.key.as_str(), g.count)).collect::<Vec<_>>(), vec![("missing-subtree:a", 2), ("mismatches", 5)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use crate::report::to_hex;
+use crate::signing::{self, PublicKey};
+
+const LATEST_RELEASE_URL: &str = "https://api.github.com/repos/mjsmith707/backup_auditor/releases/latest";
+
+// What a release carries for each target it was built for: the binary and a
+// detached signature of it (see signing::detached_signature), e.g.
+//
+//   backup_auditor-x86_64-unknown-linux-gnu
+//   backup_auditor-x86_64-unknown-linux-gnu.sig
+const ASSET_PREFIX: &str = "backup_auditor-";
+
+// larger than any build, so a wrong asset isn't read into memory for good
+const MAX_BINARY_SIZE: u64 = 256 * 1024 * 1024;
+
+pub enum UpdateStatus {
+    UpToDate,
+    Available { version: String, url: String },
+    NoReleases,
+}
+
+pub enum Updated {
+    UpToDate,
+    NoReleases,
+    Replaced { version: String, path: PathBuf },
+}
+
+struct Release {
+    tag: String,
+    url: String,
+    // name and download URL
+    assets: Vec<(String, String)>,
+}
+
+// The key releases are signed with, when this build was made with one (see
+// BACKUP_AUDITOR_RELEASE_KEY in build.rs).
+pub fn release_key() -> Option<PublicKey> {
+    option_env!("BUILD_RELEASE_KEY").and_then(|k| PublicKey::from_base64(k).ok())
+}
+
+fn parse_version(v: &str) -> Vec<u64> {
+    v.trim_start_matches('v')
+        .split(|c: char| !c.is_ascii_digit())
+        .take(3)
+        .map(|p| p.parse().unwrap_or(0))
+        .collect()
+}
+
+fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(Duration::from_secs(30)).build()
+}
+
+fn latest_release(agent: &ureq::Agent) -> Result<Option<Release>, String> {
+    let response = match agent
+        .get(LATEST_RELEASE_URL)
+        .set("User-Agent", concat!("backup_auditor/", env!("CARGO_PKG_VERSION")))
+        .set("Accept", "application/vnd.github+json")
+        .call()
+    {
+        Ok(r) => r,
+        Err(ureq::Error::Status(404, _)) => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    let body: serde_json::Value = response.into_json().map_err(|e| e.to_string())?;
+    let tag = body["tag_name"].as_str().ok_or("release has no tag_name")?;
+    let assets = body["assets"]
+        .as_array()
+        .map(|assets| {
+            assets
+                .iter()
+                .filter_map(|a| Some((a["name"].as_str()?.to_string(), a["browser_download_url"].as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Some(Release { tag: tag.to_string(), url: body["html_url"].as_str().unwrap_or_default().to_string(), assets }))
+}
+
+fn is_newer(tag: &str) -> bool {
+    parse_version(tag) > parse_version(env!("CARGO_PKG_VERSION"))
+}
+
+pub fn check_update() -> Result<UpdateStatus, String> {
+    match latest_release(&agent())? {
+        None => Ok(UpdateStatus::NoReleases),
+        Some(release) if is_newer(&release.tag) => Ok(UpdateStatus::Available { version: release.tag, url: release.url }),
+        Some(_) => Ok(UpdateStatus::UpToDate),
+    }
+}
+
+fn download(agent: &ureq::Agent, url: &str) -> Result<Vec<u8>, String> {
+    let response = agent.get(url).set("User-Agent", concat!("backup_auditor/", env!("CARGO_PKG_VERSION"))).call().map_err(|e| e.to_string())?;
+    let mut data = Vec::new();
+    response.into_reader().take(MAX_BINARY_SIZE + 1).read_to_end(&mut data).map_err(|e| e.to_string())?;
+    if data.len() as u64 > MAX_BINARY_SIZE {
+        return Err(format!("{} is larger than {} bytes", url, MAX_BINARY_SIZE));
+    }
+    Ok(data)
+}
+
+// Writes the new binary next to the running one and renames it over it, so
+// an update that fails part way leaves the old one as it was.
+fn install(exe: &Path, binary: &[u8]) -> io::Result<()> {
+    let new = exe.with_extension("new");
+    let written = File::create(&new).and_then(|mut file| {
+        file.write_all(binary)?;
+        file.set_permissions(fs::metadata(exe)?.permissions())?;
+        file.sync_all()
+    });
+    match written.and_then(|()| fs::rename(&new, exe)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&new);
+            Err(e)
+        }
+    }
+}
+
+// Replaces the running binary with the latest release's build for this
+// target, and only once its signature by `key` checks out.
+pub fn self_update(key: &PublicKey) -> Result<Updated, String> {
+    let agent = agent();
+    let release = match latest_release(&agent)? {
+        Some(r) if is_newer(&r.tag) => r,
+        Some(_) => return Ok(Updated::UpToDate),
+        None => return Ok(Updated::NoReleases),
+    };
+    let name = format!("{}{}", ASSET_PREFIX, env!("BUILD_TARGET"));
+    let asset = |name: &str| {
+        release.assets.iter().find(|(n, _)| n == name).map(|(_, url)| url.clone()).ok_or_else(|| format!("release {} has no {}", release.tag, name))
+    };
+    let binary = download(&agent, &asset(&name)?)?;
+    let signature = download(&agent, &asset(&format!("{}.sig", name))?)?;
+    let digest = to_hex(&Sha256::digest(&binary));
+    signing::check_detached(key, &digest, &String::from_utf8_lossy(&signature)).map_err(|e| format!("not installing {} of {}: {}", name, release.tag, e))?;
+    let exe = env::current_exe().map_err(|e| e.to_string())?;
+    install(&exe, &binary).map_err(|e| format!("failed to replace {:?}: {}", exe, e))?;
+    Ok(Updated::Replaced { version: release.tag, path: exe })
+}
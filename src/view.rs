@@ -0,0 +1,471 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use serde_json::Value;
+use crate::ack::relative_key;
+
+// A finding of a report (--format json) as the viewer lists it.
+pub struct Item {
+    pub kind: String,
+    // relative to the root of its side, '/' separated; the path as reported
+    // when the report has no summary naming the roots
+    pub rel: String,
+    pub object: Value,
+}
+
+// Browses a report's findings: by kind, by a search of their paths, and
+// either as a tree of the directories they are in or as one flat list.
+pub struct View {
+    pub title: String,
+    items: Vec<Item>,
+    pub kind: Option<String>,
+    pub search: String,
+    pub tree: bool,
+    // the directory the tree shows, relative, "" for the root
+    pub dir: String,
+    selected: usize,
+    top: usize,
+    // the finding whose whole object is shown
+    detail: Option<usize>,
+    // the search before the one being typed, for Esc to go back to
+    typing: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Row {
+    // a subdirectory of the one shown and the findings under it
+    Dir(String, usize),
+    Finding(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Enter,
+    Backspace,
+    Tab,
+    Esc,
+    Quit,
+    Char(char),
+}
+
+// Keys in what the terminal sent, escape sequences for the arrows and the
+// page keys decoded. An Esc on its own is the Esc key.
+pub fn keys(input: &str) -> Vec<Key> {
+    let mut keys = Vec::new();
+    let mut chars = input.chars().peekable();
+    while let Some(c) = chars.next() {
+        let key = match c {
+            '\x1b' => match chars.peek() {
+                Some('[') | Some('O') => {
+                    chars.next();
+                    let mut sequence = String::new();
+                    while let Some(&c) = chars.peek() {
+                        chars.next();
+                        sequence.push(c);
+                        if !c.is_ascii_digit() && c != ';' {
+                            break;
+                        }
+                    }
+                    match sequence.as_str() {
+                        "A" => Key::Up,
+                        "B" => Key::Down,
+                        "C" => Key::Right,
+                        "D" => Key::Left,
+                        "H" | "1~" | "7~" => Key::Home,
+                        "F" | "4~" | "8~" => Key::End,
+                        "5~" => Key::PageUp,
+                        "6~" => Key::PageDown,
+                        _ => continue,
+                    }
+                }
+                _ => Key::Esc,
+            },
+            '\r' | '\n' => Key::Enter,
+            '\t' => Key::Tab,
+            '\x7f' | '\x08' => Key::Backspace,
+            // raw mode turns Ctrl-C into a character
+            '\x03' => Key::Quit,
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        };
+        keys.push(key);
+    }
+    keys
+}
+
+// The findings in `report_file`, of every run in it when it was appended to.
+pub fn load(report_file: &Path) -> io::Result<View> {
+    let mut objects = Vec::new();
+    let mut roots = HashMap::new();
+    for (i, line) in BufReader::new(File::open(report_file)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object: Value = serde_json::from_str(&line).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: not JSON; only reports written with --format json can be viewed", i + 1))
+        })?;
+        match object["type"].as_str() {
+            Some("finding") => objects.push(object),
+            Some("summary") => {
+                let root = |side: &str| object[side].as_str().unwrap_or_default().to_string();
+                roots.insert(object["run_id"].as_str().unwrap_or_default().to_string(), (root("source"), root("target")));
+            }
+            _ => {}
+        }
+    }
+    let items = objects
+        .into_iter()
+        .map(|object| {
+            let path = object["path"].as_str().unwrap_or_default();
+            let root = roots.get(object["run_id"].as_str().unwrap_or_default()).map(|(src, tgt)| match object["side"].as_str() {
+                Some("tgt") => tgt.as_str(),
+                _ => src.as_str(),
+            });
+            let rel = root.and_then(|root| relative_key(root, path)).unwrap_or_else(|| path.trim_start_matches("./").trim_matches('/').to_string());
+            Item { kind: object["kind"].as_str().unwrap_or("unknown").to_string(), rel, object }
+        })
+        .collect();
+    Ok(View::new(report_file.display().to_string(), items))
+}
+
+impl View {
+    pub fn new(title: String, items: Vec<Item>) -> View {
+        View { title, items, kind: None, search: String::new(), tree: true, dir: String::new(), selected: 0, top: 0, detail: None, typing: None }
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    // The findings the search matches, case-insensitively, in their paths.
+    fn searched(&self) -> impl Iterator<Item = (usize, &Item)> {
+        let search = self.search.to_lowercase();
+        self.items.iter().enumerate().filter(move |(_, item)| search.is_empty() || item.rel.to_lowercase().contains(&search))
+    }
+
+    // Those of the kind shown too.
+    pub fn matching(&self) -> impl Iterator<Item = (usize, &Item)> {
+        self.searched().filter(move |(_, item)| self.kind.as_ref().map(|k| *k == item.kind).unwrap_or(true))
+    }
+
+    // Each kind with the number of findings of it the search matches.
+    pub fn counts(&self) -> BTreeMap<&str, usize> {
+        let mut counts = BTreeMap::new();
+        for item in &self.items {
+            counts.entry(item.kind.as_str()).or_insert(0);
+        }
+        for (_, item) in self.searched() {
+            *counts.entry(item.kind.as_str()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn rows(&self) -> Vec<Row> {
+        if !self.tree {
+            return self.matching().map(|(i, _)| Row::Finding(i)).collect();
+        }
+        let mut dirs: BTreeMap<&str, usize> = BTreeMap::new();
+        let mut findings = Vec::new();
+        for (i, item) in self.matching() {
+            let below = match self.dir.as_str() {
+                "" => Some(item.rel.as_str()),
+                dir => item.rel.strip_prefix(dir).and_then(|rest| rest.strip_prefix('/')),
+            };
+            match below.map(|rest| rest.split_once('/')) {
+                Some(Some((name, _))) => *dirs.entry(name).or_insert(0) += 1,
+                Some(None) => findings.push(Row::Finding(i)),
+                None => {}
+            }
+        }
+        dirs.into_iter().map(|(name, n)| Row::Dir(name.to_string(), n)).chain(findings).collect()
+    }
+
+    fn next_kind(&mut self, forward: bool) {
+        let kinds: Vec<String> = self.counts().into_keys().map(String::from).collect();
+        let at = self.kind.as_ref().and_then(|k| kinds.iter().position(|kind| kind == k));
+        // None, the first kind, ..., the last kind, None again
+        self.kind = match (at, forward) {
+            (None, true) => kinds.first().cloned(),
+            (None, false) => kinds.last().cloned(),
+            (Some(i), true) => kinds.get(i + 1).cloned(),
+            (Some(0), false) => None,
+            (Some(i), false) => kinds.get(i - 1).cloned(),
+        };
+        self.selected = 0;
+    }
+
+    fn enter(&mut self, name: &str) {
+        self.dir = match self.dir.as_str() {
+            "" => name.to_string(),
+            dir => format!("{}/{}", dir, name),
+        };
+        self.selected = 0;
+    }
+
+    // Back to the parent directory, with the one left selected.
+    fn leave(&mut self) {
+        if self.dir.is_empty() {
+            return;
+        }
+        let (parent, name) = match self.dir.rsplit_once('/') {
+            Some((parent, name)) => (parent.to_string(), name.to_string()),
+            None => (String::new(), self.dir.clone()),
+        };
+        self.dir = parent;
+        self.selected = self.rows().iter().position(|row| matches!(row, Row::Dir(n, _) if *n == name)).unwrap_or(0);
+    }
+
+    // Acts on a key; false once it's one to quit on.
+    pub fn key(&mut self, key: Key, page: usize) -> bool {
+        if self.detail.is_some() {
+            match key {
+                Key::Quit | Key::Char('q') => return false,
+                _ => self.detail = None,
+            }
+            return true;
+        }
+        if self.typing.is_some() {
+            match key {
+                Key::Enter => self.typing = None,
+                Key::Esc => self.search = self.typing.take().unwrap_or_default(),
+                Key::Backspace => {
+                    self.search.pop();
+                }
+                Key::Char(c) => self.search.push(c),
+                Key::Quit => return false,
+                _ => {}
+            }
+            self.selected = 0;
+            return true;
+        }
+        let rows = self.rows();
+        match key {
+            Key::Quit | Key::Char('q') => return false,
+            Key::Up | Key::Char('k') => self.selected = self.selected.saturating_sub(1),
+            Key::Down | Key::Char('j') => self.selected += 1,
+            Key::PageUp => self.selected = self.selected.saturating_sub(page.max(1)),
+            Key::PageDown => self.selected += page.max(1),
+            Key::Home | Key::Char('g') => self.selected = 0,
+            Key::End | Key::Char('G') => self.selected = rows.len(),
+            Key::Enter | Key::Right | Key::Char('l') => match rows.get(self.selected) {
+                Some(Row::Dir(name, _)) => self.enter(&name.clone()),
+                Some(Row::Finding(i)) => self.detail = Some(*i),
+                None => {}
+            },
+            Key::Left | Key::Backspace | Key::Char('h') => self.leave(),
+            Key::Tab | Key::Char('c') => self.next_kind(true),
+            Key::Char('C') => self.next_kind(false),
+            Key::Char('/') => self.typing = Some(self.search.clone()),
+            Key::Char('t') => {
+                self.tree = !self.tree;
+                self.selected = 0;
+            }
+            Key::Esc => {
+                self.search.clear();
+                self.kind = None;
+                self.selected = 0;
+            }
+            _ => {}
+        }
+        self.selected = self.selected.min(self.rows().len().saturating_sub(1));
+        true
+    }
+
+    // The screen, `height` lines of at most `width` characters each, the
+    // selected row and the current kind in reverse video.
+    pub fn render(&mut self, width: usize, height: usize) -> Vec<String> {
+        let mut lines = vec![fit(&format!("{} — {} findings", self.title, self.items.len()), width)];
+        if let Some(i) = self.detail {
+            let text = serde_json::to_string_pretty(&self.items[i].object).unwrap_or_default();
+            lines.extend(text.lines().map(|l| fit(l, width)).take(height.saturating_sub(2)));
+            lines.resize(height.saturating_sub(1), String::new());
+            lines.push(fit("any key: back   q: quit", width));
+            return lines;
+        }
+
+        let counts = self.counts();
+        let labels = [(None, format!(" all {} ", counts.values().sum::<usize>()))]
+            .into_iter()
+            .chain(counts.iter().map(|(kind, n)| (Some(*kind), format!(" {} {} ", kind, n))));
+        let (mut bar, mut used) = (String::new(), 0);
+        for (kind, label) in labels {
+            let label = fit(&label, width - used.min(width));
+            used += label.chars().count();
+            bar.push_str(&if self.kind.as_deref() == kind { reverse(&label) } else { label });
+        }
+        lines.push(bar);
+        let mut place = match (self.tree, self.dir.as_str()) {
+            (false, _) => String::from("all paths"),
+            (true, "") => String::from("/"),
+            (true, dir) => format!("/{}/", dir),
+        };
+        if !self.search.is_empty() {
+            place.push_str(&format!("   search: {}", self.search));
+        }
+        lines.push(fit(&place, width));
+
+        let rows = self.rows();
+        let shown = height.saturating_sub(4);
+        if self.selected < self.top {
+            self.top = self.selected;
+        } else if shown > 0 && self.selected >= self.top + shown {
+            self.top = self.selected + 1 - shown;
+        }
+        for (n, row) in rows.iter().enumerate().skip(self.top).take(shown) {
+            let line = match row {
+                Row::Dir(name, count) => format!("  {}/  ({})", name, count),
+                Row::Finding(i) => {
+                    let item = &self.items[*i];
+                    let name = match self.tree {
+                        true => item.rel.rsplit('/').next().filter(|n| !n.is_empty()).unwrap_or("."),
+                        false => item.rel.as_str(),
+                    };
+                    format!("  {:<22} {}", item.kind, name)
+                }
+            };
+            let line = fit(&line, width);
+            lines.push(if n == self.selected { reverse(&format!("{:<width$}", line, width = width)) } else { line });
+        }
+        if rows.is_empty() {
+            lines.push(String::from("  (no findings)"));
+        }
+        lines.resize(height.saturating_sub(1), String::new());
+        lines.push(match &self.typing {
+            Some(_) => fit(&format!("search: {}_   Enter: keep   Esc: cancel", self.search), width),
+            None => fit("↑↓ move  → open  ← up  Tab/c kind  / search  t tree/flat  Esc clear  q quit", width),
+        });
+        lines
+    }
+}
+
+fn fit(s: &str, width: usize) -> String {
+    s.chars().take(width).collect()
+}
+
+fn reverse(s: &str) -> String {
+    format!("\x1b[7m{}\x1b[0m", s)
+}
+
+// Runs the viewer on the terminal until a key to quit on: raw input, on the
+// alternate screen so what was there before is back afterwards.
+#[cfg(unix)]
+pub fn run(view: &mut View) -> io::Result<()> {
+    use std::io::{Read, Write};
+    let terminal = Terminal::raw()?;
+    let mut stdout = io::stdout();
+    let mut size = (0, 0);
+    let mut redraw = true;
+    loop {
+        let now = terminal_size::terminal_size().map(|(w, h)| (w.0 as usize, h.0 as usize)).unwrap_or((80, 24));
+        if redraw || now != size {
+            size = now;
+            let lines = view.render(size.0, size.1);
+            write!(stdout, "\x1b[H\x1b[2J{}", lines.join("\r\n"))?;
+            stdout.flush()?;
+        }
+        let mut buf = [0; 64];
+        // returns with nothing after a tenth of a second, to notice resizes
+        let n = io::stdin().read(&mut buf)?;
+        redraw = n > 0;
+        for key in keys(&String::from_utf8_lossy(&buf[..n])) {
+            if !view.key(key, size.1.saturating_sub(5)) {
+                drop(terminal);
+                return Ok(());
+            }
+        }
+    }
+}
+
+// The terminal in raw mode until dropped.
+#[cfg(unix)]
+struct Terminal {
+    saved: libc::termios,
+}
+
+#[cfg(unix)]
+impl Terminal {
+    fn raw() -> io::Result<Terminal> {
+        use std::io::Write;
+        let mut saved = std::mem::MaybeUninit::<libc::termios>::uninit();
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, saved.as_mut_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = unsafe { saved.assume_init() };
+        let mut raw = saved;
+        unsafe { libc::cfmakeraw(&mut raw) };
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 1;
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // the alternate screen, without a cursor
+        print!("\x1b[?1049h\x1b[?25l");
+        io::stdout().flush()?;
+        Ok(Terminal { saved })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        use std::io::Write;
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.saved) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn view(paths: &[(&str, &str)]) -> View {
+        let items = paths.iter().map(|(kind, rel)| Item { kind: kind.to_string(), rel: rel.to_string(), object: json!({ "kind": kind }) }).collect();
+        View::new(String::from("report"), items)
+    }
+
+    #[test]
+    fn tree_groups_by_directory() {
+        let mut v = view(&[("hash_mismatch", "a/b/c.txt"), ("missing_in_target", "a/d.txt"), ("hash_mismatch", "e.txt")]);
+        assert_eq!(v.rows(), vec![Row::Dir(String::from("a"), 2), Row::Finding(2)]);
+        assert!(v.key(Key::Enter, 10));
+        assert_eq!(v.dir, "a");
+        assert_eq!(v.rows(), vec![Row::Dir(String::from("b"), 1), Row::Finding(1)]);
+        v.key(Key::Left, 10);
+        assert_eq!(v.dir, "");
+        assert_eq!(v.rows()[v.selected], Row::Dir(String::from("a"), 2));
+    }
+
+    #[test]
+    fn kind_and_search_filter() {
+        let mut v = view(&[("hash_mismatch", "a/b/c.txt"), ("missing_in_target", "a/d.txt"), ("hash_mismatch", "e.txt")]);
+        v.key(Key::Char('t'), 10);
+        v.key(Key::Tab, 10);
+        assert_eq!(v.kind.as_deref(), Some("hash_mismatch"));
+        assert_eq!(v.rows(), vec![Row::Finding(0), Row::Finding(2)]);
+        for key in keys("/C.TXT\r") {
+            v.key(key, 10);
+        }
+        assert_eq!(v.rows(), vec![Row::Finding(0)]);
+        assert_eq!(v.counts().get("missing_in_target"), Some(&0));
+        v.key(Key::Esc, 10);
+        assert_eq!(v.rows().len(), 3);
+    }
+
+    #[test]
+    fn escape_sequences() {
+        assert_eq!(keys("\x1b[A\x1b[B\x1bOC\x1b[5~x\x1b"), vec![Key::Up, Key::Down, Key::Right, Key::PageUp, Key::Char('x'), Key::Esc]);
+        assert_eq!(keys("\x03\x7f\r"), vec![Key::Quit, Key::Backspace, Key::Enter]);
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use notify::event::{Event, EventKind, ModifyKind, RenameMode};
+use notify::{RecursiveMode, Watcher};
+
+// Paths that may have new content. Removals and the old side of renames have
+// nothing left to verify; metadata-only changes don't alter the hash.
+fn changed_paths(event: Event) -> Vec<PathBuf> {
+    match event.kind {
+        EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Any)
+        | EventKind::Modify(ModifyKind::Data(_))
+        | EventKind::Modify(ModifyKind::Other)
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        | EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => event.paths,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => event.paths.into_iter().skip(1).collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Subscribes to changes under `root` and hands batches of changed paths to
+// `verify` once each has been quiet for `delay`, which is the time the backup
+// tool gets to copy it. A path that changes again while waiting starts over.
+pub fn watch(root: &Path, delay: Duration, mut verify: impl FnMut(Vec<PathBuf>)) -> notify::Result<()> {
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    // events may name paths through the canonical root; map them back so the
+    // caller sees the root it asked for
+    let canonical = root.canonicalize()?;
+    let under_root = |path: PathBuf| match path.strip_prefix(&canonical) {
+        Ok(rel) if !path.starts_with(root) => root.join(rel),
+        _ => path,
+    };
+
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    loop {
+        let event = match pending.values().min() {
+            Some(due) => rx.recv_timeout(due.saturating_duration_since(Instant::now())),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Ok(Ok(event)) => {
+                if event.need_rescan() {
+                    eprintln!("Filesystem events were dropped; changes made meanwhile may go unverified");
+                }
+                for path in changed_paths(event) {
+                    pending.insert(under_root(path), Instant::now() + delay);
+                }
+            }
+            Ok(Err(e)) => eprintln!("Watch error: {}", e),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+
+        let now = Instant::now();
+        let due: Vec<PathBuf> = pending.iter().filter(|(_, d)| **d <= now).map(|(p, _)| p.clone()).collect();
+        if !due.is_empty() {
+            for path in &due {
+                pending.remove(path);
+            }
+            verify(due);
+        }
+    }
+}
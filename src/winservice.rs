@@ -0,0 +1,405 @@
+use std::env;
+use std::ffi::c_void;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::schedule::Schedule;
+
+pub const DEFAULT_NAME: &str = "BackupAuditor";
+
+// A service that runs an audit on a schedule, installed with sc.exe to start
+// with Windows and run as LocalSystem:
+//
+//   backup_auditor.exe service run --name NAME --at HH:MM --dir DIR -- AUDIT OPTIONS
+//
+// The audit runs as a child process in DIR with its output appended to
+// %ProgramData%\backup_auditor\NAME.log, and how it went is reported to the
+// Application event log under NAME: event 1 when the trees match, 2 (a
+// warning) when they differ, 3 (an error) when the audit failed to run or
+// exited with a read or usage error. The messages are EventCreate.exe's, so
+// no message DLL of our own has to be registered.
+pub struct Service {
+    pub name: String,
+    pub schedule: Schedule,
+    // the audit's working directory, as services start in System32
+    pub working_dir: String,
+    pub args: Vec<String>,
+}
+
+pub const EVENT_MATCHED: u32 = 1;
+pub const EVENT_DIFFERENT: u32 = 2;
+pub const EVENT_FAILED: u32 = 3;
+
+// how often the schedule is looked at; well under the minute it names, and
+// unfazed by the clock changing
+const TICK: Duration = Duration::from_secs(20);
+
+type Handle = *mut c_void;
+
+#[repr(C)]
+struct ServiceTableEntry {
+    name: *mut u16,
+    main: Option<unsafe extern "system" fn(u32, *mut *mut u16)>,
+}
+
+#[repr(C)]
+struct ServiceStatus {
+    service_type: u32,
+    current_state: u32,
+    controls_accepted: u32,
+    win32_exit_code: u32,
+    service_specific_exit_code: u32,
+    check_point: u32,
+    wait_hint: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct SystemTime {
+    year: u16,
+    month: u16,
+    day_of_week: u16,
+    day: u16,
+    hour: u16,
+    minute: u16,
+    second: u16,
+    milliseconds: u16,
+}
+
+const SERVICE_WIN32_OWN_PROCESS: u32 = 0x10;
+const SERVICE_STOPPED: u32 = 1;
+const SERVICE_STOP_PENDING: u32 = 3;
+const SERVICE_RUNNING: u32 = 4;
+const SERVICE_ACCEPT_STOP: u32 = 1;
+const SERVICE_ACCEPT_SHUTDOWN: u32 = 4;
+const SERVICE_CONTROL_STOP: u32 = 1;
+const SERVICE_CONTROL_INTERROGATE: u32 = 4;
+const SERVICE_CONTROL_SHUTDOWN: u32 = 5;
+const NO_ERROR: u32 = 0;
+const ERROR_CALL_NOT_IMPLEMENTED: u32 = 120;
+const EVENTLOG_ERROR_TYPE: u16 = 1;
+const EVENTLOG_WARNING_TYPE: u16 = 2;
+const EVENTLOG_INFORMATION_TYPE: u16 = 4;
+
+#[link(name = "advapi32")]
+extern "system" {
+    fn StartServiceCtrlDispatcherW(table: *const ServiceTableEntry) -> i32;
+    fn RegisterServiceCtrlHandlerExW(name: *const u16, handler: unsafe extern "system" fn(u32, u32, *mut c_void, *mut c_void) -> u32, context: *mut c_void) -> Handle;
+    fn SetServiceStatus(handle: Handle, status: *const ServiceStatus) -> i32;
+    fn RegisterEventSourceW(server: *const u16, source: *const u16) -> Handle;
+    #[allow(clippy::too_many_arguments)]
+    fn ReportEventW(log: Handle, kind: u16, category: u16, event_id: u32, sid: *mut c_void, strings: u16, data_size: u32, texts: *const *const u16, data: *mut c_void) -> i32;
+    fn DeregisterEventSource(log: Handle) -> i32;
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetLocalTime(time: *mut SystemTime);
+}
+
+// The service's state for the callbacks the service control manager makes,
+// which take no context of ours.
+static SERVICE: OnceLock<Service> = OnceLock::new();
+static STATUS: AtomicPtr<c_void> = AtomicPtr::new(ptr::null_mut());
+static STOP: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(Some(0)).collect()
+}
+
+// Quotes an argument so CommandLineToArgvW, and so the service's own
+// argument parsing, gives it back as it was.
+pub fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut out = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                out.push_str(&"\\".repeat(backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                out.push_str(&"\\".repeat(backslashes));
+                backslashes = 0;
+            }
+        }
+        if c != '\\' {
+            out.push(c);
+        }
+    }
+    out.push_str(&"\\".repeat(backslashes * 2));
+    out.push('"');
+    out
+}
+
+pub fn log_path(name: &str) -> PathBuf {
+    let data = env::var_os("ProgramData").map(PathBuf::from).unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"));
+    data.join("backup_auditor").join(format!("{}.log", name))
+}
+
+fn event_source_key(name: &str) -> String {
+    format!(r"HKLM\SYSTEM\CurrentControlSet\Services\EventLog\Application\{}", name)
+}
+
+fn run(program: &str, args: &[&str]) -> io::Result<String> {
+    let out = Command::new(program).args(args).output()?;
+    // sc.exe says what went wrong on stdout
+    let said = format!("{}{}", String::from_utf8_lossy(&out.stdout), String::from_utf8_lossy(&out.stderr));
+    if out.status.success() {
+        Ok(said)
+    } else {
+        Err(io::Error::other(format!("{} {} failed ({}): {}", program, args.join(" "), out.status, said.trim())))
+    }
+}
+
+fn state(name: &str) -> Option<String> {
+    let out = run("sc.exe", &["query", name]).ok()?;
+    let line = out.lines().find(|l| l.trim_start().starts_with("STATE"))?;
+    line.split_whitespace().last().map(String::from)
+}
+
+fn stop_and_wait(name: &str) {
+    if state(name).as_deref().map(|s| s == "STOPPED").unwrap_or(true) {
+        return;
+    }
+    let _ = run("sc.exe", &["stop", name]);
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while Instant::now() < deadline && state(name).as_deref() != Some("STOPPED") {
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+// Installs the service and starts it, replacing one of the same name; needs
+// an elevated prompt.
+pub fn install(service: &Service) -> io::Result<()> {
+    let exe = env::current_exe()?;
+    let mut command_line = vec![exe.to_string_lossy().into_owned(), String::from("service"), String::from("run")];
+    command_line.extend([String::from("--name"), service.name.clone()]);
+    command_line.extend([String::from("--at"), format!("{:02}:{:02}", service.schedule.hour, service.schedule.minute)]);
+    if let Some(day) = service.schedule.weekday {
+        command_line.extend([String::from("--weekday"), day.to_string()]);
+    }
+    command_line.extend([String::from("--dir"), service.working_dir.clone(), String::from("--")]);
+    command_line.extend(service.args.iter().cloned());
+    let bin_path = command_line.iter().map(|a| quote_arg(a)).collect::<Vec<_>>().join(" ");
+    let display = format!("Backup Auditor ({})", service.name);
+    if state(&service.name).is_some() {
+        stop_and_wait(&service.name);
+        run("sc.exe", &["config", &service.name, "binPath=", &bin_path, "start=", "auto", "DisplayName=", &display])?;
+    } else {
+        run("sc.exe", &["create", &service.name, "binPath=", &bin_path, "start=", "auto", "DisplayName=", &display])?;
+    }
+    run("sc.exe", &["description", &service.name, &format!("Runs backup audits {}; results go to the Application event log.", service.schedule)])?;
+    let key = event_source_key(&service.name);
+    run("reg.exe", &["add", &key, "/v", "EventMessageFile", "/t", "REG_EXPAND_SZ", "/d", r"%SystemRoot%\System32\EventCreate.exe", "/f"])?;
+    run("reg.exe", &["add", &key, "/v", "TypesSupported", "/t", "REG_DWORD", "/d", "7", "/f"])?;
+    run("sc.exe", &["start", &service.name])?;
+    Ok(())
+}
+
+pub fn uninstall(name: &str) -> io::Result<()> {
+    if state(name).is_none() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("no service named {}", name)));
+    }
+    stop_and_wait(name);
+    run("sc.exe", &["delete", name])?;
+    // earlier events stay readable without it, if less nicely
+    let _ = run("reg.exe", &["delete", &event_source_key(name), "/f"]);
+    Ok(())
+}
+
+// The service's state and the last audit it reported, from the service
+// control manager and the event log.
+pub fn status(name: &str) -> io::Result<String> {
+    let state = state(name).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no service named {}", name)))?;
+    let query = format!("*[System[Provider[@Name='{}']]]", name);
+    let last = run("wevtutil.exe", &["qe", "Application", &format!("/q:{}", query), "/c:1", "/rd:true", "/f:text"])?;
+    let last = match last.trim() {
+        "" => String::from("No audit has run yet"),
+        event => format!("Last audit:\n{}", event),
+    };
+    Ok(format!("Service {} is {}\nLog: {}\n{}\n", name, state, log_path(name).display(), last))
+}
+
+struct EventLog(Handle);
+
+impl EventLog {
+    fn open(name: &str) -> EventLog {
+        let source = wide(name);
+        EventLog(unsafe { RegisterEventSourceW(ptr::null(), source.as_ptr()) })
+    }
+
+    fn report(&self, kind: u16, id: u32, text: &str) {
+        if self.0.is_null() {
+            return;
+        }
+        let text = wide(text);
+        let texts = [text.as_ptr()];
+        unsafe {
+            ReportEventW(self.0, kind, 0, id, ptr::null_mut(), 1, 0, texts.as_ptr(), ptr::null_mut());
+        }
+    }
+}
+
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe {
+                DeregisterEventSource(self.0);
+            }
+        }
+    }
+}
+
+fn set_status(state: u32, wait_hint: Duration) {
+    let handle = STATUS.load(Ordering::SeqCst);
+    if handle.is_null() {
+        return;
+    }
+    let status = ServiceStatus {
+        service_type: SERVICE_WIN32_OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == SERVICE_RUNNING { SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN } else { 0 },
+        win32_exit_code: NO_ERROR,
+        service_specific_exit_code: 0,
+        check_point: 0,
+        wait_hint: wait_hint.as_millis() as u32,
+    };
+    unsafe {
+        SetServiceStatus(handle, &status);
+    }
+}
+
+// Waits up to `timeout` for the service to be told to stop; whether it was.
+fn stopping(timeout: Duration) -> bool {
+    let (stopped, told) = &STOP;
+    let guard = stopped.lock().unwrap();
+    *told.wait_timeout_while(guard, timeout, |stopped| !*stopped).unwrap().0
+}
+
+unsafe extern "system" fn control(control: u32, _event: u32, _data: *mut c_void, _context: *mut c_void) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            set_status(SERVICE_STOP_PENDING, Duration::from_secs(30));
+            *STOP.0.lock().unwrap() = true;
+            STOP.1.notify_all();
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let service = match SERVICE.get() {
+        Some(s) => s,
+        None => return,
+    };
+    let name = wide(&service.name);
+    let handle = RegisterServiceCtrlHandlerExW(name.as_ptr(), control, ptr::null_mut());
+    if handle.is_null() {
+        return;
+    }
+    STATUS.store(handle, Ordering::SeqCst);
+    set_status(SERVICE_RUNNING, Duration::ZERO);
+    serve(service);
+    set_status(SERVICE_STOPPED, Duration::ZERO);
+}
+
+// Runs the audit whenever its minute comes around, once a day at most; a
+// machine asleep or off at the time skips that run.
+fn serve(service: &Service) {
+    let events = EventLog::open(&service.name);
+    let mut last_day = None;
+    loop {
+        let mut now = SystemTime::default();
+        unsafe { GetLocalTime(&mut now) };
+        let day = (now.year, now.month, now.day);
+        if last_day != Some(day) && service.schedule.due(now.day_of_week as u8, now.hour as u8, now.minute as u8) {
+            last_day = Some(day);
+            let (kind, id, text) = audit(service);
+            events.report(kind, id, &text);
+        }
+        if stopping(TICK) {
+            return;
+        }
+    }
+}
+
+fn audit(service: &Service) -> (u16, u32, String) {
+    let log = log_path(&service.name);
+    let command = service.args.iter().map(|a| quote_arg(a)).collect::<Vec<_>>().join(" ");
+    let failed = |e: io::Error| (EVENTLOG_ERROR_TYPE, EVENT_FAILED, format!("The audit {} could not be started: {}", command, e));
+    let out = match log.parent().map(fs::create_dir_all).unwrap_or(Ok(())).and_then(|()| OpenOptions::new().append(true).create(true).open(&log)) {
+        Ok(out) => out,
+        Err(e) => return failed(e),
+    };
+    let spawned = env::current_exe().and_then(|exe| {
+        Command::new(exe)
+            .args(&service.args)
+            .current_dir(&service.working_dir)
+            .stdin(Stdio::null())
+            .stdout(out.try_clone()?)
+            .stderr(out)
+            .spawn()
+    });
+    let mut child = match spawned {
+        Ok(c) => c,
+        Err(e) => return failed(e),
+    };
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if stopping(Duration::from_secs(1)) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return (EVENTLOG_WARNING_TYPE, EVENT_FAILED, format!("The audit {} was stopped with the service before it finished", command));
+            }
+            Ok(None) => {}
+            Err(e) => return failed(e),
+        }
+    };
+    let log = log.display();
+    match status.code() {
+        Some(0) => (EVENTLOG_INFORMATION_TYPE, EVENT_MATCHED, format!("The audit {} found the backup matching its source. Output is in {}", command, log)),
+        Some(1) => (EVENTLOG_WARNING_TYPE, EVENT_DIFFERENT, format!("The audit {} found the backup differing from its source. Output is in {}", command, log)),
+        _ => (EVENTLOG_ERROR_TYPE, EVENT_FAILED, format!("The audit {} failed ({}). Output is in {}", command, status, log)),
+    }
+}
+
+// Hands the process over to the service control manager, returning once the
+// service has stopped. Fails straight away when not started by it.
+pub fn run_service(service: Service) -> io::Result<()> {
+    let mut name = wide(&service.name);
+    if SERVICE.set(service).is_err() {
+        return Err(io::Error::other("the service is already running"));
+    }
+    let table = [ServiceTableEntry { name: name.as_mut_ptr(), main: Some(service_main) }, ServiceTableEntry { name: ptr::null_mut(), main: None }];
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote_arg(r"C:\x\y"), r"C:\x\y");
+        assert_eq!(quote_arg(r"C:\My Files\"), r#""C:\My Files\\""#);
+        assert_eq!(quote_arg(r#"a "b""#), r#""a \"b\"""#);
+        assert_eq!(quote_arg(""), r#""""#);
+    }
+}